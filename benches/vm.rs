@@ -0,0 +1,84 @@
+// Compares the stack VM, running the bytecode of codegen, with the register VM, running the
+// code lowered from the IR, on loop-heavy and call-heavy programs. Run with `cargo bench`.
+
+extern crate xscript;
+use xscript::{codegen, ir, lexer, parser, regvm, vm};
+
+use std::time::{Duration, Instant};
+
+const FACT: &str = "def fact(n: int) -> int { if n <= 1 { 1 } else { n * fact(n - 1) } }
+                            i = 0
+                            while i < 20000 { fact(20) \n i += 1 }
+                            print(fact(20))";
+
+const FIB: &str = "def fib(n: int) -> int {
+                             a = 0
+                             b = 1
+                             while n > 0 {
+                               a, b = b, a + b
+                               n -= 1
+                             }
+                             a
+                           }
+                           def rfib(n: int) -> int {
+                             if n < 2 { n } else { rfib(n - 1) + rfib(n - 2) }
+                           }
+                           i = 0
+                           while i < 2000 { fib(90) \n i += 1 }
+                           print(fib(90) + rfib(24))";
+
+fn bench(name: &str, src: &str) {
+    let mut lexer = lexer::Lexer::new_from_string(src.to_string());
+    let mut parser = parser::Parser::new(&mut lexer);
+    let mut codegen = codegen::Codegen::new(&mut parser);
+    let nodes = codegen.read_program().unwrap();
+    codegen.gen_program(&nodes).unwrap();
+    let code = regvm::lower_program(&ir::lower_program(&nodes).unwrap());
+
+    let stack = time(|| {
+        let mut vm = vm::VM::new();
+        vm.output = Some(String::new());
+        while vm.pc < codegen.vm_insts.len() {
+            vm.step(&codegen.vm_insts).unwrap();
+        }
+        vm.output.unwrap()
+    });
+    let register = time(|| {
+        let mut vm = regvm::RegVM::new();
+        vm.output = Some(String::new());
+        vm.run(&code).unwrap();
+        vm.output.unwrap()
+    });
+    assert_eq!(stack.1, register.1);
+    println!(
+        "{:6} stack: {:8.2?}  register: {:8.2?}  ({:.2}x)",
+        name,
+        stack.0,
+        register.0,
+        secs(stack.0) / secs(register.0)
+    );
+}
+
+// The fastest of a few runs, and the output
+fn time<F: FnMut() -> String>(mut f: F) -> (Duration, String) {
+    let mut best = None;
+    let mut output = String::new();
+    for _ in 0..5 {
+        let start = Instant::now();
+        output = f();
+        let elapsed = start.elapsed();
+        if best.is_none_or(|best| elapsed < best) {
+            best = Some(elapsed);
+        }
+    }
+    (best.unwrap(), output)
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
+
+fn main() {
+    bench("fact", FACT);
+    bench("fib", FIB);
+}
@@ -0,0 +1,26 @@
+struct P { x: int, y: u8 }
+struct Box { p: P, tags: Map<string, int> }
+calls = 0
+m = {"a": 1}
+def key() -> string {
+  calls += 1
+  "a"
+}
+m[key()] += 10
+print(m["a"])
+print(calls)
+m["b"] = 5
+print(m["b"])
+p = P { x: 1, y: 250 }
+p.x += 1
+p.y += 10
+print(p.x)
+print(p.y)
+b = Box { p: p, tags: {"t": 1} }
+b.p.x = 7
+b.tags["t"] *= 3
+print(b.p.x)
+print(b.tags["t"])
+ms = {0: {"k": 1}}
+ms[0]["k"] <<= 4
+print(ms[0]["k"])
@@ -0,0 +1,14 @@
+enum Shape {
+  Circle(int)
+  Rect(int, int)
+  Empty
+}
+
+s = Shape::Rect(2, 3)
+area = match s {
+  Shape::Circle(r) => r * r * 3
+  Shape::Rect(w, h) if w == h => w * w
+  Shape::Rect(w, h) => w * h
+  Shape::Empty => 0
+}
+print(area)
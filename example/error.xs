@@ -0,0 +1,20 @@
+def div(a: int, b: int) -> int { a / b }
+def sqrt(n: int) -> int {
+  if n < 0 { throw "negative square root" } else {
+    r = 0
+    while (r + 1) * (r + 1) <= n { r = r + 1 }
+    r
+  }
+}
+print(try { div(10, 3) } catch e { 0 })
+print(try { div(10, 0) } catch e {
+  print(e)
+  0
+})
+print(try { sqrt(17) } catch e { -1 })
+print(try { sqrt(-4) } catch e {
+  print(e)
+  -1
+})
+ages = {"alice": 30}
+try { print(ages["bob"]) } catch e { print(e) }
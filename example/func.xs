@@ -1,4 +1,4 @@
-def fact:i32 x:i32 {
+def fact(x: i32) -> i32 {
   if x == 1 {
     1
   } else {
@@ -8,3 +8,14 @@ def fact:i32 x:i32 {
 
 print(fact(10))
 
+def make_counter() -> fn() -> int {
+  count = 0
+  fn() -> int {
+    count = count + 1
+    count
+  }
+}
+
+counter = make_counter()
+counter()
+print(counter())
@@ -0,0 +1,28 @@
+enum Option<T> { Some(T), None }
+struct Pair<A, B> { first: A, second: B }
+def id<T>(x: T) -> T { x }
+def swap<A, B>(p: Pair<A, B>) -> Pair<B, A> {
+  Pair { first: p.second, second: p.first }
+}
+def unwrap_or<T>(o: Option<T>, d: T) -> T {
+  match o {
+    Option::Some(x) => x,
+    Option::None => d,
+  }
+}
+def map<T, U>(o: Option<T>, f: fn(T) -> U) -> Option<U> {
+  match o {
+    Option::Some(x) => Option::Some(f(x)),
+    Option::None => Option::None,
+  }
+}
+print(id(3))
+print(id("hi"))
+p = swap(Pair { first: 1, second: "one" })
+print(p.first)
+print(p.second)
+n: Option<int> = Option::None
+print(unwrap_or(n, 7))
+print(unwrap_or(map(Option::Some(20), fn(x: int) -> string { "mapped" }), "none"))
+b: Option<bool> = Option::Some(true)
+print(match b { Option::Some(true) => 1, Option::Some(false) => 2, Option::None => 3 })
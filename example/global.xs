@@ -0,0 +1,13 @@
+const WIDTH = 4
+const GREETING = "hello"
+
+calls = 0
+
+def area(height: int) -> int {
+  calls += 1
+  WIDTH * height
+}
+
+print(area(2) + area(3))
+print(calls)
+print(GREETING)
@@ -1,6 +1,8 @@
 n = 1 * (2 + 3)
 if n < 1 {
-  print "n < 1"
+  print("n < 1")
 } else {
-  print "n >= 1"
+  print("n >= 1")
 }
+big = n > 3 && !(n == 4)
+print(big)
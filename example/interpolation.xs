@@ -0,0 +1,11 @@
+struct P { x: int, y: int }
+impl P {
+  def to_string(self) -> string { "(${self.x}, ${self.y})" }
+}
+name = "bob"
+n = 2
+print("hello ${name}, you have ${n + 1} items")
+print("p = ${P { x: 1, y: 2 }}!")
+print("${1.5} ${true} ${"nested ${n}"} $x {y}")
+m = {"a": 1}
+print("m: ${m} ${m["a"] * 2}")
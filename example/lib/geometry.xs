@@ -0,0 +1,15 @@
+pub struct Point { x: float, y: float }
+
+pub unit = 10.0
+
+def square(x: float) -> float {
+  x * x
+}
+
+pub def origin() -> Point {
+  Point { x: 0.0, y: 0.0 }
+}
+
+pub def dist2(a: Point, b: Point) -> float {
+  square(a.x - b.x) + square(a.y - b.y)
+}
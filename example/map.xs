@@ -0,0 +1,6 @@
+ages = {"bob": 30, "alice": 25}
+insert(ages, "carol", 41)
+for name, age in ages {
+  print(name)
+  print(age)
+}
@@ -0,0 +1,6 @@
+import "lib/geometry"
+
+use geometry::Point
+
+p = Point { x: 3.0 * geometry::unit, y: 4.0 * geometry::unit }
+print(geometry::dist2(geometry::origin(), p))
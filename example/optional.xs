@@ -0,0 +1,23 @@
+struct Point { x: int, y: int }
+impl Point {
+  def sum(self) -> int { self.x + self.y }
+}
+def find(m: Map<string, int>, k: string) -> int? {
+  if contains(m, k) { m[k] } else { none }
+}
+m = {"a": 1, "b": 2}
+print(find(m, "a") ?? 0)
+print(find(m, "z") ?? -1)
+if let v = find(m, "b") { print(v * 10) } else { print("missing") }
+if let v = find(m, "q") { print(v) } else { print("missing") }
+p: Point? = Point { x: 3, y: 4 }
+q: Point? = none
+print(p?.x ?? 0)
+print(q?.x ?? 0)
+print(p?.sum() ?? 0)
+n: int? = 5
+print(n == 5)
+print(n == none)
+x: int?? = none
+y: int? = none
+print((x ?? y) ?? 9)
@@ -0,0 +1,46 @@
+trait Display {
+  def to_string(self) -> string
+}
+trait Area {
+  def area(self) -> int
+  def scaled(self, k: int) -> Self
+}
+struct Rect { w: int, h: int }
+enum Option<T> { Some(T), None }
+impl Rect {
+  def new(w: int, h: int) -> Rect { Rect { w: w, h: h } }
+  def is_square(self) -> bool { self.w == self.h }
+}
+impl Display for Rect {
+  def to_string(self) -> string { "rect" }
+}
+impl Area for Rect {
+  def area(self) -> int { self.w * self.h }
+  def scaled(self, k: int) -> Rect { Rect { w: self.w * k, h: self.h * k } }
+}
+impl Display for int {
+  def to_string(self) -> string { if self > 9 { "many" } else { "few" } }
+}
+impl<T> Option<T> {
+  def unwrap_or(self, d: T) -> T {
+    match self { Option::Some(x) => x, Option::None => d }
+  }
+}
+def show<T: Display>(x: T) -> string { x.to_string() }
+def total<T: Area + Display>(x: T, k: int) -> int {
+  f = fn() { x.scaled(k).area() }
+  f()
+}
+def twice_show<U: Display>(x: U) -> string { show(x) }
+r = Rect::new(2, 3)
+print(r.is_square())
+print(r.area())
+print(show(r))
+print(show(12))
+print(twice_show(3))
+print(total(r, 2))
+n = 5
+print(n.to_string())
+o: Option<int> = Option::None
+print(o.unwrap_or(4))
+print(Option::Some(9).unwrap_or(4))
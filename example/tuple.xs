@@ -0,0 +1,15 @@
+def divmod(a: int, b: int) -> (int, int) { (a / b, a % b) }
+let (q, r) = divmod(17, 5)
+print(q)
+print(r)
+a = 1
+b = 2
+a, b = b, a
+print(a)
+print(b)
+p: (u8, string) = (3, "x")
+print(p.1)
+t = divmod(9, 4)
+print(t.0 + t.1)
+(x, (y, z)) = (1, (2, 3))
+print(x + y + z)
@@ -0,0 +1,584 @@
+use vm_base::{DebugInfo, VMInst};
+use typing::{FloatTy, IntTy};
+
+use std::collections::{BTreeMap, HashMap};
+
+// A textual form of bytecode, which `disassemble` writes and `assemble` reads:
+//
+//     add:
+//         2  Entry 2
+//         3  LoadV 0                  ; line 2: if a < b { a + b } else { 0 } (a)
+//         4  LoadV 1                  ; b
+//         5  Lt
+//         6  JmpIfFalse .L11
+//
+// An instruction is its name and its operands, separated by commas, optionally after its
+// address (which the assembler ignores). Jump targets and the entries of closures are
+// labels (defined by `label:` lines, for the instruction after them) or addresses.
+// Strings are quoted as in Rust, and comments run from `;` to the end of the line.
+
+pub fn disassemble(insts: &[VMInst], debug: &DebugInfo, source: &str) -> String {
+    let labels = labels(insts, debug);
+    let source: Vec<&str> = source.lines().collect();
+    let mut starts = HashMap::new();
+    // Nested statements come first, and are the ones to annotate
+    for &(ref pcs, line) in &debug.lines {
+        if !pcs.is_empty() {
+            starts.entry(pcs.start).or_insert(line);
+        }
+    }
+    let funcs = functions(insts);
+
+    let mut text = String::new();
+    let mut last_line = None;
+    for (pc, inst) in insts.iter().enumerate() {
+        if let Some(label) = labels.get(&pc) {
+            text.push_str(&format!("{}:\n", label));
+        }
+        // Each line is annotated where its code starts, not again for its nested statements
+        let mut comment = match starts.get(&pc) {
+            Some(&line) if last_line != Some(line) => {
+                last_line = Some(line);
+                match source.get(line - 1) {
+                    Some(src) => format!("line {}: {}", line, src.trim()),
+                    None => format!("line {}", line),
+                }
+            }
+            _ => String::new(),
+        };
+        let name = match *inst {
+            VMInst::LoadV(n)
+            | VMInst::StoreV(n)
+            | VMInst::MakeCell(n)
+            | VMInst::LoadC(n)
+            | VMInst::StoreC(n) => {
+                let entry = funcs.range(..pc + 1).next_back().map_or(0, |(_, &entry)| entry);
+                debug.locals.get(&entry).and_then(|locals| {
+                    locals.iter().find(|&&(_, id)| id == n).map(|(name, _)| name.clone())
+                })
+            }
+            VMInst::LoadG(n) | VMInst::StoreG(n) => debug.globals.get(n).cloned(),
+            _ => None,
+        };
+        if let Some(name) = name {
+            comment = if comment.is_empty() {
+                name
+            } else {
+                format!("{} ({})", comment, name)
+            };
+        }
+        let line = format!("{:>5}  {}", pc, inst_text(inst, &labels));
+        if comment.is_empty() {
+            text.push_str(&format!("{}\n", line));
+        } else {
+            text.push_str(&format!("{:<32}; {}\n", line, comment));
+        }
+    }
+    if let Some(label) = labels.get(&insts.len()) {
+        text.push_str(&format!("{}:\n", label));
+    }
+    text
+}
+
+pub fn assemble(src: &str) -> Result<Vec<VMInst>, String> {
+    let mut insts = vec![];
+    let mut labels = HashMap::new();
+    let mut fixups = vec![]; // The operands that are labels: instruction, line, labels
+    for (i, line) in src.lines().enumerate() {
+        let err = |msg: String| format!("line {}: {}", i + 1, msg);
+        let mut rest = strip_comment(line).trim();
+        while let Some(end) = rest.find(':') {
+            let label = &rest[..end];
+            if !is_label(label) {
+                break;
+            }
+            if labels.insert(label.to_string(), insts.len()).is_some() {
+                return Err(err(format!("label `{}` is already defined", label)));
+            }
+            rest = rest[end + 1..].trim();
+        }
+        if rest.starts_with(|c: char| c.is_ascii_digit()) {
+            rest = rest.trim_start_matches(|c: char| c.is_ascii_digit()).trim();
+        }
+        if rest.is_empty() {
+            continue;
+        }
+        let (name, operands) = match rest.find(char::is_whitespace) {
+            Some(end) => (&rest[..end], rest[end..].trim()),
+            None => (rest, ""),
+        };
+        let (inst, targets) = parse_inst(name, operands).map_err(&err)?;
+        if !targets.is_empty() {
+            fixups.push((insts.len(), i + 1, targets));
+        }
+        insts.push(inst);
+    }
+    for (pc, line, targets) in fixups {
+        let mut pcs = vec![];
+        for target in targets {
+            match labels.get(&target) {
+                Some(&pc) => pcs.push(pc),
+                None => return Err(format!("line {}: unknown label `{}`", line, target)),
+            }
+        }
+        let mut pcs = pcs.into_iter();
+        insts[pc] = match insts[pc] {
+            VMInst::Jmp(_) => VMInst::Jmp(pcs.next().unwrap()),
+            VMInst::JmpIfFalse(_) => VMInst::JmpIfFalse(pcs.next().unwrap()),
+            VMInst::IterNext(_) => VMInst::IterNext(pcs.next().unwrap()),
+            VMInst::Try(_) => VMInst::Try(pcs.next().unwrap()),
+            VMInst::MakeClosure(_, n) => VMInst::MakeClosure(pcs.next().unwrap(), n),
+            VMInst::JmpTable(ref table) => {
+                let resolve = |&dst: &usize| if dst == LABEL { pcs.next().unwrap() } else { dst };
+                VMInst::JmpTable(table.iter().map(resolve).collect())
+            }
+            ref inst => inst.clone(),
+        };
+    }
+    Ok(insts)
+}
+
+// Functions are labeled by their names (with their entry if another has the same name),
+// and the other targets of jumps by their addresses
+fn labels(insts: &[VMInst], debug: &DebugInfo) -> HashMap<usize, String> {
+    let mut entries = vec![];
+    let mut targets = vec![];
+    for inst in insts {
+        match *inst {
+            VMInst::MakeClosure(entry, _) => entries.push(entry),
+            VMInst::Jmp(dst)
+            | VMInst::JmpIfFalse(dst)
+            | VMInst::IterNext(dst)
+            | VMInst::Try(dst) => targets.push(dst),
+            VMInst::JmpTable(ref table) => targets.extend(table),
+            _ => {}
+        }
+    }
+    let name = |entry: &usize| -> String {
+        let name = debug.func_names.get(entry).map_or("", |name| name.as_str());
+        name.chars().filter(|&c| c.is_alphanumeric() || c == '_').collect()
+    };
+    let mut counts = HashMap::new();
+    entries.sort();
+    entries.dedup();
+    for entry in &entries {
+        *counts.entry(name(entry)).or_insert(0) += 1;
+    }
+    let mut labels = HashMap::new();
+    for entry in entries {
+        let name = name(&entry);
+        let label = if name.is_empty() {
+            format!("func@{}", entry)
+        } else if counts[&name] > 1 || name.starts_with(|c: char| c.is_ascii_digit()) {
+            format!("{}@{}", name, entry)
+        } else {
+            name
+        };
+        labels.insert(entry, label);
+    }
+    for dst in targets {
+        labels.entry(dst).or_insert_with(|| format!(".L{}", dst));
+    }
+    labels
+}
+
+// The first pc of each function, or of the rest of a function after a nested one, mapped
+// to the entry of the function. The code of a function made by Codegen is jumped over.
+fn functions(insts: &[VMInst]) -> BTreeMap<usize, usize> {
+    let mut entries: Vec<usize> = insts
+        .iter()
+        .filter_map(|inst| match *inst {
+            VMInst::MakeClosure(entry, _) => Some(entry),
+            _ => None,
+        })
+        .collect();
+    entries.sort();
+    entries.dedup();
+    let mut funcs = BTreeMap::new();
+    funcs.insert(0, 0);
+    for (i, &entry) in entries.iter().enumerate() {
+        let end = match insts.get(entry.wrapping_sub(1)) {
+            Some(&VMInst::Jmp(end)) if end > entry => end,
+            _ => entries.get(i + 1).cloned().unwrap_or(insts.len()),
+        };
+        let outer = funcs.range(..entry).next_back().map_or(0, |(_, &outer)| outer);
+        funcs.insert(entry, entry);
+        funcs.entry(end).or_insert(outer);
+    }
+    funcs
+}
+
+fn inst_text(inst: &VMInst, labels: &HashMap<usize, String>) -> String {
+    let label = |pc: usize| labels.get(&pc).cloned().unwrap_or_else(|| pc.to_string());
+    match *inst {
+        VMInst::PushB(b) => format!("PushB {}", b),
+        VMInst::PushI(n) => format!("PushI {}", n),
+        VMInst::PushU(n) => format!("PushU {}", n),
+        VMInst::PushF(f) => format!("PushF {:?}", f),
+        VMInst::PushS(ref s) => format!("PushS {:?}", s),
+        VMInst::Call(ref name) => format!("Call {}", name),
+        VMInst::IntCast(ty) => format!("IntCast {}", int_ty_name(ty)),
+        VMInst::FloatCast(ty) => format!("FloatCast {}", float_ty_name(ty)),
+        VMInst::StoreV(n) => format!("StoreV {}", n),
+        VMInst::LoadV(n) => format!("LoadV {}", n),
+        VMInst::StoreG(n) => format!("StoreG {}", n),
+        VMInst::LoadG(n) => format!("LoadG {}", n),
+        VMInst::MakeCell(n) => format!("MakeCell {}", n),
+        VMInst::StoreC(n) => format!("StoreC {}", n),
+        VMInst::LoadC(n) => format!("LoadC {}", n),
+        VMInst::StoreU(n) => format!("StoreU {}", n),
+        VMInst::LoadU(n) => format!("LoadU {}", n),
+        VMInst::CaptureU(n) => format!("CaptureU {}", n),
+        VMInst::MakeClosure(entry, n) => format!("MakeClosure {}, {}", label(entry), n),
+        VMInst::Apply(n) => format!("Apply {}", n),
+        VMInst::NewVariant(tag, n) => format!("NewVariant {}, {}", tag, n),
+        VMInst::Field(n) => format!("Field {}", n),
+        VMInst::SetField(n) => format!("SetField {}", n),
+        VMInst::NewMap(n) => format!("NewMap {}", n),
+        VMInst::IterNext(dst) => format!("IterNext {}", label(dst)),
+        VMInst::Jmp(dst) => format!("Jmp {}", label(dst)),
+        VMInst::JmpIfFalse(dst) => format!("JmpIfFalse {}", label(dst)),
+        VMInst::JmpTable(ref table) => {
+            let table: Vec<String> = table.iter().map(|&dst| label(dst)).collect();
+            format!("JmpTable {}", table.join(", "))
+        }
+        VMInst::Entry(n) => format!("Entry {}", n),
+        VMInst::Try(dst) => format!("Try {}", label(dst)),
+        // The rest have no operands
+        ref inst => format!("{:?}", inst),
+    }
+}
+
+// A target that is a label, until the label is resolved
+const LABEL: usize = !0;
+
+// The instruction, with the labels its targets refer to
+fn parse_inst(name: &str, operands: &str) -> Result<(VMInst, Vec<String>), String> {
+    let mut labels = vec![];
+    if name == "PushS" {
+        return Ok((VMInst::PushS(parse_string(operands)?), labels));
+    }
+    let args: Vec<&str> = if operands.is_empty() {
+        vec![]
+    } else {
+        operands.split(',').map(|arg| arg.trim()).collect()
+    };
+    let count = match name {
+        "MakeClosure" | "NewVariant" => 2,
+        "JmpTable" => args.len().max(1),
+        "Pop" | "Not" | "Neg" | "Add" | "Sub" | "Mul" | "Div" | "Rem" | "And" | "Or"
+        | "Xor" | "Shl" | "Shr" | "Eq" | "Ne" | "Lt" | "Gt" | "Le" | "Ge" | "Index"
+        | "SetIndex" | "Iter" | "Ret" | "EndTry" | "Throw" => 0,
+        "PushB" | "PushI" | "PushU" | "PushF" | "Call" | "IntCast" | "FloatCast" | "StoreV"
+        | "LoadV" | "StoreG" | "LoadG" | "MakeCell" | "StoreC" | "LoadC" | "StoreU" | "LoadU"
+        | "CaptureU" | "Apply" | "Field" | "SetField" | "NewMap" | "IterNext" | "Jmp"
+        | "JmpIfFalse" | "Entry" | "Try" => 1,
+        _ => return Err(format!("unknown instruction `{}`", name)),
+    };
+    if args.len() != count {
+        return Err(format!("`{}` takes {} operands, not {}", name, count, args.len()));
+    }
+    let num = |arg: &str| -> Result<usize, String> {
+        arg.parse().map_err(|_| format!("invalid operand `{}`", arg))
+    };
+    let mut target = |arg: &str| -> Result<usize, String> {
+        if is_label(arg) {
+            labels.push(arg.to_string());
+            Ok(LABEL)
+        } else {
+            num(arg)
+        }
+    };
+    let invalid = |arg: &str| format!("invalid operand `{}`", arg);
+    let inst = match name {
+        "PushB" => VMInst::PushB(args[0].parse().map_err(|_| invalid(args[0]))?),
+        "PushI" => VMInst::PushI(args[0].parse().map_err(|_| invalid(args[0]))?),
+        "PushU" => VMInst::PushU(args[0].parse().map_err(|_| invalid(args[0]))?),
+        "PushF" => VMInst::PushF(args[0].parse().map_err(|_| invalid(args[0]))?),
+        "Pop" => VMInst::Pop,
+        "Call" => VMInst::Call(args[0].to_string()),
+        "Not" => VMInst::Not,
+        "Neg" => VMInst::Neg,
+        "IntCast" => match parse_int_ty(args[0]) {
+            Some(ty) => VMInst::IntCast(ty),
+            None => return Err(invalid(args[0])),
+        },
+        "FloatCast" => match args[0] {
+            "f32" => VMInst::FloatCast(FloatTy::F32),
+            "f64" => VMInst::FloatCast(FloatTy::F64),
+            arg => return Err(invalid(arg)),
+        },
+        "Add" => VMInst::Add,
+        "Sub" => VMInst::Sub,
+        "Mul" => VMInst::Mul,
+        "Div" => VMInst::Div,
+        "Rem" => VMInst::Rem,
+        "And" => VMInst::And,
+        "Or" => VMInst::Or,
+        "Xor" => VMInst::Xor,
+        "Shl" => VMInst::Shl,
+        "Shr" => VMInst::Shr,
+        "Eq" => VMInst::Eq,
+        "Ne" => VMInst::Ne,
+        "Lt" => VMInst::Lt,
+        "Gt" => VMInst::Gt,
+        "Le" => VMInst::Le,
+        "Ge" => VMInst::Ge,
+        "StoreV" => VMInst::StoreV(num(args[0])?),
+        "LoadV" => VMInst::LoadV(num(args[0])?),
+        "StoreG" => VMInst::StoreG(num(args[0])?),
+        "LoadG" => VMInst::LoadG(num(args[0])?),
+        "MakeCell" => VMInst::MakeCell(num(args[0])?),
+        "StoreC" => VMInst::StoreC(num(args[0])?),
+        "LoadC" => VMInst::LoadC(num(args[0])?),
+        "StoreU" => VMInst::StoreU(num(args[0])?),
+        "LoadU" => VMInst::LoadU(num(args[0])?),
+        "CaptureU" => VMInst::CaptureU(num(args[0])?),
+        "MakeClosure" => VMInst::MakeClosure(target(args[0])?, num(args[1])?),
+        "Apply" => VMInst::Apply(num(args[0])?),
+        "NewVariant" => VMInst::NewVariant(num(args[0])?, num(args[1])?),
+        "Field" => VMInst::Field(num(args[0])?),
+        "SetField" => VMInst::SetField(num(args[0])?),
+        "NewMap" => VMInst::NewMap(num(args[0])?),
+        "Index" => VMInst::Index,
+        "SetIndex" => VMInst::SetIndex,
+        "Iter" => VMInst::Iter,
+        "IterNext" => VMInst::IterNext(target(args[0])?),
+        "Jmp" => VMInst::Jmp(target(args[0])?),
+        "JmpIfFalse" => VMInst::JmpIfFalse(target(args[0])?),
+        "JmpTable" => {
+            let mut table = vec![];
+            for arg in &args {
+                table.push(target(arg)?);
+            }
+            VMInst::JmpTable(table)
+        }
+        "Entry" => VMInst::Entry(num(args[0])?),
+        "Ret" => VMInst::Ret,
+        "Try" => VMInst::Try(target(args[0])?),
+        "EndTry" => VMInst::EndTry,
+        "Throw" => VMInst::Throw,
+        _ => unreachable!(),
+    };
+    Ok((inst, labels))
+}
+
+fn is_label(s: &str) -> bool {
+    !s.is_empty() && !s.starts_with(|c: char| c.is_ascii_digit())
+        && s.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.' || c == '@')
+}
+
+// Skips a `;` in a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_string(s: &str) -> Result<String, String> {
+    let invalid = || format!("invalid string {}", s);
+    if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+        return Err(invalid());
+    }
+    let mut string = String::new();
+    let mut chars = s[1..s.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        string.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some(c @ '\\') | Some(c @ '"') | Some(c @ '\'') => c,
+            Some('u') => {
+                let code: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                let code = u32::from_str_radix(code.trim_start_matches('{'), 16);
+                match code.ok().and_then(::std::char::from_u32) {
+                    Some(c) => c,
+                    None => return Err(invalid()),
+                }
+            }
+            _ => return Err(invalid()),
+        });
+    }
+    Ok(string)
+}
+
+fn int_ty_name(ty: IntTy) -> &'static str {
+    match ty {
+        IntTy::I8 => "i8",
+        IntTy::I16 => "i16",
+        IntTy::I32 => "i32",
+        IntTy::I64 => "i64",
+        IntTy::U8 => "u8",
+        IntTy::U16 => "u16",
+        IntTy::U32 => "u32",
+        IntTy::U64 => "u64",
+    }
+}
+
+fn parse_int_ty(name: &str) -> Option<IntTy> {
+    Some(match name {
+        "i8" => IntTy::I8,
+        "i16" => IntTy::I16,
+        "i32" => IntTy::I32,
+        "i64" => IntTy::I64,
+        "u8" => IntTy::U8,
+        "u16" => IntTy::U16,
+        "u32" => IntTy::U32,
+        "u64" => IntTy::U64,
+        _ => return None,
+    })
+}
+
+fn float_ty_name(ty: FloatTy) -> &'static str {
+    match ty {
+        FloatTy::F32 => "f32",
+        FloatTy::F64 => "f64",
+    }
+}
+
+#[test]
+fn test_disassemble() {
+    use vm::compile_source;
+    let src = "def add(a: int, b: int) -> int {
+  if a < b { a + b } else { 0 }
+}
+total = add(1, 2)
+print(\"a; b\")";
+    let (insts, debug) = compile_source(src, 0);
+    let text = disassemble(&insts, &debug, src);
+    assert_eq!(
+        text,
+        "    0  Entry 0
+    1  Jmp .L13                 ; line 1: def add(a: int, b: int) -> int {
+add:
+    2  Entry 2
+    3  LoadV 0                  ; line 2: if a < b { a + b } else { 0 } (a)
+    4  LoadV 1                  ; b
+    5  Lt
+    6  JmpIfFalse .L11
+    7  LoadV 0                  ; a
+    8  LoadV 1                  ; b
+    9  Add
+   10  Jmp .L12
+.L11:
+   11  PushI 0
+.L12:
+   12  Ret
+.L13:
+   13  MakeClosure add, 0
+   14  StoreG 0                 ; add
+   15  Pop
+   16  PushI 0
+   17  Pop
+   18  LoadG 0                  ; line 4: total = add(1, 2) (add)
+   19  PushI 1
+   20  PushI 2
+   21  Apply 2
+   22  StoreG 1                 ; total
+   23  Pop
+   24  PushS \"a; b\"             ; line 5: print(\"a; b\")
+   25  Call print
+   26  Pop
+   27  Ret
+"
+    );
+    assert_eq!(assemble(&text), Ok(insts));
+}
+
+#[test]
+fn test_assemble() {
+    use vm::{compile_source, VM};
+    // fact(5), and a division by zero caught
+    let src = "
+        Entry 0
+        Jmp main
+    fact:
+        Entry 1
+        LoadV 0
+        PushI 0
+        Eq
+        JmpIfFalse .recurse
+        PushI 1
+        Ret
+    .recurse:
+        LoadV 0
+        LoadG 0
+        LoadV 0
+        PushI 1
+        Sub
+        Apply 1         ; fact(n - 1)
+        Mul
+        Ret
+    main:
+        MakeClosure fact, 0
+        StoreG 0
+        PushI 5
+        Apply 1
+        Call print
+        Pop
+        Try .caught
+        PushI 1
+        PushI 0
+        Div
+        EndTry
+        Jmp .end
+    .caught:
+        PushS \"caught: \\\"\\u{3b1}\\\";\"
+        Call print
+    .end:
+        Pop
+        Ret
+    ";
+    let insts = assemble(src).unwrap();
+    assert_eq!(insts[1], VMInst::Jmp(17));
+    assert_eq!(insts[17], VMInst::MakeClosure(2, 0));
+    let mut vm = VM::new();
+    vm.output = Some(String::new());
+    vm.run(insts).unwrap();
+    assert_eq!(vm.output.unwrap(), "120\ncaught: \"\u{3b1}\";\n");
+
+    let errors = [
+        ("Jmp nowhere", "line 1: unknown label `nowhere`"),
+        ("Add 1", "line 1: `Add` takes 0 operands, not 1"),
+        ("IntCast i128", "line 1: invalid operand `i128`"),
+        ("\nPushS \"open", "line 2: invalid string \"open"),
+        ("a:\na:", "line 2: label `a` is already defined"),
+        ("Halt", "line 1: unknown instruction `Halt`"),
+    ];
+    for &(src, msg) in &errors {
+        assert_eq!(assemble(src), Err(msg.to_string()));
+    }
+
+    // Whatever Codegen makes is assembled back from its disassembly
+    let src = "enum Shape { Circle(int), Rect(int, int) }
+               def area(s: Shape) -> int {
+                 match s { Shape::Circle(r) => r * r * 3, Shape::Rect(w, h) => w * h }
+               }
+               def count(n: int) -> int {
+                 def down(i: int) -> int { if i == 0 { 0 } else { down(i - 1) + 1 } }
+                 down(n)
+               }
+               names = {1: \"one\", 2: \"two\"}
+               for k, v in names { print(v) }
+               half = 1.5 / 2.0
+               small:u8 = 250
+               print(try { 1 / (small - small) } catch e { print(e)\n 0 })
+               print(area(Shape::Rect(2, count(3))))";
+    let (insts, debug) = compile_source(src, 1);
+    let text = disassemble(&insts, &debug, src);
+    assert_eq!(assemble(&text), Ok(insts));
+}
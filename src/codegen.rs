@@ -1,9 +1,17 @@
 use parser::Parser;
-use node::{BinOp, Node, NodeKind};
-use vm_base::VMInst;
-use typing::Type;
+use module::ModuleLoader;
+use optimize::Optimizer;
+use ir;
+use node::{BinOp, FuncDef, MatchArm, Node, NodeKind, Pattern, PatternKind, UnaryOp};
+use vm_base::{DebugInfo, VMInst, Value};
+use vm::VM;
+use typing::{is_map_builtin, FloatTy, Type, TypeChecker, TypeError, TypeKind};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::slice;
 
 pub struct IdManager {
     counter: usize,
@@ -19,49 +27,315 @@ impl IdManager {
         self.counter += 1;
         id
     }
+
+    // The number of ids given out
+    pub fn count(&self) -> usize {
+        self.counter
+    }
 }
 
 pub type Id = usize;
 
+// The state of a function being compiled (the outermost one is the top level)
+struct FuncCtx {
+    id_manager: IdManager,
+    captured: HashSet<String>, // Locals with these names may be captured, so live in cells
+    upvalues: Vec<String>,     // Variables of enclosing functions, in the order of the cells
+    env: HashMap<String, Id>,  // The locals visible where the current nested function is
+    names: Vec<(String, Id)>,  // The named locals, for the debugger
+}
+
+impl FuncCtx {
+    fn new(body: &[Node]) -> FuncCtx {
+        let mut captured = HashSet::new();
+        for node in body {
+            captured_names(node, false, &mut captured);
+        }
+        FuncCtx {
+            id_manager: IdManager::new(),
+            captured,
+            upvalues: vec![],
+            env: HashMap::new(),
+            names: vec![],
+        }
+    }
+}
+
+enum Var {
+    Local(Id),
+    Cell(Id), // A local kept in a cell
+    Upvalue(usize),
+    Global(usize),
+    Const(Value),
+}
+
 pub struct Codegen<'a> {
     pub parser: &'a mut Parser<'a>,
-    pub id_manager: IdManager,
     pub vm_insts: Vec<VMInst>,
+    pub search_path: Vec<PathBuf>, // Where `import`ed modules are looked for
+    pub opt_level: u32,            // 0 disables Optimizer
+    pub via_ir: bool,              // Compile through the IR, if it supports the program
+    pub debug: DebugInfo,
+    enums: HashMap<String, Vec<String>>, // enum name -> variant names (in tag order)
+    structs: HashMap<String, Vec<String>>, // struct name -> field names (in field order)
+    funcs: Vec<FuncCtx>,
+    globals: HashMap<String, usize>, // Variables declared at the top level, outside blocks
+    consts: HashMap<String, Value>,
+    blocks: usize, // The number of blocks enclosing the code being generated
+    imported: usize, // The number of nodes of modules, at the start of the program
+    in_module: bool, // Whether generating the code of a module, which has no lines
 }
 
 impl<'a> Codegen<'a> {
     pub fn new(parser: &'a mut Parser<'a>) -> Codegen<'a> {
         Codegen {
             parser: parser,
-            id_manager: IdManager::new(),
             vm_insts: Vec::new(),
+            search_path: vec![],
+            opt_level: 1,
+            via_ir: false,
+            debug: DebugInfo::default(),
+            enums: HashMap::new(),
+            structs: HashMap::new(),
+            funcs: vec![],
+            globals: HashMap::new(),
+            consts: HashMap::new(),
+            blocks: 0,
+            imported: 0,
+            in_module: false,
         }
     }
 }
 
 impl<'a> Codegen<'a> {
-    pub fn gen(&mut self) {
+    pub fn gen(&mut self) -> Result<(), TypeError> {
+        let nodes = self.read_program()?;
+        self.gen_program(&nodes)
+    }
+
+    // Parses the program and the modules it imports, and type checks and optimizes them
+    pub fn read_program(&mut self) -> Result<Vec<Node>, TypeError> {
+        let nodes = self.parser.read_all()?;
+        let mut loader = ModuleLoader::new(self.search_path.clone());
+        let mut nodes = loader.load(nodes, self.parser.path())?;
+        self.imported = loader.imported;
+        TypeChecker::new().check(&mut nodes)?;
+        if self.opt_level > 0 {
+            Optimizer::new(|node: &Node| self.eval_const(node)).optimize(&mut nodes);
+        }
+        Ok(nodes)
+    }
+
+    pub fn gen_program(&mut self, nodes: &[Node]) -> Result<(), TypeError> {
+        // The programs the IR does not support yet are compiled from the nodes
+        if self.via_ir {
+            if let Ok(program) = ir::lower_program(nodes) {
+                self.vm_insts = program.to_bytecode();
+                return Ok(());
+            }
+        }
+
+        // Constants are evaluated in order, as they only refer to those before them
+        for node in nodes {
+            if let NodeKind::Const(ref name, _, ref value) = node.kind {
+                let val = self.eval_const(value).map_err(|msg| {
+                    TypeError::new(
+                        format!("cannot evaluate constant `{}`: {}", name, msg),
+                        node.range.clone(),
+                    )
+                })?;
+                self.consts.insert(name.clone(), val);
+            }
+        }
+
+        for node in nodes {
+            match node.kind {
+                NodeKind::Enum(ref name, _, ref variants) => {
+                    self.enums.insert(
+                        name.clone(),
+                        variants.iter().map(|v| v.name.clone()).collect(),
+                    );
+                }
+                NodeKind::Struct(ref name, _, ref fields) => {
+                    self.structs.insert(
+                        name.clone(),
+                        fields.iter().map(|(f, _)| f.clone()).collect(),
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        // The top-level functions have their globals before any body is compiled, so that
+        // they can call each other
+        for node in nodes {
+            if let NodeKind::Func(FuncDef {
+                name: Some(ref name),
+                ..
+            }) = node.kind
+            {
+                if !self.globals.contains_key(name) {
+                    self.new_global(name);
+                }
+            }
+        }
+
         let mut local_env = HashMap::new();
-        while let Ok(node) = self.parser.get_node() {
-            self.gen_inst(&node, &mut local_env).unwrap();
+        self.funcs.push(FuncCtx::new(nodes));
+        self.vm_insts.push(VMInst::Entry(0)); // The number of locals is known after codegen
+        for (i, node) in nodes.iter().enumerate() {
+            self.in_module = i < self.imported;
+            self.gen_stmt(node, &mut local_env)?;
+            self.vm_insts.push(VMInst::Pop);
+        }
+        self.in_module = false;
+        let top_level = self.funcs.pop().unwrap();
+        self.vm_insts[0] = VMInst::Entry(top_level.id_manager.count());
+        self.debug.locals.insert(0, top_level.names);
+        self.debug.globals = vec![String::new(); self.globals.len()];
+        for (name, &n) in &self.globals {
+            self.debug.globals[n] = name.clone();
         }
-        self.vm_insts.insert(0, VMInst::Entry(local_env.len()));
         self.vm_insts.push(VMInst::Ret);
+        Ok(())
+    }
+
+    // Generates a statement, recording its instructions and line for the debugger
+    fn gen_stmt(
+        &mut self,
+        node: &Node,
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        let start = self.vm_insts.len();
+        self.gen_inst(node, local_env)?;
+        if !self.in_module {
+            let line = self.parser.line_of(node.range.start);
+            self.debug.lines.push((start..self.vm_insts.len(), line));
+        }
+        Ok(())
     }
 
-    pub fn gen_inst(&mut self, node: &Node, local_env: &mut HashMap<String, Id>) -> Result<(), ()> {
+    pub fn gen_inst(
+        &mut self,
+        node: &Node,
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
         match node.kind {
-            NodeKind::Int(n) => self.vm_insts.push(VMInst::PushI(n)),
-            NodeKind::Float(f) => self.vm_insts.push(VMInst::PushF(f)),
+            NodeKind::Bool(b) => self.vm_insts.push(VMInst::PushB(b)),
+            NodeKind::Int(n) => self.gen_int(n, &node.ty),
+            NodeKind::Float(f) => self.vm_insts.push(match node.ty {
+                Some(Type {
+                    kind: TypeKind::Float(FloatTy::F32),
+                }) => VMInst::PushF(f as f32 as f64),
+                _ => VMInst::PushF(f),
+            }),
             NodeKind::String(ref s) => self.vm_insts.push(VMInst::PushS(s.clone())),
-            NodeKind::Variable(ref name, ref ty) => self.gen_variable(name, ty, local_env)?,
+            NodeKind::Variable(ref name, ref ty) => {
+                self.gen_variable(name, ty, &node.range, local_env)?
+            }
+            NodeKind::Path(ref path) => self.gen_variant(path, &[], local_env)?,
+            NodeKind::UnaryOp(ref expr, UnaryOp::Not) => {
+                self.gen_inst(expr, local_env)?;
+                self.vm_insts.push(VMInst::Not);
+            }
+            NodeKind::UnaryOp(ref expr, UnaryOp::Neg) => {
+                self.gen_inst(expr, local_env)?;
+                self.vm_insts.push(VMInst::Neg);
+                self.gen_wrap(&node.ty);
+            }
+            NodeKind::Cast(ref expr, ref ty) => {
+                self.gen_inst(expr, local_env)?;
+                match ty.kind {
+                    TypeKind::Int(ty) => self.vm_insts.push(VMInst::IntCast(ty)),
+                    TypeKind::Float(ty) => self.vm_insts.push(VMInst::FloatCast(ty)),
+                    _ => {}
+                }
+            }
             NodeKind::BinaryOp(ref lhs, ref rhs, BinOp::Assign) => {
                 self.gen_store(&*lhs, &*rhs, local_env)?
             }
+            NodeKind::BinaryOp(ref lhs, ref rhs, BinOp::LAnd) => {
+                self.gen_logical(lhs, rhs, true, local_env)?
+            }
+            NodeKind::BinaryOp(ref lhs, ref rhs, BinOp::LOr) => {
+                self.gen_logical(lhs, rhs, false, local_env)?
+            }
+            NodeKind::BinaryOp(ref lhs, ref rhs, BinOp::Coalesce) => {
+                self.gen_coalesce(lhs, rhs, lhs.ty == node.ty, local_env)?
+            }
             NodeKind::BinaryOp(ref lhs, ref rhs, ref op) => {
-                self.gen_binop(&*lhs, &*rhs, &*op, local_env)?
+                self.gen_binop(lhs, rhs, op, &node.ty, local_env)?
+            }
+            NodeKind::AssignOp(ref lhs, ref rhs, ref op) => {
+                self.gen_assign_op(lhs, rhs, op, &node.ty, local_env)?
+            }
+            NodeKind::Apply(ref callee, ref args) => self.gen_apply(callee, args, local_env)?,
+            NodeKind::Index(ref map, ref key) => {
+                self.gen_inst(map, local_env)?;
+                self.gen_inst(key, local_env)?;
+                self.vm_insts.push(VMInst::Index);
+            }
+            // A tuple is laid out like a struct
+            NodeKind::Tuple(ref elems) => {
+                for elem in elems {
+                    self.gen_inst(elem, local_env)?;
+                }
+                self.vm_insts.push(VMInst::NewVariant(0, elems.len()));
+            }
+            NodeKind::Map(ref pairs) => {
+                for (key, value) in pairs {
+                    self.gen_inst(key, local_env)?;
+                    self.gen_inst(value, local_env)?;
+                }
+                self.vm_insts.push(VMInst::NewMap(pairs.len()));
+            }
+            NodeKind::For(ref vars, ref iterable, ref body) => {
+                self.gen_for(vars, iterable, body, local_env)?
+            }
+            NodeKind::If(ref cond, ref then_, ref else_) => {
+                self.gen_if(cond, then_, else_, local_env)?
+            }
+            NodeKind::While(ref cond, ref body) => self.gen_while(cond, body, local_env)?,
+            NodeKind::Block(ref nodes) => self.gen_block(nodes, local_env)?,
+            NodeKind::Match(ref cond, ref arms) => self.gen_match(cond, arms, local_env)?,
+            NodeKind::Func(ref func) => self.gen_func(func, local_env)?,
+            // An optional is the variant 0 (`none`) or 1 (with the value as its field)
+            NodeKind::None => self.vm_insts.push(VMInst::NewVariant(0, 0)),
+            NodeKind::Wrap(ref expr) => {
+                self.gen_inst(expr, local_env)?;
+                self.vm_insts.push(VMInst::NewVariant(1, 1));
+            }
+            NodeKind::IfLet(ref name, ref expr, ref then_, ref else_) => {
+                self.gen_if_let(name, expr, then_, Some(&**else_), local_env)?
+            }
+            NodeKind::OptChain(ref name, ref expr, ref rest) => {
+                self.gen_if_let(name, expr, rest, None, local_env)?
+            }
+            NodeKind::Try(ref body, ref name, ref handler) => {
+                self.gen_try(body, name, handler, local_env)?
             }
-            _ => {}
+            NodeKind::Throw(ref expr) => {
+                self.gen_inst(expr, local_env)?;
+                self.vm_insts.push(VMInst::Throw);
+            }
+            NodeKind::Impl(ref impl_) => {
+                // Methods are plain functions, named `Type::method` by the type checker
+                for method in &impl_.methods {
+                    self.gen_func(method, local_env)?;
+                    self.vm_insts.push(VMInst::Pop);
+                }
+                self.vm_insts.push(VMInst::PushI(0));
+            }
+            NodeKind::StructLit(ref name, ref fields) => {
+                self.gen_struct_lit(name, fields, local_env)?
+            }
+            NodeKind::Field(ref expr, ref name) => {
+                self.gen_inst(expr, local_env)?;
+                let index = self.field_index(expr.ty.as_ref().unwrap(), name);
+                self.vm_insts.push(VMInst::Field(index));
+            }
+            // Declarations, which have no value
+            _ => self.vm_insts.push(VMInst::PushI(0)),
         };
         Ok(())
     }
@@ -71,31 +345,226 @@ impl<'a> Codegen<'a> {
         lhs: &Node,
         rhs: &Node,
         op: &BinOp,
+        ty: &Option<Type>,
         local_env: &mut HashMap<String, Id>,
-    ) -> Result<(), ()> {
+    ) -> Result<(), TypeError> {
         self.gen_inst(lhs, local_env)?;
         self.gen_inst(rhs, local_env)?;
-        self.vm_insts.push(match op {
-            &BinOp::Add => VMInst::Add,
-            &BinOp::Sub => VMInst::Sub,
-            &BinOp::Mul => VMInst::Mul,
-            &BinOp::Div => VMInst::Div,
-            &BinOp::Rem => VMInst::Rem,
-            _ => unimplemented!(),
-        });
+        self.gen_op(op, ty);
+        Ok(())
+    }
+
+    // Applies `op` to the two values on the top of the stack, giving a value of type `ty`
+    fn gen_op(&mut self, op: &BinOp, ty: &Option<Type>) {
+        self.vm_insts.push(binop_inst(op));
+        if wraps(op) {
+            self.gen_wrap(ty)
+        }
+    }
+
+    fn gen_int(&mut self, n: i64, ty: &Option<Type>) {
+        self.vm_insts.push(match ty.as_ref().map(|ty| &ty.kind) {
+            Some(&TypeKind::Int(ty)) if !ty.is_signed() => VMInst::PushU(n as u64),
+            _ => VMInst::PushI(n),
+        })
+    }
+
+    // Brings the result of arithmetic back into the range of a type narrower than 64 bits
+    fn gen_wrap(&mut self, ty: &Option<Type>) {
+        if let Some(inst) = ty.as_ref().and_then(wrap_inst) {
+            self.vm_insts.push(inst)
+        }
+    }
+
+    // `a && b` is `if a { b } else { false }`, `a || b` is `if a { true } else { b }`
+    pub fn gen_logical(
+        &mut self,
+        lhs: &Node,
+        rhs: &Node,
+        is_and: bool,
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        self.gen_inst(lhs, local_env)?;
+        let cond_pos = self.vm_insts.len();
+        self.vm_insts.push(VMInst::JmpIfFalse(0));
+        if is_and {
+            self.gen_inst(rhs, local_env)?;
+        } else {
+            self.vm_insts.push(VMInst::PushB(true));
+        }
+        let end_jump = self.vm_insts.len();
+        self.vm_insts.push(VMInst::Jmp(0));
+        let else_pos = self.vm_insts.len();
+        self.patch_jump(cond_pos, else_pos);
+        if is_and {
+            self.vm_insts.push(VMInst::PushB(false));
+        } else {
+            self.gen_inst(rhs, local_env)?;
+        }
+        let end_pos = self.vm_insts.len();
+        self.patch_jump(end_jump, end_pos);
+        Ok(())
+    }
+
+    pub fn gen_if(
+        &mut self,
+        cond: &Node,
+        then_: &Node,
+        else_: &Node,
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        self.gen_inst(cond, local_env)?;
+        let cond_pos = self.vm_insts.len();
+        self.vm_insts.push(VMInst::JmpIfFalse(0));
+        self.gen_inst(then_, local_env)?;
+        let end_jump = self.vm_insts.len();
+        self.vm_insts.push(VMInst::Jmp(0));
+        let else_pos = self.vm_insts.len();
+        self.patch_jump(cond_pos, else_pos);
+        self.gen_inst(else_, local_env)?;
+        let end_pos = self.vm_insts.len();
+        self.patch_jump(end_jump, end_pos);
+        Ok(())
+    }
+
+    // Binds the value of the optional `expr` to `name` in `then_` if there is one, or else
+    // evaluates `else_` (`none` if omitted)
+    pub fn gen_if_let(
+        &mut self,
+        name: &str,
+        expr: &Node,
+        then_: &Node,
+        else_: Option<&Node>,
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        let optional = self.gen_optional(expr, local_env)?;
+        let table_pos = self.vm_insts.len() - 1;
+        let then_pos = self.vm_insts.len();
+        let mut env = local_env.clone();
+        let id = self.new_local(name);
+        env.insert(name.to_string(), id);
+        self.vm_insts.push(VMInst::LoadV(optional));
+        self.vm_insts.push(VMInst::Field(0));
+        self.gen_declare(name, id);
+        self.vm_insts.push(VMInst::Pop);
+        self.gen_inst(then_, &mut env)?;
+        let jmp_pos = self.vm_insts.len();
+        self.vm_insts.push(VMInst::Jmp(0));
+        let else_pos = self.vm_insts.len();
+        match else_ {
+            Some(else_) => self.gen_inst(else_, local_env)?,
+            None => self.vm_insts.push(VMInst::NewVariant(0, 0)),
+        }
+        self.vm_insts[table_pos] = VMInst::JmpTable(vec![else_pos, then_pos]);
+        let end_pos = self.vm_insts.len();
+        self.patch_jump(jmp_pos, end_pos);
+        Ok(())
+    }
+
+    // The VM pushes the thrown value before jumping to the handler
+    pub fn gen_try(
+        &mut self,
+        body: &Node,
+        name: &str,
+        handler: &Node,
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        let try_pos = self.vm_insts.len();
+        self.vm_insts.push(VMInst::Try(0));
+        self.gen_inst(body, local_env)?;
+        self.vm_insts.push(VMInst::EndTry);
+        let jmp_pos = self.vm_insts.len();
+        self.vm_insts.push(VMInst::Jmp(0));
+        let catch_pos = self.vm_insts.len();
+        self.patch_jump(try_pos, catch_pos);
+        let mut env = local_env.clone();
+        let id = self.new_local(name);
+        env.insert(name.to_string(), id);
+        self.gen_declare(name, id);
+        self.vm_insts.push(VMInst::Pop);
+        self.gen_inst(handler, &mut env)?;
+        let end_pos = self.vm_insts.len();
+        self.patch_jump(jmp_pos, end_pos);
+        Ok(())
+    }
+
+    // `lhs ?? rhs`; the value of `lhs` is unwrapped unless `rhs` is optional too
+    pub fn gen_coalesce(
+        &mut self,
+        lhs: &Node,
+        rhs: &Node,
+        optional_rhs: bool,
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        let optional = self.gen_optional(lhs, local_env)?;
+        let table_pos = self.vm_insts.len() - 1;
+        let some_pos = self.vm_insts.len();
+        self.vm_insts.push(VMInst::LoadV(optional));
+        if !optional_rhs {
+            self.vm_insts.push(VMInst::Field(0));
+        }
+        let jmp_pos = self.vm_insts.len();
+        self.vm_insts.push(VMInst::Jmp(0));
+        let none_pos = self.vm_insts.len();
+        self.gen_inst(rhs, local_env)?;
+        self.vm_insts[table_pos] = VMInst::JmpTable(vec![none_pos, some_pos]);
+        let end_pos = self.vm_insts.len();
+        self.patch_jump(jmp_pos, end_pos);
+        Ok(())
+    }
+
+    // Stores the value of the optional `expr` in a hidden local, followed by a jump table
+    // (to be filled in) on whether there is a value
+    fn gen_optional(
+        &mut self,
+        expr: &Node,
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<Id, TypeError> {
+        self.gen_inst(expr, local_env)?;
+        let id = self.new_id();
+        self.vm_insts.push(VMInst::StoreV(id));
+        self.vm_insts.push(VMInst::JmpTable(vec![]));
+        Ok(id)
+    }
+
+    pub fn gen_while(
+        &mut self,
+        cond: &Node,
+        body: &Node,
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        let loop_pos = self.vm_insts.len();
+        self.gen_inst(cond, local_env)?;
+        let cond_pos = self.vm_insts.len();
+        self.vm_insts.push(VMInst::JmpIfFalse(0));
+        self.gen_inst(body, local_env)?;
+        self.vm_insts.push(VMInst::Pop);
+        self.vm_insts.push(VMInst::Jmp(loop_pos));
+        let end_pos = self.vm_insts.len();
+        self.patch_jump(cond_pos, end_pos);
+        self.vm_insts.push(VMInst::PushI(0));
         Ok(())
     }
 
     pub fn gen_variable(
         &mut self,
-        name: &String,
+        name: &str,
         ty: &Option<Type>,
+        range: &Range<usize>,
         local_env: &mut HashMap<String, Id>,
-    ) -> Result<(), ()> {
-        if let Some(id) = local_env.get(name) {
-            self.vm_insts.push(VMInst::LoadV(*id))
-        } else {
-            panic!("TODO: implement err handler");
+    ) -> Result<(), TypeError> {
+        match self.lookup_var(name, local_env) {
+            Some(Var::Local(id)) => self.vm_insts.push(VMInst::LoadV(id)),
+            Some(Var::Cell(id)) => self.vm_insts.push(VMInst::LoadC(id)),
+            Some(Var::Upvalue(i)) => self.vm_insts.push(VMInst::LoadU(i)),
+            Some(Var::Global(n)) => self.vm_insts.push(VMInst::LoadG(n)),
+            Some(Var::Const(val)) => self.gen_const(val),
+            None => {
+                return Err(TypeError::new(
+                    format!("undefined variable `{}`", name),
+                    range.clone(),
+                ))
+            }
         }
         Ok(())
     }
@@ -105,17 +574,710 @@ impl<'a> Codegen<'a> {
         lhs: &Node,
         rhs: &Node,
         local_env: &mut HashMap<String, Id>,
-    ) -> Result<(), ()> {
-        let var_id = match lhs.kind {
-            NodeKind::Variable(ref name, _) => *local_env
-                .entry(name.clone())
-                .or_insert_with(|| self.id_manager.get_id()),
-            _ => unimplemented!(),
-        };
-        self.gen_inst(rhs, local_env)?;
-        self.vm_insts.push(VMInst::StoreV(var_id));
+    ) -> Result<(), TypeError> {
+        match lhs.kind {
+            NodeKind::Index(ref map, ref key) => {
+                self.gen_inst(map, local_env)?;
+                self.gen_inst(key, local_env)?;
+                self.gen_inst(rhs, local_env)?;
+                self.vm_insts.push(VMInst::SetIndex);
+            }
+            NodeKind::Field(ref expr, ref name) => {
+                self.gen_inst(expr, local_env)?;
+                self.gen_inst(rhs, local_env)?;
+                let index = self.field_index(expr.ty.as_ref().unwrap(), name);
+                self.vm_insts.push(VMInst::SetField(index));
+            }
+            _ => {
+                self.gen_inst(rhs, local_env)?;
+                self.gen_assign_to(lhs, local_env)?;
+            }
+        }
         Ok(())
     }
+
+    // `lhs op= rhs`. The map and key, or the object, of `lhs` are kept in hidden locals
+    // to read and then write the element without evaluating them twice.
+    pub fn gen_assign_op(
+        &mut self,
+        lhs: &Node,
+        rhs: &Node,
+        op: &BinOp,
+        ty: &Option<Type>,
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        match lhs.kind {
+            NodeKind::Index(ref map, ref key) => {
+                let map_id = self.new_id();
+                let key_id = self.new_id();
+                self.gen_inst(map, local_env)?;
+                self.vm_insts.push(VMInst::StoreV(map_id));
+                self.gen_inst(key, local_env)?;
+                self.vm_insts.push(VMInst::StoreV(key_id));
+                self.vm_insts.push(VMInst::LoadV(map_id));
+                self.vm_insts.push(VMInst::LoadV(key_id));
+                self.vm_insts.push(VMInst::Index);
+                self.gen_inst(rhs, local_env)?;
+                self.gen_op(op, ty);
+                self.vm_insts.push(VMInst::SetIndex);
+            }
+            NodeKind::Field(ref expr, ref name) => {
+                let index = self.field_index(expr.ty.as_ref().unwrap(), name);
+                let obj_id = self.new_id();
+                self.gen_inst(expr, local_env)?;
+                self.vm_insts.push(VMInst::StoreV(obj_id));
+                self.vm_insts.push(VMInst::LoadV(obj_id));
+                self.vm_insts.push(VMInst::Field(index));
+                self.gen_inst(rhs, local_env)?;
+                self.gen_op(op, ty);
+                self.vm_insts.push(VMInst::SetField(index));
+            }
+            _ => {
+                self.gen_binop(lhs, rhs, op, ty, local_env)?;
+                self.gen_assign_to(lhs, local_env)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Assigns the value on the top of the stack, which is left there, to `target`
+    fn gen_assign_to(
+        &mut self,
+        target: &Node,
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        match target.kind {
+            NodeKind::Variable(ref name, _) => match self.lookup_var(name, local_env) {
+                Some(var) => self.gen_set(var),
+                None if self.at_top_level() => {
+                    let n = self.new_global(name);
+                    self.vm_insts.push(VMInst::StoreG(n))
+                }
+                None => {
+                    let id = self.new_local(name);
+                    local_env.insert(name.clone(), id);
+                    self.gen_declare(name, id)
+                }
+            },
+            // The elements of the tuple are taken out of a hidden local, so that the values
+            // of all of them are read before any variable is assigned
+            NodeKind::Tuple(ref targets) => {
+                let tuple = self.new_id();
+                self.vm_insts.push(VMInst::StoreV(tuple));
+                for (i, target) in targets.iter().enumerate() {
+                    self.vm_insts.push(VMInst::LoadV(tuple));
+                    self.vm_insts.push(VMInst::Field(i));
+                    self.gen_assign_to(target, local_env)?;
+                    self.vm_insts.push(VMInst::Pop);
+                }
+            }
+            // Rejected by TypeChecker, unless the AST was made some other way
+            _ => {
+                let what = match target.kind {
+                    NodeKind::Apply(_, _) => "a call",
+                    NodeKind::Bool(_)
+                    | NodeKind::Int(_)
+                    | NodeKind::Float(_)
+                    | NodeKind::String(_) => "a literal",
+                    _ => "this expression",
+                };
+                return Err(TypeError::new(
+                    format!("cannot assign to {}", what),
+                    target.range.clone(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn gen_apply(
+        &mut self,
+        callee: &Node,
+        args: &[Node],
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        match callee.kind {
+            NodeKind::Variable(ref name, _)
+                if (name == "print" || name == "to_string" || is_map_builtin(name))
+                    && self.lookup_var(name, local_env).is_none() =>
+            {
+                for arg in args {
+                    self.gen_inst(arg, local_env)?;
+                }
+                self.vm_insts.push(VMInst::Call(name.clone()));
+            }
+            NodeKind::Path(ref path) => self.gen_variant(path, args, local_env)?,
+            _ => {
+                self.gen_inst(callee, local_env)?;
+                for arg in args {
+                    self.gen_inst(arg, local_env)?;
+                }
+                self.vm_insts.push(VMInst::Apply(args.len()));
+            }
+        }
+        Ok(())
+    }
+
+    // The body of a function is emitted in place (and jumped over), followed by the
+    // code creating the closure from the cells of the variables it captures.
+    // Generic functions are compiled only once, as every value has the same representation.
+    pub fn gen_func(
+        &mut self,
+        func: &FuncDef,
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        // A named function is declared first, so that it can call itself
+        if let Some(ref name) = func.name {
+            if self.at_top_level() {
+                if !self.globals.contains_key(name) {
+                    self.new_global(name);
+                }
+            } else {
+                let id = self.new_local(name);
+                local_env.insert(name.clone(), id);
+                if self.funcs.last().unwrap().captured.contains(name) {
+                    self.vm_insts.push(VMInst::PushI(0));
+                    self.vm_insts.push(VMInst::MakeCell(id));
+                    self.vm_insts.push(VMInst::Pop);
+                }
+            }
+        }
+
+        let skip_pos = self.vm_insts.len();
+        self.vm_insts.push(VMInst::Jmp(0));
+        let entry = self.vm_insts.len();
+        self.vm_insts.push(VMInst::Entry(0));
+        let name = func.name.clone().unwrap_or_else(|| "<closure>".to_string());
+        self.debug.func_names.insert(entry, name);
+        self.funcs.last_mut().unwrap().env = local_env.clone();
+        self.funcs.push(FuncCtx::new(slice::from_ref(&*func.body)));
+        let mut env = HashMap::new();
+        for (name, _) in &func.params {
+            let id = self.new_local(name);
+            env.insert(name.clone(), id);
+            if self.funcs.last().unwrap().captured.contains(name) {
+                self.vm_insts.push(VMInst::LoadV(id));
+                self.vm_insts.push(VMInst::MakeCell(id));
+                self.vm_insts.push(VMInst::Pop);
+            }
+        }
+        self.gen_inst(&func.body, &mut env)?;
+        self.vm_insts.push(VMInst::Ret);
+        let mut ctx = self.funcs.pop().unwrap();
+        self.vm_insts[entry] = VMInst::Entry(ctx.id_manager.count());
+        self.debug.locals.insert(entry, mem::take(&mut ctx.names));
+        let end_pos = self.vm_insts.len();
+        self.patch_jump(skip_pos, end_pos);
+
+        for name in &ctx.upvalues {
+            match self.lookup_var(name, local_env) {
+                Some(Var::Cell(id)) => self.vm_insts.push(VMInst::LoadV(id)),
+                Some(Var::Upvalue(i)) => self.vm_insts.push(VMInst::CaptureU(i)),
+                _ => unreachable!(),
+            }
+        }
+        self.vm_insts.push(VMInst::MakeClosure(entry, ctx.upvalues.len()));
+
+        if let Some(ref name) = func.name {
+            match self.lookup_var(name, local_env) {
+                Some(var) => self.gen_set(var),
+                None => unreachable!(),
+            }
+            self.vm_insts.push(VMInst::Pop);
+            self.vm_insts.push(VMInst::PushI(0));
+        }
+        Ok(())
+    }
+
+    fn new_id(&mut self) -> Id {
+        self.funcs.last_mut().unwrap().id_manager.get_id()
+    }
+
+    fn new_local(&mut self, name: &str) -> Id {
+        let id = self.new_id();
+        self.funcs.last_mut().unwrap().names.push((name.to_string(), id));
+        id
+    }
+
+    fn lookup_var(&mut self, name: &str, local_env: &HashMap<String, Id>) -> Option<Var> {
+        if let Some(&id) = local_env.get(name) {
+            return Some(if self.funcs.last().unwrap().captured.contains(name) {
+                Var::Cell(id)
+            } else {
+                Var::Local(id)
+            });
+        }
+        let depth = self.funcs.len() - 1;
+        if let Some(i) = self.lookup_upvalue(depth, name) {
+            return Some(Var::Upvalue(i));
+        }
+        if let Some(&n) = self.globals.get(name) {
+            return Some(Var::Global(n));
+        }
+        self.consts.get(name).cloned().map(Var::Const)
+    }
+
+    // Variables declared in the top level outside any block are globals
+    fn at_top_level(&self) -> bool {
+        self.funcs.len() == 1 && self.blocks == 0
+    }
+
+    fn new_global(&mut self, name: &str) -> usize {
+        let n = self.globals.len();
+        self.globals.insert(name.to_string(), n);
+        n
+    }
+
+    fn gen_const(&mut self, val: Value) {
+        self.vm_insts.push(match val {
+            Value::Bool(b) => VMInst::PushB(b),
+            Value::Int(n) => VMInst::PushI(n),
+            Value::UInt(n) => VMInst::PushU(n),
+            Value::Float(f) => VMInst::PushF(f),
+            Value::Object(_) => VMInst::PushS(val.to_string()),
+        })
+    }
+
+    // Runs the code of a constant expression
+    fn eval_const(&mut self, value: &Node) -> Result<Value, String> {
+        let insts = mem::take(&mut self.vm_insts);
+        self.funcs.push(FuncCtx::new(&[]));
+        let result = self.gen_inst(value, &mut HashMap::new());
+        self.funcs.pop();
+        let code = mem::replace(&mut self.vm_insts, insts);
+        result.map_err(|e| e.msg)?;
+        VM::new().eval(&code)
+    }
+
+    // Finds `name` in the functions enclosing `self.funcs[depth]`, adding it to the
+    // upvalues of every function in between
+    fn lookup_upvalue(&mut self, depth: usize, name: &str) -> Option<usize> {
+        if depth == 0 {
+            return None;
+        }
+        if let Some(i) = self.funcs[depth].upvalues.iter().position(|u| u == name) {
+            return Some(i);
+        }
+        if !self.funcs[depth - 1].env.contains_key(name)
+            && self.lookup_upvalue(depth - 1, name).is_none()
+        {
+            return None;
+        }
+        self.funcs[depth].upvalues.push(name.to_string());
+        Some(self.funcs[depth].upvalues.len() - 1)
+    }
+
+    // Initializes a new local with the value on the top of the stack
+    fn gen_declare(&mut self, name: &str, id: Id) {
+        if self.funcs.last().unwrap().captured.contains(name) {
+            self.vm_insts.push(VMInst::MakeCell(id))
+        } else {
+            self.vm_insts.push(VMInst::StoreV(id))
+        }
+    }
+
+    fn gen_set(&mut self, var: Var) {
+        self.vm_insts.push(match var {
+            Var::Local(id) => VMInst::StoreV(id),
+            Var::Cell(id) => VMInst::StoreC(id),
+            Var::Upvalue(i) => VMInst::StoreU(i),
+            Var::Global(n) => VMInst::StoreG(n),
+            Var::Const(_) => unreachable!("rejected by TypeChecker"),
+        })
+    }
+
+    pub fn gen_variant(
+        &mut self,
+        path: &[String],
+        args: &[Node],
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        for arg in args {
+            self.gen_inst(arg, local_env)?;
+        }
+        let tag = self.variant_tag(path);
+        self.vm_insts.push(VMInst::NewVariant(tag, args.len()));
+        Ok(())
+    }
+
+    // A struct is a variant with the fields in declaration order. Fields initialized in
+    // another order are evaluated into hidden locals first.
+    pub fn gen_struct_lit(
+        &mut self,
+        name: &str,
+        fields: &[(String, Node)],
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        let decl = self.structs[name].clone();
+        if fields.iter().zip(decl.iter()).all(|((f, _), d)| f == d) {
+            for (_, value) in fields {
+                self.gen_inst(value, local_env)?;
+            }
+        } else {
+            let mut ids = HashMap::new();
+            for (field, value) in fields {
+                self.gen_inst(value, local_env)?;
+                let id = self.new_id();
+                self.vm_insts.push(VMInst::StoreV(id));
+                self.vm_insts.push(VMInst::Pop);
+                ids.insert(field.clone(), id);
+            }
+            for field in &decl {
+                self.vm_insts.push(VMInst::LoadV(ids[field]));
+            }
+        }
+        self.vm_insts.push(VMInst::NewVariant(0, decl.len()));
+        Ok(())
+    }
+
+    fn field_index(&self, ty: &Type, name: &str) -> usize {
+        match ty.kind {
+            TypeKind::Named(ref struct_name, _) => self.structs[struct_name]
+                .iter()
+                .position(|f| f == name)
+                .unwrap(),
+            TypeKind::Tuple(_) => name.parse().unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn gen_for(
+        &mut self,
+        vars: &[String],
+        iterable: &Node,
+        body: &Node,
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        self.gen_inst(iterable, local_env)?;
+        self.vm_insts.push(VMInst::Iter);
+        let iter = self.new_id();
+        self.vm_insts.push(VMInst::StoreV(iter));
+        self.vm_insts.push(VMInst::Pop);
+
+        let mut env = local_env.clone();
+        let ids: Vec<Id> = vars.iter()
+            .map(|var| {
+                let id = self.new_local(var);
+                env.insert(var.clone(), id);
+                id
+            })
+            .collect();
+
+        let loop_pos = self.vm_insts.len();
+        self.vm_insts.push(VMInst::LoadV(iter));
+        self.vm_insts.push(VMInst::IterNext(0));
+        if ids.len() == 1 {
+            self.vm_insts.push(VMInst::Pop); // value
+        } else {
+            self.gen_declare(&vars[1], ids[1]);
+            self.vm_insts.push(VMInst::Pop);
+        }
+        self.gen_declare(&vars[0], ids[0]);
+        self.vm_insts.push(VMInst::Pop);
+        self.gen_inst(body, &mut env)?;
+        self.vm_insts.push(VMInst::Pop);
+        self.vm_insts.push(VMInst::Jmp(loop_pos));
+        let end_pos = self.vm_insts.len();
+        self.vm_insts[loop_pos + 1] = VMInst::IterNext(end_pos);
+        self.vm_insts.push(VMInst::PushI(0));
+        Ok(())
+    }
+
+    pub fn gen_block(
+        &mut self,
+        nodes: &[Node],
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        if nodes.is_empty() {
+            self.vm_insts.push(VMInst::PushI(0));
+        }
+        // Variables introduced in a block are not visible outside of it
+        let mut env = local_env.clone();
+        self.blocks += 1;
+        for (i, node) in nodes.iter().enumerate() {
+            if i > 0 {
+                self.vm_insts.push(VMInst::Pop);
+            }
+            self.gen_stmt(node, &mut env)?;
+        }
+        self.blocks -= 1;
+        Ok(())
+    }
+}
+
+impl<'a> Codegen<'a> {
+    // A match is compiled as follows: the scrutinee is stored in a hidden local,
+    // then (if it is an enum) a jump table dispatches on its tag to a chain of
+    // the arms that can match that variant. Each arm in a chain tests nested
+    // patterns, binds variables and evaluates its guard, jumping to the next arm
+    // on failure or to the arm's body (emitted only once) on success.
+    pub fn gen_match(
+        &mut self,
+        cond: &Node,
+        arms: &[MatchArm],
+        local_env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        self.gen_inst(cond, local_env)?;
+        let scrutinee = self.new_id();
+        self.vm_insts.push(VMInst::StoreV(scrutinee));
+        self.vm_insts.push(VMInst::Pop);
+
+        let mut arm_envs = vec![];
+        for arm in arms {
+            let mut env = local_env.clone();
+            let mut names = vec![];
+            pattern_bindings(&arm.pattern, &mut names);
+            for name in names {
+                let id = self.new_local(&name);
+                env.insert(name, id);
+            }
+            arm_envs.push(env);
+        }
+
+        let mut body_jumps = vec![vec![]; arms.len()];
+        let enum_name = arms.iter()
+            .filter_map(|arm| match arm.pattern.kind {
+                PatternKind::Variant(ref path, _) => Some(path[0].clone()),
+                _ => None,
+            })
+            .next();
+        if let Some(enum_name) = enum_name {
+            self.vm_insts.push(VMInst::LoadV(scrutinee));
+            let table_pos = self.vm_insts.len();
+            self.vm_insts.push(VMInst::JmpTable(vec![]));
+            let mut table = vec![];
+            for tag in 0..self.enums[&enum_name].len() {
+                table.push(self.vm_insts.len());
+                for (i, arm) in arms.iter().enumerate() {
+                    let compatible = match arm.pattern.kind {
+                        PatternKind::Variant(ref path, _) => self.variant_tag(path) == tag,
+                        _ => true,
+                    };
+                    if compatible {
+                        self.gen_arm_test(arm, scrutinee, true, &mut arm_envs[i])?;
+                        body_jumps[i].push(self.vm_insts.len() - 1);
+                    }
+                }
+            }
+            self.vm_insts[table_pos] = VMInst::JmpTable(table);
+        } else {
+            for (i, arm) in arms.iter().enumerate() {
+                self.gen_arm_test(arm, scrutinee, false, &mut arm_envs[i])?;
+                body_jumps[i].push(self.vm_insts.len() - 1);
+            }
+        }
+
+        let mut end_jumps = vec![];
+        for (i, arm) in arms.iter().enumerate() {
+            let body_pos = self.vm_insts.len();
+            for &pos in &body_jumps[i] {
+                self.patch_jump(pos, body_pos);
+            }
+            self.gen_inst(&arm.body, &mut arm_envs[i])?;
+            end_jumps.push(self.vm_insts.len());
+            self.vm_insts.push(VMInst::Jmp(0));
+        }
+        let end_pos = self.vm_insts.len();
+        for pos in end_jumps {
+            self.patch_jump(pos, end_pos);
+        }
+        Ok(())
+    }
+
+    // Emits the tests for one arm, ending with a jump (to be patched) to its body
+    fn gen_arm_test(
+        &mut self,
+        arm: &MatchArm,
+        scrutinee: Id,
+        tag_known: bool,
+        env: &mut HashMap<String, Id>,
+    ) -> Result<(), TypeError> {
+        let mut fail_jumps = vec![];
+        self.gen_pattern_test(
+            &arm.pattern,
+            scrutinee,
+            &mut vec![],
+            tag_known,
+            env,
+            &mut fail_jumps,
+        );
+        if let Some(ref guard) = arm.guard {
+            self.gen_inst(guard, env)?;
+            fail_jumps.push(self.vm_insts.len());
+            self.vm_insts.push(VMInst::JmpIfFalse(0));
+        }
+        self.vm_insts.push(VMInst::Jmp(0));
+        let next_pos = self.vm_insts.len();
+        for pos in fail_jumps {
+            self.patch_jump(pos, next_pos);
+        }
+        Ok(())
+    }
+
+    fn gen_pattern_test(
+        &mut self,
+        pat: &Pattern,
+        scrutinee: Id,
+        path: &mut Vec<usize>,
+        tag_known: bool,
+        env: &HashMap<String, Id>,
+        fail_jumps: &mut Vec<usize>,
+    ) {
+        match pat.kind {
+            PatternKind::Wildcard => {}
+            PatternKind::Variable(ref name) => {
+                self.gen_load_path(scrutinee, path);
+                self.gen_declare(name, env[name]);
+                self.vm_insts.push(VMInst::Pop);
+            }
+            PatternKind::Bool(b) => {
+                self.gen_load_path(scrutinee, path);
+                self.vm_insts.push(VMInst::PushB(b));
+                self.vm_insts.push(VMInst::Eq);
+                fail_jumps.push(self.vm_insts.len());
+                self.vm_insts.push(VMInst::JmpIfFalse(0));
+            }
+            PatternKind::Int(n) => {
+                self.gen_load_path(scrutinee, path);
+                self.gen_int(n, &pat.ty);
+                self.vm_insts.push(VMInst::Eq);
+                fail_jumps.push(self.vm_insts.len());
+                self.vm_insts.push(VMInst::JmpIfFalse(0));
+            }
+            PatternKind::String(ref s) => {
+                self.gen_load_path(scrutinee, path);
+                self.vm_insts.push(VMInst::PushS(s.clone()));
+                self.vm_insts.push(VMInst::Eq);
+                fail_jumps.push(self.vm_insts.len());
+                self.vm_insts.push(VMInst::JmpIfFalse(0));
+            }
+            PatternKind::Variant(ref variant, ref subpats) => {
+                if !tag_known {
+                    // JmpTable falls through to the next instruction on a match,
+                    // and to the jump to the failure label otherwise.
+                    self.gen_load_path(scrutinee, path);
+                    let pos = self.vm_insts.len();
+                    let mut table = vec![pos + 1; self.enums[&variant[0]].len()];
+                    table[self.variant_tag(variant)] = pos + 2;
+                    self.vm_insts.push(VMInst::JmpTable(table));
+                    fail_jumps.push(pos + 1);
+                    self.vm_insts.push(VMInst::Jmp(0));
+                }
+                for (i, subpat) in subpats.iter().enumerate() {
+                    path.push(i);
+                    self.gen_pattern_test(subpat, scrutinee, path, false, env, fail_jumps);
+                    path.pop();
+                }
+            }
+        }
+    }
+
+    fn gen_load_path(&mut self, scrutinee: Id, path: &[usize]) {
+        self.vm_insts.push(VMInst::LoadV(scrutinee));
+        for &i in path {
+            self.vm_insts.push(VMInst::Field(i));
+        }
+    }
+
+    fn patch_jump(&mut self, pos: usize, dst: usize) {
+        self.vm_insts[pos] = match self.vm_insts[pos] {
+            VMInst::Jmp(_) => VMInst::Jmp(dst),
+            VMInst::JmpIfFalse(_) => VMInst::JmpIfFalse(dst),
+            VMInst::Try(_) => VMInst::Try(dst),
+            ref inst => panic!("not a jump: {:?}", inst),
+        };
+    }
+
+    fn variant_tag(&self, path: &[String]) -> usize {
+        self.enums[&path[0]]
+            .iter()
+            .position(|name| name == &path[1])
+            .unwrap()
+    }
 }
 
-impl<'a> Codegen<'a> {}
+pub fn binop_inst(op: &BinOp) -> VMInst {
+    match *op {
+        BinOp::Add => VMInst::Add,
+        BinOp::Sub => VMInst::Sub,
+        BinOp::Mul => VMInst::Mul,
+        BinOp::Div => VMInst::Div,
+        BinOp::Rem => VMInst::Rem,
+        BinOp::And => VMInst::And,
+        BinOp::Or => VMInst::Or,
+        BinOp::Xor => VMInst::Xor,
+        BinOp::Shl => VMInst::Shl,
+        BinOp::Shr => VMInst::Shr,
+        BinOp::Eq => VMInst::Eq,
+        BinOp::Ne => VMInst::Ne,
+        BinOp::Lt => VMInst::Lt,
+        BinOp::Gt => VMInst::Gt,
+        BinOp::Le => VMInst::Le,
+        BinOp::Ge => VMInst::Ge,
+        BinOp::LAnd | BinOp::LOr | BinOp::Coalesce | BinOp::Assign => {
+            unreachable!("{:?} is compiled to jumps or stores, not an instruction", op)
+        }
+    }
+}
+
+// Whether the result of `op` may be out of the range of a narrow type
+pub fn wraps(op: &BinOp) -> bool {
+    matches!(*op, BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem | BinOp::Shl)
+}
+
+// The instruction bringing the result of arithmetic back into the range of `ty`, if narrower
+// than 64 bits
+pub fn wrap_inst(ty: &Type) -> Option<VMInst> {
+    match ty.kind {
+        TypeKind::Int(ty) if ty.bits() < 64 => Some(VMInst::IntCast(ty)),
+        TypeKind::Float(FloatTy::F32) => Some(VMInst::FloatCast(FloatTy::F32)),
+        _ => None,
+    }
+}
+
+// Collects the names of the variables referred to in the functions nested in `node`
+fn captured_names(node: &Node, in_func: bool, names: &mut HashSet<String>) {
+    match node.kind {
+        NodeKind::Variable(ref name, _) if in_func => {
+            names.insert(name.clone());
+        }
+        NodeKind::Func(ref func) => return captured_names(&func.body, true, names),
+        NodeKind::Impl(ref impl_) => {
+            for method in &impl_.methods {
+                captured_names(&method.body, true, names);
+            }
+            return;
+        }
+        _ => {}
+    }
+    for child in node.children() {
+        captured_names(child, in_func, names)
+    }
+}
+
+fn pattern_bindings(pat: &Pattern, names: &mut Vec<String>) {
+    match pat.kind {
+        PatternKind::Variable(ref name) => names.push(name.clone()),
+        PatternKind::Variant(_, ref subpats) => for subpat in subpats {
+            pattern_bindings(subpat, names)
+        },
+        _ => {}
+    }
+}
+
+#[test]
+fn test_invalid_assignment() {
+    use lexer::Lexer;
+    // Programs that TypeChecker would reject, compiled without it
+    let gen = |src: &str| {
+        let mut lexer = Lexer::new_from_string(src.to_string());
+        let mut parser = Parser::new(&mut lexer);
+        let nodes = parser.read_all().unwrap();
+        let mut codegen = Codegen::new(&mut parser);
+        codegen.gen_program(&nodes).map_err(|e| e.msg)
+    };
+    assert_eq!(gen("a = 1\na = 2"), Ok(()));
+    assert_eq!(gen("f() = 1"), Err("cannot assign to a call".to_string()));
+    assert_eq!(gen("a, 2 = 1, 2"), Err("cannot assign to a literal".to_string()));
+    assert_eq!(gen("(1 + 2) += 3"), Err("cannot assign to this expression".to_string()));
+}
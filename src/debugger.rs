@@ -0,0 +1,267 @@
+use vm::VM;
+use vm_base::{Object, VMInst, Value};
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, Write};
+
+// Runs a program on the VM a statement at a time, with the commands:
+//
+//     break LINE, clear LINE   set or remove a breakpoint at the statements of a line
+//     continue                 run to the next breakpoint
+//     step                     run to the next statement
+//     next                     run to the next statement of this call or a caller
+//     finish                   run until this call returns
+//     print NAME               show a local of this call, or a global
+//     locals                   show the locals of this call
+//     stack                    show the values on the stack in this call
+//     backtrace                show the functions being called
+//
+// Each can be shortened to its first letter, and `backtrace` to `bt`.
+pub struct Debugger {
+    pub vm: VM,
+    insts: Vec<VMInst>,
+    source: Vec<String>,           // The lines of the program
+    starts: HashMap<usize, usize>, // pc -> the line of the statement starting there
+    breakpoints: BTreeSet<usize>,
+    lines: Vec<Option<usize>>, // The line each call is at, outermost first
+}
+
+enum Resume {
+    Step,
+    Next(usize),   // The number of frames at most
+    Finish(usize), // The number of frames of the call
+    Continue,
+}
+
+const HELP: &str = "commands: break LINE, clear LINE, continue, step, next, finish, \
+                   print NAME, locals, stack, backtrace, quit";
+
+impl Debugger {
+    pub fn new(vm: VM, insts: Vec<VMInst>, source: &str) -> Debugger {
+        let mut starts = HashMap::new();
+        // Nested statements come first, and are the ones to stop at
+        for &(ref pcs, line) in &vm.debug.lines {
+            if !pcs.is_empty() {
+                starts.entry(pcs.start).or_insert(line);
+            }
+        }
+        Debugger {
+            vm,
+            insts,
+            source: source.lines().map(|line| line.to_string()).collect(),
+            starts,
+            breakpoints: BTreeSet::new(),
+            lines: vec![None],
+        }
+    }
+
+    // Runs to the first statement
+    pub fn start(&mut self) -> String {
+        self.resume(Resume::Step)
+    }
+
+    pub fn command(&mut self, cmd: &str) -> String {
+        let mut words = cmd.split_whitespace();
+        let (name, arg) = (words.next().unwrap_or(""), words.next());
+        let depth = self.vm.frames.len();
+        match (name, arg) {
+            ("b", Some(line)) | ("break", Some(line)) => match line.parse() {
+                Ok(line) if self.starts.values().any(|&l| l == line) => {
+                    self.breakpoints.insert(line);
+                    format!("breakpoint at line {}", line)
+                }
+                _ => format!("no code at line {}", line),
+            },
+            ("clear", Some(line)) => match line.parse() {
+                Ok(line) if self.breakpoints.remove(&line) => {
+                    format!("removed the breakpoint at line {}", line)
+                }
+                _ => format!("no breakpoint at line {}", line),
+            },
+            ("c", None) | ("continue", None) => self.resume(Resume::Continue),
+            ("s", None) | ("step", None) => self.resume(Resume::Step),
+            ("n", None) | ("next", None) => self.resume(Resume::Next(depth)),
+            ("f", None) | ("finish", None) => self.resume(Resume::Finish(depth)),
+            ("p", Some(name)) | ("print", Some(name)) => self.print(name),
+            ("l", None) | ("locals", None) => {
+                let locals: Vec<String> = self.locals()
+                    .iter()
+                    .map(|&(ref name, id)| format!("{} = {}", name, self.local(id)))
+                    .collect();
+                locals.join("\n")
+            }
+            ("stack", None) => {
+                let slots: Vec<String> = (self.vm.bp..self.vm.sp + 1)
+                    .map(|i| format!("{}: {}", i - self.vm.bp, show(&self.vm.stack[i])))
+                    .collect();
+                slots.join("\n")
+            }
+            ("bt", None) | ("backtrace", None) => self.vm.backtrace(),
+            ("help", None) => HELP.to_string(),
+            _ => format!("unknown command `{}`; try `help`", cmd),
+        }
+    }
+
+    pub fn repl(&mut self) {
+        println!("{}", self.start());
+        let stdin = io::stdin();
+        loop {
+            print!("(xdb) ");
+            io::stdout().flush().unwrap();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                break;
+            }
+            match line.trim() {
+                "q" | "quit" => break,
+                "" => {}
+                cmd => println!("{}", self.command(cmd)),
+            }
+        }
+    }
+
+    // Stops where a call enters another line, like at a breakpoint, or when it returns. An
+    // uncaught error ends the program.
+    fn resume(&mut self, mode: Resume) -> String {
+        while self.vm.pc < self.insts.len() {
+            if let Err(e) = self.vm.step(&self.insts) {
+                self.vm.pc = self.insts.len();
+                return e.to_string();
+            }
+            let depth = self.vm.frames.len();
+            self.lines.resize(depth + 1, None);
+            let entered = match self.starts.get(&self.vm.pc) {
+                Some(&line) if self.lines[depth] != Some(line) => {
+                    self.lines[depth] = Some(line);
+                    Some(line)
+                }
+                _ => None,
+            };
+            let stop = match entered {
+                Some(ref line) if self.breakpoints.contains(line) => true,
+                Some(_) => match mode {
+                    Resume::Step => true,
+                    Resume::Next(frames) => depth <= frames,
+                    Resume::Finish(_) | Resume::Continue => false,
+                },
+                None => false,
+            };
+            let returned = match mode {
+                Resume::Finish(frames) => depth < frames,
+                _ => false,
+            };
+            if stop || returned {
+                return self.location();
+            }
+        }
+        "the program exited".to_string()
+    }
+
+    // The innermost statement being run, like `f at line 3: x = y + 1`
+    fn location(&self) -> String {
+        let pc = self.vm.pc;
+        let line = self.vm
+            .debug
+            .lines
+            .iter()
+            .filter(|&(pcs, _)| pcs.start <= pc && pc < pcs.end)
+            .min_by_key(|&(pcs, _)| pcs.len())
+            .map(|&(_, line)| line);
+        let func = self.vm
+            .frames
+            .last()
+            .map(|frame| match self.vm.debug.func_names.get(&frame.entry) {
+                Some(name) => format!("{} at ", name),
+                None => format!("<function at {}> at ", frame.entry),
+            })
+            .unwrap_or_default();
+        match line {
+            Some(line) => {
+                let text = self.source.get(line - 1).map_or("", |text| text.trim());
+                format!("{}line {}: {}", func, line, text)
+            }
+            None => format!("{}an unknown line", func),
+        }
+    }
+
+    fn locals(&self) -> Vec<(String, usize)> {
+        let entry = self.vm.frames.last().map_or(0, |frame| frame.entry);
+        let mut locals = self.vm.debug.locals.get(&entry).cloned().unwrap_or_default();
+        locals.sort_by_key(|&(_, id)| id);
+        locals
+    }
+
+    fn local(&self, id: usize) -> String {
+        show(&self.vm.stack[self.vm.bp + id])
+    }
+
+    // A local shadows a global, and a local declared later one declared earlier
+    fn print(&self, name: &str) -> String {
+        if let Some(&(_, id)) = self.locals().iter().rev().find(|&(n, _)| n == name) {
+            return format!("{} = {}", name, self.local(id));
+        }
+        match self.vm.debug.globals.iter().position(|n| n == name) {
+            Some(n) => match self.vm.globals.get(n) {
+                Some(val) => format!("{} = {}", name, show(val)),
+                None => format!("`{}` has no value yet", name),
+            },
+            None => format!("no variable `{}` here", name),
+        }
+    }
+}
+
+// Strings are quoted, and captured locals shown by the values in their cells
+fn show(val: &Value) -> String {
+    if let Value::Object(ref obj) = *val {
+        match *obj.borrow() {
+            Object::String(ref s) => return format!("{:?}", s),
+            Object::Cell(ref val) => return show(val),
+            _ => {}
+        }
+    }
+    val.to_string()
+}
+
+#[test]
+fn test_debugger() {
+    use vm::compile_source;
+    let src = "def add(a: int, b: int) -> int {
+  c = a + b
+  c * 2
+}
+x = 1
+y = add(x, 2)
+print(y)
+s = \"hi\"";
+    let (insts, debug) = compile_source(src, 0);
+    let mut vm = VM::new();
+    vm.output = Some(String::new());
+    vm.debug = debug;
+    let mut debugger = Debugger::new(vm, insts, src);
+    assert_eq!(debugger.start(), "line 1: def add(a: int, b: int) -> int {");
+    let session = [
+        ("break 8", "breakpoint at line 8"),
+        ("break 4", "no code at line 4"),
+        ("step", "line 5: x = 1"),
+        ("s", "line 6: y = add(x, 2)"),
+        ("step", "add at line 2: c = a + b"),
+        ("next", "add at line 3: c * 2"),
+        ("print c", "c = 3"),
+        ("print x", "x = 1"),
+        ("print s", "`s` has no value yet"),
+        ("print z", "no variable `z` here"),
+        ("locals", "a = 1\nb = 2\nc = 3"),
+        ("stack", "0: 1\n1: 2\n2: 3"),
+        ("bt", "backtrace:\n    add\n    <top level>"),
+        ("finish", "line 6: y = add(x, 2)"),
+        ("next", "line 7: print(y)"),
+        ("continue", "line 8: s = \"hi\""),
+        ("clear 8", "removed the breakpoint at line 8"),
+        ("continue", "the program exited"),
+        ("jump", "unknown command `jump`; try `help`"),
+    ];
+    for &(cmd, expected) in &session {
+        assert_eq!(debugger.command(cmd), expected, "{}", cmd);
+    }
+    assert_eq!(debugger.vm.output.clone().unwrap(), "6\n");
+}
@@ -0,0 +1,153 @@
+use vm_base::{Object, Value};
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::mem;
+use std::rc::{Rc, Weak};
+
+// A mark-sweep collector for the objects of the VM.
+//
+// Objects are reference-counted, which frees most of them as soon as they become
+// unreachable, but not cycles, such as a local recursive function, whose closure captures
+// the cell it is stored in. So the heap tracks every object that can refer to others (all
+// but strings), and a collection marks those reachable from the roots given by the VM and
+// clears the rest, which breaks the cycles they are in.
+#[derive(Clone)]
+pub struct Heap {
+    objects: Vec<Weak<RefCell<Object>>>,
+    threshold: usize, // The number of tracked objects that triggers a collection
+    allocated: usize, // Since the last collection
+    pub stress: bool, // Collect after every allocation, for testing
+    stats: GcStats,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GcStats {
+    pub collections: usize,
+    pub allocated: usize, // The objects tracked in total
+    pub freed: usize,     // The unreachable objects cleared by the collector
+    pub live: usize,      // The objects reachable at the last collection
+}
+
+const MIN_THRESHOLD: usize = 1024;
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: vec![],
+            threshold: MIN_THRESHOLD,
+            allocated: 0,
+            stress: false,
+            stats: GcStats::default(),
+        }
+    }
+
+    // Strings, which cannot refer to other objects, are made with Value::new_string instead
+    pub fn alloc(&mut self, obj: Object) -> Value {
+        let obj = Rc::new(RefCell::new(obj));
+        self.objects.push(Rc::downgrade(&obj));
+        self.allocated += 1;
+        self.stats.allocated += 1;
+        Value::Object(obj)
+    }
+
+    // Whether to collect at the next point where every object in use is reachable from
+    // the roots (between two instructions)
+    pub fn should_collect(&self) -> bool {
+        if self.stress {
+            self.allocated > 0
+        } else {
+            self.objects.len() >= self.threshold
+        }
+    }
+
+    pub fn collect<'a, I>(&mut self, roots: I)
+    where
+        I: Iterator<Item = &'a Value>,
+    {
+        let mut marked = HashSet::new();
+        let mut work: Vec<Value> = roots.cloned().collect();
+        while let Some(val) = work.pop() {
+            let obj = match val {
+                Value::Object(obj) => obj,
+                _ => continue,
+            };
+            if !marked.insert(&*obj as *const RefCell<Object>) {
+                continue;
+            }
+            match *obj.borrow() {
+                Object::String(_) => {}
+                Object::Variant(_, ref fields) => work.extend(fields.iter().cloned()),
+                Object::Map(ref map) => work.extend(map.values().cloned()),
+                Object::Iter(ref entries, _) => for (key, val) in entries {
+                    work.push(key.clone());
+                    work.push(val.clone());
+                },
+                Object::Cell(ref val) => work.push(val.clone()),
+                Object::Closure(_, ref cells) => work.extend(cells.iter().cloned()),
+            };
+        }
+
+        // The contents are dropped once all are taken, as dropping them may free others
+        let mut garbage = vec![];
+        let mut live = vec![];
+        for weak in self.objects.drain(..) {
+            if let Some(obj) = weak.upgrade() {
+                if marked.contains(&(&*obj as *const RefCell<Object>)) {
+                    live.push(weak);
+                } else {
+                    let dead = Object::Variant(0, vec![]);
+                    garbage.push(mem::replace(&mut *obj.borrow_mut(), dead));
+                }
+            }
+        }
+        self.stats.collections += 1;
+        self.stats.freed += garbage.len();
+        self.stats.live = live.len();
+        self.objects = live;
+        self.threshold = MIN_THRESHOLD.max(self.objects.len() * 2);
+        self.allocated = 0;
+    }
+
+    pub fn stats(&self) -> &GcStats {
+        &self.stats
+    }
+}
+
+#[test]
+fn test_gc() {
+    use vm::{compile_source, VM};
+    // Every call of `count` makes a cycle: `down` captures the cell it is stored in
+    let src = "def count(n: int) -> int {
+                 def down(i: int) -> int { if i == 0 { 0 } else { down(i - 1) + 1 } }
+                 down(n)
+               }
+               kept = {1: (2, \"two\")}
+               total = 0
+               i = 0
+               while i < 100 { total += count(i) \n i += 1 }
+               print(total)
+               a, b = kept[1]
+               print(b)";
+    let (insts, _) = compile_source(src, 1);
+    for &stress in &[false, true] {
+        let mut vm = VM::new();
+        vm.output = Some(String::new());
+        vm.heap.stress = stress;
+        vm.run(insts.clone()).unwrap();
+        assert_eq!(vm.output.clone().unwrap(), "4950\ntwo\n");
+        vm.collect_garbage();
+        // Left are `count` and `kept`, with its tuple
+        let stats = vm.heap.stats().clone();
+        assert_eq!(stats.live, 3);
+        assert_eq!(stats.allocated, 3 + 2 * 100);
+        assert_eq!(stats.freed, 2 * 100);
+        assert!(stats.collections > if stress { 200 } else { 0 });
+    }
+}
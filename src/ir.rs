@@ -0,0 +1,812 @@
+use node::{BinOp, FuncDef, Node, NodeKind, UnaryOp};
+use typing::{Type, TypeError, TypeKind};
+use vm_base::VMInst;
+use codegen::{binop_inst, wrap_inst, wraps};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Range;
+
+// A typed intermediate representation of a program, in which each function is a control-flow
+// graph of basic blocks in SSA form: every value is defined once, and values merging at the
+// start of a block are its parameters, passed by the jumps to it (instead of phi nodes).
+//
+// It is lowered from type-checked nodes by `lower_program`, and to VM bytecode by
+// `Program::to_bytecode` (which Codegen uses with `via_ir`, for the programs it supports);
+// this is also where a JIT would take functions from. Only the
+// first-order subset of the language is supported for now: scalars and strings, locals,
+// `if`, `while`, and calls of the functions defined at the top level and of `print`.
+
+pub type ValueId = usize;
+pub type BlockId = usize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub funcs: Vec<Function>,
+    pub main: Function, // The code at the top level
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<ValueId>,
+    pub ret: Type,
+    pub values: Vec<Type>,  // The type of every value, indexed by ValueId
+    pub blocks: Vec<Block>, // The first one is the entry
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub params: Vec<ValueId>,
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inst {
+    pub dest: ValueId,
+    pub kind: InstKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstKind {
+    Const(Const),
+    Unary(UnaryOp, ValueId),
+    Binary(BinOp, ValueId, ValueId), // Neither `&&`, `||`, `??` nor an assignment
+    Cast(ValueId, Type),
+    Call(String, Vec<ValueId>),    // A function of the program
+    Builtin(String, Vec<ValueId>), // `print` or `to_string`
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Const {
+    Bool(bool),
+    Int(i64), // The bits of a u64
+    Float(f64),
+    String(String),
+    Unit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId, Vec<ValueId>),
+    Branch(ValueId, (BlockId, Vec<ValueId>), (BlockId, Vec<ValueId>)),
+    Return(ValueId),
+}
+
+// Lowers the functions defined at the top level and the rest of the code, which may not
+// refer to the variables of each other
+pub fn lower_program(nodes: &[Node]) -> Result<Program, TypeError> {
+    let mut funcs = HashMap::new();
+    for node in nodes {
+        if let NodeKind::Func(FuncDef {
+            name: Some(ref name),
+            ..
+        }) = node.kind
+        {
+            funcs.insert(name.clone(), funcs.len());
+        }
+    }
+    let mut lowered = vec![];
+    let mut main = Lowerer::new(&funcs, Function::new("main".to_string(), Type::new_unit()));
+    for node in nodes {
+        match node.kind {
+            NodeKind::Func(ref func) if func.name.is_some() => {
+                lowered.push(lower_func(func, &funcs, &main.consts, &node.range)?)
+            }
+            NodeKind::Const(ref name, _, ref value) => {
+                main.consts.insert(name.clone(), (**value).clone());
+            }
+            _ => {
+                main.lower(node)?;
+            }
+        }
+    }
+    let unit = main.constant(Const::Unit, Type::new_unit());
+    main.terminate(Terminator::Return(unit));
+    Ok(Program {
+        funcs: lowered,
+        main: main.finish(),
+    })
+}
+
+fn lower_func(
+    func: &FuncDef,
+    funcs: &HashMap<String, usize>,
+    consts: &HashMap<String, Node>,
+    range: &Range<usize>,
+) -> Result<Function, TypeError> {
+    let name = func.name.clone().unwrap();
+    if !func.type_params.is_empty() {
+        return Err(TypeError::new(
+            format!("generic function `{}` cannot be lowered to the IR", name),
+            range.clone(),
+        ));
+    }
+    let ret = func.ret.clone().or_else(|| func.body.ty.clone()).unwrap();
+    let mut lowerer = Lowerer::new(funcs, Function::new(name, ret));
+    lowerer.consts = consts.clone();
+    for (name, ty) in &func.params {
+        let param = lowerer.new_value(ty.clone());
+        lowerer.func.params.push(param);
+        lowerer.declare(name, param);
+    }
+    let ret = lowerer.lower(&func.body)?;
+    lowerer.terminate(Terminator::Return(ret));
+    Ok(lowerer.finish())
+}
+
+impl Function {
+    fn new(name: String, ret: Type) -> Function {
+        Function {
+            name,
+            params: vec![],
+            ret,
+            values: vec![],
+            blocks: vec![],
+        }
+    }
+}
+
+// Builds a function, keeping track of the value each local variable has at the current
+// point of the current block
+struct Lowerer<'a> {
+    func: Function,
+    terms: Vec<Option<Terminator>>, // Of the blocks of `func`, set when they are complete
+    current: BlockId,
+    scopes: Vec<BTreeMap<String, ValueId>>,
+    funcs: &'a HashMap<String, usize>,
+    consts: HashMap<String, Node>, // Their values, lowered where they are used
+}
+
+impl<'a> Lowerer<'a> {
+    fn new(funcs: &'a HashMap<String, usize>, func: Function) -> Lowerer<'a> {
+        let mut lowerer = Lowerer {
+            func,
+            terms: vec![],
+            current: 0,
+            scopes: vec![BTreeMap::new()],
+            funcs,
+            consts: HashMap::new(),
+        };
+        lowerer.current = lowerer.new_block();
+        lowerer
+    }
+
+    fn finish(self) -> Function {
+        let mut func = self.func;
+        for (block, term) in func.blocks.iter_mut().zip(self.terms) {
+            block.term = term.unwrap();
+        }
+        func
+    }
+
+    fn new_value(&mut self, ty: Type) -> ValueId {
+        self.func.values.push(ty);
+        self.func.values.len() - 1
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.func.blocks.push(Block {
+            params: vec![],
+            insts: vec![],
+            term: Terminator::Return(0), // Replaced by `finish`
+        });
+        self.terms.push(None);
+        self.func.blocks.len() - 1
+    }
+
+    fn push(&mut self, kind: InstKind, ty: Type) -> ValueId {
+        let dest = self.new_value(ty);
+        let current = self.current;
+        self.func.blocks[current].insts.push(Inst {
+            dest,
+            kind,
+        });
+        dest
+    }
+
+    fn constant(&mut self, c: Const, ty: Type) -> ValueId {
+        self.push(InstKind::Const(c), ty)
+    }
+
+    fn terminate(&mut self, term: Terminator) {
+        self.terms[self.current] = Some(term);
+    }
+
+    fn lookup(&self, name: &str) -> Option<ValueId> {
+        self.scopes.iter().rev().filter_map(|scope| scope.get(name)).next().cloned()
+    }
+
+    fn declare(&mut self, name: &str, val: ValueId) {
+        self.scopes.last_mut().unwrap().insert(name.to_string(), val);
+    }
+
+    // Makes `name` have the value `val` from now on, declaring it if it is new
+    fn assign(&mut self, name: &str, val: ValueId) {
+        for scope in self.scopes.iter_mut().rev() {
+            if let Some(var) = scope.get_mut(name) {
+                *var = val;
+                return;
+            }
+        }
+        self.declare(name, val)
+    }
+
+    // The variables in scope and their values, in a fixed order
+    fn vars(&self) -> Vec<ValueId> {
+        self.scopes.iter().flat_map(|scope| scope.values().cloned()).collect()
+    }
+
+    fn set_vars(&mut self, vals: &[ValueId]) {
+        let mut vals = vals.iter();
+        for scope in &mut self.scopes {
+            for var in scope.values_mut() {
+                *var = *vals.next().unwrap();
+            }
+        }
+    }
+
+    fn lower(&mut self, node: &Node) -> Result<ValueId, TypeError> {
+        let ty = node.ty.clone().unwrap();
+        Ok(match node.kind {
+            NodeKind::Bool(b) => self.constant(Const::Bool(b), ty),
+            NodeKind::Int(n) => self.constant(Const::Int(n), ty),
+            NodeKind::Float(f) => self.constant(Const::Float(f), ty),
+            NodeKind::String(ref s) => self.constant(Const::String(s.clone()), ty),
+            NodeKind::Variable(ref name, _) => match self.lookup(name) {
+                Some(val) => val,
+                None => match self.consts.get(name).cloned() {
+                    Some(value) => self.lower(&value)?,
+                    None => return Err(unsupported(node, "a variable not local to the function")),
+                },
+            },
+            NodeKind::UnaryOp(ref expr, ref op) => {
+                let val = self.lower(expr)?;
+                self.push(InstKind::Unary(op.clone(), val), ty)
+            }
+            NodeKind::Cast(ref expr, ref to) => {
+                let val = self.lower(expr)?;
+                self.push(InstKind::Cast(val, to.clone()), ty)
+            }
+            NodeKind::BinaryOp(ref lhs, ref rhs, BinOp::Assign) => match lhs.kind {
+                NodeKind::Variable(ref name, _) => {
+                    let val = self.lower(rhs)?;
+                    self.assign(name, val);
+                    val
+                }
+                // `a, b = b, a`, where all the values are computed before any is assigned
+                NodeKind::Tuple(ref targets) => match rhs.kind {
+                    NodeKind::Tuple(ref elems) if elems.len() == targets.len() => {
+                        let mut vals = vec![];
+                        for elem in elems {
+                            vals.push(self.lower(elem)?);
+                        }
+                        for (target, &val) in targets.iter().zip(&vals) {
+                            match target.kind {
+                                NodeKind::Variable(ref name, _) => self.assign(name, val),
+                                _ => return Err(unsupported(target, "a nested tuple")),
+                            }
+                        }
+                        self.constant(Const::Unit, ty)
+                    }
+                    _ => return Err(unsupported(node, "a tuple")),
+                },
+                _ => return Err(unsupported(node, "an assignment to other than a variable")),
+            },
+            NodeKind::BinaryOp(ref lhs, ref rhs, BinOp::LAnd) => {
+                self.lower_logical(lhs, rhs, true, ty)?
+            }
+            NodeKind::BinaryOp(ref lhs, ref rhs, BinOp::LOr) => {
+                self.lower_logical(lhs, rhs, false, ty)?
+            }
+            NodeKind::BinaryOp(_, _, BinOp::Coalesce) => {
+                return Err(unsupported(node, "an optional"))
+            }
+            NodeKind::BinaryOp(ref lhs, ref rhs, ref op) => {
+                let lhs = self.lower(lhs)?;
+                let rhs = self.lower(rhs)?;
+                self.push(InstKind::Binary(op.clone(), lhs, rhs), ty)
+            }
+            NodeKind::AssignOp(ref lhs, ref rhs, ref op) => match lhs.kind {
+                NodeKind::Variable(ref name, _) => {
+                    let lhs = self.lower(lhs)?;
+                    let rhs = self.lower(rhs)?;
+                    let val = self.push(InstKind::Binary(op.clone(), lhs, rhs), ty);
+                    self.assign(name, val);
+                    val
+                }
+                _ => return Err(unsupported(node, "an assignment to other than a variable")),
+            },
+            NodeKind::Block(ref nodes) => {
+                self.scopes.push(BTreeMap::new());
+                let mut last = None;
+                for node in nodes {
+                    last = Some(self.lower(node)?);
+                }
+                self.scopes.pop();
+                match last {
+                    Some(val) => val,
+                    None => self.constant(Const::Unit, ty),
+                }
+            }
+            NodeKind::If(ref cond, ref then_, ref else_) => {
+                let cond = self.lower(cond)?;
+                self.lower_if(cond, then_, else_, ty)?
+            }
+            NodeKind::While(ref cond, ref body) => self.lower_while(cond, body, ty)?,
+            NodeKind::Apply(ref callee, ref args) => {
+                let name = match callee.kind {
+                    NodeKind::Variable(ref name, _) if self.lookup(name).is_none() => name,
+                    _ => return Err(unsupported(node, "a call of a function value")),
+                };
+                let mut vals = vec![];
+                for arg in args {
+                    vals.push(self.lower(arg)?);
+                }
+                if self.funcs.contains_key(name) {
+                    self.push(InstKind::Call(name.clone(), vals), ty)
+                } else if name == "print" || name == "to_string" {
+                    self.push(InstKind::Builtin(name.clone(), vals), ty)
+                } else {
+                    return Err(unsupported(node, "a call of this function"));
+                }
+            }
+            _ => return Err(unsupported(node, "this expression")),
+        })
+    }
+
+    // Lowers `node` in a new scope, so that the variables in scope are the same after it
+    fn lower_scoped(&mut self, node: &Node) -> Result<ValueId, TypeError> {
+        self.scopes.push(BTreeMap::new());
+        let val = self.lower(node);
+        self.scopes.pop();
+        val
+    }
+
+    // Both branches jump to a block taking the value of the `if`, and then the values of
+    // the variables assigned in either branch
+    fn lower_if(
+        &mut self,
+        cond: ValueId,
+        then_: &Node,
+        else_: &Node,
+        ty: Type,
+    ) -> Result<ValueId, TypeError> {
+        let (then_block, else_block) = (self.new_block(), self.new_block());
+        self.terminate(Terminator::Branch(
+            cond,
+            (then_block, vec![]),
+            (else_block, vec![]),
+        ));
+        let before = self.vars();
+
+        self.current = then_block;
+        let then_val = self.lower_scoped(then_)?;
+        let (then_end, then_vars) = (self.current, self.vars());
+
+        self.set_vars(&before);
+        self.current = else_block;
+        let else_val = self.lower_scoped(else_)?;
+        let (else_end, else_vars) = (self.current, self.vars());
+
+        let join = self.new_block();
+        let result = self.new_value(ty);
+        let mut params = vec![result];
+        let (mut then_args, mut else_args) = (vec![then_val], vec![else_val]);
+        let mut after = vec![];
+        for (i, &var) in before.iter().enumerate() {
+            if then_vars[i] == var && else_vars[i] == var {
+                after.push(var);
+                continue;
+            }
+            let param = self.new_value(self.func.values[var].clone());
+            params.push(param);
+            then_args.push(then_vars[i]);
+            else_args.push(else_vars[i]);
+            after.push(param);
+        }
+        self.func.blocks[join].params = params;
+        self.terms[then_end] = Some(Terminator::Jump(join, then_args));
+        self.terms[else_end] = Some(Terminator::Jump(join, else_args));
+        self.current = join;
+        self.set_vars(&after);
+        Ok(result)
+    }
+
+    // `a && b` is `if a { b } else { false }`, `a || b` is `if a { true } else { b }`
+    fn lower_logical(
+        &mut self,
+        lhs: &Node,
+        rhs: &Node,
+        is_and: bool,
+        ty: Type,
+    ) -> Result<ValueId, TypeError> {
+        let cond = self.lower(lhs)?;
+        let short = Node {
+            kind: NodeKind::Bool(!is_and),
+            range: lhs.range.clone(),
+            ty: Some(ty.clone()),
+        };
+        if is_and {
+            self.lower_if(cond, rhs, &short, ty)
+        } else {
+            self.lower_if(cond, &short, rhs, ty)
+        }
+    }
+
+    // Every variable in scope is a parameter of the block evaluating the condition, which
+    // is jumped to from before the loop and from the end of the body
+    fn lower_while(&mut self, cond: &Node, body: &Node, ty: Type) -> Result<ValueId, TypeError> {
+        let header = self.new_block();
+        let before = self.vars();
+        let params: Vec<ValueId> = before
+            .iter()
+            .map(|&var| {
+                let ty = self.func.values[var].clone();
+                self.new_value(ty)
+            })
+            .collect();
+        self.func.blocks[header].params = params.clone();
+        self.terminate(Terminator::Jump(header, before));
+
+        // Variables declared in the condition are visible in the body
+        self.current = header;
+        self.set_vars(&params);
+        self.scopes.push(BTreeMap::new());
+        let cond = self.lower(cond)?;
+        let after_cond = self.vars();
+        let (body_block, exit) = (self.new_block(), self.new_block());
+        self.terminate(Terminator::Branch(cond, (body_block, vec![]), (exit, vec![])));
+
+        self.current = body_block;
+        self.lower_scoped(body)?;
+        self.scopes.pop();
+        let vars = self.vars();
+        self.terminate(Terminator::Jump(header, vars));
+
+        self.current = exit;
+        self.set_vars(&after_cond[..params.len()]);
+        Ok(self.constant(Const::Unit, ty))
+    }
+}
+
+fn unsupported(node: &Node, what: &str) -> TypeError {
+    TypeError::new(
+        format!("{} cannot be lowered to the IR", what),
+        node.range.clone(),
+    )
+}
+
+impl Program {
+    // The functions are emitted first (and jumped over), then the code at the top level,
+    // which begins by storing them in globals, in their order
+    pub fn to_bytecode(&self) -> Vec<VMInst> {
+        let mut insts = vec![VMInst::Jmp(0)];
+        let mut prologue = vec![];
+        for (i, func) in self.funcs.iter().enumerate() {
+            prologue.push(VMInst::MakeClosure(insts.len(), 0));
+            prologue.push(VMInst::StoreG(i));
+            prologue.push(VMInst::Pop);
+            func.to_bytecode(&self.funcs, vec![], &mut insts);
+        }
+        insts[0] = VMInst::Jmp(insts.len());
+        self.main.to_bytecode(&self.funcs, prologue, &mut insts);
+        // `Ret` at the top level leaves the locals, but does not stop the VM
+        let end = insts.len() + 1;
+        insts.push(VMInst::Jmp(end));
+        insts
+    }
+}
+
+impl Function {
+    // Every value lives in its own local; parameters are the first ones, as in Codegen
+    fn to_bytecode(&self, funcs: &[Function], prologue: Vec<VMInst>, insts: &mut Vec<VMInst>) {
+        insts.push(VMInst::Entry(self.values.len()));
+        insts.extend(prologue);
+        let mut block_pcs = vec![];
+        let mut fixups = vec![]; // Jumps to blocks, whose pcs are only known at the end
+        for block in &self.blocks {
+            block_pcs.push(insts.len());
+            for inst in &block.insts {
+                self.inst_to_bytecode(inst, funcs, insts);
+                insts.push(VMInst::StoreV(inst.dest));
+                insts.push(VMInst::Pop);
+            }
+            match block.term {
+                Terminator::Jump(target, ref args) => {
+                    self.move_args(target, args, insts);
+                    fixups.push(insts.len());
+                    insts.push(VMInst::Jmp(target));
+                }
+                Terminator::Branch(cond, (then_, ref then_args), (else_, ref else_args)) => {
+                    insts.push(VMInst::LoadV(cond));
+                    let branch = insts.len();
+                    insts.push(VMInst::JmpIfFalse(0));
+                    self.move_args(then_, then_args, insts);
+                    fixups.push(insts.len());
+                    insts.push(VMInst::Jmp(then_));
+                    insts[branch] = VMInst::JmpIfFalse(insts.len());
+                    self.move_args(else_, else_args, insts);
+                    fixups.push(insts.len());
+                    insts.push(VMInst::Jmp(else_));
+                }
+                Terminator::Return(val) => {
+                    insts.push(VMInst::LoadV(val));
+                    insts.push(VMInst::Ret);
+                }
+            }
+        }
+        for pos in fixups {
+            if let VMInst::Jmp(ref mut target) = insts[pos] {
+                *target = block_pcs[*target];
+            }
+        }
+    }
+
+    // All the arguments are read before any parameter is written, as they may be swapped
+    fn move_args(&self, target: BlockId, args: &[ValueId], insts: &mut Vec<VMInst>) {
+        for &arg in args {
+            insts.push(VMInst::LoadV(arg));
+        }
+        for &param in self.blocks[target].params.iter().rev() {
+            insts.push(VMInst::StoreV(param));
+            insts.push(VMInst::Pop);
+        }
+    }
+
+    fn inst_to_bytecode(&self, inst: &Inst, funcs: &[Function], insts: &mut Vec<VMInst>) {
+        let ty = &self.values[inst.dest];
+        match inst.kind {
+            InstKind::Const(ref c) => insts.push(match *c {
+                Const::Bool(b) => VMInst::PushB(b),
+                Const::Int(n) => match ty.kind {
+                    TypeKind::Int(ty) if !ty.is_signed() => VMInst::PushU(n as u64),
+                    _ => VMInst::PushI(n),
+                },
+                Const::Float(f) => match wrap_inst(ty) {
+                    Some(_) => VMInst::PushF(f as f32 as f64),
+                    None => VMInst::PushF(f),
+                },
+                Const::String(ref s) => VMInst::PushS(s.clone()),
+                Const::Unit => VMInst::PushI(0),
+            }),
+            InstKind::Unary(ref op, val) => {
+                insts.push(VMInst::LoadV(val));
+                match *op {
+                    UnaryOp::Not => insts.push(VMInst::Not),
+                    UnaryOp::Neg => {
+                        insts.push(VMInst::Neg);
+                        insts.extend(wrap_inst(ty));
+                    }
+                }
+            }
+            InstKind::Binary(ref op, lhs, rhs) => {
+                insts.push(VMInst::LoadV(lhs));
+                insts.push(VMInst::LoadV(rhs));
+                insts.push(binop_inst(op));
+                if wraps(op) {
+                    insts.extend(wrap_inst(ty));
+                }
+            }
+            InstKind::Cast(val, ref to) => {
+                insts.push(VMInst::LoadV(val));
+                match to.kind {
+                    TypeKind::Int(ty) => insts.push(VMInst::IntCast(ty)),
+                    TypeKind::Float(ty) => insts.push(VMInst::FloatCast(ty)),
+                    _ => {}
+                }
+            }
+            InstKind::Call(ref name, ref args) => {
+                let index = funcs.iter().position(|f| &f.name == name).unwrap();
+                insts.push(VMInst::LoadG(index));
+                for &arg in args {
+                    insts.push(VMInst::LoadV(arg));
+                }
+                insts.push(VMInst::Apply(args.len()));
+            }
+            InstKind::Builtin(ref name, ref args) => {
+                for &arg in args {
+                    insts.push(VMInst::LoadV(arg));
+                }
+                insts.push(VMInst::Call(name.clone()));
+            }
+        }
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for func in &self.funcs {
+            writeln!(f, "{}", func)?;
+        }
+        write!(f, "{}", self.main)
+    }
+}
+
+// fn fact(%0: int) -> int {
+// bb0:
+//     %1 = const 1
+//     %2 = Le %0, %1
+//     br %2, bb1, bb2
+// ...
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params: Vec<String> = self.params
+            .iter()
+            .map(|&p| format!("%{}: {}", p, self.values[p]))
+            .collect();
+        writeln!(f, "fn {}({}) -> {} {{", self.name, params.join(", "), self.ret)?;
+        for (i, block) in self.blocks.iter().enumerate() {
+            if block.params.is_empty() {
+                writeln!(f, "bb{}:", i)?;
+            } else {
+                let params: Vec<String> = block
+                    .params
+                    .iter()
+                    .map(|&p| format!("%{}: {}", p, self.values[p]))
+                    .collect();
+                writeln!(f, "bb{}({}):", i, params.join(", "))?;
+            }
+            for inst in &block.insts {
+                writeln!(f, "    %{} = {}", inst.dest, inst.kind)?;
+            }
+            writeln!(f, "    {}", block.term)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+fn values(vals: &[ValueId]) -> String {
+    let vals: Vec<String> = vals.iter().map(|v| format!("%{}", v)).collect();
+    vals.join(", ")
+}
+
+fn target(block: BlockId, args: &[ValueId]) -> String {
+    if args.is_empty() {
+        format!("bb{}", block)
+    } else {
+        format!("bb{}({})", block, values(args))
+    }
+}
+
+impl fmt::Display for InstKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InstKind::Const(ref c) => match *c {
+                Const::Bool(b) => write!(f, "const {}", b),
+                Const::Int(n) => write!(f, "const {}", n),
+                Const::Float(x) => write!(f, "const {:?}", x),
+                Const::String(ref s) => write!(f, "const {:?}", s),
+                Const::Unit => write!(f, "const ()"),
+            },
+            InstKind::Unary(ref op, val) => write!(f, "{:?} %{}", op, val),
+            InstKind::Binary(ref op, lhs, rhs) => write!(f, "{:?} %{}, %{}", op, lhs, rhs),
+            InstKind::Cast(val, ref ty) => write!(f, "cast %{} as {}", val, ty),
+            InstKind::Call(ref name, ref args) => write!(f, "call {}({})", name, values(args)),
+            InstKind::Builtin(ref name, ref args) => {
+                write!(f, "builtin {}({})", name, values(args))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Terminator::Jump(block, ref args) => write!(f, "jmp {}", target(block, args)),
+            Terminator::Branch(cond, (then_, ref then_args), (else_, ref else_args)) => write!(
+                f,
+                "br %{}, {}, {}",
+                cond,
+                target(then_, then_args),
+                target(else_, else_args)
+            ),
+            Terminator::Return(val) => write!(f, "ret %{}", val),
+        }
+    }
+}
+
+#[test]
+fn test_ir() {
+    use lexer::Lexer;
+    use parser::Parser;
+    use codegen::Codegen;
+    use vm::VM;
+    use verify::verify;
+    let src = "def fact(n: int) -> int { if n <= 1 { 1 } else { n * fact(n - 1) } }
+               def fib(n: int) -> int {
+                 a = 0
+                 b = 1
+                 while n > 0 {
+                   a, b = b, a + b
+                   n -= 1
+                 }
+                 a
+               }
+               def count(n: u8) -> u8 {
+                 x: u8 = 250
+                 i: u8 = 0
+                 while i < n && true { x += 1 \n i += 1 }
+                 x
+               }
+               print(fact(10))
+               print(fib(30))
+               print(count(10))
+               print(to_string(2.5) + \"!\")";
+    let mut lexer = Lexer::new_from_string(src.to_string());
+    let mut parser = Parser::new(&mut lexer);
+    let mut codegen = Codegen::new(&mut parser);
+    codegen.opt_level = 0;
+    let nodes = codegen.read_program().unwrap();
+    let program = lower_program(&nodes).unwrap();
+    assert_eq!(
+        program.funcs[0].to_string(),
+        "fn fact(%0: int) -> int {
+bb0:
+    %1 = const 1
+    %2 = Le %0, %1
+    br %2, bb1, bb2
+bb1:
+    %3 = const 1
+    jmp bb3(%3)
+bb2:
+    %4 = const 1
+    %5 = Sub %0, %4
+    %6 = call fact(%5)
+    %7 = Mul %0, %6
+    jmp bb3(%7)
+bb3(%8: int):
+    ret %8
+}
+"
+    );
+    // Each value is defined once
+    for func in program.funcs.iter().chain(Some(&program.main)) {
+        let mut defined = vec![0; func.values.len()];
+        for &param in &func.params {
+            defined[param] += 1;
+        }
+        for block in &func.blocks {
+            for &param in &block.params {
+                defined[param] += 1;
+            }
+            for inst in &block.insts {
+                defined[inst.dest] += 1;
+            }
+        }
+        assert!(defined.iter().all(|&n| n == 1));
+    }
+
+    codegen.via_ir = true;
+    codegen.gen_program(&nodes).unwrap();
+    assert_eq!(codegen.vm_insts, program.to_bytecode());
+    assert_eq!(verify(&codegen.vm_insts, VM::new().stack_limit), Ok(()));
+    let mut vm = VM::new();
+    vm.output = Some(String::new());
+    vm.run(codegen.vm_insts.clone()).unwrap();
+    codegen.via_ir = false;
+    codegen.vm_insts.clear();
+    codegen.gen_program(&nodes).unwrap();
+    let mut reference = VM::new();
+    reference.output = Some(String::new());
+    reference.run(codegen.vm_insts.clone()).unwrap();
+    assert_eq!(vm.output.unwrap(), "3628800\n832040\n4\n2.5!\n");
+    assert_eq!(reference.output.unwrap(), "3628800\n832040\n4\n2.5!\n");
+
+    // What the IR lacks is compiled from the nodes instead
+    let mut lexer = Lexer::new_from_string("m = {1: 2}\nprint(m[1])".to_string());
+    let mut parser = Parser::new(&mut lexer);
+    let mut codegen = Codegen::new(&mut parser);
+    let nodes = codegen.read_program().unwrap();
+    assert_eq!(
+        lower_program(&nodes).unwrap_err().msg,
+        "this expression cannot be lowered to the IR"
+    );
+    codegen.via_ir = true;
+    codegen.gen_program(&nodes).unwrap();
+    let mut vm = VM::new();
+    vm.output = Some(String::new());
+    vm.run(codegen.vm_insts.clone()).unwrap();
+    assert_eq!(vm.output.unwrap(), "2\n");
+}
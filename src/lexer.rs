@@ -3,9 +3,11 @@ use std::io::prelude::*;
 use std::str;
 use std::process;
 use std::collections::VecDeque;
+use std::mem;
 use std::ops::Range;
+use std::path::{Path, PathBuf};
 
-use token::{Symbol, Token, TokenKind};
+use token::{StringPart, Symbol, Token, TokenKind};
 
 use ansi_term::{Colour, Style};
 
@@ -14,6 +16,7 @@ pub struct Lexer {
     source: String,
     pos: usize,
     buf: VecDeque<Token>,
+    path: Option<PathBuf>, // The file the source was read from
 }
 
 impl Lexer {
@@ -45,6 +48,7 @@ impl Lexer {
             source: file_body,
             pos: 0,
             buf: VecDeque::new(),
+            path: Some(PathBuf::from(source_file_name)),
         }
     }
 
@@ -53,8 +57,18 @@ impl Lexer {
             source: src,
             pos: 0,
             buf: VecDeque::new(),
+            path: None,
         }
     }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    // The line (from 1) of the position `offset` in the source
+    pub fn line_of(&self, offset: usize) -> usize {
+        self.source[..offset].matches('\n').count() + 1
+    }
 }
 
 impl Lexer {
@@ -78,18 +92,32 @@ impl Lexer {
 
     pub fn peek(&mut self) -> Result<Token, ()> {
         let tok = self.read_token()?;
-        self.buf.push_back(tok.clone());
+        self.buf.push_front(tok.clone());
         Ok(tok)
     }
 
     pub fn unget(&mut self, tok: &Token) {
-        self.buf.push_back(tok.clone());
+        self.buf.push_front(tok.clone());
+    }
+
+    // Whether only whitespace is left
+    pub fn at_end(&self) -> bool {
+        self.buf.iter().all(|tok| tok.kind == TokenKind::Newline)
+            && self.source[self.pos..].trim().is_empty()
+    }
+
+    // The offset of the next character to read
+    pub fn pos(&self) -> usize {
+        self.pos
     }
 }
 
 impl Lexer {
     pub fn skip_symbol(&mut self, sym: Symbol) -> Result<bool, ()> {
-        let tok = self.read_token()?;
+        let tok = match self.read_token() {
+            Ok(tok) => tok,
+            Err(()) => return Ok(false), // EOF
+        };
         if tok.kind == TokenKind::Symbol(sym) {
             return Ok(true);
         }
@@ -196,15 +224,61 @@ impl Lexer {
         let start = self.pos;
         assert_eq!(self.skip_char()?, '\"');
         // TODO: support escape sequence
-        let s = self.skip_while(|c| c != '\"')?;
-        assert_eq!(self.skip_char()?, '\"');
-        Ok(Token::new_string(
-            s,
-            Range {
-                start: start,
-                end: self.pos,
-            },
-        ))
+        let mut parts = vec![];
+        let mut s = String::new();
+        loop {
+            match self.skip_char()? {
+                '\"' => break,
+                '$' if self.next_char()? == '{' => {
+                    self.skip_char()?;
+                    parts.push(StringPart::Str(mem::take(&mut s)));
+                    let expr_start = self.pos;
+                    self.skip_interpolated_expr()?;
+                    parts.push(StringPart::Expr(expr_start..self.pos));
+                    assert_eq!(self.skip_char()?, '}');
+                }
+                c => s.push(c),
+            }
+        }
+        let range = Range {
+            start,
+            end: self.pos,
+        };
+        if parts.is_empty() {
+            return Ok(Token::new_string(s, range));
+        }
+        parts.push(StringPart::Str(s));
+        Ok(Token::new_interpolated_string(parts, range))
+    }
+
+    // Skips the expression in `${...}` up to the closing brace. The expression may contain
+    // braces and strings of its own.
+    fn skip_interpolated_expr(&mut self) -> Result<(), ()> {
+        let mut depth = 0;
+        loop {
+            match self.next_char()? {
+                '}' if depth == 0 => return Ok(()),
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                '\"' => {
+                    self.read_string_literal()?;
+                    continue;
+                }
+                _ => {}
+            }
+            self.skip_char()?;
+        }
+    }
+
+    // A lexer for the expression at `range` of the source, ending there, so that the
+    // positions of its tokens are those in the whole source
+    pub fn sub_lexer(&self, range: Range<usize>) -> Lexer {
+        Lexer {
+            source: self.source[..range.end].to_string(),
+            pos: range.start,
+            buf: VecDeque::new(),
+            path: self.path.clone(),
+        }
     }
 }
 
@@ -281,6 +355,8 @@ impl Lexer {
             '=' => {
                 if self.skip_char_is('=')? {
                     symbol = Symbol::Eq
+                } else if self.skip_char_is('>')? {
+                    symbol = Symbol::FatArrow
                 } else {
                     symbol = Symbol::Assign
                 }
@@ -344,9 +420,23 @@ impl Lexer {
             '.' => symbol = Symbol::Point,
             ',' => symbol = Symbol::Comma,
             ';' => symbol = Symbol::Semicolon,
-            ':' => symbol = Symbol::Colon,
+            ':' => {
+                if self.skip_char_is(':')? {
+                    symbol = Symbol::DoubleColon
+                } else {
+                    symbol = Symbol::Colon
+                }
+            }
             '~' => symbol = Symbol::BitwiseNot,
-            '?' => symbol = Symbol::Question,
+            '?' => {
+                if self.skip_char_is('?')? {
+                    symbol = Symbol::DoubleQuestion
+                } else if self.skip_char_is('.')? {
+                    symbol = Symbol::QuestionPoint
+                } else {
+                    symbol = Symbol::Question
+                }
+            }
             '#' => symbol = Symbol::Hash,
             _ => {}
         };
@@ -651,3 +741,21 @@ fn test_symbols() {
         TokenKind::Symbol(Symbol::Hash)
     );
 }
+
+#[test]
+fn test_interpolation() {
+    use token::{StringPart, TokenKind};
+    let src = "\"a${x + 1}b${ {\"}\"} }\" 1";
+    let mut lexer = Lexer::new_from_string(src.to_string());
+    assert_eq!(
+        lexer.read_token().unwrap().kind,
+        TokenKind::InterpolatedString(vec![
+            StringPart::Str("a".to_string()),
+            StringPart::Expr(4..9),
+            StringPart::Str("b".to_string()),
+            StringPart::Expr(13..20),
+            StringPart::Str("".to_string()),
+        ])
+    );
+    assert_eq!(lexer.read_token().unwrap().kind, TokenKind::Int(1));
+}
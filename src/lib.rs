@@ -3,8 +3,18 @@ pub mod lexer;
 pub mod node;
 pub mod parser;
 pub mod typing;
+pub mod optimize;
+pub mod module;
 pub mod codegen;
+pub mod ir;
+pub mod regvm;
 pub mod vm_base;
+pub mod gc;
 pub mod vm;
+pub mod trace;
+pub mod debugger;
+pub mod xsc;
+pub mod verify;
+pub mod asm;
 
 extern crate ansi_term;
@@ -1,8 +1,16 @@
+extern crate ansi_term;
+use ansi_term::Colour;
+
 extern crate clap;
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
 
 extern crate xscript;
-use xscript::{codegen, lexer, parser, vm};
+use xscript::{asm, codegen, debugger, ir, lexer, parser, regvm, trace, verify, vm, xsc};
 
 const VERSION_STR: &'static str = env!("CARGO_PKG_VERSION");
 
@@ -17,18 +25,240 @@ fn main() {
                 .long("version")
                 .help("Show version info"),
         )
-        .arg(Arg::with_name("FILE").help("Input file").index(1));
+        .arg(
+            Arg::with_name("include")
+                .short("I")
+                .long("include")
+                .value_name("DIR")
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .help("Add a directory to search for imported modules"),
+        )
+        .arg(
+            Arg::with_name("opt-level")
+                .short("O")
+                .value_name("LEVEL")
+                .possible_values(&["0", "1"])
+                .default_value("1")
+                .global(true)
+                .help("Set the optimization level"),
+        )
+        .arg(
+            Arg::with_name("via-ir")
+                .long("via-ir")
+                .global(true)
+                .help("Compile the program through the IR, unless it uses what the IR lacks"),
+        )
+        .arg(
+            Arg::with_name("stack-limit")
+                .long("stack-limit")
+                .value_name("VALUES")
+                .help("Set the maximum number of stack values (or registers, with --register-vm)"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .help("Run the program in the debugger (without optimizations)"),
+        )
+        .arg(
+            Arg::with_name("gc-stress")
+                .long("gc-stress")
+                .help("Collect garbage after every allocation"),
+        )
+        .arg(
+            Arg::with_name("gc-stats")
+                .long("gc-stats")
+                .help("Print the statistics of the garbage collector at exit"),
+        )
+        .arg(
+            Arg::with_name("emit-ir")
+                .long("emit-ir")
+                .help("Print the IR of the program instead of running it"),
+        )
+        .arg(
+            Arg::with_name("dump-bytecode")
+                .long("dump-bytecode")
+                .help("Print the VM instructions of the program instead of running it"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("Print every instruction run, with the stack of its call, to stderr"),
+        )
+        .arg(
+            Arg::with_name("trace-file")
+                .long("trace-file")
+                .value_name("FILE")
+                .help("Write the trace to FILE instead of stderr (implies --trace)"),
+        )
+        .arg(
+            Arg::with_name("trace-json")
+                .long("trace-json")
+                .help("Write the trace as JSON lines (implies --trace)"),
+        )
+        .arg(
+            Arg::with_name("register-vm")
+                .long("register-vm")
+                .help("Run the program on the register VM (only if it can be lowered to the IR)"),
+        )
+        .arg(Arg::with_name("FILE").help("Input file (a program or a compiled .xsc file)").index(1))
+        .subcommand(
+            SubCommand::with_name("compile")
+                .about("Compile a program to a .xsc file, to be run without compiling it again")
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .value_name("OUTPUT")
+                        .help("Write to OUTPUT instead of the input file with the extension .xsc"),
+                )
+                .arg(Arg::with_name("FILE").help("Input file").required(true).index(1)),
+        );
     let app_matches = app.clone().get_matches();
 
-    if let Some(file_name) = app_matches.value_of("FILE") {
+    if let Some(matches) = app_matches.subcommand_matches("compile") {
+        let file_name = matches.value_of("FILE").unwrap();
         let mut lexer = lexer::Lexer::new(file_name);
         let mut parser = parser::Parser::new(&mut lexer);
         let mut codegen = codegen::Codegen::new(&mut parser);
+        codegen.opt_level = matches.value_of("opt-level").unwrap().parse().unwrap();
+        codegen.via_ir = matches.is_present("via-ir");
+        if let Some(dirs) = matches.values_of("include") {
+            codegen.search_path = dirs.map(PathBuf::from).collect();
+        }
+        if let Err(e) = codegen.gen() {
+            println!("{} {}", Colour::Red.bold().paint("error:"), e.msg);
+            process::exit(-1);
+        }
+        let output = match matches.value_of("output") {
+            Some(output) => PathBuf::from(output),
+            None => Path::new(file_name).with_extension("xsc"),
+        };
+        let bytecode = xsc::Bytecode {
+            insts: codegen.vm_insts,
+            debug: codegen.debug,
+        };
+        if let Err(e) = fs::write(&output, bytecode.encode()) {
+            let output = output.display();
+            println!("{} cannot write '{}': {}", Colour::Red.bold().paint("error:"), output, e);
+            process::exit(-1);
+        }
+        return;
+    }
+
+    if let Some(file_name) = app_matches.value_of("FILE") {
         let mut vm = vm::VM::new();
+        let stack_limit = match app_matches.value_of("stack-limit") {
+            Some(limit) => limit.parse().unwrap_or_else(|_| {
+                println!("{} invalid stack limit '{}'", Colour::Red.bold().paint("error:"), limit);
+                process::exit(-1)
+            }),
+            None => vm.stack_limit,
+        };
+        let compiled = Path::new(file_name).extension() == Some("xsc".as_ref());
+        let bytecode = if compiled {
+            if app_matches.is_present("emit-ir") || app_matches.is_present("register-vm") {
+                println!("{} a compiled program has no IR", Colour::Red.bold().paint("error:"));
+                process::exit(-1);
+            }
+            // Bytecode from a file is checked, so that it cannot crash the VM
+            let bytes = fs::read(file_name).map_err(|e| e.to_string());
+            let bytecode = bytes.and_then(|bytes| {
+                let bytecode = xsc::Bytecode::decode(&bytes)?;
+                verify::verify(&bytecode.insts, stack_limit)
+                    .map_err(|msg| format!("invalid bytecode {}", msg))?;
+                Ok(bytecode)
+            });
+            bytecode.unwrap_or_else(|msg| {
+                println!("{} {}: {}", Colour::Red.bold().paint("error:"), file_name, msg);
+                process::exit(-1)
+            })
+        } else {
+            let mut lexer = lexer::Lexer::new(file_name);
+            let mut parser = parser::Parser::new(&mut lexer);
+            let mut codegen = codegen::Codegen::new(&mut parser);
+            codegen.opt_level = app_matches.value_of("opt-level").unwrap().parse().unwrap();
+            codegen.via_ir = app_matches.is_present("via-ir");
+            if app_matches.is_present("debug") {
+                codegen.opt_level = 0;
+                codegen.via_ir = false;
+            }
+            if let Some(dirs) = app_matches.values_of("include") {
+                codegen.search_path = dirs.map(PathBuf::from).collect();
+            }
 
-        codegen.gen();
-        println!("{:?}", codegen.vm_insts);
-        vm.run(codegen.vm_insts);
+            let result = codegen.read_program().and_then(|nodes| {
+                if app_matches.is_present("emit-ir") {
+                    print!("{}", ir::lower_program(&nodes)?);
+                    process::exit(0);
+                }
+                if app_matches.is_present("register-vm") {
+                    let code = regvm::lower_program(&ir::lower_program(&nodes)?);
+                    let mut vm = regvm::RegVM::new();
+                    vm.stack_limit = stack_limit;
+                    if let Err(msg) = vm.run(&code) {
+                        let error = Colour::Red.bold().paint("error:");
+                        println!("{} uncaught error: {}", error, msg);
+                        process::exit(-1);
+                    }
+                    process::exit(0);
+                }
+                codegen.gen_program(&nodes)
+            });
+            if let Err(e) = result {
+                println!("{} {}", Colour::Red.bold().paint("error:"), e.msg);
+                process::exit(-1);
+            }
+            xsc::Bytecode {
+                insts: codegen.vm_insts,
+                debug: codegen.debug,
+            }
+        };
+        vm.stack_limit = stack_limit;
+        vm.debug = bytecode.debug;
+        vm.heap.stress = app_matches.is_present("gc-stress");
+        // The lines of a compiled program are shown without their text
+        let source = if compiled {
+            String::new()
+        } else {
+            fs::read_to_string(file_name).unwrap_or_default()
+        };
+        if app_matches.is_present("debug") {
+            debugger::Debugger::new(vm, bytecode.insts, &source).repl();
+            return;
+        }
+        if app_matches.is_present("dump-bytecode") {
+            print!("{}", asm::disassemble(&bytecode.insts, &vm.debug, &source));
+            return;
+        }
+        let format = if app_matches.is_present("trace-json") {
+            trace::TraceFormat::Json
+        } else {
+            trace::TraceFormat::Text
+        };
+        if let Some(path) = app_matches.value_of("trace-file") {
+            let file = fs::File::create(path).unwrap_or_else(|e| {
+                println!("{} cannot create '{}': {}", Colour::Red.bold().paint("error:"), path, e);
+                process::exit(-1)
+            });
+            vm.tracer = Some(trace::Tracer::to_writer(format, io::BufWriter::new(file)));
+        } else if app_matches.is_present("trace") || app_matches.is_present("trace-json") {
+            vm.tracer = Some(trace::Tracer::to_writer(format, io::stderr()));
+        }
+        let result = vm.run(bytecode.insts);
+        // Dropping the tracer flushes the trace file, which exiting would not
+        vm.tracer = None;
+        if app_matches.is_present("gc-stats") {
+            let stats = vm.heap.stats();
+            eprintln!(
+                "gc: {} collections, {} objects allocated, {} freed, {} live",
+                stats.collections, stats.allocated, stats.freed, stats.live
+            );
+        }
+        if let Err(e) = result {
+            println!("{} {}", Colour::Red.bold().paint("error:"), e);
+            process::exit(-1);
+        }
     } else {
         app.print_help().unwrap();
         println!();
@@ -0,0 +1,578 @@
+use lexer::Lexer;
+use parser::Parser;
+use node::{FuncDef, Node, NodeKind, Pattern, PatternKind};
+use typing::{Type, TypeError, TypeKind};
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+// Loads the modules imported by a program with `import "path"`, and merges them into it.
+//
+// The top-level names of a module `m` (of the file `m.xs`) are renamed to `m::name`, which
+// is also how other modules refer to them. Modules are executed once, in the order they are
+// first imported, before the modules importing them.
+pub struct ModuleLoader {
+    pub search_path: Vec<PathBuf>, // Searched after the directory of the importing file
+    modules: HashMap<PathBuf, Module>,
+    loading: Vec<PathBuf>, // The chain of imports being loaded, to detect cycles
+    nodes: Vec<Node>,      // The code of the loaded modules
+    pub imported: usize,   // The number of nodes of modules, which the program starts with
+}
+
+#[derive(Clone)]
+struct Module {
+    name: String,
+    values: HashSet<String>, // functions and variables
+    types: HashSet<String>,  // structs, enums and traits
+    public: HashSet<String>,
+}
+
+impl ModuleLoader {
+    pub fn new(search_path: Vec<PathBuf>) -> ModuleLoader {
+        ModuleLoader {
+            search_path,
+            modules: HashMap::new(),
+            loading: vec![],
+            nodes: vec![],
+            imported: 0,
+        }
+    }
+
+    // Returns the program `nodes`, read from the file `path` if any, preceded by the
+    // modules it imports
+    pub fn load(&mut self, nodes: Vec<Node>, path: Option<&Path>) -> Result<Vec<Node>, TypeError> {
+        let dir = match path {
+            Some(path) => {
+                self.loading.push(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+                path.parent().map(|dir| dir.to_path_buf())
+            }
+            None => None,
+        };
+        let (nodes, _) = self.resolve(nodes, None, dir.as_deref())?;
+        self.loading.clear();
+        let mut program = mem::take(&mut self.nodes);
+        self.imported = program.len();
+        program.extend(nodes);
+        Ok(program)
+    }
+
+    fn find(
+        &self,
+        name: &str,
+        dir: Option<&Path>,
+        range: &Range<usize>,
+    ) -> Result<PathBuf, TypeError> {
+        let mut file = PathBuf::from(name);
+        if file.extension().is_none() {
+            file.set_extension("xs");
+        }
+        dir.into_iter()
+            .chain(self.search_path.iter().map(|dir| dir.as_path()))
+            .map(|dir| dir.join(&file))
+            .chain(if dir.is_none() { Some(file.clone()) } else { None })
+            .find(|path| path.is_file())
+            .and_then(|path| path.canonicalize().ok())
+            .ok_or_else(|| TypeError::new(format!("cannot find module `{}`", name), range.clone()))
+    }
+
+    fn load_module(&mut self, path: PathBuf, range: &Range<usize>) -> Result<Module, TypeError> {
+        if let Some(module) = self.modules.get(&path) {
+            return Ok(module.clone());
+        }
+        if let Some(pos) = self.loading.iter().position(|p| p == &path) {
+            let cycle: Vec<String> = self.loading[pos..]
+                .iter()
+                .chain(Some(&path))
+                .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                .collect();
+            return Err(TypeError::new(
+                format!("import cycle: {}", cycle.join(" -> ")),
+                range.clone(),
+            ));
+        }
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        if let Some((other, _)) = self.modules.iter().find(|&(_, m)| m.name == name) {
+            return Err(TypeError::new(
+                format!(
+                    "module `{}` is defined by both `{}` and `{}`",
+                    name,
+                    other.display(),
+                    path.display()
+                ),
+                range.clone(),
+            ));
+        }
+
+        let mut lexer = Lexer::new(path.to_str().unwrap());
+        let nodes = Parser::new(&mut lexer).read_all().map_err(|e| {
+            TypeError::new(format!("{} in `{}`", e.msg, path.display()), range.clone())
+        })?;
+        self.loading.push(path.clone());
+        let resolved = self.resolve(nodes, Some(&name), path.parent());
+        self.loading.pop();
+        let (nodes, module) = resolved?;
+        self.nodes.extend(nodes);
+        self.modules.insert(path, module.clone());
+        Ok(module)
+    }
+
+    // Loads the modules imported by `nodes`, and renames the names in them. `prefix` is
+    // the name of the module, or None for the program itself.
+    fn resolve(
+        &mut self,
+        nodes: Vec<Node>,
+        prefix: Option<&str>,
+        dir: Option<&Path>,
+    ) -> Result<(Vec<Node>, Module), TypeError> {
+        let mut module = Module {
+            name: prefix.unwrap_or("").to_string(),
+            values: HashSet::new(),
+            types: HashSet::new(),
+            public: HashSet::new(),
+        };
+        let mut imports: HashMap<String, Module> = HashMap::new();
+        let mut uses = vec![];
+        let mut items = vec![];
+        for node in nodes {
+            match node.kind {
+                NodeKind::Import(ref name) => {
+                    let path = self.find(name, dir, &node.range)?;
+                    let imported = self.load_module(path, &node.range)?;
+                    imports.insert(imported.name.clone(), imported);
+                }
+                NodeKind::Use(path) => uses.push((path, node.range)),
+                NodeKind::Pub(item) => {
+                    let (mut values, mut types) = (vec![], vec![]);
+                    if !declared_names(&item, &mut values, &mut types) {
+                        return Err(TypeError::new(
+                            "`pub` must be followed by a definition or an assignment".to_string(),
+                            node.range.clone(),
+                        ));
+                    }
+                    module.public.extend(values.into_iter().chain(types));
+                    items.push(*item);
+                }
+                _ => items.push(node),
+            }
+        }
+        for item in &items {
+            let mut values = vec![];
+            let mut types = vec![];
+            declared_names(item, &mut values, &mut types);
+            module.values.extend(values);
+            module.types.extend(types);
+        }
+
+        let mut renamer = Renamer {
+            values: HashMap::new(),
+            types: HashMap::new(),
+            modules: imports,
+            bound: vec![],
+        };
+        if let Some(prefix) = prefix {
+            for name in &module.values {
+                renamer.values.insert(name.clone(), format!("{}::{}", prefix, name));
+            }
+            for name in &module.types {
+                renamer.types.insert(name.clone(), format!("{}::{}", prefix, name));
+            }
+        }
+        for (path, range) in uses {
+            let (is_value, is_type) = renamer.lookup_item(&path, &range)?;
+            let name = path.join("::");
+            if is_value {
+                renamer.values.insert(path[1].clone(), name.clone());
+            }
+            if is_type {
+                renamer.types.insert(path[1].clone(), name);
+            }
+        }
+        for item in &mut items {
+            renamer.rename_top_level(item)?;
+        }
+        Ok((items, module))
+    }
+}
+
+// Collects the names declared at the top level by `node`, and returns whether it is a
+// definition or an assignment at all
+fn declared_names(node: &Node, values: &mut Vec<String>, types: &mut Vec<String>) -> bool {
+    match node.kind {
+        NodeKind::Func(FuncDef {
+            name: Some(ref name),
+            ..
+        })
+        | NodeKind::Const(ref name, _, _) => values.push(name.clone()),
+        NodeKind::Struct(ref name, _, _)
+        | NodeKind::Enum(ref name, _, _)
+        | NodeKind::Trait(ref name, _) => types.push(name.clone()),
+        NodeKind::BinaryOp(ref lhs, _, ::node::BinOp::Assign) => assigned_names(lhs, values),
+        _ => return false,
+    }
+    true
+}
+
+fn assigned_names(target: &Node, names: &mut Vec<String>) {
+    match target.kind {
+        NodeKind::Variable(ref name, _) => names.push(name.clone()),
+        NodeKind::Tuple(ref targets) => for target in targets {
+            assigned_names(target, names);
+        },
+        _ => {}
+    }
+}
+
+// Renames the names in a module to those of the items they refer to
+struct Renamer {
+    values: HashMap<String, String>,
+    types: HashMap<String, String>,
+    modules: HashMap<String, Module>, // The modules imported, by name
+    bound: Vec<String>,               // Locals, which shadow top-level names
+}
+
+impl Renamer {
+    // Whether `module::name` is a value and whether it is a type, if it is public
+    fn lookup_item(
+        &self,
+        path: &[String],
+        range: &Range<usize>,
+    ) -> Result<(bool, bool), TypeError> {
+        let module = match self.modules.get(&path[0]) {
+            Some(module) if path.len() == 2 => module,
+            _ => {
+                return Err(TypeError::new(
+                    format!("cannot find module `{}`; import it first", path[0]),
+                    range.clone(),
+                ))
+            }
+        };
+        let is_value = module.values.contains(&path[1]);
+        let is_type = module.types.contains(&path[1]);
+        if !is_value && !is_type {
+            return Err(TypeError::new(
+                format!("cannot find `{}` in module `{}`", path[1], path[0]),
+                range.clone(),
+            ));
+        }
+        if !module.public.contains(&path[1]) {
+            return Err(TypeError::new(
+                format!("`{}` is private to module `{}`", path[1], path[0]),
+                range.clone(),
+            ));
+        }
+        Ok((is_value, is_type))
+    }
+
+    // Top-level definitions are renamed along with the references to them
+    fn rename_top_level(&mut self, node: &mut Node) -> Result<(), TypeError> {
+        match node.kind {
+            NodeKind::Func(ref mut func) => {
+                if let Some(ref mut name) = func.name {
+                    self.rename_value(name);
+                }
+                return self.rename_func(func, &node.range);
+            }
+            NodeKind::Struct(ref mut name, _, ref mut fields) => {
+                self.rename_type_name(name);
+                for &mut (_, ref mut ty) in fields {
+                    self.rename_type(ty, &node.range)?;
+                }
+                return Ok(());
+            }
+            NodeKind::Enum(ref mut name, _, ref mut variants) => {
+                self.rename_type_name(name);
+                for variant in variants {
+                    for ty in &mut variant.fields {
+                        self.rename_type(ty, &node.range)?;
+                    }
+                }
+                return Ok(());
+            }
+            NodeKind::Const(ref mut name, ref mut ty, ref mut value) => {
+                self.rename_value(name);
+                if let Some(ref mut ty) = *ty {
+                    self.rename_type(ty, &node.range)?;
+                }
+                return self.rename(value);
+            }
+            NodeKind::Trait(ref mut name, ref mut methods) => {
+                self.rename_type_name(name);
+                for &mut (_, ref mut ty) in methods {
+                    self.rename_type(ty, &node.range)?;
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+        self.rename(node)
+    }
+
+    fn rename(&mut self, node: &mut Node) -> Result<(), TypeError> {
+        let range = node.range.clone();
+        let len = self.bound.len();
+        match node.kind {
+            NodeKind::Variable(ref mut name, ref mut annotation) => {
+                if let Some(ref mut ty) = *annotation {
+                    self.rename_type(ty, &range)?;
+                }
+                self.rename_value(name);
+                return Ok(());
+            }
+            NodeKind::Path(ref mut path) => {
+                if let Some(name) = self.rename_path(path, &range)? {
+                    node.kind = NodeKind::Variable(name, None);
+                }
+                return Ok(());
+            }
+            NodeKind::StructLit(ref mut name, ref mut fields) => {
+                *name = self.resolve_type_name(name, &range)?;
+                for &mut (_, ref mut value) in fields {
+                    self.rename(value)?;
+                }
+                return Ok(());
+            }
+            NodeKind::Cast(ref mut expr, ref mut ty) => {
+                self.rename_type(ty, &range)?;
+                return self.rename(expr);
+            }
+            NodeKind::Func(ref mut func) => {
+                // A nested `def` declares a local
+                if let Some(ref name) = func.name {
+                    self.bound.push(name.clone());
+                }
+                return self.rename_func(func, &range);
+            }
+            NodeKind::Impl(ref mut impl_) => {
+                self.rename_type(&mut impl_.ty, &range)?;
+                if let Some(ref mut trait_) = impl_.trait_ {
+                    *trait_ = self.resolve_type_name(trait_, &range)?;
+                }
+                for method in &mut impl_.methods {
+                    self.rename_func(method, &range)?;
+                }
+                return Ok(());
+            }
+            NodeKind::For(ref vars, ref mut iterable, ref mut body) => {
+                self.rename(iterable)?;
+                self.bound.extend(vars.iter().cloned());
+                self.rename(body)?;
+            }
+            NodeKind::IfLet(ref name, ref mut expr, ref mut then_, ref mut else_) => {
+                self.rename(expr)?;
+                self.bound.push(name.clone());
+                self.rename(then_)?;
+                self.bound.truncate(len);
+                self.rename(else_)?;
+            }
+            NodeKind::OptChain(ref name, ref mut expr, ref mut rest) => {
+                self.rename(expr)?;
+                self.bound.push(name.clone());
+                self.rename(rest)?;
+            }
+            NodeKind::Try(ref mut body, ref name, ref mut handler) => {
+                self.rename(body)?;
+                self.bound.push(name.clone());
+                self.rename(handler)?;
+            }
+            NodeKind::Match(ref mut cond, ref mut arms) => {
+                self.rename(cond)?;
+                for arm in arms {
+                    self.rename_pattern(&mut arm.pattern)?;
+                    if let Some(ref mut guard) = arm.guard {
+                        self.rename(guard)?;
+                    }
+                    self.rename(&mut arm.body)?;
+                    self.bound.truncate(len);
+                }
+            }
+            NodeKind::Import(_) | NodeKind::Use(_) | NodeKind::Pub(_) => {
+                return Err(TypeError::new(
+                    "`import`, `use` and `pub` are only allowed at the top level".to_string(),
+                    range,
+                ))
+            }
+            _ => for child in node.children_mut() {
+                self.rename(child)?;
+            },
+        }
+        // Blocks and the constructs binding variables end their scope
+        self.bound.truncate(len);
+        Ok(())
+    }
+
+    fn rename_func(&mut self, func: &mut FuncDef, range: &Range<usize>) -> Result<(), TypeError> {
+        let len = self.bound.len();
+        for param in &mut func.type_params {
+            for bound in &mut param.bounds {
+                *bound = self.resolve_type_name(bound, range)?;
+            }
+        }
+        for &mut (ref name, ref mut ty) in &mut func.params {
+            self.rename_type(ty, range)?;
+            self.bound.push(name.clone());
+        }
+        if let Some(ref mut ret) = func.ret {
+            self.rename_type(ret, range)?;
+        }
+        let result = self.rename(&mut func.body);
+        self.bound.truncate(len);
+        result
+    }
+
+    fn rename_pattern(&mut self, pat: &mut Pattern) -> Result<(), TypeError> {
+        match pat.kind {
+            PatternKind::Variable(ref name) => self.bound.push(name.clone()),
+            PatternKind::Variant(ref mut path, ref mut fields) => {
+                self.rename_path(path, &pat.range)?;
+                for field in fields {
+                    self.rename_pattern(field)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn rename_value(&self, name: &mut String) {
+        if !self.bound.contains(name) {
+            if let Some(renamed) = self.values.get(name) {
+                *name = renamed.clone();
+            }
+        }
+    }
+
+    fn rename_type_name(&self, name: &mut String) {
+        if let Some(renamed) = self.types.get(name) {
+            *name = renamed.clone();
+        }
+    }
+
+    // `Name` or `module::Name`
+    fn resolve_type_name(&self, name: &str, range: &Range<usize>) -> Result<String, TypeError> {
+        let path: Vec<String> = name.split("::").map(|s| s.to_string()).collect();
+        if path.len() > 1 {
+            self.lookup_item(&path, range)?;
+            return Ok(name.to_string());
+        }
+        Ok(self.types.get(name).cloned().unwrap_or_else(|| name.to_string()))
+    }
+
+    fn rename_type(&self, ty: &mut Type, range: &Range<usize>) -> Result<(), TypeError> {
+        match ty.kind {
+            TypeKind::Named(ref mut name, ref mut args) => {
+                *name = self.resolve_type_name(name, range)?;
+                for arg in args {
+                    self.rename_type(arg, range)?;
+                }
+            }
+            TypeKind::Map(ref mut key, ref mut value) => {
+                self.rename_type(key, range)?;
+                self.rename_type(value, range)?;
+            }
+            TypeKind::Func(ref mut params, ref mut ret) => {
+                for param in params {
+                    self.rename_type(param, range)?;
+                }
+                self.rename_type(ret, range)?;
+            }
+            TypeKind::Optional(ref mut inner) => self.rename_type(inner, range)?,
+            TypeKind::Tuple(ref mut elems) => for elem in elems {
+                self.rename_type(elem, range)?;
+            },
+            TypeKind::Generic(ref mut params, ref mut inner) => {
+                for param in params {
+                    for bound in &mut param.bounds {
+                        *bound = self.resolve_type_name(bound, range)?;
+                    }
+                }
+                self.rename_type(inner, range)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Renames the path `Enum::Variant`, `Type::method` or `module::item...`. Returns the
+    // name of the variable it is if it refers to a value of a module.
+    fn rename_path(
+        &self,
+        path: &mut Vec<String>,
+        range: &Range<usize>,
+    ) -> Result<Option<String>, TypeError> {
+        if self.modules.contains_key(&path[0]) {
+            let (is_value, _) = self.lookup_item(&path[..2], range)?;
+            let name = format!("{}::{}", path[0], path[1]);
+            if path.len() == 2 && is_value {
+                return Ok(Some(name));
+            }
+            path.splice(..2, Some(name));
+            return Ok(None);
+        }
+        if !self.bound.contains(&path[0]) {
+            self.rename_type_name(&mut path[0]);
+        }
+        Ok(None)
+    }
+}
+
+#[test]
+fn test_modules() {
+    use codegen::Codegen;
+    use vm::VM;
+    use std::fs;
+    let dir = ::std::env::temp_dir().join("xscript_test_modules");
+    fs::create_dir_all(&dir).unwrap();
+    let files = [
+        ("counter.xs", "pub struct Counter { n: int }\n\
+                        step = 2\n\
+                        pub def inc(c: Counter) -> Counter { Counter { n: c.n + step } }\n"),
+        ("twice.xs", "import \"counter\"\n\
+                      use counter::Counter\n\
+                      pub def twice(c: Counter) -> Counter { counter::inc(counter::inc(c)) }\n"),
+        ("a.xs", "import \"b\"\n"),
+        ("b.xs", "import \"a\"\n"),
+        ("bad.xs", "pub def f() -> int {\n  1 +\n}\n"),
+    ];
+    for &(name, src) in &files {
+        fs::write(dir.join(name), src).unwrap();
+    }
+    let run = |src: &str| -> Result<String, String> {
+        let mut lexer = Lexer::new_from_string(src.to_string());
+        let mut parser = Parser::new(&mut lexer);
+        let mut codegen = Codegen::new(&mut parser);
+        codegen.search_path = vec![dir.clone()];
+        codegen.gen().map_err(|e| e.msg)?;
+        let mut vm = VM::new();
+        vm.output = Some(String::new());
+        vm.run(codegen.vm_insts.clone()).map_err(|e| e.to_string())?;
+        Ok(vm.output.unwrap())
+    };
+    // `counter` is imported twice but loaded once; `step` is only visible inside it
+    let src = "import \"counter\"
+               import \"twice\"
+               step = 10
+               c = twice::twice(counter::Counter { n: step })
+               print(c.n)";
+    assert_eq!(run(src), Ok("14\n".to_string()));
+    assert_eq!(
+        run("import \"counter\"\nprint(counter::step)"),
+        Err("`step` is private to module `counter`".to_string())
+    );
+    assert_eq!(
+        run("import \"counter\"\nuse counter::dec"),
+        Err("cannot find `dec` in module `counter`".to_string())
+    );
+    assert_eq!(
+        run("import \"a\""),
+        Err("import cycle: a.xs -> b.xs -> a.xs".to_string())
+    );
+    assert_eq!(
+        run("import \"nowhere\""),
+        Err("cannot find module `nowhere`".to_string())
+    );
+    let msg = run("import \"bad\"").unwrap_err();
+    assert!(msg.starts_with("syntax error at line 3 in `"));
+    assert!(msg.ends_with("bad.xs`"));
+}
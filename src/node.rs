@@ -1,12 +1,13 @@
 use std::ops::Range;
 use std::boxed::Box;
 
-use typing::Type;
+use typing::{Type, TypeParam};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Node {
     pub kind: NodeKind,      // The kind this node represents
     pub range: Range<usize>, // The range within this node (for error handler)
+    pub ty: Option<Type>,    // Filled in by TypeChecker
 }
 
 impl Node {
@@ -14,19 +15,223 @@ impl Node {
         Node {
             kind: kind,
             range: range,
+            ty: None,
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum NodeKind {
+    Bool(bool),
     Int(i64),
     Float(f64),
     String(String),
     Variable(String, Option<Type>),
+    Path(Vec<String>),
+    UnaryOp(Box<Node>, UnaryOp),
+    Cast(Box<Node>, Type),
     BinaryOp(Box<Node>, Box<Node>, BinOp),
+    AssignOp(Box<Node>, Box<Node>, BinOp), // lhs op= rhs
     If(Box<Node>, Box<Node>, Box<Node>),
+    While(Box<Node>, Box<Node>),
     Apply(Box<Node>, Vec<Node>),
+    Index(Box<Node>, Box<Node>),
+    Map(Vec<(Node, Node)>),
+    Tuple(Vec<Node>), // also the left-hand side of a destructuring assignment
+    For(Vec<String>, Box<Node>, Box<Node>), // for k, v in iterable { body }
+    Block(Vec<Node>),
+    Enum(String, Vec<String>, Vec<EnumVariant>), // name, type parameters, variants
+    Struct(String, Vec<String>, Vec<(String, Type)>), // name, type parameters, fields
+    StructLit(String, Vec<(String, Node)>),
+    Field(Box<Node>, String),
+    Match(Box<Node>, Vec<MatchArm>),
+    Func(FuncDef), // `def name(params) -> ret { body }` or a lambda `fn(params) -> ret { body }`
+    Trait(String, Vec<(String, Type)>), // name, method signatures (`self` is of type `Self`)
+    None,
+    Wrap(Box<Node>), // Makes a `T?` of a `T`; inserted by TypeChecker where one is expected
+    IfLet(String, Box<Node>, Box<Node>, Box<Node>), // if let x = optional { then } else { else }
+    // `optional?.rest`: `rest` refers to the value of `optional` (if any) as the variable
+    OptChain(String, Box<Node>, Box<Node>),
+    Impl(Impl),
+    Try(Box<Node>, String, Box<Node>), // try { body } catch e { handler }
+    Throw(Box<Node>),
+    Const(String, Option<Type>, Box<Node>), // const NAME: type = value, evaluated by Codegen
+    // Top-level declarations of modules, resolved by ModuleLoader before type checking
+    Import(String),   // import "path"
+    Use(Vec<String>), // use module::name
+    Pub(Box<Node>),   // pub def f() ..., which can be used from other modules
+}
+
+impl Node {
+    // The nodes directly contained in this node, in evaluation order
+    pub fn children(&self) -> Vec<&Node> {
+        match self.kind {
+            NodeKind::UnaryOp(ref expr, _)
+            | NodeKind::Cast(ref expr, _)
+            | NodeKind::Field(ref expr, _)
+            | NodeKind::Wrap(ref expr)
+            | NodeKind::Throw(ref expr)
+            | NodeKind::Const(_, _, ref expr) => vec![&**expr],
+            NodeKind::IfLet(_, ref expr, ref then_, ref else_) => {
+                vec![&**expr, &**then_, &**else_]
+            }
+            NodeKind::OptChain(_, ref expr, ref rest) => vec![&**expr, &**rest],
+            NodeKind::Try(ref body, _, ref handler) => vec![&**body, &**handler],
+            NodeKind::BinaryOp(ref lhs, ref rhs, _) | NodeKind::AssignOp(ref lhs, ref rhs, _) => {
+                vec![&**lhs, &**rhs]
+            }
+            NodeKind::If(ref cond, ref then_, ref else_) => vec![&**cond, &**then_, &**else_],
+            NodeKind::While(ref cond, ref body) => vec![&**cond, &**body],
+            NodeKind::Apply(ref callee, ref args) => {
+                let mut nodes = vec![&**callee];
+                nodes.extend(args.iter());
+                nodes
+            }
+            NodeKind::Index(ref map, ref key) => vec![&**map, &**key],
+            NodeKind::Map(ref pairs) => pairs
+                .iter()
+                .flat_map(|(key, value)| vec![key, value])
+                .collect(),
+            NodeKind::StructLit(_, ref fields) => {
+                fields.iter().map(|(_, value)| value).collect()
+            }
+            NodeKind::For(_, ref iterable, ref body) => vec![&**iterable, &**body],
+            NodeKind::Block(ref nodes) | NodeKind::Tuple(ref nodes) => nodes.iter().collect(),
+            NodeKind::Match(ref cond, ref arms) => {
+                let mut nodes = vec![&**cond];
+                for arm in arms {
+                    nodes.extend(arm.guard.iter());
+                    nodes.push(&arm.body);
+                }
+                nodes
+            }
+            NodeKind::Func(ref func) => vec![&*func.body],
+            NodeKind::Impl(ref impl_) => impl_.methods.iter().map(|m| &*m.body).collect(),
+            NodeKind::Pub(ref item) => vec![&**item],
+            _ => vec![],
+        }
+    }
+
+    // Like `children`, but mutable
+    pub fn children_mut(&mut self) -> Vec<&mut Node> {
+        match self.kind {
+            NodeKind::UnaryOp(ref mut expr, _)
+            | NodeKind::Cast(ref mut expr, _)
+            | NodeKind::Field(ref mut expr, _)
+            | NodeKind::Wrap(ref mut expr)
+            | NodeKind::Throw(ref mut expr)
+            | NodeKind::Const(_, _, ref mut expr)
+            | NodeKind::Pub(ref mut expr) => vec![&mut **expr],
+            NodeKind::IfLet(_, ref mut expr, ref mut then_, ref mut else_) => {
+                vec![&mut **expr, &mut **then_, &mut **else_]
+            }
+            NodeKind::OptChain(_, ref mut expr, ref mut rest) => vec![&mut **expr, &mut **rest],
+            NodeKind::Try(ref mut body, _, ref mut handler) => vec![&mut **body, &mut **handler],
+            NodeKind::BinaryOp(ref mut lhs, ref mut rhs, _)
+            | NodeKind::AssignOp(ref mut lhs, ref mut rhs, _) => vec![&mut **lhs, &mut **rhs],
+            NodeKind::If(ref mut cond, ref mut then_, ref mut else_) => {
+                vec![&mut **cond, &mut **then_, &mut **else_]
+            }
+            NodeKind::While(ref mut cond, ref mut body) => vec![&mut **cond, &mut **body],
+            NodeKind::Apply(ref mut callee, ref mut args) => {
+                let mut nodes = vec![&mut **callee];
+                nodes.extend(args.iter_mut());
+                nodes
+            }
+            NodeKind::Index(ref mut map, ref mut key) => vec![&mut **map, &mut **key],
+            NodeKind::Map(ref mut pairs) => pairs
+                .iter_mut()
+                .flat_map(|&mut (ref mut key, ref mut value)| vec![key, value])
+                .collect(),
+            NodeKind::StructLit(_, ref mut fields) => {
+                fields.iter_mut().map(|&mut (_, ref mut value)| value).collect()
+            }
+            NodeKind::For(_, ref mut iterable, ref mut body) => {
+                vec![&mut **iterable, &mut **body]
+            }
+            NodeKind::Block(ref mut nodes) | NodeKind::Tuple(ref mut nodes) => {
+                nodes.iter_mut().collect()
+            }
+            NodeKind::Match(ref mut cond, ref mut arms) => {
+                let mut nodes = vec![&mut **cond];
+                for arm in arms {
+                    nodes.extend(arm.guard.iter_mut());
+                    nodes.push(&mut arm.body);
+                }
+                nodes
+            }
+            NodeKind::Func(ref mut func) => vec![&mut *func.body],
+            NodeKind::Impl(ref mut impl_) => {
+                impl_.methods.iter_mut().map(|m| &mut *m.body).collect()
+            }
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncDef {
+    pub name: Option<String>, // None for lambdas
+    pub type_params: Vec<TypeParam>,
+    pub params: Vec<(String, Type)>,
+    pub ret: Option<Type>, // Inferred from the body if omitted
+    pub body: Box<Node>,
+}
+
+// `impl<T> Trait for Type<T> { methods }`, or `impl Type { methods }` without a trait. The
+// type parameters of the impl are prepended to those of each method.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Impl {
+    pub type_params: Vec<String>,
+    pub trait_: Option<String>,
+    pub ty: Type,
+    pub methods: Vec<FuncDef>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnumVariant {
+    pub name: String,
+    pub fields: Vec<Type>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub guard: Option<Node>,
+    pub body: Node,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    pub kind: PatternKind,
+    pub range: Range<usize>,
+    pub ty: Option<Type>, // The type of the matched value, filled in by TypeChecker
+}
+
+impl Pattern {
+    pub fn new(kind: PatternKind, range: Range<usize>) -> Pattern {
+        Pattern {
+            kind,
+            range,
+            ty: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatternKind {
+    Wildcard,
+    Variable(String),
+    Bool(bool),
+    Int(i64),
+    String(String),
+    Variant(Vec<String>, Vec<Pattern>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -49,5 +254,6 @@ pub enum BinOp {
     Ge,
     Shl,
     Shr,
+    Coalesce, // ??
     Assign,
 }
@@ -0,0 +1,218 @@
+use node::{BinOp, Node, NodeKind, Pattern, PatternKind};
+use vm_base::{Object, Value};
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+// Simplifies type-checked nodes before code generation (at `-O1`):
+//
+// - Operations on literals and constants are computed at compile time, with `eval`.
+// - `if` and `while` on a constant condition are replaced with the branch taken.
+// - Assignments to variables that are never read are replaced with their values.
+//
+// Names are not resolved here, so a constant is not inlined if its name is also declared
+// as a local anywhere, and a variable is considered read if any variable of that name is.
+pub struct Optimizer<F> {
+    eval: F,
+    consts: HashMap<String, Node>, // The values of the constants, as literals
+    bound: HashSet<String>,        // The names of parameters and other locals
+    read: HashSet<String>,         // The names of the variables read somewhere
+    in_const: bool,                // Whether in the value of a constant, where no local is
+}
+
+impl<F> Optimizer<F>
+where
+    F: FnMut(&Node) -> Result<Value, String>,
+{
+    pub fn new(eval: F) -> Optimizer<F> {
+        Optimizer {
+            eval,
+            consts: HashMap::new(),
+            bound: HashSet::new(),
+            read: HashSet::new(),
+            in_const: false,
+        }
+    }
+
+    pub fn optimize(&mut self, nodes: &mut [Node]) {
+        for node in nodes.iter() {
+            collect_names(node, &mut self.bound, &mut self.read);
+        }
+        for node in nodes.iter_mut() {
+            self.fold(node);
+            if let NodeKind::Const(ref name, _, ref value) = node.kind {
+                if is_literal(value) {
+                    self.consts.insert(name.clone(), (**value).clone());
+                }
+            }
+        }
+    }
+
+    fn fold(&mut self, node: &mut Node) {
+        if let NodeKind::Const(_, _, ref mut value) = node.kind {
+            self.in_const = true;
+            self.fold(value);
+            self.in_const = false;
+            return;
+        }
+        for child in node.children_mut() {
+            self.fold(child);
+        }
+        let computable = match node.kind {
+            NodeKind::UnaryOp(ref expr, _) | NodeKind::Cast(ref expr, _) => is_literal(expr),
+            NodeKind::BinaryOp(ref lhs, ref rhs, ref op) => match *op {
+                BinOp::Assign | BinOp::Coalesce | BinOp::LAnd | BinOp::LOr => false,
+                _ => is_literal(lhs) && is_literal(rhs),
+            },
+            _ => false,
+        };
+        let folded = match node.kind {
+            _ if computable => self.eval_literal(node),
+            NodeKind::Variable(ref name, None) if self.in_const || !self.bound.contains(name) => {
+                self.consts.get(name).cloned()
+            }
+            NodeKind::BinaryOp(ref mut lhs, ref mut rhs, ref op) => match (op, &lhs.kind) {
+                // `true && b` is `b`, `false && b` is `false`, and likewise for `||`
+                (&BinOp::LAnd, &NodeKind::Bool(b)) | (&BinOp::LOr, &NodeKind::Bool(b)) => {
+                    if b == (*op == BinOp::LAnd) {
+                        Some(take(rhs))
+                    } else {
+                        Some(take(lhs))
+                    }
+                }
+                (&BinOp::Assign, NodeKind::Variable(name, _)) if !self.read.contains(name) => {
+                    Some(take(rhs))
+                }
+                _ => None,
+            },
+            NodeKind::If(ref mut cond, ref mut then_, ref mut else_) => match cond.kind {
+                NodeKind::Bool(true) => Some(take(then_)),
+                NodeKind::Bool(false) => Some(take(else_)),
+                _ => None,
+            },
+            NodeKind::While(ref cond, _) if cond.kind == NodeKind::Bool(false) => {
+                Some(Node::new(NodeKind::Block(vec![]), 0..0))
+            }
+            _ => None,
+        };
+        // A part of the node keeps its own type, which may be more precise (`never`)
+        if let Some(folded) = folded {
+            *node = Node {
+                kind: folded.kind,
+                range: node.range.clone(),
+                ty: folded.ty.or_else(|| node.ty.clone()),
+            };
+        }
+    }
+
+    // The literal `node` evaluates to, unless it throws an error (which is left to runtime)
+    fn eval_literal(&mut self, node: &Node) -> Option<Node> {
+        let kind = match (self.eval)(node) {
+            Ok(Value::Bool(b)) => NodeKind::Bool(b),
+            Ok(Value::Int(n)) => NodeKind::Int(n),
+            Ok(Value::UInt(n)) => NodeKind::Int(n as i64),
+            Ok(Value::Float(f)) => NodeKind::Float(f),
+            Ok(Value::Object(obj)) => match *obj.borrow() {
+                Object::String(ref s) => NodeKind::String(s.clone()),
+                _ => return None,
+            },
+            Err(_) => return None,
+        };
+        Some(Node::new(kind, node.range.clone()))
+    }
+}
+
+fn is_literal(node: &Node) -> bool {
+    matches!(
+        node.kind,
+        NodeKind::Bool(_) | NodeKind::Int(_) | NodeKind::Float(_) | NodeKind::String(_)
+    )
+}
+
+fn take(node: &mut Node) -> Node {
+    mem::replace(node, Node::new(NodeKind::None, 0..0))
+}
+
+// Collects the names declared as locals other than by assignment, and those read
+fn collect_names(node: &Node, bound: &mut HashSet<String>, read: &mut HashSet<String>) {
+    match node.kind {
+        NodeKind::Variable(ref name, _) => {
+            read.insert(name.clone());
+        }
+        // The target of an assignment is not read, unlike that of `+=`
+        NodeKind::BinaryOp(ref lhs, ref rhs, BinOp::Assign) => {
+            match lhs.kind {
+                NodeKind::Variable(_, _) | NodeKind::Tuple(_) => {}
+                _ => collect_names(lhs, bound, read),
+            }
+            return collect_names(rhs, bound, read);
+        }
+        NodeKind::Func(ref func) => {
+            bound.extend(func.name.iter().cloned());
+            bound.extend(func.params.iter().map(|(name, _)| name.clone()));
+        }
+        NodeKind::Impl(ref impl_) => for method in &impl_.methods {
+            bound.extend(method.params.iter().map(|(name, _)| name.clone()));
+        },
+        NodeKind::For(ref vars, _, _) => bound.extend(vars.iter().cloned()),
+        NodeKind::IfLet(ref name, _, _, _)
+        | NodeKind::OptChain(ref name, _, _)
+        | NodeKind::Try(_, ref name, _) => {
+            bound.insert(name.clone());
+        }
+        NodeKind::Match(_, ref arms) => for arm in arms {
+            pattern_names(&arm.pattern, bound);
+        },
+        _ => {}
+    }
+    for child in node.children() {
+        collect_names(child, bound, read);
+    }
+}
+
+fn pattern_names(pat: &Pattern, names: &mut HashSet<String>) {
+    match pat.kind {
+        PatternKind::Variable(ref name) => {
+            names.insert(name.clone());
+        }
+        PatternKind::Variant(_, ref fields) => for field in fields {
+            pattern_names(field, names);
+        },
+        _ => {}
+    }
+}
+
+#[test]
+fn test_optimize() {
+    use vm::{compile_source, VM};
+    use vm_base::VMInst;
+    let src = "const N = 3
+               const M = N
+               x = 1 + 2 * M
+               unused = x * 2
+               def f(N: int) -> int { N + 1 }
+               if M > 2 && true { print(x) } else { print(0) }
+               while !true { print(1) }
+               print(f(1))
+               print(200 as u8 + 100)
+               print(\"a\" + \"b\" == \"ab\")
+               try { print(1 / 0) } catch e { print(e) }";
+    let run = |insts: Vec<VMInst>| {
+        let mut vm = VM::new();
+        vm.output = Some(String::new());
+        vm.run(insts).unwrap();
+        vm.output.unwrap()
+    };
+    let (unoptimized, optimized) = (compile_source(src, 0).0, compile_source(src, 1).0);
+    assert_eq!(run(unoptimized.clone()), "7\n2\n44\ntrue\nattempt to divide by zero\n");
+    assert_eq!(run(optimized.clone()), run(unoptimized.clone()));
+    assert!(optimized.len() < unoptimized.len());
+    assert!(optimized.contains(&VMInst::PushI(7)));
+    assert!(optimized
+        .iter()
+        .all(|inst| !matches!(*inst, VMInst::JmpIfFalse(_) | VMInst::Not)));
+    // `unused` is never stored (so `f` is the second global, not the third), and `N` is
+    // not inlined, as it is also the name of a parameter
+    assert!(unoptimized.contains(&VMInst::StoreG(2)) && !optimized.contains(&VMInst::StoreG(2)));
+    assert!(optimized.contains(&VMInst::Add));
+}
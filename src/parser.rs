@@ -1,21 +1,36 @@
-use node::{BinOp, Node, NodeKind};
+use node::{BinOp, EnumVariant, FuncDef, Impl, MatchArm, Node, NodeKind, Pattern, PatternKind,
+           UnaryOp};
 use token::*;
 use lexer::Lexer;
-use typing::ToType;
+use typing::{ToType, Type, TypeError, TypeKind, TypeParam};
 
 use std::ops::Range;
+use std::path::Path;
 
 // #[derive(Clone, Debug, PartialEq)]
 pub struct Parser<'a> {
     lexer: &'a mut Lexer,
+    type_params: Vec<String>, // The type parameters in scope
+    self_ty: Option<Type>,    // The type `Self` stands for in a trait or impl
+    hidden_vars: usize,       // The number of variables introduced by `?.`
 }
 
+// The parameters of a function, and its return type if given
+type Signature = (Vec<(String, Type)>, Option<Type>);
+
 impl<'a> Parser<'a> {
     pub fn new(lexer: &'a mut Lexer) -> Parser<'a> {
-        Parser { lexer: lexer }
+        Parser {
+            lexer,
+            type_params: vec![],
+            self_ty: None,
+            hidden_vars: 0,
+        }
     }
 }
 
+macro_rules! range { ($start:expr, $end:expr) => (Range { start:$start, end:$end }) }
+
 impl<'a> Parser<'a> {
     pub fn get_node(&mut self) -> Result<Node, ()> {
         match self.lexer.peek()?.kind {
@@ -23,14 +38,82 @@ impl<'a> Parser<'a> {
                 self.lexer.read_token()?; // skip newline
                 self.get_node()
             }
-            _ => self.read_expr(),
+            TokenKind::Identifier(ref name) if name == "enum" => self.read_enum(),
+            TokenKind::Identifier(ref name) if name == "struct" => self.read_struct(),
+            TokenKind::Identifier(ref name) if name == "trait" => self.read_trait(),
+            TokenKind::Identifier(ref name) if name == "impl" => self.read_impl(),
+            TokenKind::Identifier(ref name) if name == "import" => self.read_import(),
+            TokenKind::Identifier(ref name) if name == "use" => self.read_use(),
+            TokenKind::Identifier(ref name) if name == "pub" => self.read_pub(),
+            TokenKind::Identifier(ref name) if name == "const" => self.read_const(),
+            _ => self.read_stmt(),
         }
     }
-}
 
-macro_rules! range { ($start:expr, $end:expr) => (Range { start:$start, end:$end }) }
+    // Reads the nodes up to the end of the input, failing at the first that is not valid
+    pub fn read_all(&mut self) -> Result<Vec<Node>, TypeError> {
+        let mut nodes = vec![];
+        while !self.lexer.at_end() {
+            match self.get_node() {
+                Ok(node) => nodes.push(node),
+                Err(()) => {
+                    let pos = self.lexer.pos();
+                    let msg = format!("syntax error at line {}", self.line_of(pos));
+                    return Err(TypeError::new(msg, range!(pos, pos)));
+                }
+            }
+        }
+        Ok(nodes)
+    }
+
+    // The file the program is read from, if any
+    pub fn path(&self) -> Option<&Path> {
+        self.lexer.path()
+    }
+
+    pub fn line_of(&self, offset: usize) -> usize {
+        self.lexer.line_of(offset)
+    }
+}
 
 impl<'a> Parser<'a> {
+    // Reads an expression, or an assignment to several variables `a, b = b, a`
+    fn read_stmt(&mut self) -> Result<Node, ()> {
+        let expr = self.read_expr()?;
+        if !self.lexer.skip_symbol(Symbol::Comma)? {
+            return Ok(expr);
+        }
+        let mut targets = vec![expr];
+        loop {
+            targets.push(self.read_coalesce()?);
+            if !self.lexer.skip_symbol(Symbol::Comma)? {
+                break;
+            }
+        }
+        if !self.lexer.skip_symbol(Symbol::Assign)? {
+            return Err(());
+        }
+        let mut values = vec![self.read_coalesce()?];
+        while self.lexer.skip_symbol(Symbol::Comma)? {
+            values.push(self.read_coalesce()?);
+        }
+        let lhs_range = range!(targets[0].range.start, targets.last().unwrap().range.end);
+        let rhs_range = range!(values[0].range.start, values.last().unwrap().range.end);
+        let rhs = if values.len() == 1 {
+            values.pop().unwrap()
+        } else {
+            Node::new(NodeKind::Tuple(values), rhs_range.clone())
+        };
+        Ok(Node::new(
+            NodeKind::BinaryOp(
+                Box::new(Node::new(NodeKind::Tuple(targets), lhs_range.clone())),
+                Box::new(rhs),
+                BinOp::Assign,
+            ),
+            range!(lhs_range.start, rhs_range.end),
+        ))
+    }
+
     pub fn read_expr(&mut self) -> Result<Node, ()> {
         self.read_assign()
     }
@@ -46,23 +129,15 @@ impl<'a> Parser<'a> {
             ))
         }
 
+        // `lhs op= rhs` is kept as is rather than desugared into `lhs = lhs op rhs`, so that
+        // e.g. the map and key of `m[f()] += 1` are evaluated only once
         macro_rules! assignx { ($lhs:expr, $op:ident) => ({
             let rhs = self.read_assign()?;
-            $lhs = assign!(
-                $lhs.clone(),
-                Node::new(
-                    NodeKind::BinaryOp(
-                        Box::new($lhs.clone()),
-                        Box::new(rhs.clone()),
-                        BinOp::$op,
-                    ),
-                    range!($lhs.range.start, rhs.range.end)
-                ),
-                range!($lhs.range.start, rhs.range.end)
-            ); })
-        }
+            let range = range!($lhs.range.start, rhs.range.end);
+            $lhs = Node::new(NodeKind::AssignOp(Box::new($lhs), Box::new(rhs), BinOp::$op), range);
+        })}
 
-        let mut lhs = self.read_lor()?;
+        let mut lhs = self.read_coalesce()?;
         while let Ok(tok) = self.lexer.read_token() {
             match tok.kind {
                 TokenKind::Symbol(Symbol::Assign) => {
@@ -92,6 +167,20 @@ impl<'a> Parser<'a> {
         Ok(lhs)
     }
 
+    // `??` is right-associative: `a ?? b ?? c` is `a ?? (b ?? c)`
+    fn read_coalesce(&mut self) -> Result<Node, ()> {
+        let lhs = self.read_lor()?;
+        if !self.lexer.skip_symbol(Symbol::DoubleQuestion)? {
+            return Ok(lhs);
+        }
+        let rhs = self.read_coalesce()?;
+        let range = range!(lhs.range.start, rhs.range.end);
+        Ok(Node::new(
+            NodeKind::BinaryOp(Box::new(lhs), Box::new(rhs), BinOp::Coalesce),
+            range,
+        ))
+    }
+
     fn read_lor(&mut self) -> Result<Node, ()> {
         let mut lhs = try!(self.read_land());
         while self.lexer.skip_symbol(Symbol::LOr)? {
@@ -253,22 +342,22 @@ impl<'a> Parser<'a> {
     }
 
     fn read_mul_div_rem(&mut self) -> Result<Node, ()> {
-        let mut lhs = self.read_call()?;
+        let mut lhs = self.read_cast()?;
         loop {
             if self.lexer.skip_symbol(Symbol::Asterisk)? {
-                let rhs = self.read_call()?;
+                let rhs = self.read_cast()?;
                 lhs = Node::new(
                     NodeKind::BinaryOp(Box::new(lhs.clone()), Box::new(rhs.clone()), BinOp::Mul),
                     range!(lhs.range.start, rhs.range.end),
                 );
             } else if self.lexer.skip_symbol(Symbol::Div)? {
-                let rhs = self.read_call()?;
+                let rhs = self.read_cast()?;
                 lhs = Node::new(
                     NodeKind::BinaryOp(Box::new(lhs.clone()), Box::new(rhs.clone()), BinOp::Div),
                     range!(lhs.range.start, rhs.range.end),
                 );
             } else if self.lexer.skip_symbol(Symbol::Mod)? {
-                let rhs = self.read_call()?;
+                let rhs = self.read_cast()?;
                 lhs = Node::new(
                     NodeKind::BinaryOp(Box::new(lhs.clone()), Box::new(rhs.clone()), BinOp::Rem),
                     range!(lhs.range.start, rhs.range.end),
@@ -280,23 +369,110 @@ impl<'a> Parser<'a> {
         Ok(lhs)
     }
 
+    fn read_cast(&mut self) -> Result<Node, ()> {
+        let mut expr = self.read_unary()?;
+        while self.skip_keyword("as")? {
+            let ty = self.read_type()?;
+            let range = expr.range.clone();
+            expr = Node::new(NodeKind::Cast(Box::new(expr), ty), range);
+        }
+        Ok(expr)
+    }
+
+    fn read_unary(&mut self) -> Result<Node, ()> {
+        let tok = self.lexer.read_token()?;
+        match tok.kind {
+            TokenKind::Symbol(Symbol::Not) => {
+                let expr = self.read_unary()?;
+                let end = expr.range.end;
+                Ok(Node::new(
+                    NodeKind::UnaryOp(Box::new(expr), UnaryOp::Not),
+                    range!(tok.range.start, end),
+                ))
+            }
+            TokenKind::Symbol(Symbol::Sub) => {
+                let expr = self.read_unary()?;
+                let range = range!(tok.range.start, expr.range.end);
+                // Negative literals are literals, so that e.g. `-128` can be an `i8`
+                Ok(match expr.kind {
+                    NodeKind::Int(n) => Node::new(NodeKind::Int(n.wrapping_neg()), range),
+                    NodeKind::Float(f) => Node::new(NodeKind::Float(-f), range),
+                    _ => Node::new(NodeKind::UnaryOp(Box::new(expr), UnaryOp::Neg), range),
+                })
+            }
+            _ => {
+                self.lexer.unget(&tok);
+                self.read_call()
+            }
+        }
+    }
+
     fn read_call(&mut self) -> Result<Node, ()> {
-        let f = self.read_primary()?;
-        if self.lexer.skip_symbol(Symbol::OpeningParen)? {
+        let mut f = self.read_primary()?;
+        let mut chains = vec![];
+        loop {
             let f_start = f.range.start;
-            let mut args = vec![];
-            while let Ok(arg) = self.read_primary() {
-                args.push(arg);
-            }
-            assert!(self.lexer.skip_symbol(Symbol::ClosingParen)?);
-            let args_end = args.last().unwrap().range.end;
-            Ok(Node::new(
-                NodeKind::Apply(Box::new(f), args),
-                range!(f_start, args_end),
-            ))
-        } else {
-            Ok(f)
+            let optional = self.lexer.skip_symbol(Symbol::QuestionPoint)?;
+            if optional {
+                // The rest of the chain is applied to a hidden variable bound to the value
+                let var = format!("?{}", self.hidden_vars);
+                self.hidden_vars += 1;
+                let range = f.range.clone();
+                chains.push((var.clone(), f));
+                f = Node::new(NodeKind::Variable(var, None), range);
+            }
+            if optional || self.lexer.skip_symbol(Symbol::Point)? {
+                let tok = self.lexer.read_token()?;
+                let name = match tok.kind {
+                    TokenKind::Identifier(name) => name,
+                    TokenKind::Int(n) => n.to_string(), // tuple element
+                    _ => return Err(()),
+                };
+                f = Node::new(
+                    NodeKind::Field(Box::new(f), name),
+                    range!(f_start, tok.range.end),
+                );
+                continue;
+            }
+            if self.lexer.skip_symbol(Symbol::OpeningParen)? {
+                let mut args = vec![];
+                let args_end;
+                loop {
+                    let tok = self.lexer.read_token()?;
+                    if tok.kind == TokenKind::Symbol(Symbol::ClosingParen) {
+                        args_end = tok.range.end;
+                        break;
+                    }
+                    self.lexer.unget(&tok);
+                    args.push(self.read_expr()?);
+                    self.lexer.skip_symbol(Symbol::Comma)?;
+                }
+                f = Node::new(
+                    NodeKind::Apply(Box::new(f), args),
+                    range!(f_start, args_end),
+                );
+            } else if self.lexer.skip_symbol(Symbol::OpeningBoxBracket)? {
+                let idx = self.read_expr()?;
+                let tok = self.lexer.read_token()?;
+                if tok.kind != TokenKind::Symbol(Symbol::ClosingBoxBracket) {
+                    return Err(());
+                }
+                f = Node::new(
+                    NodeKind::Index(Box::new(f), Box::new(idx)),
+                    range!(f_start, tok.range.end),
+                );
+            } else {
+                break;
+            }
+        }
+        while let Some((var, optional)) = chains.pop() {
+            let range = range!(optional.range.start, f.range.end);
+            f = Node::new(
+                NodeKind::OptChain(var, Box::new(optional), Box::new(f)),
+                range,
+            );
         }
+        Ok(f)
     }
 
     fn read_primary(&mut self) -> Result<Node, ()> {
@@ -304,17 +480,47 @@ impl<'a> Parser<'a> {
         match tok.kind {
             TokenKind::Int(n) => Ok(Node::new(NodeKind::Int(n), tok.range)),
             TokenKind::Float(f) => Ok(Node::new(NodeKind::Float(f), tok.range)),
+            TokenKind::Identifier(ref name) if name == "match" => self.read_match(tok.range.start),
+            TokenKind::Identifier(ref name) if name == "for" => self.read_for(tok.range.start),
+            TokenKind::Identifier(ref name) if name == "if" => self.read_if(tok.range.start),
+            TokenKind::Identifier(ref name) if name == "while" => self.read_while(tok.range.start),
+            TokenKind::Identifier(ref name) if name == "try" => self.read_try(tok.range.start),
+            // `let` is optional before an assignment, but reads better before `(q, r) = ...`
+            TokenKind::Identifier(ref name) if name == "let" => {
+                let node = self.read_expr()?;
+                match node.kind {
+                    NodeKind::BinaryOp(_, _, BinOp::Assign) => Ok(node),
+                    _ => Err(()),
+                }
+            }
+            TokenKind::Identifier(ref name) if name == "throw" => {
+                let expr = self.read_expr()?;
+                let end = expr.range.end;
+                Ok(Node::new(NodeKind::Throw(Box::new(expr)), range!(tok.range.start, end)))
+            }
+            TokenKind::Identifier(ref name) if name == "def" => {
+                let name = self.read_identifier()?;
+                let type_params = self.read_type_params()?;
+                self.read_func(Some(name), type_params, tok.range.start)
+            }
+            TokenKind::Identifier(ref name) if name == "fn" => {
+                self.read_func(None, vec![], tok.range.start)
+            }
+            TokenKind::Identifier(ref name) if name == "true" => {
+                Ok(Node::new(NodeKind::Bool(true), tok.range))
+            }
+            TokenKind::Identifier(ref name) if name == "none" => {
+                Ok(Node::new(NodeKind::None, tok.range))
+            }
+            TokenKind::Identifier(ref name) if name == "false" => {
+                Ok(Node::new(NodeKind::Bool(false), tok.range))
+            }
             TokenKind::Identifier(name) => self.read_variable(name, tok.range),
             TokenKind::String(s) => Ok(Node::new(NodeKind::String(s), tok.range)),
+            TokenKind::InterpolatedString(parts) => self.read_interpolation(parts, tok.range),
             TokenKind::Symbol(ref sym) => match sym {
-                &Symbol::OpeningParen => {
-                    let expr = self.read_expr();
-                    if !self.lexer.skip_symbol(Symbol::ClosingParen)? {
-                        // TODO
-                        return Err(());
-                    }
-                    expr
-                }
+                &Symbol::OpeningBrace => self.read_block(tok.range.start),
+                &Symbol::OpeningParen => self.read_paren(tok.range.start),
                 _ => {
                     self.lexer.unget(&tok);
                     Err(())
@@ -327,18 +533,911 @@ impl<'a> Parser<'a> {
         }
     }
 
+    // `"a${x}b"` is `"a" + to_string(x) + "b"`
+    fn read_interpolation(
+        &mut self,
+        parts: Vec<StringPart>,
+        range: Range<usize>,
+    ) -> Result<Node, ()> {
+        let mut node: Option<Node> = None;
+        for part in parts {
+            let part = match part {
+                StringPart::Str(ref s) if s.is_empty() => continue,
+                StringPart::Str(s) => Node::new(NodeKind::String(s), range.clone()),
+                StringPart::Expr(expr_range) => {
+                    let expr = self.read_embedded_expr(expr_range.clone())?;
+                    let to_string = NodeKind::Variable("to_string".to_string(), None);
+                    Node::new(
+                        NodeKind::Apply(
+                            Box::new(Node::new(to_string, expr_range.clone())),
+                            vec![expr],
+                        ),
+                        expr_range,
+                    )
+                }
+            };
+            node = Some(match node {
+                Some(lhs) => Node::new(
+                    NodeKind::BinaryOp(Box::new(lhs), Box::new(part), BinOp::Add),
+                    range.clone(),
+                ),
+                None => part,
+            });
+        }
+        Ok(node.unwrap())
+    }
+
+    // Parses the expression in `${...}`, which must span all of `range`, up to the `}`
+    fn read_embedded_expr(&mut self, range: Range<usize>) -> Result<Node, ()> {
+        let mut lexer = self.lexer.sub_lexer(range!(range.start, range.end + 1));
+        let expr = {
+            let mut parser = Parser::new(&mut lexer);
+            parser.type_params = self.type_params.clone();
+            parser.self_ty = self.self_ty.clone();
+            parser.hidden_vars = self.hidden_vars;
+            let expr = parser.read_expr().and_then(|expr| {
+                parser.skip_newlines()?;
+                Ok(expr)
+            });
+            self.hidden_vars = parser.hidden_vars;
+            expr?
+        };
+        if !lexer.skip_symbol(Symbol::ClosingBrace)? || !lexer.at_end() {
+            return Err(());
+        }
+        Ok(expr)
+    }
+
+    // Reads `(expr)` or a tuple `(a, b)`
+    fn read_paren(&mut self, start: usize) -> Result<Node, ()> {
+        let expr = self.read_expr()?;
+        if !self.lexer.skip_symbol(Symbol::Comma)? {
+            if !self.lexer.skip_symbol(Symbol::ClosingParen)? {
+                // TODO
+                return Err(());
+            }
+            return Ok(expr);
+        }
+        let mut elems = vec![expr];
+        loop {
+            elems.push(self.read_expr()?);
+            let tok = self.lexer.read_token()?;
+            match tok.kind {
+                TokenKind::Symbol(Symbol::Comma) => {}
+                TokenKind::Symbol(Symbol::ClosingParen) => {
+                    return Ok(Node::new(NodeKind::Tuple(elems), range!(start, tok.range.end)))
+                }
+                _ => return Err(()),
+            }
+        }
+    }
+
     fn read_variable(&mut self, var: String, range: Range<usize>) -> Result<Node, ()> {
+        if self.lexer.skip_symbol(Symbol::DoubleColon)? {
+            let (path, end) = self.read_path(var)?;
+            // `module::Struct { ... }`
+            if path.last().unwrap().starts_with(char::is_uppercase)
+                && self.is_struct_lit_start()?
+            {
+                return self.read_struct_lit(path.join("::"), range.start);
+            }
+            return Ok(Node::new(NodeKind::Path(path), range!(range.start, end)));
+        }
+        if var.starts_with(char::is_uppercase) && self.is_struct_lit_start()? {
+            return self.read_struct_lit(var, range.start);
+        }
         if self.lexer.skip_symbol(Symbol::Colon)? {
-            if let TokenKind::Identifier(name) = self.lexer.read_token()?.kind {
-                Ok(Node::new(
-                    NodeKind::Variable(var, name.as_str().to_type()),
+            let ty = self.read_type()?;
+            Ok(Node::new(NodeKind::Variable(var, Some(ty)), range))
+        } else {
+            Ok(Node::new(NodeKind::Variable(var, None), range))
+        }
+    }
+}
+
+impl<'a> Parser<'a> {
+    // Whether the next tokens are `{}` or `{ name:`, which after a capitalized name start
+    // a struct literal rather than e.g. the body of an `if`
+    fn is_struct_lit_start(&mut self) -> Result<bool, ()> {
+        let mut toks = vec![];
+        let mut is_lit = false;
+        if let Ok(tok) = self.lexer.read_token() {
+            toks.push(tok);
+            if toks[0].kind == TokenKind::Symbol(Symbol::OpeningBrace) {
+                if let Ok(tok) = self.lexer.read_token() {
+                    toks.push(tok);
+                    match toks[1].kind {
+                        TokenKind::Symbol(Symbol::ClosingBrace) => is_lit = true,
+                        TokenKind::Identifier(_) => if let Ok(tok) = self.lexer.read_token() {
+                            is_lit = tok.kind == TokenKind::Symbol(Symbol::Colon);
+                            toks.push(tok);
+                        },
+                        _ => {}
+                    }
+                }
+            }
+        }
+        for tok in toks.iter().rev() {
+            self.lexer.unget(tok);
+        }
+        Ok(is_lit)
+    }
+
+    fn read_struct_lit(&mut self, name: String, start: usize) -> Result<Node, ()> {
+        self.lexer.read_token()?; // {
+        let mut fields = vec![];
+        loop {
+            self.skip_newlines()?;
+            let tok = self.lexer.read_token()?;
+            match tok.kind {
+                TokenKind::Symbol(Symbol::ClosingBrace) => {
+                    return Ok(Node::new(
+                        NodeKind::StructLit(name, fields),
+                        range!(start, tok.range.end),
+                    ))
+                }
+                TokenKind::Identifier(field) => {
+                    if !self.lexer.skip_symbol(Symbol::Colon)? {
+                        return Err(());
+                    }
+                    fields.push((field, self.read_expr()?));
+                    self.lexer.skip_symbol(Symbol::Comma)?;
+                }
+                _ => return Err(()),
+            }
+        }
+    }
+
+    // Reads either a block `{ a \n b }` or a map literal `{k: v, ...}` (`{:}` if empty)
+    fn read_block(&mut self, start: usize) -> Result<Node, ()> {
+        self.skip_newlines()?;
+        if self.lexer.skip_symbol(Symbol::Colon)? {
+            let tok = self.lexer.read_token()?;
+            if tok.kind != TokenKind::Symbol(Symbol::ClosingBrace) {
+                return Err(());
+            }
+            return Ok(Node::new(NodeKind::Map(vec![]), range!(start, tok.range.end)));
+        }
+        let mut stmts = vec![];
+        loop {
+            self.skip_newlines()?;
+            let tok = self.lexer.read_token()?;
+            if tok.kind == TokenKind::Symbol(Symbol::ClosingBrace) {
+                return Ok(Node::new(NodeKind::Block(stmts), range!(start, tok.range.end)));
+            }
+            self.lexer.unget(&tok);
+            let node = self.read_stmt()?;
+            if stmts.is_empty() && self.lexer.skip_symbol(Symbol::Colon)? {
+                return self.read_map(start, node);
+            }
+            stmts.push(node);
+        }
+    }
+
+    fn read_map(&mut self, start: usize, first_key: Node) -> Result<Node, ()> {
+        let mut pairs = vec![];
+        let mut key = first_key;
+        loop {
+            self.skip_newlines()?;
+            let value = self.read_expr()?;
+            pairs.push((key, value));
+            self.skip_newlines()?;
+            self.lexer.skip_symbol(Symbol::Comma)?;
+            self.skip_newlines()?;
+            let tok = self.lexer.read_token()?;
+            if tok.kind == TokenKind::Symbol(Symbol::ClosingBrace) {
+                return Ok(Node::new(NodeKind::Map(pairs), range!(start, tok.range.end)));
+            }
+            self.lexer.unget(&tok);
+            key = self.read_expr()?;
+            if !self.lexer.skip_symbol(Symbol::Colon)? {
+                return Err(());
+            }
+        }
+    }
+
+    // Reads the rest of `head::a::b` after the first `::` has been consumed
+    fn read_path(&mut self, head: String) -> Result<(Vec<String>, usize), ()> {
+        let mut path = vec![head];
+        loop {
+            let tok = self.lexer.read_token()?;
+            match tok.kind {
+                TokenKind::Identifier(name) => path.push(name),
+                _ => return Err(()),
+            }
+            if !self.lexer.skip_symbol(Symbol::DoubleColon)? {
+                return Ok((path, tok.range.end));
+            }
+        }
+    }
+
+    // Reads the rest of the name of a type or trait of another module, `module::Name`
+    fn read_qualified_name(&mut self, head: String) -> Result<String, ()> {
+        if self.lexer.skip_symbol(Symbol::DoubleColon)? {
+            Ok(self.read_path(head)?.0.join("::"))
+        } else {
+            Ok(head)
+        }
+    }
+
+    fn read_type(&mut self) -> Result<Type, ()> {
+        let mut ty = self.read_type_name()?;
+        loop {
+            if self.lexer.skip_symbol(Symbol::Question)? {
+                ty = Type::new_optional(ty);
+            } else if self.lexer.skip_symbol(Symbol::DoubleQuestion)? {
+                ty = Type::new_optional(Type::new_optional(ty));
+            } else {
+                return Ok(ty);
+            }
+        }
+    }
+
+    fn read_type_name(&mut self) -> Result<Type, ()> {
+        // `(T)` or a tuple `(A, B)`
+        if self.lexer.skip_symbol(Symbol::OpeningParen)? {
+            let mut elems = vec![self.read_type()?];
+            while self.lexer.skip_symbol(Symbol::Comma)? {
+                elems.push(self.read_type()?);
+            }
+            if !self.lexer.skip_symbol(Symbol::ClosingParen)? {
+                return Err(());
+            }
+            return Ok(if elems.len() == 1 {
+                elems.pop().unwrap()
+            } else {
+                Type::new_tuple(elems)
+            });
+        }
+        let name = self.read_identifier()?;
+        if name == "fn" {
+            if !self.lexer.skip_symbol(Symbol::OpeningParen)? {
+                return Err(());
+            }
+            let mut params = vec![];
+            if !self.lexer.skip_symbol(Symbol::ClosingParen)? {
+                loop {
+                    params.push(self.read_type()?);
+                    if self.lexer.skip_symbol(Symbol::ClosingParen)? {
+                        break;
+                    }
+                    if !self.lexer.skip_symbol(Symbol::Comma)? {
+                        return Err(());
+                    }
+                }
+            }
+            let ret = if self.lexer.skip_symbol(Symbol::Arrow)? {
+                self.read_type()?
+            } else {
+                Type::new_unit()
+            };
+            return Ok(Type::new_func(params, ret));
+        }
+        if name == "Map" {
+            if !self.lexer.skip_symbol(Symbol::Lt)? {
+                return Err(());
+            }
+            let key = self.read_type()?;
+            if !self.lexer.skip_symbol(Symbol::Comma)? {
+                return Err(());
+            }
+            let value = self.read_type()?;
+            self.skip_closing_angle()?;
+            return Ok(Type::new_map(key, value));
+        }
+        if self.type_params.contains(&name) {
+            return Ok(Type::new_param(name));
+        }
+        if name == "Self" && self.self_ty.is_some() {
+            return Ok(self.self_ty.clone().unwrap());
+        }
+        if let Some(ty) = name.as_str().to_type() {
+            return Ok(ty);
+        }
+        let name = self.read_qualified_name(name)?;
+        let mut args = vec![];
+        if self.lexer.skip_symbol(Symbol::Lt)? {
+            loop {
+                args.push(self.read_type()?);
+                if !self.lexer.skip_symbol(Symbol::Comma)? {
+                    break;
+                }
+            }
+            self.skip_closing_angle()?;
+        }
+        Ok(Type::new_named(name, args))
+    }
+
+    // Reads `<T: Trait + Trait, U>` after the name of a generic function, if any
+    fn read_type_params(&mut self) -> Result<Vec<TypeParam>, ()> {
+        let mut params = vec![];
+        if self.lexer.skip_symbol(Symbol::Lt)? {
+            loop {
+                let name = self.read_identifier()?;
+                let mut bounds = vec![];
+                if self.lexer.skip_symbol(Symbol::Colon)? {
+                    loop {
+                        let bound = self.read_identifier()?;
+                        bounds.push(self.read_qualified_name(bound)?);
+                        if !self.lexer.skip_symbol(Symbol::Add)? {
+                            break;
+                        }
+                    }
+                }
+                params.push(TypeParam {
+                    name,
+                    bounds,
+                });
+                if !self.lexer.skip_symbol(Symbol::Comma)? {
+                    break;
+                }
+            }
+            self.skip_closing_angle()?;
+        }
+        Ok(params)
+    }
+
+    // Reads `<T, U>` after the name of a generic type, which cannot have bounds
+    fn read_type_param_names(&mut self) -> Result<Vec<String>, ()> {
+        let params = self.read_type_params()?;
+        if params.iter().any(|param| !param.bounds.is_empty()) {
+            return Err(());
+        }
+        Ok(params.into_iter().map(|param| param.name).collect())
+    }
+
+    // `>>` closes two type argument lists at once, e.g. `Map<int, Map<int, int>>`
+    fn skip_closing_angle(&mut self) -> Result<(), ()> {
+        let tok = self.lexer.read_token()?;
+        match tok.kind {
+            TokenKind::Symbol(Symbol::Gt) => Ok(()),
+            TokenKind::Symbol(Symbol::Shr) => {
+                let range = range!(tok.range.start + 1, tok.range.end);
+                self.lexer.unget(&Token::new_symbol(Symbol::Gt, range));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn read_enum(&mut self) -> Result<Node, ()> {
+        let start = self.lexer.read_token()?.range.start; // enum
+        let name = self.read_identifier()?;
+        let type_params = self.read_type_param_names()?;
+        self.type_params = type_params.clone();
+        let node = self.read_enum_variants(name, type_params, start);
+        self.type_params.clear();
+        node
+    }
+
+    fn read_enum_variants(
+        &mut self,
+        name: String,
+        type_params: Vec<String>,
+        start: usize,
+    ) -> Result<Node, ()> {
+        self.skip_newlines()?;
+        if !self.lexer.skip_symbol(Symbol::OpeningBrace)? {
+            return Err(());
+        }
+        let mut variants = vec![];
+        loop {
+            self.skip_newlines()?;
+            let tok = self.lexer.read_token()?;
+            match tok.kind {
+                TokenKind::Symbol(Symbol::ClosingBrace) => {
+                    return Ok(Node::new(
+                        NodeKind::Enum(name, type_params, variants),
+                        range!(start, tok.range.end),
+                    ))
+                }
+                TokenKind::Identifier(variant_name) => {
+                    let mut fields = vec![];
+                    if self.lexer.skip_symbol(Symbol::OpeningParen)? {
+                        while !self.lexer.skip_symbol(Symbol::ClosingParen)? {
+                            fields.push(self.read_type()?);
+                            self.lexer.skip_symbol(Symbol::Comma)?;
+                        }
+                    }
+                    variants.push(EnumVariant {
+                        name: variant_name,
+                        fields,
+                    });
+                    self.lexer.skip_symbol(Symbol::Comma)?;
+                }
+                _ => return Err(()),
+            }
+        }
+    }
+
+    fn read_struct(&mut self) -> Result<Node, ()> {
+        let start = self.lexer.read_token()?.range.start; // struct
+        let name = self.read_identifier()?;
+        let type_params = self.read_type_param_names()?;
+        self.type_params = type_params.clone();
+        let node = self.read_struct_fields(name, type_params, start);
+        self.type_params.clear();
+        node
+    }
+
+    fn read_struct_fields(
+        &mut self,
+        name: String,
+        type_params: Vec<String>,
+        start: usize,
+    ) -> Result<Node, ()> {
+        self.skip_newlines()?;
+        if !self.lexer.skip_symbol(Symbol::OpeningBrace)? {
+            return Err(());
+        }
+        let mut fields = vec![];
+        loop {
+            self.skip_newlines()?;
+            let tok = self.lexer.read_token()?;
+            match tok.kind {
+                TokenKind::Symbol(Symbol::ClosingBrace) => {
+                    return Ok(Node::new(
+                        NodeKind::Struct(name, type_params, fields),
+                        range!(start, tok.range.end),
+                    ))
+                }
+                TokenKind::Identifier(field) => {
+                    if !self.lexer.skip_symbol(Symbol::Colon)? {
+                        return Err(());
+                    }
+                    fields.push((field, self.read_type()?));
+                    self.lexer.skip_symbol(Symbol::Comma)?;
+                }
+                _ => return Err(()),
+            }
+        }
+    }
+
+    fn read_trait(&mut self) -> Result<Node, ()> {
+        let start = self.lexer.read_token()?.range.start; // trait
+        let name = self.read_identifier()?;
+        self.self_ty = Some(Type::new_param("Self".to_string()));
+        let node = self.read_trait_methods(name, start);
+        self.self_ty = None;
+        node
+    }
+
+    fn read_trait_methods(&mut self, name: String, start: usize) -> Result<Node, ()> {
+        self.skip_newlines()?;
+        if !self.lexer.skip_symbol(Symbol::OpeningBrace)? {
+            return Err(());
+        }
+        let mut methods = vec![];
+        loop {
+            self.skip_newlines()?;
+            if self.lexer.peek()?.kind == TokenKind::Symbol(Symbol::ClosingBrace) {
+                let end = self.lexer.read_token()?.range.end;
+                return Ok(Node::new(NodeKind::Trait(name, methods), range!(start, end)));
+            }
+            if !self.skip_keyword("def")? {
+                return Err(());
+            }
+            let method = self.read_identifier()?;
+            let (params, ret) = self.read_signature()?;
+            let params = params.into_iter().map(|(_, ty)| ty).collect();
+            methods.push((method, Type::new_func(params, ret.unwrap_or_else(Type::new_unit))));
+        }
+    }
+
+    // Reads `impl<T> Trait for Type<T> { methods }` or `impl Type { methods }`
+    fn read_impl(&mut self) -> Result<Node, ()> {
+        let start = self.lexer.read_token()?.range.start; // impl
+        let type_params = self.read_type_param_names()?;
+        self.type_params = type_params.clone();
+        let node = self.read_impl_methods(type_params, start);
+        self.type_params.clear();
+        self.self_ty = None;
+        node
+    }
+
+    fn read_impl_methods(&mut self, type_params: Vec<String>, start: usize) -> Result<Node, ()> {
+        let mut ty = self.read_type()?;
+        let mut trait_ = None;
+        if self.skip_keyword("for")? {
+            trait_ = match ty.kind {
+                TypeKind::Named(ref name, ref args) if args.is_empty() => Some(name.clone()),
+                _ => return Err(()),
+            };
+            ty = self.read_type()?;
+        }
+        self.self_ty = Some(ty.clone());
+        self.skip_newlines()?;
+        if !self.lexer.skip_symbol(Symbol::OpeningBrace)? {
+            return Err(());
+        }
+        let mut methods = vec![];
+        loop {
+            self.skip_newlines()?;
+            let tok = self.lexer.read_token()?;
+            match tok.kind {
+                TokenKind::Symbol(Symbol::ClosingBrace) => {
+                    let impl_ = Impl {
+                        type_params,
+                        trait_,
+                        ty,
+                        methods,
+                    };
+                    return Ok(Node::new(NodeKind::Impl(impl_), range!(start, tok.range.end)));
+                }
+                TokenKind::Identifier(ref name) if name == "def" => {
+                    let name = self.read_identifier()?;
+                    let mut method_params: Vec<TypeParam> = type_params
+                        .iter()
+                        .map(|param| TypeParam {
+                            name: param.clone(),
+                            bounds: vec![],
+                        })
+                        .collect();
+                    method_params.extend(self.read_type_params()?);
+                    match self.read_func(Some(name), method_params, tok.range.start)?.kind {
+                        NodeKind::Func(func) => methods.push(func),
+                        _ => unreachable!(),
+                    }
+                }
+                _ => return Err(()),
+            }
+        }
+    }
+
+    fn read_match(&mut self, start: usize) -> Result<Node, ()> {
+        let cond = self.read_expr()?;
+        if !self.lexer.skip_symbol(Symbol::OpeningBrace)? {
+            return Err(());
+        }
+        let mut arms = vec![];
+        loop {
+            self.skip_newlines()?;
+            let tok = self.lexer.read_token()?;
+            if tok.kind == TokenKind::Symbol(Symbol::ClosingBrace) {
+                return Ok(Node::new(
+                    NodeKind::Match(Box::new(cond), arms),
+                    range!(start, tok.range.end),
+                ));
+            }
+            self.lexer.unget(&tok);
+            let pattern = self.read_pattern()?;
+            let guard = if self.skip_keyword("if")? {
+                Some(self.read_expr()?)
+            } else {
+                None
+            };
+            if !self.lexer.skip_symbol(Symbol::FatArrow)? {
+                return Err(());
+            }
+            let body = self.read_expr()?;
+            arms.push(MatchArm {
+                pattern,
+                guard,
+                body,
+            });
+            self.lexer.skip_symbol(Symbol::Comma)?;
+        }
+    }
+
+    fn read_if(&mut self, start: usize) -> Result<Node, ()> {
+        let var = if self.skip_keyword("let")? {
+            let var = self.read_identifier()?;
+            if !self.lexer.skip_symbol(Symbol::Assign)? {
+                return Err(());
+            }
+            Some(var)
+        } else {
+            None
+        };
+        let cond = self.read_expr()?;
+        let then_ = self.read_brace_block()?;
+        // `else` must be on the same line as the closing brace
+        let else_ = if self.skip_keyword("else")? {
+            let tok = self.lexer.read_token()?;
+            match tok.kind {
+                TokenKind::Identifier(ref name) if name == "if" => {
+                    self.read_if(tok.range.start)?
+                }
+                _ => {
+                    self.lexer.unget(&tok);
+                    self.read_brace_block()?
+                }
+            }
+        } else {
+            let end = then_.range.end;
+            Node::new(NodeKind::Block(vec![]), range!(end, end))
+        };
+        let end = else_.range.end;
+        let kind = match var {
+            Some(var) => NodeKind::IfLet(var, Box::new(cond), Box::new(then_), Box::new(else_)),
+            None => NodeKind::If(Box::new(cond), Box::new(then_), Box::new(else_)),
+        };
+        Ok(Node::new(kind, range!(start, end)))
+    }
+
+    // `import "path/to/module"`
+    fn read_import(&mut self) -> Result<Node, ()> {
+        let start = self.lexer.read_token()?.range.start;
+        let tok = self.lexer.read_token()?;
+        match tok.kind {
+            TokenKind::String(path) => Ok(Node::new(
+                NodeKind::Import(path),
+                range!(start, tok.range.end),
+            )),
+            _ => Err(()),
+        }
+    }
+
+    // `pub` followed by a definition
+    fn read_pub(&mut self) -> Result<Node, ()> {
+        let start = self.lexer.read_token()?.range.start;
+        let item = self.get_node()?;
+        let end = item.range.end;
+        Ok(Node::new(NodeKind::Pub(Box::new(item)), range!(start, end)))
+    }
+
+    // `const NAME = value`, or `const NAME: type = value`
+    fn read_const(&mut self) -> Result<Node, ()> {
+        let start = self.lexer.read_token()?.range.start;
+        let name = self.read_identifier()?;
+        let ty = if self.lexer.skip_symbol(Symbol::Colon)? {
+            Some(self.read_type()?)
+        } else {
+            None
+        };
+        if !self.lexer.skip_symbol(Symbol::Assign)? {
+            return Err(());
+        }
+        let value = self.read_expr()?;
+        let end = value.range.end;
+        Ok(Node::new(
+            NodeKind::Const(name, ty, Box::new(value)),
+            range!(start, end),
+        ))
+    }
+
+    // `use module::name`
+    fn read_use(&mut self) -> Result<Node, ()> {
+        let start = self.lexer.read_token()?.range.start;
+        let name = self.read_identifier()?;
+        if !self.lexer.skip_symbol(Symbol::DoubleColon)? {
+            return Err(());
+        }
+        let (path, end) = self.read_path(name)?;
+        Ok(Node::new(NodeKind::Use(path), range!(start, end)))
+    }
+
+    fn read_try(&mut self, start: usize) -> Result<Node, ()> {
+        let body = self.read_brace_block()?;
+        // `catch` must be on the same line as the closing brace, like `else`
+        if !self.skip_keyword("catch")? {
+            return Err(());
+        }
+        let var = self.read_identifier()?;
+        let handler = self.read_brace_block()?;
+        let end = handler.range.end;
+        Ok(Node::new(
+            NodeKind::Try(Box::new(body), var, Box::new(handler)),
+            range!(start, end),
+        ))
+    }
+
+    fn read_while(&mut self, start: usize) -> Result<Node, ()> {
+        let cond = self.read_expr()?;
+        let body = self.read_brace_block()?;
+        let end = body.range.end;
+        Ok(Node::new(
+            NodeKind::While(Box::new(cond), Box::new(body)),
+            range!(start, end),
+        ))
+    }
+
+    // Reads the rest of `def name<T>(x: T) -> T { body }` or `fn(x: int) -> int { body }`
+    fn read_func(
+        &mut self,
+        name: Option<String>,
+        type_params: Vec<TypeParam>,
+        start: usize,
+    ) -> Result<Node, ()> {
+        let len = self.type_params.len();
+        self.type_params
+            .extend(type_params.iter().map(|param| param.name.clone()));
+        let func = self.read_func_rest(name, type_params, start);
+        self.type_params.truncate(len);
+        func
+    }
+
+    fn read_func_rest(
+        &mut self,
+        name: Option<String>,
+        type_params: Vec<TypeParam>,
+        start: usize,
+    ) -> Result<Node, ()> {
+        let (params, ret) = self.read_signature()?;
+        let body = self.read_brace_block()?;
+        let end = body.range.end;
+        Ok(Node::new(
+            NodeKind::Func(FuncDef {
+                name,
+                type_params,
+                params,
+                ret,
+                body: Box::new(body),
+            }),
+            range!(start, end),
+        ))
+    }
+
+    // Reads `(x: int, y: int) -> int`. In a trait or impl, the first parameter may be `self`.
+    fn read_signature(&mut self) -> Result<Signature, ()> {
+        if !self.lexer.skip_symbol(Symbol::OpeningParen)? {
+            return Err(());
+        }
+        let mut params = vec![];
+        if !self.lexer.skip_symbol(Symbol::ClosingParen)? {
+            loop {
+                let param = self.read_identifier()?;
+                if param == "self" && params.is_empty() && self.self_ty.is_some() {
+                    let self_ty = self.self_ty.clone().unwrap();
+                    params.push((param, self_ty));
+                } else {
+                    if !self.lexer.skip_symbol(Symbol::Colon)? {
+                        return Err(());
+                    }
+                    params.push((param, self.read_type()?));
+                }
+                if self.lexer.skip_symbol(Symbol::ClosingParen)? {
+                    break;
+                }
+                if !self.lexer.skip_symbol(Symbol::Comma)? {
+                    return Err(());
+                }
+            }
+        }
+        let ret = if self.lexer.skip_symbol(Symbol::Arrow)? {
+            Some(self.read_type()?)
+        } else {
+            None
+        };
+        Ok((params, ret))
+    }
+
+    fn read_brace_block(&mut self) -> Result<Node, ()> {
+        let tok = self.lexer.read_token()?;
+        if tok.kind != TokenKind::Symbol(Symbol::OpeningBrace) {
+            return Err(());
+        }
+        self.read_block(tok.range.start)
+    }
+
+    fn read_for(&mut self, start: usize) -> Result<Node, ()> {
+        let mut vars = vec![self.read_identifier()?];
+        if self.lexer.skip_symbol(Symbol::Comma)? {
+            vars.push(self.read_identifier()?);
+        }
+        if !self.skip_keyword("in")? {
+            return Err(());
+        }
+        let iterable = self.read_expr()?;
+        let body = self.read_brace_block()?;
+        let end = body.range.end;
+        Ok(Node::new(
+            NodeKind::For(vars, Box::new(iterable), Box::new(body)),
+            range!(start, end),
+        ))
+    }
+
+    fn read_pattern(&mut self) -> Result<Pattern, ()> {
+        let tok = self.lexer.read_token()?;
+        match tok.kind {
+            TokenKind::Int(n) => Ok(Pattern::new(PatternKind::Int(n), tok.range)),
+            TokenKind::String(s) => Ok(Pattern::new(PatternKind::String(s), tok.range)),
+            TokenKind::Symbol(Symbol::Sub) => match self.lexer.read_token()? {
+                Token {
+                    kind: TokenKind::Int(n),
                     range,
+                } => Ok(Pattern::new(
+                    PatternKind::Int(-n),
+                    range!(tok.range.start, range.end),
+                )),
+                _ => Err(()),
+            },
+            TokenKind::Identifier(ref name) if name == "_" => {
+                Ok(Pattern::new(PatternKind::Wildcard, tok.range))
+            }
+            TokenKind::Identifier(ref name) if name == "true" || name == "false" => Ok(
+                Pattern::new(PatternKind::Bool(name == "true"), tok.range),
+            ),
+            TokenKind::Identifier(name) => {
+                if !self.lexer.skip_symbol(Symbol::DoubleColon)? {
+                    return Ok(Pattern::new(PatternKind::Variable(name), tok.range));
+                }
+                let (path, mut end) = self.read_path(name)?;
+                let mut subpats = vec![];
+                if self.lexer.skip_symbol(Symbol::OpeningParen)? {
+                    loop {
+                        let tok = self.lexer.read_token()?;
+                        if tok.kind == TokenKind::Symbol(Symbol::ClosingParen) {
+                            end = tok.range.end;
+                            break;
+                        }
+                        self.lexer.unget(&tok);
+                        subpats.push(self.read_pattern()?);
+                        self.lexer.skip_symbol(Symbol::Comma)?;
+                    }
+                }
+                Ok(Pattern::new(
+                    PatternKind::Variant(path, subpats),
+                    range!(tok.range.start, end),
                 ))
-            } else {
+            }
+            _ => {
+                self.lexer.unget(&tok);
                 Err(())
             }
-        } else {
-            Ok(Node::new(NodeKind::Variable(var, None), range))
         }
     }
 }
+
+impl<'a> Parser<'a> {
+    fn read_identifier(&mut self) -> Result<String, ()> {
+        match self.lexer.read_token()?.kind {
+            TokenKind::Identifier(name) => Ok(name),
+            _ => Err(()),
+        }
+    }
+
+    fn skip_keyword(&mut self, keyword: &str) -> Result<bool, ()> {
+        let tok = match self.lexer.read_token() {
+            Ok(tok) => tok,
+            Err(()) => return Ok(false), // EOF
+        };
+        if let TokenKind::Identifier(ref name) = tok.kind {
+            if name == keyword {
+                return Ok(true);
+            }
+        }
+        self.lexer.unget(&tok);
+        Ok(false)
+    }
+
+    fn skip_newlines(&mut self) -> Result<(), ()> {
+        loop {
+            let tok = self.lexer.read_token()?;
+            if tok.kind != TokenKind::Newline {
+                self.lexer.unget(&tok);
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_read_all() {
+    let read = |src: &str| {
+        let mut lexer = Lexer::new_from_string(src.to_string());
+        Parser::new(&mut lexer).read_all().map(|nodes| nodes.len()).map_err(|e| e.msg)
+    };
+    assert_eq!(read("a = 1\n\nprint(a)\n\n"), Ok(2));
+    assert_eq!(read(""), Ok(0));
+    assert_eq!(read("print(1)\nprint(2 +)\nprint(3)"), Err("syntax error at line 2".to_string()));
+    assert_eq!(read("a = (1\n"), Err("syntax error at line 2".to_string()));
+}
+
+#[test]
+fn test_interpolation_errors() {
+    let read = |src: &str| {
+        let mut lexer = Lexer::new_from_string(src.to_string());
+        Parser::new(&mut lexer).read_all().map(|nodes| nodes.len()).map_err(|e| e.msg)
+    };
+    assert_eq!(read("print(\"a${1 + 2}b${ {\"}\"} }\")\nprint(1)"), Ok(2));
+    assert_eq!(read("print(\"a${1\n}b\")"), Ok(1));
+    let error = Err("syntax error at line 2".to_string());
+    assert_eq!(read("a = 1\nprint(\"a${1 +}b\")"), error);
+    assert_eq!(read("a = 1\nprint(\"a${1 2}b\")\nprint(3)"), error);
+    assert_eq!(read("a = 1\nprint(\"a${}b\")"), error);
+    assert_eq!(read("a = 1\nprint(\"a${(1}b\")"), error);
+    assert_eq!(read("a = 1\nprint(\"a${1)}b\")"), error);
+}
@@ -0,0 +1,406 @@
+use ir::{self, BlockId, Const, InstKind, Terminator};
+use node::UnaryOp;
+use typing::TypeKind;
+use vm::{binop, cast_float, cast_int};
+use vm_base::{VMInst, Value};
+use codegen::{binop_inst, wrap_inst, wraps};
+
+// A register machine for the functions lowered to the IR: instructions name the registers
+// they read and write, instead of moving values through the stack one at a time.
+//
+// Each call has its own window of registers, one for every SSA value of the function and a
+// few temporaries for moving the arguments of jumps. Operators are the same instructions as
+// for the stack VM (Add to Ge, IntCast and FloatCast), run by the same code.
+
+pub type Reg = usize;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegInst {
+    Const(Reg, Value), // dst, value
+    Move(Reg, Reg),    // dst, src
+    Not(Reg, Reg),
+    Neg(Reg, Reg),
+    Binary(VMInst, Reg, Reg, Reg), // op, dst, lhs, rhs
+    Cast(VMInst, Reg, Reg),        // IntCast or FloatCast, dst, src
+
+    Jmp(usize),
+    JmpIfFalse(Reg, usize),
+    Call(Reg, usize, Vec<Reg>), // dst, index of the function, arguments
+    Builtin(Reg, String, Vec<Reg>),
+    Ret(Reg),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Code {
+    pub insts: Vec<RegInst>,
+    pub funcs: Vec<FuncInfo>, // The functions of the program, then main
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FuncInfo {
+    pub name: String,
+    pub entry: usize,
+    pub params: Vec<Reg>,
+    pub nregs: usize,
+}
+
+pub fn lower_program(program: &ir::Program) -> Code {
+    let mut code = Code {
+        insts: vec![],
+        funcs: vec![],
+    };
+    for func in program.funcs.iter().chain(Some(&program.main)) {
+        lower_func(func, &program.funcs, &mut code);
+    }
+    code
+}
+
+fn lower_func(func: &ir::Function, funcs: &[ir::Function], code: &mut Code) {
+    let temps = func.values.len();
+    let max_args = func.blocks.iter().map(|b| b.params.len()).max().unwrap_or(0);
+    code.funcs.push(FuncInfo {
+        name: func.name.clone(),
+        entry: code.insts.len(),
+        params: func.params.clone(),
+        nregs: temps + max_args,
+    });
+    let insts = &mut code.insts;
+    let mut block_pcs = vec![0; func.blocks.len()];
+    let mut fixups = vec![]; // The jumps to blocks, whose operands are the block ids yet
+    for (i, block) in func.blocks.iter().enumerate() {
+        block_pcs[i] = insts.len();
+        for inst in &block.insts {
+            lower_inst(func, inst, funcs, insts);
+        }
+        match block.term {
+            Terminator::Jump(target, ref args) => {
+                move_args(func, target, args, temps, insts);
+                if target != i + 1 {
+                    fixups.push(insts.len());
+                    insts.push(RegInst::Jmp(target));
+                }
+            }
+            Terminator::Branch(cond, (then_, ref then_args), (else_, ref else_args)) => {
+                let branch = insts.len();
+                insts.push(RegInst::JmpIfFalse(cond, 0));
+                move_args(func, then_, then_args, temps, insts);
+                fixups.push(insts.len());
+                insts.push(RegInst::Jmp(then_));
+                insts[branch] = RegInst::JmpIfFalse(cond, insts.len());
+                move_args(func, else_, else_args, temps, insts);
+                if else_ != i + 1 {
+                    fixups.push(insts.len());
+                    insts.push(RegInst::Jmp(else_));
+                }
+            }
+            Terminator::Return(val) => insts.push(RegInst::Ret(val)),
+        }
+    }
+    for pos in fixups {
+        if let RegInst::Jmp(ref mut target) = insts[pos] {
+            *target = block_pcs[*target];
+        }
+    }
+}
+
+// The arguments go through temporaries if one of them is a parameter that is written first
+fn move_args(
+    func: &ir::Function,
+    target: BlockId,
+    args: &[Reg],
+    temps: Reg,
+    insts: &mut Vec<RegInst>,
+) {
+    let params = &func.blocks[target].params;
+    let overlaps = args.iter()
+        .enumerate()
+        .any(|(i, arg)| params[..i].contains(arg));
+    if overlaps {
+        for (i, &arg) in args.iter().enumerate() {
+            insts.push(RegInst::Move(temps + i, arg));
+        }
+        for (i, &param) in params.iter().enumerate() {
+            insts.push(RegInst::Move(param, temps + i));
+        }
+    } else {
+        for (&param, &arg) in params.iter().zip(args) {
+            if param != arg {
+                insts.push(RegInst::Move(param, arg));
+            }
+        }
+    }
+}
+
+fn lower_inst(
+    func: &ir::Function,
+    inst: &ir::Inst,
+    funcs: &[ir::Function],
+    insts: &mut Vec<RegInst>,
+) {
+    let (dst, ty) = (inst.dest, &func.values[inst.dest]);
+    match inst.kind {
+        InstKind::Const(ref c) => insts.push(RegInst::Const(
+            dst,
+            match *c {
+                Const::Bool(b) => Value::Bool(b),
+                Const::Int(n) => match ty.kind {
+                    TypeKind::Int(ty) if !ty.is_signed() => Value::UInt(n as u64),
+                    _ => Value::Int(n),
+                },
+                Const::Float(f) => match wrap_inst(ty) {
+                    Some(_) => Value::Float(f as f32 as f64),
+                    None => Value::Float(f),
+                },
+                Const::String(ref s) => Value::new_string(s.clone()),
+                Const::Unit => Value::Int(0),
+            },
+        )),
+        InstKind::Unary(UnaryOp::Not, val) => insts.push(RegInst::Not(dst, val)),
+        InstKind::Unary(UnaryOp::Neg, val) => {
+            insts.push(RegInst::Neg(dst, val));
+            insts.extend(wrap_inst(ty).map(|wrap| RegInst::Cast(wrap, dst, dst)));
+        }
+        InstKind::Binary(ref op, lhs, rhs) => {
+            insts.push(RegInst::Binary(binop_inst(op), dst, lhs, rhs));
+            if wraps(op) {
+                insts.extend(wrap_inst(ty).map(|wrap| RegInst::Cast(wrap, dst, dst)));
+            }
+        }
+        InstKind::Cast(val, ref to) => insts.push(match to.kind {
+            TypeKind::Int(ty) => RegInst::Cast(VMInst::IntCast(ty), dst, val),
+            TypeKind::Float(ty) => RegInst::Cast(VMInst::FloatCast(ty), dst, val),
+            _ => RegInst::Move(dst, val),
+        }),
+        InstKind::Call(ref name, ref args) => {
+            let index = funcs.iter().position(|f| &f.name == name).unwrap();
+            insts.push(RegInst::Call(dst, index, args.clone()))
+        }
+        InstKind::Builtin(ref name, ref args) => {
+            insts.push(RegInst::Builtin(dst, name.clone(), args.clone()))
+        }
+    }
+}
+
+pub struct RegVM {
+    pub regs: Vec<Value>, // Grow as needed, up to stack_limit registers
+    pub stack_limit: usize,
+    pub base: usize, // The first register of the running function
+    pub top: usize,  // The register after its last one
+    pub pc: usize,
+    pub frames: Vec<RegFrame>,
+    pub output: Option<String>, // If set, `print` writes here instead of stdout
+}
+
+#[derive(Clone, Debug)]
+pub struct RegFrame {
+    pub ret_pc: usize,
+    pub base: usize,
+    pub dst: Reg, // The register of the caller to return to
+}
+
+impl Default for RegVM {
+    fn default() -> RegVM {
+        RegVM::new()
+    }
+}
+
+impl RegVM {
+    pub fn new() -> RegVM {
+        RegVM {
+            regs: vec![],
+            stack_limit: 1 << 20,
+            base: 0,
+            top: 0,
+            pc: 0,
+            frames: vec![],
+            output: None,
+        }
+    }
+
+    // Runs main; errors (which the IR cannot catch) are returned as their messages
+    pub fn run(&mut self, code: &Code) -> Result<(), String> {
+        let main = code.funcs.last().unwrap();
+        if main.nregs > self.stack_limit {
+            return Err("stack overflow".to_string());
+        }
+        self.regs.resize(main.nregs, Value::Int(0));
+        self.top = main.nregs;
+        self.pc = main.entry;
+        loop {
+            let inst = &code.insts[self.pc];
+            self.pc += 1;
+            let base = self.base;
+            match *inst {
+                RegInst::Const(dst, ref val) => self.regs[base + dst] = val.clone(),
+                RegInst::Move(dst, src) => self.regs[base + dst] = self.regs[base + src].clone(),
+                RegInst::Not(dst, src) => {
+                    self.regs[base + dst] = match self.regs[base + src] {
+                        Value::Bool(b) => Value::Bool(!b),
+                        _ => return Err("Not: not a bool".to_string()),
+                    }
+                }
+                RegInst::Neg(dst, src) => {
+                    self.regs[base + dst] = match self.regs[base + src] {
+                        Value::Int(n) => Value::Int(n.wrapping_neg()),
+                        Value::Float(f) => Value::Float(-f),
+                        _ => return Err("Neg: not a signed number".to_string()),
+                    }
+                }
+                RegInst::Binary(ref op, dst, lhs, rhs) => {
+                    let lhs = self.regs[base + lhs].clone();
+                    let rhs = self.regs[base + rhs].clone();
+                    self.regs[base + dst] = binop(op, lhs, rhs)?
+                }
+                RegInst::Cast(ref op, dst, src) => {
+                    let val = self.regs[base + src].clone();
+                    self.regs[base + dst] = match *op {
+                        VMInst::IntCast(ty) => cast_int(val, ty)?,
+                        VMInst::FloatCast(ty) => cast_float(val, ty)?,
+                        _ => return Err("Cast: not a cast".to_string()),
+                    }
+                }
+                RegInst::Jmp(dst) => self.pc = dst,
+                RegInst::JmpIfFalse(cond, dst) => if self.regs[base + cond] == Value::Bool(false) {
+                    self.pc = dst
+                },
+                RegInst::Call(dst, index, ref args) => {
+                    let callee = &code.funcs[index];
+                    // The registers of returned calls are reused, not freed
+                    let callee_base = self.top;
+                    if callee_base + callee.nregs > self.stack_limit {
+                        return Err("stack overflow".to_string());
+                    }
+                    if self.regs.len() < callee_base + callee.nregs {
+                        self.regs.resize(callee_base + callee.nregs, Value::Int(0));
+                    }
+                    for (&param, &arg) in callee.params.iter().zip(args) {
+                        self.regs[callee_base + param] = self.regs[base + arg].clone();
+                    }
+                    self.frames.push(RegFrame {
+                        ret_pc: self.pc,
+                        base,
+                        dst,
+                    });
+                    self.base = callee_base;
+                    self.top = callee_base + callee.nregs;
+                    self.pc = callee.entry
+                }
+                RegInst::Builtin(dst, ref name, ref args) => {
+                    let val = self.call_builtin(name, &args[0])?;
+                    self.regs[base + dst] = val
+                }
+                RegInst::Ret(src) => {
+                    let val = self.regs[base + src].clone();
+                    let frame = match self.frames.pop() {
+                        Some(frame) => frame,
+                        None => return Ok(()),
+                    };
+                    self.top = base;
+                    self.base = frame.base;
+                    self.regs[frame.base + frame.dst] = val;
+                    self.pc = frame.ret_pc
+                }
+            }
+        }
+    }
+
+    fn call_builtin(&mut self, name: &str, arg: &Reg) -> Result<Value, String> {
+        let val = &self.regs[self.base + arg];
+        match name {
+            "print" => {
+                match self.output {
+                    Some(ref mut output) => output.push_str(format!("{}\n", val).as_str()),
+                    None => println!("{}", val),
+                }
+                Ok(Value::Int(0))
+            }
+            "to_string" => Ok(Value::new_string(format!("{}", val))),
+            _ => Err(format!("unknown builtin function '{}'", name)),
+        }
+    }
+}
+
+#[test]
+fn test_regvm() {
+    use lexer::Lexer;
+    use parser::Parser;
+    use codegen::Codegen;
+    let src = "def fact(n: int) -> int { if n <= 1 { 1 } else { n * fact(n - 1) } }
+               def fib(n: int) -> int {
+                 a = 0
+                 b = 1
+                 while n > 0 {
+                   a, b = b, a + b
+                   n -= 1
+                 }
+                 a
+               }
+               def count(n: u8) -> u8 {
+                 x: u8 = 250
+                 i: u8 = 0
+                 while i < n { x += 1 \n i += 1 }
+                 x
+               }
+               print(fact(10))
+               print(fib(30))
+               print(count(10))
+               print(-(3 as i8 * 50))
+               print(to_string(2.5) + \"!\")
+               print(1 / (fib(1) - 1))
+               print(0)";
+    let mut lexer = Lexer::new_from_string(src.to_string());
+    let mut parser = Parser::new(&mut lexer);
+    let mut codegen = Codegen::new(&mut parser);
+    let nodes = codegen.read_program().unwrap();
+    let code = lower_program(&ir::lower_program(&nodes).unwrap());
+    // Operands are read from registers, and results written to them, with no stack traffic
+    assert!(code.insts.contains(&RegInst::Binary(VMInst::Mul, 7, 0, 6)));
+    let mut vm = RegVM::new();
+    vm.output = Some(String::new());
+    assert_eq!(vm.run(&code), Err("attempt to divide by zero".to_string()));
+    assert_eq!(vm.output.unwrap(), "3628800\n832040\n4\n106\n2.5!\n");
+
+    // Unbounded recursion stops at the limit, as on the stack VM
+    let src = "def forever(n: int) -> int { forever(n + 1) }
+               print(forever(0))";
+    let mut lexer = Lexer::new_from_string(src.to_string());
+    let mut parser = Parser::new(&mut lexer);
+    let mut codegen = Codegen::new(&mut parser);
+    let nodes = codegen.read_program().unwrap();
+    let code = lower_program(&ir::lower_program(&nodes).unwrap());
+    let mut vm = RegVM::new();
+    vm.stack_limit = 10000;
+    assert_eq!(vm.run(&code), Err("stack overflow".to_string()));
+    assert!(vm.regs.len() <= 10000);
+    // So do the registers of main, which are taken before anything runs
+    let mut vm = RegVM::new();
+    vm.stack_limit = 1;
+    assert_eq!(vm.run(&code), Err("stack overflow".to_string()));
+
+    // Code the lowering would not make is an error, not a crash
+    let code = Code {
+        insts: vec![
+            RegInst::Const(0, Value::Int(1)),
+            RegInst::Not(1, 0),
+            RegInst::Ret(1),
+        ],
+        funcs: vec![FuncInfo {
+            name: "main".to_string(),
+            entry: 0,
+            params: vec![],
+            nregs: 2,
+        }],
+    };
+    assert_eq!(RegVM::new().run(&code), Err("Not: not a bool".to_string()));
+    let code = Code {
+        insts: vec![RegInst::Builtin(0, "exit".to_string(), vec![0]), RegInst::Ret(0)],
+        funcs: vec![FuncInfo {
+            name: "main".to_string(),
+            entry: 0,
+            params: vec![],
+            nregs: 1,
+        }],
+    };
+    let msg = "unknown builtin function 'exit'".to_string();
+    assert_eq!(RegVM::new().run(&code), Err(msg));
+}
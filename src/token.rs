@@ -35,6 +35,13 @@ impl Token {
         }
     }
 
+    pub fn new_interpolated_string(parts: Vec<StringPart>, range: Range<usize>) -> Token {
+        Token {
+            kind: TokenKind::InterpolatedString(parts),
+            range,
+        }
+    }
+
     pub fn new_symbol(symbol: Symbol, range: Range<usize>) -> Token {
         Token {
             kind: TokenKind::Symbol(symbol),
@@ -56,10 +63,17 @@ pub enum TokenKind {
     Int(i64),
     Float(f64),
     String(String),
+    InterpolatedString(Vec<StringPart>), // "a${x}b"
     Symbol(Symbol),
     Newline,
 }
 
+#[derive(PartialEq, Debug, Clone)]
+pub enum StringPart {
+    Str(String),
+    Expr(Range<usize>), // The source of the expression in `${...}`, parsed by Parser
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum Symbol {
     OpeningParen,
@@ -71,8 +85,10 @@ pub enum Symbol {
     Comma,
     Semicolon,
     Colon,
+    DoubleColon,
     Point,
     Arrow,
+    FatArrow,
     Inc,
     Dec,
     Add,
@@ -96,6 +112,8 @@ pub enum Symbol {
     LAnd,
     LOr,
     Question,
+    DoubleQuestion, // ??
+    QuestionPoint,  // ?.
     Assign,
     AssignAdd,
     AssignSub,
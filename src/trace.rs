@@ -0,0 +1,162 @@
+use vm::VM;
+use vm_base::{VMInst, Value};
+
+use std::io::Write;
+
+// Records every instruction the VM runs, with the state of the VM after it: the stack of
+// the current call (from bp to sp), as text or as JSON lines like
+//
+//     {"pc":12,"inst":"LoadV(0)","sp":6,"bp":4,"depth":1,"stack":[3,"foo",true]}
+//
+// where numbers, bools and strings are JSON values, and other objects their text.
+pub struct Tracer {
+    format: TraceFormat,
+    sink: Box<dyn FnMut(&str)>, // Called with each record
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Json,
+}
+
+impl Tracer {
+    pub fn new<F>(format: TraceFormat, sink: F) -> Tracer
+    where
+        F: FnMut(&str) + 'static,
+    {
+        Tracer {
+            format,
+            sink: Box::new(sink),
+        }
+    }
+
+    // Writes a record per line, to a file or stderr for example. If writing fails (to a
+    // closed pipe, say), the program goes on, and the rest of the trace is dropped.
+    pub fn to_writer<W>(format: TraceFormat, mut writer: W) -> Tracer
+    where
+        W: Write + 'static,
+    {
+        let mut failed = false;
+        Tracer::new(format, move |record| {
+            if !failed {
+                failed = writeln!(writer, "{}", record).is_err();
+            }
+        })
+    }
+
+    pub fn trace(&mut self, vm: &VM, pc: usize, inst: &VMInst) {
+        let stack = vm.stack.get(vm.bp..vm.sp + 1).unwrap_or(&[]);
+        let record = match self.format {
+            TraceFormat::Text => {
+                let stack: Vec<String> = stack.iter().map(|val| val.to_string()).collect();
+                let inst = format!("{:?}", inst);
+                format!("{:>5}  {:<24} [{}]", pc, inst, stack.join(", "))
+            }
+            TraceFormat::Json => {
+                let stack: Vec<String> = stack.iter().map(json_value).collect();
+                format!(
+                    "{{\"pc\":{},\"inst\":{},\"sp\":{},\"bp\":{},\"depth\":{},\"stack\":[{}]}}",
+                    pc,
+                    json_string(&format!("{:?}", inst)),
+                    vm.sp,
+                    vm.bp,
+                    vm.frames.len(),
+                    stack.join(",")
+                )
+            }
+        };
+        (self.sink)(&record)
+    }
+}
+
+fn json_value(val: &Value) -> String {
+    match *val {
+        Value::Bool(b) => b.to_string(),
+        Value::Int(n) => n.to_string(),
+        Value::UInt(n) => n.to_string(),
+        Value::Float(f) if f.is_finite() => format!("{:?}", f),
+        _ => json_string(&val.to_string()),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = "\"".to_string();
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[test]
+fn test_trace() {
+    use vm::compile_source;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    let src = "def greet(name: string) -> string { \"hi \" + name }
+               print(greet(\"bob\"))";
+    let (insts, _) = compile_source(src, 1);
+
+    let mut vm = VM::new();
+    vm.output = Some(String::new());
+    let records = Rc::new(RefCell::new(vec![]));
+    let sink = records.clone();
+    vm.tracer = Some(Tracer::new(TraceFormat::Json, move |record| {
+        sink.borrow_mut().push(record.to_string())
+    }));
+    vm.run(insts.clone()).unwrap();
+    assert_eq!(vm.output.take().unwrap(), "hi bob\n");
+    assert_eq!(records.borrow().len(), insts.len()); // Each is run once
+    let at = |pc: usize| {
+        let prefix = format!("{{\"pc\":{},", pc);
+        let records = records.borrow();
+        records.iter().find(|record| record.starts_with(&prefix)).unwrap().clone()
+    };
+    assert_eq!(
+        at(3),
+        "{\"pc\":3,\"inst\":\"PushS(\\\"hi \\\")\",\"sp\":3,\"bp\":2,\"depth\":1,\
+         \"stack\":[\"bob\",\"hi \"]}"
+    );
+    assert_eq!(
+        at(5),
+        "{\"pc\":5,\"inst\":\"Add\",\"sp\":3,\"bp\":2,\"depth\":1,\
+         \"stack\":[\"bob\",\"hi bob\"]}"
+    );
+
+    // Once its tracer is taken away, the VM records nothing, and only the program prints
+    let tracer = vm.tracer.take();
+    records.borrow_mut().clear();
+    vm.pc = 0;
+    vm.output = Some(String::new());
+    vm.run(insts.clone()).unwrap();
+    assert!(records.borrow().is_empty());
+    assert_eq!(vm.output.take().unwrap(), "hi bob\n");
+    // The sink itself still works
+    vm.tracer = tracer;
+    vm.pc = 0;
+    vm.run(insts.clone()).unwrap();
+    assert_eq!(records.borrow().len(), insts.len());
+
+    // A sink that fails does not stop the program
+    struct Closed;
+    impl Write for Closed {
+        fn write(&mut self, _: &[u8]) -> ::std::io::Result<usize> {
+            Err(::std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let mut vm = VM::new();
+    vm.output = Some(String::new());
+    vm.tracer = Some(Tracer::to_writer(TraceFormat::Text, Closed));
+    vm.run(insts).unwrap();
+    assert_eq!(vm.output.unwrap(), "hi bob\n");
+}
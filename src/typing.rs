@@ -1,18 +1,41 @@
+use node::{BinOp, EnumVariant, FuncDef, Impl, MatchArm, Node, NodeKind, Pattern, PatternKind,
+           UnaryOp};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem;
+use std::ops::Range;
+use std::slice;
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Type {
-    kind: TypeKind,
+    pub kind: TypeKind,
 }
 
 impl Type {
+    pub fn new_bool() -> Type {
+        Type {
+            kind: TypeKind::Bool,
+        }
+    }
+
     pub fn new_int() -> Type {
+        Type::new_int_ty(IntTy::I64)
+    }
+
+    pub fn new_int_ty(ty: IntTy) -> Type {
         Type {
-            kind: TypeKind::Int,
+            kind: TypeKind::Int(ty),
         }
     }
 
     pub fn new_float() -> Type {
+        Type::new_float_ty(FloatTy::F64)
+    }
+
+    pub fn new_float_ty(ty: FloatTy) -> Type {
         Type {
-            kind: TypeKind::Float,
+            kind: TypeKind::Float(ty),
         }
     }
 
@@ -21,6 +44,68 @@ impl Type {
             kind: TypeKind::String,
         }
     }
+
+    pub fn new_unit() -> Type {
+        Type {
+            kind: TypeKind::Unit,
+        }
+    }
+
+    pub fn new_never() -> Type {
+        Type {
+            kind: TypeKind::Never,
+        }
+    }
+
+    pub fn new_tuple(elems: Vec<Type>) -> Type {
+        Type {
+            kind: TypeKind::Tuple(elems),
+        }
+    }
+
+    pub fn new_map(key: Type, value: Type) -> Type {
+        Type {
+            kind: TypeKind::Map(Box::new(key), Box::new(value)),
+        }
+    }
+
+    pub fn new_func(params: Vec<Type>, ret: Type) -> Type {
+        Type {
+            kind: TypeKind::Func(params, Box::new(ret)),
+        }
+    }
+
+    pub fn new_named(name: String, args: Vec<Type>) -> Type {
+        Type {
+            kind: TypeKind::Named(name, args),
+        }
+    }
+
+    pub fn new_optional(ty: Type) -> Type {
+        Type {
+            kind: TypeKind::Optional(Box::new(ty)),
+        }
+    }
+
+    pub fn new_param(name: String) -> Type {
+        Type {
+            kind: TypeKind::Param(name),
+        }
+    }
+
+    pub fn new_generic(params: Vec<TypeParam>, ty: Type) -> Type {
+        Type {
+            kind: TypeKind::Generic(params, Box::new(ty)),
+        }
+    }
+
+    pub fn is_int(&self) -> bool {
+        matches!(self.kind, TypeKind::Int(_))
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(self.kind, TypeKind::Int(_) | TypeKind::Float(_))
+    }
 }
 
 pub trait ToType {
@@ -30,8 +115,17 @@ pub trait ToType {
 impl<'a> ToType for &'a str {
     fn to_type(&self) -> Option<Type> {
         match self {
-            &"int" => Some(Type::new_int()),
-            &"float" => Some(Type::new_float()),
+            &"bool" => Some(Type::new_bool()),
+            &"int" | &"i64" => Some(Type::new_int()),
+            &"i8" => Some(Type::new_int_ty(IntTy::I8)),
+            &"i16" => Some(Type::new_int_ty(IntTy::I16)),
+            &"i32" => Some(Type::new_int_ty(IntTy::I32)),
+            &"u8" => Some(Type::new_int_ty(IntTy::U8)),
+            &"u16" => Some(Type::new_int_ty(IntTy::U16)),
+            &"u32" => Some(Type::new_int_ty(IntTy::U32)),
+            &"u64" => Some(Type::new_int_ty(IntTy::U64)),
+            &"float" | &"f64" => Some(Type::new_float()),
+            &"f32" => Some(Type::new_float_ty(FloatTy::F32)),
             &"string" => Some(Type::new_string()),
             _ => None,
         }
@@ -40,8 +134,2820 @@ impl<'a> ToType for &'a str {
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum TypeKind {
-    Int,
-    Float,
+    Bool,
+    Int(IntTy),
+    Float(FloatTy),
     String,
-    // Something...
+    Unit,
+    Map(Box<Type>, Box<Type>),
+    Func(Vec<Type>, Box<Type>), // fn(params) -> ret
+    Optional(Box<Type>),        // T?, either `none` or a T
+    Tuple(Vec<Type>),           // (A, B), with at least two elements
+    Named(String, Vec<Type>), // User-defined type and its type arguments
+    Param(String),            // Type parameter of the enclosing generic function or type
+    Generic(Vec<TypeParam>, Box<Type>), // Generic function, instantiated at each call
+    Never,                    // `throw`, which never evaluates to a value
+}
+
+// A type parameter of a generic function, and the traits its arguments must implement
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypeParam {
+    pub name: String,
+    pub bounds: Vec<String>,
+}
+
+// Integer arithmetic wraps around at the width of the type. Division by zero and
+// shifting by 64 or more bits are runtime errors.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IntTy {
+    I8,
+    I16,
+    I32,
+    I64, // `int`
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl IntTy {
+    pub fn bits(&self) -> u32 {
+        match *self {
+            IntTy::I8 | IntTy::U8 => 8,
+            IntTy::I16 | IntTy::U16 => 16,
+            IntTy::I32 | IntTy::U32 => 32,
+            IntTy::I64 | IntTy::U64 => 64,
+        }
+    }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(*self, IntTy::I8 | IntTy::I16 | IntTy::I32 | IntTy::I64)
+    }
+
+    pub fn contains(&self, n: i64) -> bool {
+        let bits = self.bits();
+        if self.is_signed() {
+            bits == 64 || (n >= -(1 << (bits - 1)) && n < 1 << (bits - 1))
+        } else {
+            n >= 0 && (bits == 64 || n < 1 << bits)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FloatTy {
+    F32,
+    F64, // `float`
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            TypeKind::Bool => write!(f, "bool"),
+            TypeKind::Int(IntTy::I64) => write!(f, "int"),
+            TypeKind::Int(ty) => write!(f, "{}", format!("{:?}", ty).to_lowercase()),
+            TypeKind::Float(FloatTy::F64) => write!(f, "float"),
+            TypeKind::Float(FloatTy::F32) => write!(f, "f32"),
+            TypeKind::String => write!(f, "string"),
+            TypeKind::Unit => write!(f, "unit"),
+            TypeKind::Never => write!(f, "never"),
+            TypeKind::Map(ref key, ref value) => write!(f, "Map<{}, {}>", key, value),
+            TypeKind::Func(ref params, ref ret) => {
+                write!(f, "fn(")?;
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", param)?;
+                }
+                write!(f, ") -> {}", ret)
+            }
+            TypeKind::Named(ref name, ref args) => {
+                write!(f, "{}", name)?;
+                if !args.is_empty() {
+                    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                    write!(f, "<{}>", args.join(", "))?;
+                }
+                Ok(())
+            }
+            TypeKind::Tuple(ref elems) => {
+                let elems: Vec<String> = elems.iter().map(|elem| elem.to_string()).collect();
+                write!(f, "({})", elems.join(", "))
+            }
+            TypeKind::Optional(ref ty) => match ty.kind {
+                TypeKind::Func(_, _) | TypeKind::Generic(_, _) => write!(f, "({})?", ty),
+                _ => write!(f, "{}?", ty),
+            },
+            TypeKind::Param(ref name) => write!(f, "{}", name),
+            TypeKind::Generic(ref params, ref ty) => {
+                // `fn<T: Display>(T) -> string`
+                let params: Vec<String> = params
+                    .iter()
+                    .map(|param| if param.bounds.is_empty() {
+                        param.name.clone()
+                    } else {
+                        format!("{}: {}", param.name, param.bounds.join(" + "))
+                    })
+                    .collect();
+                write!(f, "fn<{}>{}", params.join(", "), &ty.to_string()[2..])
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypeError {
+    pub msg: String,
+    pub range: Range<usize>,
+}
+
+impl TypeError {
+    pub fn new(msg: String, range: Range<usize>) -> TypeError {
+        TypeError {
+            msg,
+            range,
+        }
+    }
+}
+
+pub struct TypeChecker {
+    scopes: Vec<HashMap<String, Type>>,
+    enums: HashMap<String, Vec<EnumVariant>>,
+    structs: HashMap<String, Vec<(String, Type)>>,
+    type_params: HashMap<String, Vec<String>>, // Of every enum and struct
+    traits: HashMap<String, Vec<(String, Type)>>,
+    impls: HashMap<(String, String), (Vec<String>, Type)>, // (trait, type name) -> impl
+    bounds: Vec<TypeParam>, // The type parameters of the enclosing generic functions
+    inferring: Vec<String>, // Functions whose return type is being inferred
+    consts: HashSet<String>,
+    ahead: HashMap<String, Type>, // Top-level functions with return types, for the bodies
+}
+
+impl TypeChecker {
+    pub fn new() -> TypeChecker {
+        TypeChecker {
+            scopes: vec![HashMap::new()],
+            enums: HashMap::new(),
+            structs: HashMap::new(),
+            type_params: HashMap::new(),
+            traits: HashMap::new(),
+            impls: HashMap::new(),
+            bounds: vec![],
+            inferring: vec![],
+            consts: HashSet::new(),
+            ahead: HashMap::new(),
+        }
+    }
+}
+
+impl Default for TypeChecker {
+    fn default() -> TypeChecker {
+        TypeChecker::new()
+    }
+}
+
+impl TypeChecker {
+    pub fn check(&mut self, nodes: &mut [Node]) -> Result<(), TypeError> {
+        // Enums and structs may be used before they are declared
+        for node in nodes.iter() {
+            let (kind, name, params) = match node.kind {
+                NodeKind::Enum(ref name, ref params, ref variants) => {
+                    self.enums.insert(name.clone(), variants.clone());
+                    ("enum", name, params)
+                }
+                NodeKind::Struct(ref name, ref params, ref fields) => {
+                    self.structs.insert(name.clone(), fields.clone());
+                    ("struct", name, params)
+                }
+                _ => continue,
+            };
+            if self.type_params.insert(name.clone(), params.clone()).is_some() {
+                return Err(TypeError::new(
+                    format!("{} `{}` is defined multiple times", kind, name),
+                    node.range.clone(),
+                ));
+            }
+        }
+        for node in nodes.iter() {
+            match node.kind {
+                NodeKind::Enum(_, _, ref variants) => for variant in variants {
+                    for ty in &variant.fields {
+                        self.check_type_exists(ty, &node.range)?;
+                    }
+                },
+                NodeKind::Struct(ref name, _, ref fields) => {
+                    for (i, (field, ty)) in fields.iter().enumerate() {
+                        if fields[..i].iter().any(|(f, _)| f == field) {
+                            return Err(TypeError::new(
+                                format!("field `{}` is already declared in `{}`", field, name),
+                                node.range.clone(),
+                            ));
+                        }
+                        self.check_type_exists(ty, &node.range)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        // The bodies of functions may call the top-level functions defined after them (to
+        // recurse mutually), if their return types are declared
+        for node in nodes.iter() {
+            if let NodeKind::Func(FuncDef {
+                name: Some(ref name),
+                ref type_params,
+                ref params,
+                ret: Some(ref ret),
+                ..
+            }) = node.kind
+            {
+                let params = params.iter().map(|(_, ty)| ty.clone()).collect();
+                let mut ty = Type::new_func(params, ret.clone());
+                if !type_params.is_empty() {
+                    ty = Type::new_generic(type_params.clone(), ty);
+                }
+                self.ahead.insert(name.clone(), ty);
+            }
+        }
+        for node in nodes.iter_mut() {
+            self.infer(node)?;
+        }
+        Ok(())
+    }
+
+    // Infers the type of `node` and records it in `node.ty`
+    pub fn infer(&mut self, node: &mut Node) -> Result<Type, TypeError> {
+        self.infer_with_hint(node, None)
+    }
+
+    // Like `infer`, but numeric literals (and `{:}`) take the type of `hint` if it fits
+    // them, including those at the tail of blocks and branches. Without a hint, integer
+    // literals are `int` and float literals are `float`.
+    fn infer_hinted(&mut self, node: &mut Node, hint: &Type) -> Result<Type, TypeError> {
+        self.infer_with_hint(node, Some(hint))
+    }
+
+    fn infer_with_hint(&mut self, node: &mut Node, hint: Option<&Type>) -> Result<Type, TypeError> {
+        let ty = self.infer_node(node, hint)?;
+        node.ty = Some(ty.clone());
+        Ok(ty)
+    }
+
+    fn infer_node(&mut self, node: &mut Node, hint: Option<&Type>) -> Result<Type, TypeError> {
+        match node.kind {
+            NodeKind::Bool(_) => Ok(Type::new_bool()),
+            NodeKind::Int(n) => match hint.map(unwrap_optional) {
+                Some(&Type {
+                    kind: TypeKind::Int(ty),
+                }) => {
+                    if !ty.contains(n) {
+                        return Err(TypeError::new(
+                            format!("literal `{}` does not fit in `{}`", n, Type::new_int_ty(ty)),
+                            node.range.clone(),
+                        ));
+                    }
+                    Ok(Type::new_int_ty(ty))
+                }
+                _ => Ok(Type::new_int()),
+            },
+            NodeKind::Float(_) => match hint.map(unwrap_optional) {
+                Some(&Type {
+                    kind: TypeKind::Float(ty),
+                }) => Ok(Type::new_float_ty(ty)),
+                _ => Ok(Type::new_float()),
+            },
+            NodeKind::String(_) => Ok(Type::new_string()),
+            NodeKind::Variable(ref name, _) => self.infer_variable(name, hint, &node.range),
+            NodeKind::Path(ref path) => self.infer_variant(path, &mut [], hint, &node.range),
+            NodeKind::UnaryOp(ref mut expr, UnaryOp::Not) => {
+                self.expect(&mut *expr, &Type::new_bool())?;
+                Ok(Type::new_bool())
+            }
+            NodeKind::UnaryOp(ref mut expr, UnaryOp::Neg) => {
+                let ty = self.infer(&mut *expr)?;
+                match ty.kind {
+                    TypeKind::Int(ity) if ity.is_signed() => Ok(ty),
+                    TypeKind::Float(_) => Ok(ty),
+                    _ => Err(TypeError::new(
+                        format!("operator `Neg` cannot be applied to type `{}`", ty),
+                        node.range.clone(),
+                    )),
+                }
+            }
+            NodeKind::Cast(ref mut expr, ref ty) => {
+                self.check_type_exists(ty, &node.range)?;
+                let from = self.infer(&mut *expr)?;
+                let ok = match (&from.kind, &ty.kind) {
+                    (&TypeKind::Bool, &TypeKind::Int(_)) => true,
+                    _ => from.is_numeric() && ty.is_numeric(),
+                };
+                if !ok {
+                    return Err(TypeError::new(
+                        format!("cannot cast `{}` as `{}`", from, ty),
+                        node.range.clone(),
+                    ));
+                }
+                Ok(ty.clone())
+            }
+            NodeKind::BinaryOp(ref mut lhs, ref mut rhs, BinOp::Assign) => {
+                self.infer_assign(&mut *lhs, &mut *rhs)
+            }
+            NodeKind::AssignOp(ref mut lhs, ref mut rhs, ref op) => {
+                match lhs.kind {
+                    NodeKind::Variable(ref name, None) if self.is_const_var(name) => {
+                        return Err(TypeError::new(
+                            format!("cannot assign to constant `{}`", name),
+                            lhs.range.clone(),
+                        ))
+                    }
+                    NodeKind::Variable(_, None)
+                    | NodeKind::Index(_, _)
+                    | NodeKind::Field(_, _) => {}
+                    _ => {
+                        return Err(TypeError::new(
+                            "invalid left-hand side of assignment".to_string(),
+                            lhs.range.clone(),
+                        ))
+                    }
+                }
+                let ty = self.infer_binop(&mut *lhs, &mut *rhs, op, &node.range)?;
+                let lty = lhs.ty.clone().unwrap();
+                if ty != lty {
+                    return Err(TypeError::new(
+                        format!("mismatched types: expected `{}`, found `{}`", lty, ty),
+                        node.range.clone(),
+                    ));
+                }
+                Ok(ty)
+            }
+            NodeKind::BinaryOp(ref mut lhs, ref mut rhs, BinOp::Coalesce) => {
+                let ty = self.infer(&mut *lhs)?;
+                let inner = self.expect_optional(&ty, "??", &lhs.range)?;
+                let rty = self.infer_hinted(&mut *rhs, &inner)?;
+                // `a ?? b` is a `T` if `b` is, or else a `T?` like `a`
+                if rty == inner || rty == ty {
+                    return Ok(rty);
+                }
+                if coerce(&mut *rhs, &rty, &inner) {
+                    return Ok(inner);
+                }
+                Err(TypeError::new(
+                    format!("mismatched types: expected `{}`, found `{}`", inner, rty),
+                    rhs.range.clone(),
+                ))
+            }
+            NodeKind::None => match hint {
+                Some(&Type {
+                    kind: TypeKind::Optional(_),
+                }) => Ok(hint.unwrap().clone()),
+                _ => Err(TypeError::new(
+                    "cannot infer the type of `none`; add a type annotation".to_string(),
+                    node.range.clone(),
+                )),
+            },
+            NodeKind::Wrap(ref mut expr) => Ok(Type::new_optional(self.infer(&mut *expr)?)),
+            NodeKind::IfLet(ref name, ref mut expr, ref mut then_, ref mut else_) => {
+                let ty = self.infer(&mut *expr)?;
+                let inner = self.expect_optional(&ty, "if let", &expr.range)?;
+                self.scopes.push(HashMap::new());
+                self.declare(name.clone(), inner);
+                let then_ty = self.infer_with_hint(&mut *then_, hint);
+                self.scopes.pop();
+                let then_ty = then_ty?;
+                let else_ty = self.infer_hinted(&mut *else_, hint.unwrap_or(&then_ty))?;
+                unify_branches(then_, then_ty, else_, else_ty, "if and else")
+            }
+            NodeKind::OptChain(ref name, ref mut expr, ref mut rest) => {
+                let ty = self.infer(&mut *expr)?;
+                let inner = self.expect_optional(&ty, "?.", &expr.range)?;
+                self.scopes.push(HashMap::new());
+                self.declare(name.clone(), inner);
+                let ty = self.infer(&mut *rest);
+                self.scopes.pop();
+                let ty = ty?;
+                // `none` if there is no value, so optional unless `rest` already is
+                if let TypeKind::Optional(_) = ty.kind {
+                    return Ok(ty);
+                }
+                let optional = Type::new_optional(ty.clone());
+                coerce(&mut *rest, &ty, &optional);
+                Ok(optional)
+            }
+            NodeKind::BinaryOp(ref mut lhs, ref mut rhs, ref op) => {
+                self.infer_binop(&mut *lhs, &mut *rhs, op, &node.range)
+            }
+            NodeKind::If(ref mut cond, ref mut then_, ref mut else_) => {
+                self.expect(&mut *cond, &Type::new_bool())?;
+                // Without a hint, a literal branch takes the type of the other one
+                let (then_ty, else_ty) = if hint.is_none() && is_literal(then_)
+                    && !is_literal(else_)
+                {
+                    let else_ty = self.infer(&mut *else_)?;
+                    (self.infer_hinted(&mut *then_, &else_ty)?, else_ty)
+                } else {
+                    let then_ty = self.infer_with_hint(&mut *then_, hint)?;
+                    let else_ty = self.infer_hinted(&mut *else_, hint.unwrap_or(&then_ty))?;
+                    (then_ty, else_ty)
+                };
+                unify_branches(then_, then_ty, else_, else_ty, "if and else")
+            }
+            // Values thrown, including the errors raised by the VM, are strings
+            NodeKind::Try(ref mut body, ref name, ref mut handler) => {
+                let body_ty = self.infer_with_hint(&mut *body, hint)?;
+                self.scopes.push(HashMap::new());
+                self.declare(name.clone(), Type::new_string());
+                let handler_ty = self.infer_hinted(&mut *handler, hint.unwrap_or(&body_ty));
+                self.scopes.pop();
+                unify_branches(body, body_ty, handler, handler_ty?, "try and catch")
+            }
+            NodeKind::Throw(ref mut expr) => {
+                self.expect(&mut *expr, &Type::new_string())?;
+                Ok(Type::new_never())
+            }
+            NodeKind::While(ref mut cond, ref mut body) => {
+                self.expect(&mut *cond, &Type::new_bool())?;
+                self.infer(&mut *body)?;
+                Ok(Type::new_unit())
+            }
+            NodeKind::Apply(ref mut callee, ref mut args) => {
+                self.infer_apply(&mut *callee, args, hint, &node.range)
+            }
+            NodeKind::Trait(ref name, ref methods) => {
+                if self.traits.contains_key(name) {
+                    return Err(TypeError::new(
+                        format!("trait `{}` is defined multiple times", name),
+                        node.range.clone(),
+                    ));
+                }
+                for (_, ty) in methods {
+                    self.check_type_exists(ty, &node.range)?;
+                }
+                self.traits.insert(name.clone(), methods.clone());
+                Ok(Type::new_unit())
+            }
+            NodeKind::Impl(ref mut impl_) => self.infer_impl(impl_, &node.range),
+            NodeKind::Index(ref mut map, ref mut key) => {
+                let (key_ty, value_ty) = self.infer_map(&mut *map)?;
+                self.expect(&mut *key, &key_ty)?;
+                Ok(value_ty)
+            }
+            // The type of `{:}` only comes from its context
+            NodeKind::Map(ref pairs)
+                if pairs.is_empty()
+                    && hint.is_some_and(|ty| matches!(ty.kind, TypeKind::Map(_, _))) =>
+            {
+                Ok(hint.unwrap().clone())
+            }
+            NodeKind::Map(ref mut pairs) => self.infer_map_literal(pairs, &node.range),
+            NodeKind::Tuple(ref mut elems) => {
+                let hints = match hint.map(unwrap_optional) {
+                    Some(&Type {
+                        kind: TypeKind::Tuple(ref hints),
+                    }) if hints.len() == elems.len() => hints.clone(),
+                    _ => vec![],
+                };
+                let mut tys = vec![];
+                for (i, elem) in elems.iter_mut().enumerate() {
+                    tys.push(self.infer_with_hint(elem, hints.get(i))?);
+                }
+                Ok(Type::new_tuple(tys))
+            }
+            NodeKind::For(ref vars, ref mut iterable, ref mut body) => {
+                let (key_ty, value_ty) = self.infer_map(&mut *iterable)?;
+                self.scopes.push(HashMap::new());
+                self.declare(vars[0].clone(), key_ty);
+                if vars.len() > 1 {
+                    self.declare(vars[1].clone(), value_ty);
+                }
+                self.infer(&mut *body)?;
+                self.scopes.pop();
+                Ok(Type::new_unit())
+            }
+            NodeKind::Block(ref mut nodes) => {
+                self.scopes.push(HashMap::new());
+                let mut ty = Ok(Type::new_unit());
+                let len = nodes.len();
+                for (i, node) in nodes.iter_mut().enumerate() {
+                    ty = if i + 1 == len {
+                        self.infer_with_hint(node, hint)
+                    } else {
+                        self.infer(node)
+                    };
+                    if ty.is_err() {
+                        break;
+                    }
+                }
+                self.scopes.pop();
+                ty
+            }
+            NodeKind::Enum(_, _, _) | NodeKind::Struct(_, _, _) => Ok(Type::new_unit()),
+            NodeKind::StructLit(ref name, ref mut fields) => {
+                self.infer_struct_lit(name, fields, hint, &node.range)
+            }
+            NodeKind::Field(ref mut expr, ref name) => {
+                let ty = self.infer(&mut *expr)?;
+                check_unwrapped(&ty, &format!("access field `{}` of", name), &node.range)?;
+                self.struct_fields(&ty)
+                    .and_then(|fields| fields.into_iter().find(|(f, _)| f == name))
+                    .map(|(_, ty)| ty)
+                    .ok_or_else(|| {
+                        TypeError::new(
+                            format!("no field `{}` on type `{}`", name, ty),
+                            node.range.clone(),
+                        )
+                    })
+            }
+            NodeKind::Match(ref mut cond, ref mut arms) => {
+                self.infer_match(&mut *cond, arms, hint, &node.range)
+            }
+            NodeKind::Func(ref mut func) => self.infer_func(func, &node.range),
+            NodeKind::Const(ref name, ref ty, ref mut value) => {
+                self.infer_const(name, ty, value, &node.range)
+            }
+            NodeKind::Import(_) | NodeKind::Use(_) | NodeKind::Pub(_) => {
+                unreachable!("resolved by ModuleLoader")
+            }
+        }
+    }
+
+    // A `T` is also accepted where a `T?` is expected
+    fn expect(&mut self, node: &mut Node, expected: &Type) -> Result<(), TypeError> {
+        let ty = self.infer_hinted(node, expected)?;
+        if &ty != expected && ty.kind != TypeKind::Never && !coerce(node, &ty, expected) {
+            return Err(TypeError::new(
+                format!("mismatched types: expected `{}`, found `{}`", expected, ty),
+                node.range.clone(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Constants are of primitive types, and their values are computed from literals and
+    // other constants only
+    fn infer_const(
+        &mut self,
+        name: &str,
+        ty: &Option<Type>,
+        value: &mut Node,
+        range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        if self.scopes[0].contains_key(name) {
+            return Err(TypeError::new(
+                format!("`{}` is already declared", name),
+                range.clone(),
+            ));
+        }
+        let ty = match *ty {
+            Some(ref ty) => {
+                self.expect(value, ty)?;
+                ty.clone()
+            }
+            None => self.infer(value)?,
+        };
+        match ty.kind {
+            TypeKind::Bool | TypeKind::Int(_) | TypeKind::Float(_) | TypeKind::String => {}
+            _ => {
+                return Err(TypeError::new(
+                    format!("constants must be of a primitive type, found `{}`", ty),
+                    range.clone(),
+                ))
+            }
+        }
+        if !self.is_constant(value) {
+            return Err(TypeError::new(
+                format!("the value of constant `{}` must be a constant expression", name),
+                value.range.clone(),
+            ));
+        }
+        self.declare(name.to_string(), ty);
+        self.consts.insert(name.to_string());
+        Ok(Type::new_unit())
+    }
+
+    fn is_constant(&self, node: &Node) -> bool {
+        match node.kind {
+            NodeKind::Bool(_) | NodeKind::Int(_) | NodeKind::Float(_) | NodeKind::String(_) => {
+                true
+            }
+            NodeKind::Variable(ref name, None) => self.is_const_var(name),
+            NodeKind::UnaryOp(ref expr, _) | NodeKind::Cast(ref expr, _) => self.is_constant(expr),
+            NodeKind::BinaryOp(ref lhs, ref rhs, ref op) => {
+                *op != BinOp::Assign
+                    && *op != BinOp::Coalesce
+                    && self.is_constant(lhs)
+                    && self.is_constant(rhs)
+            }
+            _ => false,
+        }
+    }
+
+    // Whether `name` refers to a constant here, rather than to a local shadowing it
+    fn is_const_var(&self, name: &str) -> bool {
+        self.consts.contains(name)
+            && self.scopes.iter().rposition(|scope| scope.contains_key(name)) == Some(0)
+    }
+
+    // Returns `T` if `ty` is `T?`, for the operand of `what`
+    fn expect_optional(
+        &self,
+        ty: &Type,
+        what: &str,
+        range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        match ty.kind {
+            TypeKind::Optional(ref inner) => Ok((**inner).clone()),
+            _ => Err(TypeError::new(
+                format!("`{}` requires an optional, found `{}`", what, ty),
+                range.clone(),
+            )),
+        }
+    }
+
+    // A `def` evaluates to unit and declares its name; a lambda evaluates to the function
+    fn infer_func(&mut self, func: &mut FuncDef, range: &Range<usize>) -> Result<Type, TypeError> {
+        for (_, ty) in &func.params {
+            self.check_type_exists(ty, range)?;
+        }
+        let params: Vec<Type> = func.params.iter().map(|(_, ty)| ty.clone()).collect();
+        let type_params = func.type_params.clone();
+        for bound in type_params.iter().flat_map(|param| param.bounds.iter()) {
+            if !self.traits.contains_key(bound) {
+                return Err(TypeError::new(
+                    format!("cannot find trait `{}`", bound),
+                    range.clone(),
+                ));
+            }
+        }
+        // The methods of the traits bounding the type parameters are passed in hidden
+        // parameters, after the others
+        for param in &type_params {
+            let self_ty = Type::new_param(param.name.clone());
+            for bound in &param.bounds {
+                for (method, sig) in &self.traits[bound] {
+                    func.params.push((
+                        bound_method_name(&param.name, bound, method),
+                        substitute(sig, &self_subst(&self_ty)),
+                    ));
+                }
+            }
+        }
+        let generalize = |ty: Type| {
+            if type_params.is_empty() {
+                ty
+            } else {
+                Type::new_generic(type_params.clone(), ty)
+            }
+        };
+        match (&func.name, &func.ret) {
+            (Some(name), Some(ret)) => {
+                self.check_type_exists(ret, range)?;
+                // Declared before the body, so that the function can call itself
+                let ty = generalize(Type::new_func(params.clone(), ret.clone()));
+                self.declare(name.clone(), ty);
+            }
+            (Some(name), &None) => self.inferring.push(name.clone()),
+            (&None, Some(ret)) => self.check_type_exists(ret, range)?,
+            (&None, &None) => {}
+        }
+
+        self.scopes.push(HashMap::new());
+        if self.scopes.len() == 2 {
+            let ahead: Vec<(String, Type)> = self.ahead.clone().into_iter().collect();
+            for (name, ty) in ahead {
+                if self.lookup(&name).is_none() {
+                    self.declare(name, ty);
+                }
+            }
+        }
+        for (name, ty) in &func.params {
+            self.declare(name.clone(), ty.clone());
+        }
+        let len = self.bounds.len();
+        self.bounds.extend(type_params.iter().cloned());
+        let ret = match func.ret {
+            Some(ref ret) => self.expect(&mut func.body, ret).map(|_| ret.clone()),
+            None => self.infer(&mut func.body),
+        };
+        self.bounds.truncate(len);
+        self.scopes.pop();
+        let ty = Type::new_func(params, ret?);
+
+        match func.name {
+            Some(ref name) => {
+                if func.ret.is_none() {
+                    self.inferring.pop();
+                    self.declare(name.clone(), generalize(ty));
+                }
+                Ok(Type::new_unit())
+            }
+            None => Ok(ty),
+        }
+    }
+
+    // The methods of an impl are declared as functions named `Type::method`
+    fn infer_impl(&mut self, impl_: &mut Impl, range: &Range<usize>) -> Result<Type, TypeError> {
+        self.check_type_exists(&impl_.ty, range)?;
+        let key = type_key(&impl_.ty).ok_or_else(|| {
+            TypeError::new(
+                format!("cannot implement methods for `{}`", impl_.ty),
+                range.clone(),
+            )
+        })?;
+        let sigs = match impl_.trait_ {
+            Some(ref trait_) => {
+                let sigs = self.traits.get(trait_).cloned().ok_or_else(|| {
+                    TypeError::new(format!("cannot find trait `{}`", trait_), range.clone())
+                })?;
+                for method in &impl_.methods {
+                    let name = method.name.as_ref().unwrap();
+                    if sigs.iter().all(|(m, _)| m != name) {
+                        return Err(TypeError::new(
+                            format!("method `{}` is not a member of trait `{}`", name, trait_),
+                            range.clone(),
+                        ));
+                    }
+                }
+                for (m, _) in &sigs {
+                    if impl_.methods.iter().all(|method| method.name.as_ref() != Some(m)) {
+                        return Err(TypeError::new(
+                            format!(
+                                "missing method `{}` in implementation of `{}` for `{}`",
+                                m, trait_, impl_.ty
+                            ),
+                            range.clone(),
+                        ));
+                    }
+                }
+                let impl_ty = (impl_.type_params.clone(), impl_.ty.clone());
+                if self.impls.insert((trait_.clone(), key.clone()), impl_ty).is_some() {
+                    return Err(TypeError::new(
+                        format!("trait `{}` is already implemented for `{}`", trait_, key),
+                        range.clone(),
+                    ));
+                }
+                sigs
+            }
+            None => vec![],
+        };
+
+        for i in 0..impl_.methods.len() {
+            let method = impl_.methods[i].name.clone().unwrap();
+            let name = format!("{}::{}", key, method);
+            if self.lookup(&name).is_some()
+                || impl_.methods[..i].iter().any(|m| m.name.as_ref() == Some(&name))
+            {
+                return Err(TypeError::new(
+                    format!("method `{}` is already defined for `{}`", method, key),
+                    range.clone(),
+                ));
+            }
+            impl_.methods[i].name = Some(name.clone());
+            // Declared up front if possible, so that the methods can call each other
+            if let Some(ref ret) = impl_.methods[i].ret {
+                let params = impl_.methods[i].params.iter().map(|(_, ty)| ty.clone());
+                let mut ty = Type::new_func(params.collect(), ret.clone());
+                if !impl_.methods[i].type_params.is_empty() {
+                    ty = Type::new_generic(impl_.methods[i].type_params.clone(), ty);
+                }
+                self.declare(name, ty);
+            }
+        }
+
+        for method in &mut impl_.methods {
+            self.infer_func(method, range)?;
+            let name = method.name.as_ref().unwrap();
+            let actual = match self.lookup(name).unwrap().kind {
+                TypeKind::Generic(_, ty) => *ty,
+                kind => Type { kind },
+            };
+            let short_name = &name[key.len() + 2..];
+            if let Some((_, sig)) = sigs.iter().find(|&(m, _)| m == short_name) {
+                let expected = substitute(sig, &self_subst(&impl_.ty));
+                if actual != expected {
+                    return Err(TypeError::new(
+                        format!(
+                            "method `{}` has an incompatible type for trait `{}`: expected `{}`, \
+                             found `{}`",
+                            short_name,
+                            impl_.trait_.as_ref().unwrap(),
+                            expected,
+                            actual
+                        ),
+                        range.clone(),
+                    ));
+                }
+            }
+        }
+        Ok(Type::new_unit())
+    }
+
+    fn infer_assign(&mut self, lhs: &mut Node, rhs: &mut Node) -> Result<Type, TypeError> {
+        if let NodeKind::Tuple(_) = lhs.kind {
+            let ty = match self.target_type(lhs) {
+                Some(hint) => self.infer_hinted(rhs, &hint)?,
+                None => self.infer(rhs)?,
+            };
+            self.destructure(lhs, &ty, &rhs.range)?;
+            return Ok(ty);
+        }
+        // `m[k] = v` and `p.x = v` modify the map or object in place
+        match lhs.kind {
+            NodeKind::Index(_, _) | NodeKind::Field(_, _) => {
+                let ty = self.infer(lhs)?;
+                self.expect(rhs, &ty)?;
+                return Ok(ty);
+            }
+            _ => {}
+        }
+        let ty = {
+            let (name, annotation) = match lhs.kind {
+                NodeKind::Variable(ref name, ref annotation) => (name, annotation),
+                _ => {
+                    return Err(TypeError::new(
+                        "invalid left-hand side of assignment".to_string(),
+                        lhs.range.clone(),
+                    ))
+                }
+            };
+            if self.is_const_var(name) {
+                return Err(TypeError::new(
+                    format!("cannot assign to constant `{}`", name),
+                    lhs.range.clone(),
+                ));
+            }
+            let ty = match (annotation, self.lookup(name)) {
+                (Some(annotation), var_ty) => {
+                    self.check_type_exists(annotation, &lhs.range)?;
+                    if let Some(ref var_ty) = var_ty {
+                        if var_ty != annotation {
+                            return Err(TypeError::new(
+                                format!("`{}` is already declared as `{}`", name, var_ty),
+                                lhs.range.clone(),
+                            ));
+                        }
+                    }
+                    self.expect(rhs, annotation)?;
+                    annotation.clone()
+                }
+                (&None, Some(var_ty)) => {
+                    self.expect(rhs, &var_ty)?;
+                    var_ty
+                }
+                (&None, None) => self.infer(rhs)?,
+            };
+            if self.lookup(name).is_none() {
+                self.declare(name.clone(), ty.clone());
+            }
+            ty
+        };
+        lhs.ty = Some(ty.clone());
+        Ok(ty)
+    }
+
+    // The type of the assignment target `(a, b: int)`, if all its variables have one yet
+    fn target_type(&self, target: &Node) -> Option<Type> {
+        match target.kind {
+            NodeKind::Variable(_, Some(ref annotation)) => Some(annotation.clone()),
+            NodeKind::Variable(ref name, None) => self.lookup(name),
+            NodeKind::Tuple(ref targets) => targets
+                .iter()
+                .map(|target| self.target_type(target))
+                .collect::<Option<Vec<Type>>>()
+                .map(Type::new_tuple),
+            _ => None,
+        }
+    }
+
+    // Assigns a value of type `ty` (from the expression at `range`) to the variables in
+    // `target`, declaring the new ones
+    fn destructure(
+        &mut self,
+        target: &mut Node,
+        ty: &Type,
+        range: &Range<usize>,
+    ) -> Result<(), TypeError> {
+        match target.kind {
+            NodeKind::Tuple(ref mut targets) => match ty.kind {
+                TypeKind::Tuple(ref elems) if elems.len() == targets.len() => {
+                    for (target, elem) in targets.iter_mut().zip(elems) {
+                        self.destructure(target, elem, range)?;
+                    }
+                }
+                _ => {
+                    return Err(TypeError::new(
+                        format!(
+                            "mismatched types: expected a tuple of {} elements, found `{}`",
+                            targets.len(),
+                            ty
+                        ),
+                        range.clone(),
+                    ))
+                }
+            },
+            NodeKind::Variable(ref name, ref annotation) => {
+                if let Some(ref annotation) = *annotation {
+                    self.check_type_exists(annotation, &target.range)?;
+                }
+                if self.is_const_var(name) {
+                    return Err(TypeError::new(
+                        format!("cannot assign to constant `{}`", name),
+                        target.range.clone(),
+                    ));
+                }
+                let var_ty = self.lookup(name);
+                if let (Some(annotation), Some(var_ty)) = (annotation, &var_ty) {
+                    if var_ty != annotation {
+                        return Err(TypeError::new(
+                            format!("`{}` is already declared as `{}`", name, var_ty),
+                            target.range.clone(),
+                        ));
+                    }
+                }
+                match annotation.clone().or(var_ty) {
+                    Some(ref var_ty) if var_ty != ty => {
+                        return Err(TypeError::new(
+                            format!("mismatched types: expected `{}`, found `{}`", var_ty, ty),
+                            target.range.clone(),
+                        ))
+                    }
+                    _ => {}
+                }
+                if self.lookup(name).is_none() {
+                    self.declare(name.clone(), ty.clone());
+                }
+            }
+            _ => {
+                return Err(TypeError::new(
+                    "invalid left-hand side of assignment".to_string(),
+                    target.range.clone(),
+                ))
+            }
+        }
+        target.ty = Some(ty.clone());
+        Ok(())
+    }
+
+    fn infer_binop(
+        &mut self,
+        lhs: &mut Node,
+        rhs: &mut Node,
+        op: &BinOp,
+        range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        // A literal operand takes the type of the other one, e.g. `x + 1` where `x: u8`
+        let (lty, rty) = if is_literal(lhs) && !is_literal(rhs) {
+            let rty = self.infer(rhs)?;
+            (self.infer_hinted(lhs, &rty)?, rty)
+        } else {
+            let lty = self.infer(lhs)?;
+            let rty = self.infer_hinted(rhs, &lty)?;
+            (lty, rty)
+        };
+        let (lty, rty) = match *op {
+            // `x == 1` where `x: int?`
+            BinOp::Eq | BinOp::Ne if coerce(rhs, &rty, &lty) => (lty.clone(), lty),
+            BinOp::Eq | BinOp::Ne if coerce(lhs, &lty, &rty) => (rty.clone(), rty),
+            BinOp::Eq | BinOp::Ne => (lty, rty),
+            _ => {
+                check_unwrapped(&lty, &format!("apply `{:?}` to", op), range)?;
+                check_unwrapped(&rty, &format!("apply `{:?}` to", op), range)?;
+                (lty, rty)
+            }
+        };
+        if lty != rty {
+            return Err(TypeError::new(
+                format!("mismatched types: `{}` and `{}`", lty, rty),
+                range.clone(),
+            ));
+        }
+        let ok = match *op {
+            BinOp::Add => lty.is_numeric() || lty.kind == TypeKind::String,
+            BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem => lty.is_numeric(),
+            BinOp::And | BinOp::Or | BinOp::Xor => lty.is_int() || lty.kind == TypeKind::Bool,
+            BinOp::Shl | BinOp::Shr => lty.is_int(),
+            BinOp::LAnd | BinOp::LOr => lty.kind == TypeKind::Bool,
+            BinOp::Eq | BinOp::Ne => !matches!(lty.kind, TypeKind::Func(_, _)),
+            BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge => {
+                lty.is_numeric() || lty.kind == TypeKind::String
+            }
+            BinOp::Coalesce | BinOp::Assign => false,
+        };
+        if !ok {
+            return Err(TypeError::new(
+                format!("operator `{:?}` cannot be applied to type `{}`", op, lty),
+                range.clone(),
+            ));
+        }
+        match *op {
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge => {
+                Ok(Type::new_bool())
+            }
+            _ => Ok(lty),
+        }
+    }
+
+    fn infer_apply(
+        &mut self,
+        callee: &mut Node,
+        args: &mut Vec<Node>,
+        hint: Option<&Type>,
+        range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        if let NodeKind::Field(_, _) = callee.kind {
+            return self.infer_method_call(callee, args, hint, range);
+        }
+        // `Type::method(args)` calls a method declared in an impl
+        let method = match callee.kind {
+            NodeKind::Path(ref path) if self.lookup_variant(path, range).is_err() => {
+                Some(path.join("::")).filter(|name| self.lookup(name).is_some())
+            }
+            _ => None,
+        };
+        if let Some(method) = method {
+            callee.kind = NodeKind::Variable(method, None);
+        }
+        match callee.kind {
+            // Variables shadow builtin functions
+            NodeKind::Variable(ref name, _) if name == "print" && self.lookup(name).is_none() => {
+                if args.len() != 1 {
+                    return Err(TypeError::new(
+                        format!("`print` expects 1 argument, found {}", args.len()),
+                        range.clone(),
+                    ));
+                }
+                let ty = self.infer(&mut args[0])?;
+                match ty.kind {
+                    TypeKind::Bool
+                    | TypeKind::Int(_)
+                    | TypeKind::Float(_)
+                    | TypeKind::String
+                    | TypeKind::Map(_, _) => Ok(Type::new_unit()),
+                    _ => Err(TypeError::new(
+                        format!("`print` cannot print a value of type `{}`", ty),
+                        args[0].range.clone(),
+                    )),
+                }
+            }
+            NodeKind::Variable(ref name, _)
+                if is_map_builtin(name) && self.lookup(name).is_none() =>
+            {
+                self.infer_map_builtin(name, args, range)
+            }
+            NodeKind::Variable(ref name, _)
+                if name == "to_string" && self.lookup(name).is_none() =>
+            {
+                self.infer_to_string(callee, args, range)
+            }
+            NodeKind::Path(ref path) => self.infer_variant(path, args, hint, range),
+            _ => {
+                let generic = match callee.kind {
+                    NodeKind::Variable(ref name, _) => self.lookup(name),
+                    _ => None,
+                };
+                let ty = match generic {
+                    Some(ty @ Type {
+                        kind: TypeKind::Generic(_, _),
+                    }) => {
+                        callee.ty = Some(ty.clone());
+                        ty
+                    }
+                    _ => self.infer(callee)?,
+                };
+                self.infer_call(ty, args, 0, hint, range, &callee.range)
+            }
+        }
+    }
+
+    // `recv.method(args)` is rewritten into `Type::method(recv, args)`, unless `method` is a
+    // field of a struct
+    // `to_string(x)` calls the `to_string` method of the type of `x` if there is one, and
+    // otherwise converts a value of a builtin type as `print` would print it
+    fn infer_to_string(
+        &mut self,
+        callee: &mut Node,
+        args: &mut Vec<Node>,
+        range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        if args.len() != 1 {
+            return Err(TypeError::new(
+                format!("`to_string` expects 1 argument, found {}", args.len()),
+                range.clone(),
+            ));
+        }
+        let ty = self.infer(&mut args[0])?;
+        if let Some((name, method_ty)) = self.resolve_method(&ty, "to_string") {
+            let callee_range = callee.range.clone();
+            callee.kind = NodeKind::Variable(name, None);
+            callee.ty = Some(method_ty.clone());
+            let ret = self.infer_call(method_ty, args, 1, None, range, &callee_range)?;
+            if ret.kind != TypeKind::String {
+                return Err(TypeError::new(
+                    format!("method `to_string` of `{}` returns `{}`, not `string`", ty, ret),
+                    range.clone(),
+                ));
+            }
+            return Ok(ret);
+        }
+        match ty.kind {
+            TypeKind::Bool
+            | TypeKind::Int(_)
+            | TypeKind::Float(_)
+            | TypeKind::String
+            | TypeKind::Map(_, _) => Ok(Type::new_string()),
+            _ => Err(TypeError::new(
+                format!("`to_string` cannot convert a value of type `{}`", ty),
+                args[0].range.clone(),
+            )),
+        }
+    }
+
+    fn infer_method_call(
+        &mut self,
+        callee: &mut Node,
+        args: &mut Vec<Node>,
+        hint: Option<&Type>,
+        range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        let callee_range = callee.range.clone();
+        let (recv_ty, method) = match callee.kind {
+            NodeKind::Field(ref mut recv, ref method) => (self.infer(&mut *recv)?, method.clone()),
+            _ => unreachable!(),
+        };
+        check_unwrapped(&recv_ty, &format!("call method `{}` on", method), &callee_range)?;
+        let field = self.struct_fields(&recv_ty)
+            .and_then(|fields| fields.into_iter().find(|(f, _)| f == &method));
+        if let Some((_, ty)) = field {
+            callee.ty = Some(ty.clone());
+            return self.infer_call(ty, args, 0, hint, range, &callee_range);
+        }
+        let (name, ty) = self.resolve_method(&recv_ty, &method).ok_or_else(|| {
+            TypeError::new(
+                format!("no method named `{}` found for type `{}`", method, recv_ty),
+                callee_range.clone(),
+            )
+        })?;
+        let recv = match mem::replace(&mut callee.kind, NodeKind::Variable(name, None)) {
+            NodeKind::Field(recv, _) => *recv,
+            _ => unreachable!(),
+        };
+        callee.ty = Some(ty.clone());
+        args.insert(0, recv);
+        self.infer_call(ty, args, 1, hint, range, &callee_range)
+    }
+
+    // Checks a call to a function of type `ty`, of which the first `inferred` arguments have
+    // already been inferred. The methods of the traits bounding its type parameters, if
+    // any, are appended to the arguments.
+    fn infer_call(
+        &mut self,
+        ty: Type,
+        args: &mut Vec<Node>,
+        inferred: usize,
+        hint: Option<&Type>,
+        range: &Range<usize>,
+        callee_range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        let (type_params, ty) = match ty.kind {
+            TypeKind::Generic(type_params, ty) => (type_params, *ty),
+            kind => (vec![], Type { kind }),
+        };
+        let (params, ret) = match ty.kind {
+            TypeKind::Func(params, ret) => (params, ret),
+            _ => {
+                return Err(TypeError::new(
+                    format!("expected function, found `{}`", ty),
+                    callee_range.clone(),
+                ))
+            }
+        };
+        if params.len() != args.len() {
+            return Err(TypeError::new(
+                format!(
+                    "{} expects {} argument(s), found {}",
+                    if inferred > 0 { "method" } else { "function" },
+                    params.len() - inferred,
+                    args.len() - inferred
+                ),
+                range.clone(),
+            ));
+        }
+        let names: Vec<String> = type_params.iter().map(|param| param.name.clone()).collect();
+        let mut subst = HashMap::new();
+        if let Some(hint) = hint {
+            bind_hint(&ret, hint, &names, &mut subst);
+        }
+        for (i, (arg, param)) in args.iter_mut().zip(params.iter()).enumerate() {
+            if i >= inferred {
+                self.check_arg(arg, param, &names, &mut subst)?;
+                continue;
+            }
+            let ty = arg.ty.clone().unwrap();
+            if !match_type(param, &ty, &names, &mut subst) {
+                return Err(TypeError::new(
+                    format!(
+                        "mismatched types: expected `{}`, found `{}`",
+                        substitute(param, &subst),
+                        ty
+                    ),
+                    arg.range.clone(),
+                ));
+            }
+        }
+        let ret = self.instantiate(&ret, &names, &subst, range)?;
+        for param in &type_params {
+            if param.bounds.is_empty() {
+                continue;
+            }
+            let ty = self.instantiate(&Type::new_param(param.name.clone()), &names, &subst, range)?;
+            for bound in &param.bounds {
+                for (method, sig) in &self.traits[bound] {
+                    args.push(self.bound_method(&ty, bound, method, sig, range)?);
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    // Finds the method named `method` of a value of type `ty`
+    fn resolve_method(&self, ty: &Type, method: &str) -> Option<(String, Type)> {
+        if let TypeKind::Param(ref name) = ty.kind {
+            let param = self.bounds.iter().rev().find(|param| &param.name == name)?;
+            return param.bounds.iter().filter_map(|bound| {
+                self.traits[bound].iter().find(|&(m, _)| m == method).map(|(_, sig)| {
+                    (
+                        bound_method_name(name, bound, method),
+                        substitute(sig, &self_subst(ty)),
+                    )
+                })
+            }).next();
+        }
+        let name = format!("{}::{}", type_key(ty)?, method);
+        self.lookup(&name).map(|ty| (name, ty))
+    }
+
+    // The implementation of `bound::method` for `ty`, to be passed to a generic function
+    fn bound_method(
+        &self,
+        ty: &Type,
+        bound: &str,
+        method: &str,
+        sig: &Type,
+        range: &Range<usize>,
+    ) -> Result<Node, TypeError> {
+        let implemented = match ty.kind {
+            TypeKind::Param(ref name) => self.bounds
+                .iter()
+                .rev()
+                .find(|param| &param.name == name)
+                .is_some_and(|param| param.bounds.iter().any(|b| b == bound)),
+            _ => type_key(ty)
+                .and_then(|key| self.impls.get(&(bound.to_string(), key)))
+                .is_some_and(|(params, impl_ty)| {
+                    match_type(impl_ty, ty, params, &mut HashMap::new())
+                }),
+        };
+        if !implemented {
+            return Err(TypeError::new(
+                format!("the trait `{}` is not implemented for `{}`", bound, ty),
+                range.clone(),
+            ));
+        }
+        let name = match ty.kind {
+            TypeKind::Param(ref name) => bound_method_name(name, bound, method),
+            _ => format!("{}::{}", type_key(ty).unwrap(), method),
+        };
+        let mut node = Node::new(NodeKind::Variable(name, None), range.clone());
+        node.ty = Some(substitute(sig, &self_subst(ty)));
+        Ok(node)
+    }
+
+    fn infer_map_builtin(
+        &mut self,
+        name: &str,
+        args: &mut [Node],
+        range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        let arity = match name {
+            "insert" => 3,
+            "remove" | "contains" => 2,
+            _ => 1, // len
+        };
+        if args.len() != arity {
+            return Err(TypeError::new(
+                format!("`{}` expects {} argument(s), found {}", name, arity, args.len()),
+                range.clone(),
+            ));
+        }
+        let (key_ty, value_ty) = self.infer_map(&mut args[0])?;
+        if arity > 1 {
+            self.expect(&mut args[1], &key_ty)?;
+        }
+        if arity > 2 {
+            self.expect(&mut args[2], &value_ty)?;
+        }
+        Ok(match name {
+            "contains" => Type::new_bool(),
+            "len" => Type::new_int(),
+            _ => Type::new_unit(),
+        })
+    }
+
+    // Returns the key and value types of the map `node` evaluates to
+    fn infer_map(&mut self, node: &mut Node) -> Result<(Type, Type), TypeError> {
+        let ty = self.infer(node)?;
+        match ty.kind {
+            TypeKind::Map(key, value) => Ok((*key, *value)),
+            _ => Err(TypeError::new(
+                format!("mismatched types: expected a map, found `{}`", ty),
+                node.range.clone(),
+            )),
+        }
+    }
+
+    fn infer_map_literal(
+        &mut self,
+        pairs: &mut [(Node, Node)],
+        range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        if pairs.is_empty() {
+            return Err(TypeError::new(
+                "cannot infer the type of an empty map; add a type annotation".to_string(),
+                range.clone(),
+            ));
+        }
+        let key_ty = self.infer(&mut pairs[0].0)?;
+        let value_ty = self.infer(&mut pairs[0].1)?;
+        self.check_map_key(&key_ty, &pairs[0].0.range)?;
+        for &mut (ref mut key, ref mut value) in &mut pairs[1..] {
+            self.expect(key, &key_ty)?;
+            self.expect(value, &value_ty)?;
+        }
+        Ok(Type::new_map(key_ty, value_ty))
+    }
+
+    fn check_map_key(&self, ty: &Type, range: &Range<usize>) -> Result<(), TypeError> {
+        match ty.kind {
+            TypeKind::Int(IntTy::I64) | TypeKind::String => Ok(()),
+            _ => Err(TypeError::new(
+                format!("`{}` cannot be used as a map key", ty),
+                range.clone(),
+            )),
+        }
+    }
+
+    fn infer_variant(
+        &mut self,
+        path: &[String],
+        args: &mut [Node],
+        hint: Option<&Type>,
+        range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        let fields = self.lookup_variant(path, range)?.1.fields.clone();
+        if fields.len() != args.len() {
+            return Err(TypeError::new(
+                format!(
+                    "variant `{}` expects {} argument(s), found {}",
+                    path.join("::"),
+                    fields.len(),
+                    args.len()
+                ),
+                range.clone(),
+            ));
+        }
+        let type_params = self.type_params[&path[0]].clone();
+        let ty = Type::new_named(
+            path[0].clone(),
+            type_params.iter().cloned().map(Type::new_param).collect(),
+        );
+        let mut subst = HashMap::new();
+        if let Some(hint) = hint {
+            bind_hint(&ty, hint, &type_params, &mut subst);
+        }
+        for (arg, field) in args.iter_mut().zip(fields.iter()) {
+            self.check_arg(arg, field, &type_params, &mut subst)?;
+        }
+        self.instantiate(&ty, &type_params, &subst, range)
+    }
+
+    fn infer_struct_lit(
+        &mut self,
+        name: &str,
+        fields: &mut [(String, Node)],
+        hint: Option<&Type>,
+        range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        let decl = self.structs.get(name).cloned().ok_or_else(|| {
+            TypeError::new(format!("cannot find struct `{}`", name), range.clone())
+        })?;
+        let type_params = self.type_params[name].clone();
+        let ty = Type::new_named(
+            name.to_string(),
+            type_params.iter().cloned().map(Type::new_param).collect(),
+        );
+        let mut subst = HashMap::new();
+        if let Some(hint) = hint {
+            bind_hint(&ty, hint, &type_params, &mut subst);
+        }
+        for i in 0..fields.len() {
+            let (prev, rest) = fields.split_at_mut(i);
+            let (ref field, ref mut value) = rest[0];
+            if prev.iter().any(|(f, _)| f == field) {
+                return Err(TypeError::new(
+                    format!("field `{}` specified more than once", field),
+                    value.range.clone(),
+                ));
+            }
+            let field_ty = match decl.iter().find(|&(f, _)| f == field) {
+                Some((_, ty)) => ty,
+                None => {
+                    return Err(TypeError::new(
+                        format!("no field `{}` in struct `{}`", field, name),
+                        value.range.clone(),
+                    ))
+                }
+            };
+            self.check_arg(value, field_ty, &type_params, &mut subst)?;
+        }
+        if let Some((field, _)) = decl
+            .iter()
+            .find(|&(field, _)| fields.iter().all(|(f, _)| f != field))
+        {
+            return Err(TypeError::new(
+                format!("missing field `{}` in initializer of `{}`", field, name),
+                range.clone(),
+            ));
+        }
+        self.instantiate(&ty, &type_params, &subst, range)
+    }
+
+    // Checks an argument of a call to a generic function (or a field of a generic variant or
+    // struct) against the declared `param` type. `subst` is extended with the type parameters
+    // bound by the argument.
+    fn check_arg(
+        &mut self,
+        arg: &mut Node,
+        param: &Type,
+        type_params: &[String],
+        subst: &mut HashMap<String, Type>,
+    ) -> Result<(), TypeError> {
+        let mut used = vec![];
+        collect_params(param, &mut used);
+        if used.iter().all(|p| !type_params.contains(p) || subst.contains_key(p)) {
+            return self.expect(arg, &substitute(param, subst));
+        }
+        let ty = self.infer(arg)?;
+        if !match_type(param, &ty, type_params, subst) {
+            return Err(TypeError::new(
+                format!(
+                    "mismatched types: expected `{}`, found `{}`",
+                    substitute(param, subst),
+                    ty
+                ),
+                arg.range.clone(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Substitutes the type parameters bound by the arguments in `ty`, the type of a call
+    fn instantiate(
+        &self,
+        ty: &Type,
+        type_params: &[String],
+        subst: &HashMap<String, Type>,
+        range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        let mut used = vec![];
+        collect_params(ty, &mut used);
+        if let Some(param) = used
+            .iter()
+            .find(|p| type_params.contains(p) && !subst.contains_key(*p))
+        {
+            return Err(TypeError::new(
+                format!(
+                    "cannot infer type parameter `{}`; add a type annotation",
+                    param
+                ),
+                range.clone(),
+            ));
+        }
+        Ok(substitute(ty, subst))
+    }
+
+    fn infer_variable(
+        &self,
+        name: &str,
+        hint: Option<&Type>,
+        range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        match self.lookup(name) {
+            // A generic function used as a value takes the type it is expected to have
+            Some(Type {
+                kind: TypeKind::Generic(type_params, ty),
+            }) => {
+                if type_params.iter().any(|param| !param.bounds.is_empty()) {
+                    return Err(TypeError::new(
+                        format!("generic function `{}` with trait bounds must be called", name),
+                        range.clone(),
+                    ));
+                }
+                let names: Vec<String> = type_params.into_iter().map(|param| param.name).collect();
+                let mut subst = HashMap::new();
+                match hint {
+                    Some(hint) if match_type(&ty, hint, &names, &mut subst) => {
+                        self.instantiate(&ty, &names, &subst, range)
+                    }
+                    _ => Err(TypeError::new(
+                        format!(
+                            "cannot infer the type parameters of `{}`; call it or add a type \
+                             annotation",
+                            name
+                        ),
+                        range.clone(),
+                    )),
+                }
+            }
+            Some(ty) => Ok(ty),
+            None if self.inferring.iter().any(|f| f == name) => Err(TypeError::new(
+                format!(
+                    "cannot infer the return type of recursive function `{}`; add one",
+                    name
+                ),
+                range.clone(),
+            )),
+            None => Err(TypeError::new(
+                format!("undefined variable `{}`", name),
+                range.clone(),
+            )),
+        }
+    }
+
+    // The fields of the struct type `ty`, with its type arguments substituted
+    fn struct_fields(&self, ty: &Type) -> Option<Vec<(String, Type)>> {
+        match ty.kind {
+            TypeKind::Named(ref name, ref args) => self.structs.get(name).map(|fields| {
+                let subst = self.type_args(name, args);
+                fields
+                    .iter()
+                    .map(|(field, ty)| (field.clone(), substitute(ty, &subst)))
+                    .collect()
+            }),
+            TypeKind::Tuple(ref elems) => Some(
+                elems
+                    .iter()
+                    .enumerate()
+                    .map(|(i, elem)| (i.to_string(), elem.clone()))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    // The fields of the `tag`th variant of the enum type `ty`, with its type arguments
+    // substituted
+    fn variant_fields(&self, ty: &Type, tag: usize) -> Vec<Type> {
+        match ty.kind {
+            TypeKind::Named(ref name, ref args) => {
+                let subst = self.type_args(name, args);
+                self.enums[name][tag]
+                    .fields
+                    .iter()
+                    .map(|field| substitute(field, &subst))
+                    .collect()
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn type_args(&self, name: &str, args: &[Type]) -> HashMap<String, Type> {
+        self.type_params[name]
+            .iter()
+            .cloned()
+            .zip(args.iter().cloned())
+            .collect()
+    }
+
+    fn infer_match(
+        &mut self,
+        cond: &mut Node,
+        arms: &mut [MatchArm],
+        hint: Option<&Type>,
+        range: &Range<usize>,
+    ) -> Result<Type, TypeError> {
+        let cond_ty = self.infer(cond)?;
+        let mut result_ty: Option<Type> = None;
+        for arm in arms.iter_mut() {
+            self.scopes.push(HashMap::new());
+            self.check_pattern(&mut arm.pattern, &cond_ty)?;
+            if let Some(ref mut guard) = arm.guard {
+                self.expect(guard, &Type::new_bool())?;
+            }
+            let ty = self.infer_with_hint(&mut arm.body, hint.or(result_ty.as_ref()))?;
+            self.scopes.pop();
+            match result_ty {
+                _ if ty.kind == TypeKind::Never => {}
+                Some(ref result_ty) if result_ty != &ty => {
+                    return Err(TypeError::new(
+                        format!(
+                            "match arms have incompatible types: expected `{}`, found `{}`",
+                            result_ty, ty
+                        ),
+                        arm.body.range.clone(),
+                    ))
+                }
+                Some(_) => {}
+                None => result_ty = Some(ty),
+            }
+        }
+        self.check_exhaustive(&cond_ty, arms, range)?;
+        Ok(result_ty.unwrap_or_else(Type::new_unit))
+    }
+
+    fn check_pattern(&mut self, pat: &mut Pattern, ty: &Type) -> Result<(), TypeError> {
+        pat.ty = Some(ty.clone());
+        let range = pat.range.clone();
+        let mismatch = |found: &str| {
+            Err(TypeError::new(
+                format!("mismatched types: expected `{}`, found `{}`", ty, found),
+                range.clone(),
+            ))
+        };
+        match pat.kind {
+            PatternKind::Wildcard => Ok(()),
+            PatternKind::Variable(ref name) => {
+                if self.scopes.last().unwrap().contains_key(name) {
+                    return Err(TypeError::new(
+                        format!("identifier `{}` is bound more than once in a pattern", name),
+                        pat.range.clone(),
+                    ));
+                }
+                self.declare(name.clone(), ty.clone());
+                Ok(())
+            }
+            PatternKind::Bool(_) if ty.kind != TypeKind::Bool => mismatch("bool"),
+            PatternKind::Int(_) if !ty.is_int() => mismatch("int"),
+            PatternKind::Int(n) => match ty.kind {
+                TypeKind::Int(ity) if !ity.contains(n) => Err(TypeError::new(
+                    format!("literal `{}` does not fit in `{}`", n, ty),
+                    pat.range.clone(),
+                )),
+                _ => Ok(()),
+            },
+            PatternKind::String(_) if ty.kind != TypeKind::String => mismatch("string"),
+            PatternKind::Bool(_) | PatternKind::String(_) => Ok(()),
+            PatternKind::Variant(ref path, ref mut subpats) => {
+                let tag = self.lookup_variant(path, &pat.range)?.0;
+                match ty.kind {
+                    TypeKind::Named(ref name, _) if name == &path[0] => {}
+                    _ => return mismatch(&path[0]),
+                }
+                let fields = self.variant_fields(ty, tag);
+                if fields.len() != subpats.len() {
+                    return Err(TypeError::new(
+                        format!(
+                            "variant `{}` has {} field(s), but the pattern has {}",
+                            path.join("::"),
+                            fields.len(),
+                            subpats.len()
+                        ),
+                        pat.range.clone(),
+                    ));
+                }
+                for (subpat, field) in subpats.iter_mut().zip(fields.iter()) {
+                    self.check_pattern(subpat, field)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn lookup_variant(
+        &self,
+        path: &[String],
+        range: &Range<usize>,
+    ) -> Result<(usize, &EnumVariant), TypeError> {
+        if path.len() != 2 {
+            return Err(TypeError::new(
+                format!("cannot find `{}`", path.join("::")),
+                range.clone(),
+            ));
+        }
+        let variants = self.enums.get(&path[0]).ok_or_else(|| {
+            TypeError::new(format!("cannot find enum `{}`", path[0]), range.clone())
+        })?;
+        variants
+            .iter()
+            .enumerate()
+            .find(|&(_, variant)| variant.name == path[1])
+            .ok_or_else(|| {
+                TypeError::new(
+                    format!("no variant `{}` in enum `{}`", path[1], path[0]),
+                    range.clone(),
+                )
+            })
+    }
+
+    fn check_type_exists(&self, ty: &Type, range: &Range<usize>) -> Result<(), TypeError> {
+        match ty.kind {
+            TypeKind::Named(ref name, ref args) => {
+                let params = self.type_params.get(name).ok_or_else(|| {
+                    TypeError::new(format!("cannot find type `{}`", name), range.clone())
+                })?;
+                if params.len() != args.len() {
+                    return Err(TypeError::new(
+                        format!(
+                            "type `{}` expects {} type argument(s), found {}",
+                            name,
+                            params.len(),
+                            args.len()
+                        ),
+                        range.clone(),
+                    ));
+                }
+                for arg in args {
+                    self.check_type_exists(arg, range)?;
+                }
+                Ok(())
+            }
+            TypeKind::Map(ref key, ref value) => {
+                self.check_type_exists(key, range)?;
+                self.check_map_key(key, range)?;
+                self.check_type_exists(value, range)
+            }
+            TypeKind::Func(ref params, ref ret) => {
+                for param in params {
+                    self.check_type_exists(param, range)?;
+                }
+                self.check_type_exists(ret, range)
+            }
+            TypeKind::Optional(ref inner) => self.check_type_exists(inner, range),
+            TypeKind::Tuple(ref elems) => {
+                for elem in elems {
+                    self.check_type_exists(elem, range)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<Type> {
+        self.scopes
+            .iter()
+            .rev()
+            .filter_map(|scope| scope.get(name))
+            .next()
+            .cloned()
+    }
+
+    fn declare(&mut self, name: String, ty: Type) {
+        self.scopes.last_mut().unwrap().insert(name, ty);
+    }
+}
+
+// Whether the type of `node` is that of a numeric literal, which depends on its context
+fn is_literal(node: &Node) -> bool {
+    match node.kind {
+        NodeKind::Int(_) | NodeKind::Float(_) => true,
+        NodeKind::Block(ref nodes) => nodes.last().is_some_and(is_literal),
+        NodeKind::If(_, ref then_, ref else_) => is_literal(then_) && is_literal(else_),
+        _ => false,
+    }
+}
+
+// Replaces the type parameters in `ty` bound in `subst`
+fn substitute(ty: &Type, subst: &HashMap<String, Type>) -> Type {
+    match ty.kind {
+        TypeKind::Param(ref name) => subst.get(name).cloned().unwrap_or_else(|| ty.clone()),
+        TypeKind::Map(ref key, ref value) => {
+            Type::new_map(substitute(key, subst), substitute(value, subst))
+        }
+        TypeKind::Optional(ref inner) => Type::new_optional(substitute(inner, subst)),
+        TypeKind::Tuple(ref elems) => {
+            Type::new_tuple(elems.iter().map(|elem| substitute(elem, subst)).collect())
+        }
+        TypeKind::Func(ref params, ref ret) => Type::new_func(
+            params.iter().map(|param| substitute(param, subst)).collect(),
+            substitute(ret, subst),
+        ),
+        TypeKind::Named(ref name, ref args) => Type::new_named(
+            name.clone(),
+            args.iter().map(|arg| substitute(arg, subst)).collect(),
+        ),
+        _ => ty.clone(),
+    }
+}
+
+// Whether `ty` is `pattern` with the type parameters `params` replaced by some types, which
+// are recorded in `subst`. Other type parameters only match themselves.
+fn match_type(
+    pattern: &Type,
+    ty: &Type,
+    params: &[String],
+    subst: &mut HashMap<String, Type>,
+) -> bool {
+    match (&pattern.kind, &ty.kind) {
+        (TypeKind::Param(name), _) if params.contains(name) => {
+            if let Some(bound) = subst.get(name) {
+                return bound == ty;
+            }
+            subst.insert(name.clone(), ty.clone());
+            true
+        }
+        (TypeKind::Map(k1, v1), TypeKind::Map(k2, v2)) => {
+            match_type(k1, k2, params, subst) && match_type(v1, v2, params, subst)
+        }
+        (TypeKind::Optional(t1), TypeKind::Optional(t2)) => {
+            match_type(t1, t2, params, subst)
+        }
+        (TypeKind::Tuple(e1), TypeKind::Tuple(e2)) => {
+            e1.len() == e2.len()
+                && e1.iter().zip(e2).all(|(t1, t2)| match_type(t1, t2, params, subst))
+        }
+        (TypeKind::Func(ps1, r1), TypeKind::Func(ps2, r2)) => {
+            ps1.len() == ps2.len()
+                && ps1.iter().zip(ps2).all(|(p1, p2)| match_type(p1, p2, params, subst))
+                && match_type(r1, r2, params, subst)
+        }
+        (TypeKind::Named(n1, a1), TypeKind::Named(n2, a2)) => {
+            n1 == n2 && a1.len() == a2.len()
+                && a1.iter().zip(a2).all(|(t1, t2)| match_type(t1, t2, params, subst))
+        }
+        _ => pattern == ty,
+    }
+}
+
+// Binds the type parameters in `ty` by matching it against the expected type, if it fits
+fn bind_hint(ty: &Type, hint: &Type, params: &[String], subst: &mut HashMap<String, Type>) {
+    let mut bound = subst.clone();
+    if match_type(ty, hint, params, &mut bound) {
+        *subst = bound;
+    }
+}
+
+fn collect_params(ty: &Type, params: &mut Vec<String>) {
+    match ty.kind {
+        TypeKind::Param(ref name) => params.push(name.clone()),
+        TypeKind::Map(ref key, ref value) => {
+            collect_params(key, params);
+            collect_params(value, params);
+        }
+        TypeKind::Optional(ref inner) => collect_params(inner, params),
+        TypeKind::Func(ref ps, ref ret) => {
+            for p in ps {
+                collect_params(p, params);
+            }
+            collect_params(ret, params);
+        }
+        TypeKind::Named(_, ref args) | TypeKind::Tuple(ref args) => for arg in args {
+            collect_params(arg, params);
+        },
+        _ => {}
+    }
+}
+
+// The type of an if (or if let) expression, whose branches may be `T` and `T?`
+fn unify_branches(
+    then_: &mut Node,
+    then_ty: Type,
+    else_: &mut Node,
+    else_ty: Type,
+    what: &str,
+) -> Result<Type, TypeError> {
+    // A branch that throws takes the type of the other one
+    if then_ty.kind == TypeKind::Never {
+        return Ok(else_ty);
+    }
+    if else_ty.kind == TypeKind::Never {
+        return Ok(then_ty);
+    }
+    // Without `else` (or with a unit one), the value of `then` is discarded
+    if else_ty.kind == TypeKind::Unit {
+        return Ok(else_ty);
+    }
+    if then_ty == else_ty || coerce(else_, &else_ty, &then_ty) {
+        return Ok(then_ty);
+    }
+    if coerce(then_, &then_ty, &else_ty) {
+        return Ok(else_ty);
+    }
+    Err(TypeError::new(
+        format!(
+            "{} have incompatible types: expected `{}`, found `{}`",
+            what, then_ty, else_ty
+        ),
+        else_.range.clone(),
+    ))
+}
+
+// Wraps `node`, of type `ty`, into an optional if `expected` is `ty?`
+fn coerce(node: &mut Node, ty: &Type, expected: &Type) -> bool {
+    match expected.kind {
+        TypeKind::Optional(ref inner) if **inner == *ty => {
+            let range = node.range.clone();
+            let value = mem::replace(node, Node::new(NodeKind::None, range.clone()));
+            *node = Node::new(NodeKind::Wrap(Box::new(value)), range);
+            node.ty = Some(expected.clone());
+            true
+        }
+        _ => false,
+    }
+}
+
+fn unwrap_optional(ty: &Type) -> &Type {
+    match ty.kind {
+        TypeKind::Optional(ref inner) => inner,
+        _ => ty,
+    }
+}
+
+// Optionals have to be unwrapped with `?.`, `??` or `if let` before most uses
+fn check_unwrapped(ty: &Type, what: &str, range: &Range<usize>) -> Result<(), TypeError> {
+    match ty.kind {
+        TypeKind::Optional(_) => Err(TypeError::new(
+            format!(
+                "cannot {} a value of optional type `{}`; unwrap it with `?.`, `??` or `if let`",
+                what, ty
+            ),
+            range.clone(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn self_subst(ty: &Type) -> HashMap<String, Type> {
+    let mut subst = HashMap::new();
+    subst.insert("Self".to_string(), ty.clone());
+    subst
+}
+
+// The name methods of `ty` are declared under, e.g. `Point` for `Point::norm`
+fn type_key(ty: &Type) -> Option<String> {
+    match ty.kind {
+        TypeKind::Named(ref name, _) => Some(name.clone()),
+        TypeKind::Bool
+        | TypeKind::Int(_)
+        | TypeKind::Float(_)
+        | TypeKind::String
+        | TypeKind::Unit => Some(ty.to_string()),
+        _ => None,
+    }
+}
+
+// The hidden parameter of a generic function holding `bound::method` for the type `param`
+fn bound_method_name(param: &str, bound: &str, method: &str) -> String {
+    format!("<{} as {}>::{}", param, bound, method)
+}
+
+pub fn is_map_builtin(name: &str) -> bool {
+    matches!(name, "insert" | "remove" | "contains" | "len")
+}
+
+// Exhaustiveness checking, based on the usefulness algorithm described in
+// "Warnings for pattern matching" (Luc Maranget, 2007).
+
+#[derive(Clone, Debug, PartialEq)]
+enum Pat {
+    Wild,
+    Bool(bool),
+    Int(i64),
+    String(String),
+    Variant(usize, Vec<Pat>),
+}
+
+impl TypeChecker {
+    fn check_exhaustive(
+        &self,
+        ty: &Type,
+        arms: &[MatchArm],
+        range: &Range<usize>,
+    ) -> Result<(), TypeError> {
+        // Guarded arms may fail, so they never make a match exhaustive
+        let rows: Vec<Vec<Pat>> = arms.iter()
+            .filter(|arm| arm.guard.is_none())
+            .map(|arm| vec![self.lower_pattern(&arm.pattern)])
+            .collect();
+        match self.is_useful(&rows, &[Pat::Wild], slice::from_ref(ty)) {
+            Some(witness) => Err(TypeError::new(
+                format!(
+                    "non-exhaustive patterns: `{}` not covered",
+                    self.show_pat(&witness[0], ty)
+                ),
+                range.clone(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn lower_pattern(&self, pat: &Pattern) -> Pat {
+        match pat.kind {
+            PatternKind::Wildcard | PatternKind::Variable(_) => Pat::Wild,
+            PatternKind::Bool(b) => Pat::Bool(b),
+            PatternKind::Int(n) => Pat::Int(n),
+            PatternKind::String(ref s) => Pat::String(s.clone()),
+            PatternKind::Variant(ref path, ref subpats) => {
+                let tag = self.lookup_variant(path, &pat.range).unwrap().0;
+                Pat::Variant(tag, subpats.iter().map(|p| self.lower_pattern(p)).collect())
+            }
+        }
+    }
+
+    fn variants_of(&self, ty: &Type) -> Option<&Vec<EnumVariant>> {
+        match ty.kind {
+            TypeKind::Named(ref name, _) => self.enums.get(name),
+            _ => None,
+        }
+    }
+
+    // Returns the values matched by `v` but by none of `rows`, if there are any
+    fn is_useful(&self, rows: &[Vec<Pat>], v: &[Pat], tys: &[Type]) -> Option<Vec<Pat>> {
+        if v.is_empty() {
+            return if rows.is_empty() { Some(vec![]) } else { None };
+        }
+        let variants = self.variants_of(&tys[0]);
+        match v[0] {
+            Pat::Variant(tag, ref args) => {
+                let fields = self.variant_fields(&tys[0], tag);
+                let mut v_ = args.clone();
+                v_.extend_from_slice(&v[1..]);
+                self.is_useful_variant(rows, &v_, tys, tag, &fields)
+            }
+            Pat::Bool(_) | Pat::Int(_) | Pat::String(_) => {
+                let rows: Vec<Vec<Pat>> = rows.iter()
+                    .filter(|row| row[0] == v[0] || row[0] == Pat::Wild)
+                    .map(|row| row[1..].to_vec())
+                    .collect();
+                self.is_useful(&rows, &v[1..], &tys[1..]).map(|mut witness| {
+                    witness.insert(0, v[0].clone());
+                    witness
+                })
+            }
+            Pat::Wild if tys[0].kind == TypeKind::Bool => {
+                // `bool` is the enum {false, true} as far as exhaustiveness is concerned
+                let bools = [Pat::Bool(false), Pat::Bool(true)];
+                if bools.iter().all(|b| rows.iter().any(|row| &row[0] == b)) {
+                    for b in &bools {
+                        let mut v_ = vec![b.clone()];
+                        v_.extend_from_slice(&v[1..]);
+                        let witness = self.is_useful(rows, &v_, tys);
+                        if witness.is_some() {
+                            return witness;
+                        }
+                    }
+                    return None;
+                }
+                let missing = bools.iter()
+                    .find(|&b| rows.iter().all(|row| &row[0] != b))
+                    .unwrap()
+                    .clone();
+                let rows: Vec<Vec<Pat>> = rows.iter()
+                    .filter(|row| row[0] == Pat::Wild)
+                    .map(|row| row[1..].to_vec())
+                    .collect();
+                self.is_useful(&rows, &v[1..], &tys[1..]).map(|mut witness| {
+                    witness.insert(0, missing);
+                    witness
+                })
+            }
+            Pat::Wild => {
+                let used: HashSet<usize> = rows.iter()
+                    .filter_map(|row| match row[0] {
+                        Pat::Variant(tag, _) => Some(tag),
+                        _ => None,
+                    })
+                    .collect();
+                if let Some(variants) = variants {
+                    if used.len() == variants.len() {
+                        // Every variant appears, so try each of them in turn
+                        for (tag, variant) in variants.iter().enumerate() {
+                            let mut v_ = vec![Pat::Wild; variant.fields.len()];
+                            v_.extend_from_slice(&v[1..]);
+                            let fields = self.variant_fields(&tys[0], tag);
+                            let witness = self.is_useful_variant(rows, &v_, tys, tag, &fields);
+                            if witness.is_some() {
+                                return witness;
+                            }
+                        }
+                        return None;
+                    }
+                }
+                let rows: Vec<Vec<Pat>> = rows.iter()
+                    .filter(|row| row[0] == Pat::Wild)
+                    .map(|row| row[1..].to_vec())
+                    .collect();
+                self.is_useful(&rows, &v[1..], &tys[1..]).map(|mut witness| {
+                    let head = match variants {
+                        Some(variants) => {
+                            let tag = (0..variants.len()).find(|t| !used.contains(t)).unwrap();
+                            Pat::Variant(tag, vec![Pat::Wild; variants[tag].fields.len()])
+                        }
+                        None => Pat::Wild,
+                    };
+                    witness.insert(0, head);
+                    witness
+                })
+            }
+        }
+    }
+
+    fn is_useful_variant(
+        &self,
+        rows: &[Vec<Pat>],
+        v: &[Pat],
+        tys: &[Type],
+        tag: usize,
+        fields: &[Type],
+    ) -> Option<Vec<Pat>> {
+        let arity = fields.len();
+        let rows: Vec<Vec<Pat>> = rows.iter()
+            .filter_map(|row| {
+                let mut row_ = match row[0] {
+                    Pat::Variant(t, ref args) if t == tag => args.clone(),
+                    Pat::Wild => vec![Pat::Wild; arity],
+                    _ => return None,
+                };
+                row_.extend_from_slice(&row[1..]);
+                Some(row_)
+            })
+            .collect();
+        let mut tys_ = fields.to_vec();
+        tys_.extend_from_slice(&tys[1..]);
+        self.is_useful(&rows, v, &tys_).map(|mut witness| {
+            let rest = witness.split_off(arity);
+            let mut result = vec![Pat::Variant(tag, witness)];
+            result.extend(rest);
+            result
+        })
+    }
+
+    fn show_pat(&self, pat: &Pat, ty: &Type) -> String {
+        match *pat {
+            Pat::Wild => "_".to_string(),
+            Pat::Bool(b) => b.to_string(),
+            Pat::Int(n) => n.to_string(),
+            Pat::String(ref s) => format!("\"{}\"", s),
+            Pat::Variant(tag, ref args) => {
+                let name = match ty.kind {
+                    TypeKind::Named(ref name, _) => name,
+                    _ => unreachable!(),
+                };
+                let variant = &self.enums[name][tag];
+                let mut s = format!("{}::{}", name, variant.name);
+                if !args.is_empty() {
+                    let args: Vec<String> = args.iter()
+                        .zip(self.variant_fields(ty, tag).iter())
+                        .map(|(arg, ty)| self.show_pat(arg, ty))
+                        .collect();
+                    s = format!("{}({})", s, args.join(", "));
+                }
+                s
+            }
+        }
+    }
+}
+
+// Parses and type checks `src`
+#[cfg(test)]
+fn check_source(src: &str) -> Result<(), TypeError> {
+    use lexer::Lexer;
+    use parser::Parser;
+    let mut lexer = Lexer::new_from_string(src.to_string());
+    let mut nodes = Parser::new(&mut lexer).read_all()?;
+    TypeChecker::new().check(&mut nodes)
+}
+
+#[test]
+fn test_match() {
+    use vm::run_source;
+    let decls = "enum Shape {
+                   Circle(int)
+                   Rect(int, int)
+                 }
+                 enum Opt { Some(Shape), None }
+                 o = Opt::Some(Shape::Circle(1))
+                 ";
+    assert!(
+        check_source(&format!(
+            "{}match o {{
+               Opt::Some(Shape::Circle(_)) => 1
+               Opt::Some(Shape::Rect(w, h)) => w * h
+               Opt::None => 0
+             }}\n",
+            decls
+        )).is_ok()
+    );
+    assert_eq!(
+        check_source(&format!(
+            "{}match o {{
+               Opt::Some(Shape::Circle(1)) => 1
+               Opt::Some(Shape::Rect(w, h)) if w > h => w * h
+               Opt::None => 0
+             }}\n",
+            decls
+        )).unwrap_err()
+            .msg,
+        "non-exhaustive patterns: `Opt::Some(Shape::Rect(_, _))` not covered"
+    );
+    assert_eq!(
+        check_source("match 1 { 0 => 1, 1 => 2 }\n").unwrap_err().msg,
+        "non-exhaustive patterns: `_` not covered"
+    );
+
+    let src = "enum Shape {
+                 Circle(int)
+                 Rect(int, int)
+                 Empty
+               }
+               enum Opt { Some(Shape), None }
+               shapes = Opt::Some(Shape::Rect(2, 3))
+               print(match shapes {
+                 Opt::Some(Shape::Circle(r)) => r * r * 3
+                 Opt::Some(Shape::Rect(w, h)) if w > h => 0
+                 Opt::Some(Shape::Rect(w, h)) => w * h
+                 Opt::Some(Shape::Empty) => 1
+                 Opt::None => 2
+               })
+               print(match Opt::Some(Shape::Circle(1)) {
+                 Opt::Some(Shape::Circle(1)) => \"unit circle\"
+                 Opt::Some(_) => \"some shape\"
+                 _ => \"none\"
+               })
+               print(match \"b\" { \"a\" => 1, \"b\" => 2, _ => 3 })
+               ";
+    assert_eq!(run_source(src), "6\nunit circle\n2\n");
+}
+
+#[test]
+fn test_map() {
+    use vm::run_source;
+    assert!(check_source("m = {\"a\": 1}\nx: int = m[\"a\"]\n").is_ok());
+    let src = "ages = {\"bob\": 30, \"alice\": 25,
+                       \"carol\": 41}
+               insert(ages, \"dave\", 19)
+               remove(ages, \"bob\")
+               print(ages[\"alice\"])
+               print(len(ages))
+               print(contains(ages, \"bob\"))
+               for name, age in ages {
+                 print(name)
+                 insert(ages, name, age + 1)
+               }
+               print(ages)
+               nested:Map<int, Map<int, int>> = {:}
+               insert(nested, 1, {2: 3})
+               print(nested[1][2])
+               ";
+    assert_eq!(
+        run_source(src),
+        "25\n3\nfalse\nalice\ncarol\ndave\n{alice: 26, carol: 42, dave: 20}\n3\n"
+    );
+}
+
+#[test]
+fn test_bool() {
+    use vm::run_source;
+    assert!(check_source("x = 1\nif x > 0 && true { x = 2 }\n").is_ok());
+    assert_eq!(
+        check_source("x = 1\nif x { x = 2 }\n").unwrap_err().msg,
+        "mismatched types: expected `bool`, found `int`"
+    );
+    assert_eq!(
+        check_source("while 1 {}\n").unwrap_err().msg,
+        "mismatched types: expected `bool`, found `int`"
+    );
+    assert_eq!(
+        check_source("b = 1 == true\n").unwrap_err().msg,
+        "mismatched types: `int` and `bool`"
+    );
+
+    let src = "i = 0
+               sum = 0
+               while i < 10 && !(sum > 20) {
+                 if i % 2 == 0 || i == 5 {
+                   sum = sum + i
+                 }
+                 i = i + 1
+               }
+               print(sum)
+               print(match sum > 10 { true => \"big\", false => \"small\" })
+               print(true ^ true)
+               ";
+    assert_eq!(run_source(src), "25\nbig\nfalse\n");
+}
+
+#[test]
+fn test_sized_numbers() {
+    use vm::run_source;
+    assert!(check_source("x:i32 = 1\ny:i32 = x * 2 + 1\nz:f32 = 1.5\n").is_ok());
+    assert_eq!(check_source("x:i33 = 1\n").unwrap_err().msg, "cannot find type `i33`");
+    assert_eq!(
+        check_source("x:u8 = 256\n").unwrap_err().msg,
+        "literal `256` does not fit in `u8`"
+    );
+    assert_eq!(
+        check_source("x:i32 = 1\ny = x + 1.0\n").unwrap_err().msg,
+        "mismatched types: `i32` and `float`"
+    );
+    assert_eq!(
+        check_source("x:u16 = 1\ny:u32 = x\n").unwrap_err().msg,
+        "mismatched types: expected `u32`, found `u16`"
+    );
+    assert_eq!(
+        check_source("x:u8 = 1\ny = -x\n").unwrap_err().msg,
+        "operator `Neg` cannot be applied to type `u8`"
+    );
+    assert_eq!(
+        check_source("x = \"1\" as int\n").unwrap_err().msg,
+        "cannot cast `string` as `int`"
+    );
+
+    let src = "a:u8 = 250
+               print(a + 10)
+               b:i8 = -128
+               print(b - 1)
+               print(-b)
+               print(300 as u8)
+               print(-1 as u32)
+               print(3.99 as i32)
+               print(1000.0 as i8)
+               c:u64 = 0
+               print(c - 1)
+               print((c - 1) / 2 > 1 as u64)
+               print(true as int + 1)
+               print(match a { 250 => \"max\", _ => \"other\" })
+               print(0.1 as f32)
+               ";
+    assert_eq!(
+        run_source(src),
+        "4\n127\n-128\n44\n4294967295\n3\n127\n18446744073709551615\ntrue\n2\nmax\n\
+         0.10000000149011612\n"
+    );
+}
+
+#[test]
+fn test_functions() {
+    use verify::verify;
+    use vm::{compile_source, run_source, VM};
+    assert!(check_source("def f(x: int, g: fn(int) -> bool) -> bool { g(x) }\n").is_ok());
+    assert_eq!(
+        check_source("f = fn(x: int) { x > 0 }\ny:int = f(1)\n").unwrap_err().msg,
+        "mismatched types: expected `int`, found `bool`"
+    );
+    assert_eq!(
+        check_source("f = fn(x: int) { x }\nf(1, 2)\n").unwrap_err().msg,
+        "function expects 1 argument(s), found 2"
+    );
+    assert_eq!(
+        check_source("x = 1\nx(1)\n").unwrap_err().msg,
+        "expected function, found `int`"
+    );
+    assert_eq!(
+        check_source("def f(n: int) { f(n) }\n").unwrap_err().msg,
+        "cannot infer the return type of recursive function `f`; add one"
+    );
+
+    let src = "def fib(n: int) -> int {
+                 if n < 2 { n } else { fib(n - 1) + fib(n - 2) }
+               }
+               print(fib(15))
+               def make_counter() -> fn() -> int {
+                 count = 0
+                 fn() -> int {
+                   count = count + 1
+                   count
+                 }
+               }
+               c1 = make_counter()
+               c2 = make_counter()
+               c1()
+               print(c1())
+               print(c2())
+               k = 1
+               add_k = fn(x: int) { x + k }
+               k = 10
+               print(add_k(1))
+               def twice(f: fn(int) -> int, x: int) -> int { f(f(x)) }
+               print(twice(add_k, 0))
+               def nest() -> int {
+                 a = 1
+                 fn() { fn() { a = a * 5 }() }()
+                 a
+               }
+               print(nest())
+               ";
+    assert_eq!(run_source(src), "610\n2\n1\n11\n20\n5\n");
+
+    // Functions may call those defined after them, but the top level only those before
+    let src = "def even(n: int) -> bool { if n == 0 { true } else { odd(n - 1) } }
+               def odd(n: int) -> bool { if n == 0 { false } else { even(n - 1) } }
+               print(even(10))
+               print(odd(7))";
+    assert_eq!(run_source(src), "true\ntrue\n");
+    let (insts, _) = compile_source(src, 1);
+    assert_eq!(verify(&insts, VM::new().stack_limit), Ok(()));
+    assert_eq!(
+        check_source("print(f())\ndef f() -> int { 1 }\n").unwrap_err().msg,
+        "undefined variable `f`"
+    );
+    assert_eq!(
+        check_source("def f() -> int { g() }\ndef g() { 1 }\n").unwrap_err().msg,
+        "undefined variable `g`"
+    );
+}
+
+#[test]
+fn test_generics() {
+    use vm::run_source;
+    let decls = "enum Option<T> { Some(T), None }
+                 struct Pair<A, B> { first: A, second: B }
+                 def id<T>(x: T) -> T { x }
+                 ";
+    let check_with_decls = |src: &str| check_source(&format!("{}{}", decls, src));
+    assert!(check_with_decls("x: i8 = id(1)\n").is_ok());
+    assert!(check_with_decls("f: fn(int) -> int = id\n").is_ok());
+    assert_eq!(
+        check_with_decls("x: string = id(1)\n").unwrap_err().msg,
+        "mismatched types: expected `string`, found `int`"
+    );
+    assert_eq!(
+        check_with_decls("x = Option::None\n").unwrap_err().msg,
+        "cannot infer type parameter `T`; add a type annotation"
+    );
+    assert_eq!(
+        check_with_decls("p = Pair { first: 1 }\n").unwrap_err().msg,
+        "missing field `second` in initializer of `Pair`"
+    );
+    assert_eq!(
+        check_with_decls("p = Pair { first: 1, second: 2 }\np.third\n").unwrap_err().msg,
+        "no field `third` on type `Pair<int, int>`"
+    );
+    assert_eq!(
+        check_with_decls("def f(o: Option) { }\n").unwrap_err().msg,
+        "type `Option` expects 1 type argument(s), found 0"
+    );
+    assert_eq!(
+        check_source("def f<T>(x: T) -> int { x }\n").unwrap_err().msg,
+        "mismatched types: expected `int`, found `T`"
+    );
+    assert_eq!(
+        check_with_decls("f = id\n").unwrap_err().msg,
+        "cannot infer the type parameters of `id`; call it or add a type annotation"
+    );
+    assert_eq!(
+        check_with_decls(
+            "o: Option<bool> = Option::None\n\
+             match o { Option::Some(true) => 1, Option::None => 0 }\n"
+        ).unwrap_err()
+            .msg,
+        "non-exhaustive patterns: `Option::Some(false)` not covered"
+    );
+
+    let src = "enum Option<T> { Some(T), None }
+               struct Pair<A, B> { first: A, second: B }
+               def id<T>(x: T) -> T { x }
+               def swap<A, B>(p: Pair<A, B>) -> Pair<B, A> {
+                 Pair { second: p.first, first: p.second }
+               }
+               def unwrap_or<T>(o: Option<T>, d: T) -> T {
+                 match o {
+                   Option::Some(x) => x,
+                   Option::None => d,
+                 }
+               }
+               def map<T, U>(o: Option<T>, f: fn(T) -> U) -> Option<U> {
+                 match o {
+                   Option::Some(x) => Option::Some(f(x)),
+                   Option::None => Option::None,
+                 }
+               }
+               print(id(3))
+               print(id(\"hi\"))
+               p = swap(Pair { first: 1, second: \"one\" })
+               print(p.first)
+               print(p.second + 1)
+               n: Option<int> = Option::None
+               print(unwrap_or(n, 7))
+               print(unwrap_or(map(Option::Some(20), fn(x: int) { x > 10 }), false))
+               ";
+    assert_eq!(run_source(src), "3\nhi\none\n2\n7\ntrue\n");
+}
+
+#[test]
+fn test_traits() {
+    use vm::run_source;
+    let decls = "trait Display {
+                   def to_string(self) -> string
+                 }
+                 struct Point { x: int, y: int }
+                 impl Display for Point {
+                   def to_string(self) -> string { \"point\" }
+                 }
+                 def show<T: Display>(x: T) -> string { x.to_string() }
+                 p = Point { x: 1, y: 2 }
+                 ";
+    let check_with_decls = |src: &str| check_source(&format!("{}{}", decls, src));
+    assert!(check_with_decls("s: string = show(p)\n").is_ok());
+    assert_eq!(
+        check_with_decls("show(1)\n").unwrap_err().msg,
+        "the trait `Display` is not implemented for `int`"
+    );
+    assert_eq!(
+        check_with_decls("p.norm()\n").unwrap_err().msg,
+        "no method named `norm` found for type `Point`"
+    );
+    assert_eq!(
+        check_with_decls("def f<T>(x: T) -> string { x.to_string() }\n").unwrap_err().msg,
+        "no method named `to_string` found for type `T`"
+    );
+    assert_eq!(
+        check_with_decls("impl Display for int { }\n").unwrap_err().msg,
+        "missing method `to_string` in implementation of `Display` for `int`"
+    );
+    assert_eq!(
+        check_with_decls("impl Display for int { def to_string(self) -> int { self } }\n")
+            .unwrap_err()
+            .msg,
+        "method `to_string` has an incompatible type for trait `Display`: \
+         expected `fn(int) -> string`, found `fn(int) -> int`"
+    );
+    assert_eq!(
+        check_with_decls("impl Display for Point { def to_string(self) -> string { \"\" } }\n")
+            .unwrap_err()
+            .msg,
+        "trait `Display` is already implemented for `Point`"
+    );
+
+    let src = "trait Display {
+                 def to_string(self) -> string
+               }
+               trait Area {
+                 def area(self) -> int
+                 def scaled(self, k: int) -> Self
+               }
+               struct Rect { w: int, h: int }
+               impl Rect {
+                 def new(w: int, h: int) -> Rect { Rect { w: w, h: h } }
+                 def is_square(self) -> bool { self.w == self.h }
+               }
+               impl Display for Rect {
+                 def to_string(self) -> string { \"rect\" }
+               }
+               impl Area for Rect {
+                 def area(self) -> int { self.w * self.h }
+                 def scaled(self, k: int) -> Rect { Rect { w: self.w * k, h: self.h * k } }
+               }
+               impl Display for int {
+                 def to_string(self) -> string { if self > 9 { \"many\" } else { \"few\" } }
+               }
+               def show<T: Display>(x: T) -> string { x.to_string() }
+               def total<T: Area + Display>(x: T, k: int) -> int {
+                 f = fn() { x.scaled(k).area() }
+                 f()
+               }
+               def show_again<U: Display>(x: U) -> string { show(x) }
+               r = Rect::new(2, 3)
+               print(r.is_square())
+               print(show(r))
+               print(show(12))
+               print(show_again(3))
+               print(total(r, 2))
+               ";
+    assert_eq!(run_source(src), "false\nrect\nmany\nfew\n24\n");
+}
+
+#[test]
+fn test_optionals() {
+    use vm::run_source;
+    assert!(check_source("x: i8? = 1\ny: i8 = x ?? 2\n").is_ok());
+    assert!(check_source("x: int? = none\nif let v = x { v + 1 } else { 0 }\n").is_ok());
+    assert_eq!(
+        check_source("x: int? = 1\nx + 1\n").unwrap_err().msg,
+        "cannot apply `Add` to a value of optional type `int?`; \
+         unwrap it with `?.`, `??` or `if let`"
+    );
+    assert_eq!(
+        check_source("struct P { x: int }\np: P? = none\np.x\n").unwrap_err().msg,
+        "cannot access field `x` of a value of optional type `P?`; \
+         unwrap it with `?.`, `??` or `if let`"
+    );
+    assert_eq!(
+        check_source("x: int? = 1\ny: int = x\n").unwrap_err().msg,
+        "mismatched types: expected `int`, found `int?`"
+    );
+    assert_eq!(
+        check_source("x = none\n").unwrap_err().msg,
+        "cannot infer the type of `none`; add a type annotation"
+    );
+    assert_eq!(
+        check_source("x = 1 ?? 2\n").unwrap_err().msg,
+        "`??` requires an optional, found `int`"
+    );
+
+    let src = "struct Point { x: int, y: int }
+               def find(m: Map<string, int>, k: string) -> int? {
+                 if contains(m, k) { m[k] } else { none }
+               }
+               m = {\"a\": 1, \"b\": 2}
+               print(find(m, \"a\") ?? 0)
+               print(find(m, \"z\") ?? -1)
+               if let v = find(m, \"b\") { print(v * 10) } else { print(\"missing\") }
+               p: Point? = Point { x: 3, y: 4 }
+               q: Point? = none
+               print(p?.x ?? 0)
+               print(q?.y ?? 0)
+               n: int? = 5
+               print(n == 5)
+               x: int?? = none
+               print((x ?? n) ?? 9)
+               ";
+    assert_eq!(run_source(src), "1\n-1\n20\n3\n0\ntrue\n5\n");
+}
+
+#[test]
+fn test_try() {
+    use vm::run_source;
+    assert!(check_source("x: int = try { 1 } catch e { 2 }\n").is_ok());
+    assert!(check_source("x: int = if true { throw \"no\" } else { 2 }\n").is_ok());
+    assert!(check_source("def f(n: int) -> int { throw \"no\" }\n").is_ok());
+    assert!(check_source("x: int = try { 1 } catch e { throw e }\n").is_ok());
+    assert_eq!(
+        check_source("try { 1 } catch e { \"a\" }\n").unwrap_err().msg,
+        "try and catch have incompatible types: expected `int`, found `string`"
+    );
+    assert_eq!(
+        check_source("throw 1\n").unwrap_err().msg,
+        "mismatched types: expected `string`, found `int`"
+    );
+    assert_eq!(
+        check_source("try { 1 } catch e { e + 1 }\n").unwrap_err().msg,
+        "mismatched types: `string` and `int`"
+    );
+
+    let src = "def div(a: int, b: int) -> int { a / b }
+               def check(n: int) -> int { if n < 0 { throw \"negative\" } else { n } }
+               print(try { div(7, 2) } catch e { -1 })
+               print(try { div(7, 0) } catch e { print(e)\n -1 })
+               m = {\"a\": 1}
+               try { m[\"b\"] } catch e { print(e)\n 0 }
+               x = try { 1 + try { check(-5) } catch e { throw e } } catch e { print(e)\n 0 }
+               print(x)
+               print(try { check(3) } catch e { 0 })
+               ";
+    assert_eq!(
+        run_source(src),
+        "3\nattempt to divide by zero\n-1\nkey b not found\nnegative\n0\n3\n"
+    );
+}
+
+#[test]
+fn test_tuples() {
+    use vm::run_source;
+    assert!(check_source("t: (u8, string) = (1, \"a\")\nx: u8 = t.0\n").is_ok());
+    assert!(check_source("x: u8 = 1\ny = 2\nx, y = 3, 4\n").is_ok());
+    assert!(check_source("(a, b: string) = (1, \"a\")\n").is_ok());
+    assert_eq!(
+        check_source("(a, b) = (1, 2, 3)\n").unwrap_err().msg,
+        "mismatched types: expected a tuple of 2 elements, found `(int, int, int)`"
+    );
+    assert_eq!(
+        check_source("a = \"s\"\na, b = 1, 2\n").unwrap_err().msg,
+        "mismatched types: expected `string`, found `int`"
+    );
+    assert_eq!(
+        check_source("t = (1, 2)\nt.2\n").unwrap_err().msg,
+        "no field `2` on type `(int, int)`"
+    );
+
+    let src = "def divmod(a: int, b: int) -> (int, int) { (a / b, a % b) }
+               let (q, r) = divmod(17, 5)
+               print(q * 10 + r)
+               a = 1
+               b = 2
+               a, b = b, a
+               print(a * 10 + b)
+               (x, (y, z)) = (1, (2, 3))
+               print(x + y * 10 + z * 100)
+               t = divmod(9, 4)
+               print(t.0 + t.1)
+               ";
+    assert_eq!(run_source(src), "32\n21\n321\n3\n");
+}
+
+#[test]
+fn test_compound_assign() {
+    use vm::run_source;
+    assert!(check_source("struct P { x: u8 }\np = P { x: 1 }\np.x += 200\n").is_ok());
+    assert!(check_source("m = {\"a\": 1.5}\nm[\"b\"] = 2.0\nm[\"a\"] -= 1.0\n").is_ok());
+    assert_eq!(
+        check_source("struct P { x: int }\np = P { x: 1 }\np.x = \"a\"\n").unwrap_err().msg,
+        "mismatched types: expected `int`, found `string`"
+    );
+    assert_eq!(
+        check_source("x += 1\n").unwrap_err().msg,
+        "undefined variable `x`"
+    );
+    assert_eq!(
+        check_source("f(1) += 1\n").unwrap_err().msg,
+        "invalid left-hand side of assignment"
+    );
+
+    let src = "struct P { x: int, y: u8 }
+               calls = 0
+               m = {\"a\": 1}
+               def key() -> string {
+                 calls += 1
+                 \"a\"
+               }
+               m[key()] += 10
+               print(m[\"a\"] * 10 + calls)
+               p = P { x: 1, y: 250 }
+               p.y += 10
+               ps = {0: p}
+               ps[0].x = 7
+               print(p.x * 10 + p.y as int)
+               nested = {0: {\"k\": 1}}
+               nested[0][\"k\"] <<= 4
+               print(nested[0][\"k\"])
+               ";
+    assert_eq!(run_source(src), "111\n74\n16\n");
+}
+
+#[test]
+fn test_interpolation() {
+    use vm::run_source;
+    assert!(check_source("s: string = \"${1} and ${\"a\" + \"b\"}\"\n").is_ok());
+    assert_eq!(
+        check_source("\"${y}\"\n").unwrap_err(),
+        TypeError::new("undefined variable `y`".to_string(), 3..4)
+    );
+    assert_eq!(
+        check_source("struct P { x: int }\n\"${P { x: 1 }}\"\n").unwrap_err().msg,
+        "`to_string` cannot convert a value of type `P`"
+    );
+    assert_eq!(
+        check_source("\"a\" - \"b\"\n").unwrap_err().msg,
+        "operator `Sub` cannot be applied to type `string`"
+    );
+
+    let src = "trait Display {
+                 def to_string(self) -> string
+               }
+               struct P { x: int, y: int }
+               impl Display for P {
+                 def to_string(self) -> string { \"(${self.x}, ${self.y})\" }
+               }
+               def show<T: Display>(x: T) -> string { \"<${x}>\" }
+               name = \"bob\"
+               n = 2
+               print(\"hello ${name}, you have ${n + 1} items\")
+               print(\"${1.5} ${true} ${\"[${n}]\"} ${P { x: 1, y: 2 }}\")
+               print(show(P { x: 3, y: 4 }))
+               ";
+    assert_eq!(run_source(src), "hello bob, you have 3 items\n1.5 true [2] (1, 2)\n<(3, 4)>\n");
+}
+
+#[test]
+fn test_globals() {
+    use vm::{compile_source, run_source};
+    use vm_base::VMInst;
+    assert!(
+        check_source("const N = 4\nconst M: u8 = 3\nconst S = \"n\" + \"m\"\nx = N * 2\n").is_ok()
+    );
+    assert!(check_source("const N = 4\ndef f(N: int) -> int { N += 1\nN }\n").is_ok());
+    assert_eq!(
+        check_source("const N = 4\nN = 5\n").unwrap_err().msg,
+        "cannot assign to constant `N`"
+    );
+    assert_eq!(
+        check_source("const N = 4\ndef f() { N -= 1 }\n").unwrap_err().msg,
+        "cannot assign to constant `N`"
+    );
+    assert_eq!(
+        check_source("x = 1\nconst N = x + 1\n").unwrap_err().msg,
+        "the value of constant `N` must be a constant expression"
+    );
+    assert_eq!(
+        check_source("const M = {1: 2}\n").unwrap_err().msg,
+        "constants must be of a primitive type, found `Map<int, int>`"
+    );
+    assert_eq!(
+        check_source("const N = 1\nconst N = 2\n").unwrap_err().msg,
+        "`N` is already declared"
+    );
+
+    let src = "const STEP = 2
+               const LIMIT: u8 = (STEP * 3 + 1) as u8
+               count = 0
+               def bump() -> int {
+                 count += STEP
+                 count
+               }
+               def reset() { count = 0 }
+               while LIMIT as int > bump() { }
+               print(count)
+               reset()
+               print(bump())
+               { count = 10 }
+               print(count)";
+    assert!(compile_source(src, 1).0.contains(&VMInst::LoadG(0)));
+    assert_eq!(run_source(src), "8\n2\n10\n");
 }
@@ -0,0 +1,773 @@
+use vm::MAX_GLOBALS;
+use vm_base::VMInst;
+
+use std::collections::{HashMap, HashSet};
+
+// Checks that bytecode, such as a program loaded from a .xsc file, cannot crash the VM
+// before running it:
+//
+// - every function starts with Entry, and its code is reached from no other function,
+// - jumps stay in the code, and only the code at the top level runs to its end (which ends
+//   the program), and no code runs into a function,
+// - at the top level, which starts at 0, Entry makes a frame and Ret removes it (and goes
+//   on to the next instruction, as there is no call to return to),
+// - the stack has as many values on every path to an instruction, and never fewer than
+//   an instruction pops (the locals of a call are not values to pop),
+// - every Try is ended by EndTry before its function returns,
+// - locals and upvalues exist where they are used, and globals are stored on every path
+//   to where they are loaded, and
+// - the values an instruction uses have the types it needs, where they are known.
+//
+// Types are known for the values made by instructions, such as constants, the results of
+// arithmetic, maps and variants (with their tags and numbers of fields), and for the locals
+// set by Entry. They are unknown for arguments, fields, upvalues, the results of calls and
+// anything that differs between the paths to an instruction. The VM checks the values of
+// unknown types itself, throwing an error rather than crashing when one does not fit.
+//
+// A function may load the globals stored where each closure of it is made, and those any
+// closure is stored to right away (the names of the top-level functions, which call
+// themselves and each other). The VM throws an error if one is loaded before it is stored.
+//
+// Locals past `stack_limit` (that of the VM to run the code) and globals past MAX_GLOBALS
+// are rejected before anything is allocated for them.
+pub fn verify(insts: &[VMInst], stack_limit: usize) -> Result<(), String> {
+    let mut verifier = Verifier {
+        insts,
+        entries: HashSet::new(),
+        owner: vec![None; insts.len()],
+        states: vec![None; insts.len()],
+        cells: HashMap::new(),
+        globals: HashMap::new(),
+        named: HashSet::new(),
+        nglobals: 0,
+        nargs: 0,
+    };
+    for (pc, inst) in insts.iter().enumerate() {
+        match *inst {
+            VMInst::MakeClosure(entry, n) => {
+                match insts.get(entry) {
+                    Some(&VMInst::Entry(_)) if entry != 0 => {}
+                    _ => return Err(error(insts, pc, "does not make a function")),
+                }
+                if *verifier.cells.entry(entry).or_insert(n) != n {
+                    return Err(error(insts, pc, "captures a different number of cells"));
+                }
+                verifier.entries.insert(entry);
+                if let Some(&VMInst::StoreG(n)) = insts.get(pc + 1) {
+                    verifier.named.insert(n);
+                }
+            }
+            VMInst::Entry(n)
+            | VMInst::LoadV(n)
+            | VMInst::StoreV(n)
+            | VMInst::MakeCell(n)
+            | VMInst::LoadC(n)
+            | VMInst::StoreC(n) if n >= stack_limit => {
+                let msg = format!("goes past the stack limit of {}", stack_limit);
+                return Err(error(insts, pc, &msg));
+            }
+            VMInst::StoreG(n) | VMInst::LoadG(n) if n >= MAX_GLOBALS => {
+                let msg = format!("uses more than {} globals", MAX_GLOBALS);
+                return Err(error(insts, pc, &msg));
+            }
+            VMInst::StoreG(n) | VMInst::LoadG(n) => {
+                verifier.nglobals = verifier.nglobals.max(n + 1);
+            }
+            VMInst::Apply(n) => verifier.nargs = verifier.nargs.max(n),
+            _ => {}
+        }
+    }
+    verifier.cells.insert(0, 0);
+    let mut entries: Vec<usize> = verifier.entries.iter().cloned().collect();
+    entries.sort();
+    verifier.function(0)?;
+    for &entry in &entries {
+        verifier.function(entry)?;
+    }
+    // The closures made after their functions were checked store what those assumed
+    for (pc, inst) in insts.iter().enumerate() {
+        if let VMInst::MakeClosure(entry, _) = *inst {
+            if let Some(stored) = verifier.stored_at(pc) {
+                let assumed = &verifier.globals[&entry];
+                if assumed.iter().zip(&stored).any(|(&assumed, &stored)| assumed && !stored) {
+                    let msg = "makes a function before storing a global it loads";
+                    return Err(error(insts, pc, msg));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Ty {
+    Any, // Unknown
+    Bool,
+    Int,
+    UInt,
+    Float,
+    Str,
+    Map,
+    Variant(usize, usize), // tag, number of fields
+    Iter,
+    Cell,
+    Closure(usize), // entry
+}
+
+// The locals of the call and the values above them
+#[derive(Clone, Debug, PartialEq)]
+struct State {
+    frame: bool, // Whether Entry has been run; false only at the top level
+    locals: Vec<Ty>,
+    stack: Vec<Ty>,
+    globals: Vec<bool>, // Whether each global has been stored on every path here
+    tries: usize,       // The number of Try not yet ended by EndTry
+}
+
+struct Verifier<'a> {
+    insts: &'a [VMInst],
+    entries: HashSet<usize>,           // The functions, besides the top level at 0
+    owner: Vec<Option<usize>>,         // pc -> the entry of the function it is in
+    states: Vec<Option<State>>,        // pc -> the state before it
+    cells: HashMap<usize, usize>,      // entry -> the number of cells its closures capture
+    globals: HashMap<usize, Vec<bool>>, // entry -> the globals stored when it is called
+    named: HashSet<usize>,              // The globals closures are stored to right away
+    nglobals: usize,
+    nargs: usize, // The most arguments of any call, which are the first locals of a callee
+}
+
+fn error(insts: &[VMInst], pc: usize, msg: &str) -> String {
+    format!("at {} ({:?}): {}", pc, insts[pc], msg)
+}
+
+impl<'a> Verifier<'a> {
+    fn function(&mut self, entry: usize) -> Result<(), String> {
+        if self.insts.is_empty() {
+            return Ok(());
+        }
+        // The globals stored by the closures of the function made so far, which are usually
+        // all of them, as a function is made by the code around it
+        let mut globals: Option<Vec<bool>> = None;
+        for pc in 0..self.insts.len() {
+            match self.insts[pc] {
+                VMInst::MakeClosure(e, _) if e == entry => {}
+                _ => continue,
+            }
+            if let Some(stored) = self.stored_at(pc) {
+                globals = Some(match globals {
+                    Some(globals) => globals.iter().zip(&stored).map(|(&a, &b)| a && b).collect(),
+                    None => stored,
+                });
+            }
+        }
+        let globals = globals.unwrap_or_else(|| vec![false; self.nglobals]);
+        self.globals.insert(entry, globals.clone());
+        self.owner[entry] = Some(entry);
+        self.states[entry] = Some(State {
+            frame: false,
+            locals: vec![],
+            stack: vec![],
+            globals,
+            tries: 0,
+        });
+        let mut work = vec![entry];
+        while let Some(pc) = work.pop() {
+            let state = self.states[pc].clone().unwrap();
+            for (next, state) in self.step(entry, pc, state)? {
+                self.enter(entry, pc, next, state, &mut work)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The globals stored when the closure made at `pc` can first be called, if it is reached
+    fn stored_at(&self, pc: usize) -> Option<Vec<bool>> {
+        let mut stored = self.states[pc].as_ref()?.globals.clone();
+        for &n in &self.named {
+            stored[n] = true;
+        }
+        Some(stored)
+    }
+
+    // Merges `state` into the state at `next`, reached from `pc`
+    fn enter(
+        &mut self,
+        entry: usize,
+        pc: usize,
+        next: usize,
+        state: State,
+        work: &mut Vec<usize>,
+    ) -> Result<(), String> {
+        if next == self.insts.len() && entry == 0 {
+            return Ok(());
+        } else if next == self.insts.len() {
+            return Err(error(self.insts, pc, "runs off the end of the code"));
+        } else if self.entries.contains(&next) {
+            return Err(error(self.insts, pc, &format!("runs into the function at {}", next)));
+        }
+        match self.owner[next] {
+            Some(owner) if owner != entry => {
+                let msg = format!("goes to {}, in the function at {}", next, owner);
+                return Err(error(self.insts, pc, &msg));
+            }
+            _ => self.owner[next] = Some(entry),
+        }
+        let merged = match self.states[next] {
+            None => state,
+            Some(ref old) => {
+                if old.frame != state.frame {
+                    let msg = format!("goes to {} both in and out of Entry and Ret", next);
+                    return Err(error(self.insts, pc, &msg));
+                }
+                if old.tries != state.tries {
+                    let msg = format!("goes to {} both in and out of a Try", next);
+                    return Err(error(self.insts, pc, &msg));
+                }
+                if old.stack.len() != state.stack.len() {
+                    let msg = format!(
+                        "goes to {} with {} values on the stack, but others with {}",
+                        next,
+                        state.stack.len(),
+                        old.stack.len()
+                    );
+                    return Err(error(self.insts, pc, &msg));
+                }
+                let merge = |a: &Vec<Ty>, b: &Vec<Ty>| -> Vec<Ty> {
+                    a.iter()
+                        .zip(b)
+                        .map(|(&a, &b)| if a == b { a } else { Ty::Any })
+                        .collect()
+                };
+                let merged = State {
+                    frame: old.frame,
+                    locals: merge(&old.locals, &state.locals),
+                    stack: merge(&old.stack, &state.stack),
+                    globals: old.globals
+                        .iter()
+                        .zip(&state.globals)
+                        .map(|(&a, &b)| a && b)
+                        .collect(),
+                    tries: old.tries,
+                };
+                if merged == *old {
+                    return Ok(());
+                }
+                merged
+            }
+        };
+        self.states[next] = Some(merged);
+        work.push(next);
+        Ok(())
+    }
+
+    // The instructions to run after the one at `pc`, with the states before them
+    fn step(
+        &self,
+        entry: usize,
+        pc: usize,
+        mut state: State,
+    ) -> Result<Vec<(usize, State)>, String> {
+        let insts = self.insts;
+        let err = |msg: &str| Err(error(insts, pc, msg));
+        let inst = &insts[pc];
+        let pops = match *inst {
+            VMInst::StoreV(_)
+            | VMInst::StoreG(_)
+            | VMInst::MakeCell(_)
+            | VMInst::StoreC(_)
+            | VMInst::StoreU(_)
+            | VMInst::Not
+            | VMInst::Neg
+            | VMInst::IntCast(_)
+            | VMInst::FloatCast(_)
+            | VMInst::Field(_)
+            | VMInst::Iter
+            | VMInst::IterNext(_)
+            | VMInst::JmpIfFalse(_)
+            | VMInst::JmpTable(_)
+            | VMInst::Throw
+            | VMInst::Pop => 1,
+            VMInst::Call(ref name) => match builtin(name) {
+                Some((args, _)) => args.len(),
+                None => return err("calls an unknown builtin function"),
+            },
+            VMInst::MakeClosure(_, n) | VMInst::NewVariant(_, n) => n,
+            VMInst::Apply(n) => n.saturating_add(1),
+            VMInst::NewMap(n) => n.saturating_mul(2),
+            VMInst::SetField(_) | VMInst::Index => 2,
+            VMInst::SetIndex => 3,
+            VMInst::Ret if entry != 0 => 1,
+            VMInst::Add
+            | VMInst::Sub
+            | VMInst::Mul
+            | VMInst::Div
+            | VMInst::Rem
+            | VMInst::And
+            | VMInst::Or
+            | VMInst::Xor
+            | VMInst::Shl
+            | VMInst::Shr
+            | VMInst::Eq
+            | VMInst::Ne
+            | VMInst::Lt
+            | VMInst::Gt
+            | VMInst::Le
+            | VMInst::Ge => 2,
+            _ => 0,
+        };
+        if state.stack.len() < pops {
+            let msg = format!("needs {} values on the stack, but has {}", pops, state.stack.len());
+            return err(&msg);
+        }
+        let args = state.stack.split_off(state.stack.len() - pops);
+        // Checks that the `i`th value popped has the type `ty`, if known
+        let expect = |i: usize, ty: Ty| -> Result<(), String> {
+            match args[i] {
+                found if found == ty || found == Ty::Any || ty == Ty::Any => Ok(()),
+                found => Err(error(
+                    insts,
+                    pc,
+                    &format!("needs {:?} but finds {:?}", ty, found),
+                )),
+            }
+        };
+        let nlocals = state.locals.len();
+        let local = |n: usize| -> Result<usize, String> {
+            if n < nlocals {
+                Ok(n)
+            } else {
+                let msg = format!("uses local {}, but the function has {}", n, nlocals);
+                Err(error(insts, pc, &msg))
+            }
+        };
+        let ncells = self.cells[&entry];
+        let upvalue = |n: usize| -> Result<(), String> {
+            if n < ncells {
+                Ok(())
+            } else {
+                let msg = format!("uses upvalue {}, but the function has {}", n, ncells);
+                Err(error(insts, pc, &msg))
+            }
+        };
+        let target = |dst: usize| -> Result<usize, String> {
+            if dst <= insts.len() {
+                Ok(dst)
+            } else {
+                Err(error(insts, pc, "jumps out of the code"))
+            }
+        };
+
+        let mut push = None;
+        match *inst {
+            VMInst::Entry(n) => {
+                if state.frame {
+                    return err("Entry in the middle of a function");
+                } else if !state.stack.is_empty() {
+                    return err("Entry with values on the stack");
+                }
+                // The arguments come first, and Entry sets the other locals to 0
+                let nargs = if entry == 0 { 0 } else { self.nargs.min(n) };
+                state.frame = true;
+                state.locals = vec![Ty::Any; nargs];
+                state.locals.resize(n, Ty::Int);
+            }
+            VMInst::PushB(_) => push = Some(Ty::Bool),
+            VMInst::PushI(_) => push = Some(Ty::Int),
+            VMInst::PushU(_) => push = Some(Ty::UInt),
+            VMInst::PushF(_) => push = Some(Ty::Float),
+            VMInst::PushS(_) => push = Some(Ty::Str),
+            VMInst::Pop => {}
+            VMInst::StoreV(n) => {
+                state.locals[local(n)?] = args[0];
+                push = Some(args[0])
+            }
+            VMInst::LoadV(n) => push = Some(state.locals[local(n)?]),
+            VMInst::StoreG(n) => {
+                state.globals[n] = true;
+                push = Some(args[0])
+            }
+            VMInst::LoadG(n) => {
+                if !state.globals[n] {
+                    return err("loads a global not stored on every path here");
+                }
+                push = Some(Ty::Any)
+            }
+            VMInst::MakeCell(n) => {
+                state.locals[local(n)?] = Ty::Cell;
+                push = Some(args[0])
+            }
+            VMInst::StoreC(n) | VMInst::LoadC(n) => {
+                match state.locals[local(n)?] {
+                    Ty::Any | Ty::Cell => {}
+                    ty => return err(&format!("needs local {} to be a Cell, not {:?}", n, ty)),
+                }
+                push = Some(args.first().cloned().unwrap_or(Ty::Any))
+            }
+            VMInst::StoreU(n) => {
+                upvalue(n)?;
+                push = Some(args[0])
+            }
+            VMInst::LoadU(n) => {
+                upvalue(n)?;
+                push = Some(Ty::Any)
+            }
+            VMInst::CaptureU(n) => {
+                upvalue(n)?;
+                push = Some(Ty::Cell)
+            }
+            VMInst::MakeClosure(entry, n) => {
+                for i in 0..n {
+                    expect(i, Ty::Cell)?;
+                }
+                push = Some(Ty::Closure(entry))
+            }
+            VMInst::Apply(n) => {
+                match args[0] {
+                    Ty::Any => {}
+                    Ty::Closure(callee) => match insts[callee] {
+                        VMInst::Entry(nlocals) if n > nlocals => {
+                            let msg = format!(
+                                "passes {} arguments to the function at {}, which has {} locals",
+                                n, callee, nlocals
+                            );
+                            return err(&msg);
+                        }
+                        _ => {}
+                    },
+                    ty => return err(&format!("calls {:?}, not a function", ty)),
+                }
+                push = Some(Ty::Any)
+            }
+            VMInst::Call(ref name) => {
+                let (params, ret) = builtin(name).unwrap();
+                for (i, &ty) in params.iter().enumerate() {
+                    expect(i, ty)?;
+                }
+                if name != "len" && params.len() > 1 {
+                    key(&args[1]).map_err(|msg| error(insts, pc, &msg))?;
+                }
+                push = Some(ret)
+            }
+            VMInst::Not => {
+                expect(0, Ty::Bool)?;
+                push = Some(Ty::Bool)
+            }
+            VMInst::Neg => {
+                match args[0] {
+                    Ty::Any | Ty::Int | Ty::Float => {}
+                    ty => return err(&format!("negates {:?}", ty)),
+                }
+                push = Some(args[0])
+            }
+            VMInst::IntCast(ty) => {
+                match args[0] {
+                    Ty::Any | Ty::Bool | Ty::Int | Ty::UInt | Ty::Float => {}
+                    ty => return err(&format!("converts {:?} to an integer", ty)),
+                }
+                push = Some(if ty.is_signed() { Ty::Int } else { Ty::UInt })
+            }
+            VMInst::FloatCast(_) => {
+                match args[0] {
+                    Ty::Any | Ty::Bool | Ty::Int | Ty::UInt | Ty::Float => {}
+                    ty => return err(&format!("converts {:?} to a float", ty)),
+                }
+                push = Some(Ty::Float)
+            }
+            VMInst::NewVariant(tag, n) => push = Some(Ty::Variant(tag, n)),
+            VMInst::Field(i) | VMInst::SetField(i) => {
+                match args[0] {
+                    Ty::Any => {}
+                    Ty::Variant(_, n) if i < n => {}
+                    Ty::Variant(_, n) => {
+                        return err(&format!("uses field {} of a variant with {}", i, n))
+                    }
+                    ty => return err(&format!("needs a variant but finds {:?}", ty)),
+                }
+                push = Some(args.get(1).cloned().unwrap_or(Ty::Any))
+            }
+            VMInst::NewMap(n) => {
+                for i in 0..n {
+                    key(&args[2 * i]).map_err(|msg| error(insts, pc, &msg))?;
+                }
+                push = Some(Ty::Map)
+            }
+            VMInst::Index => {
+                expect(0, Ty::Map)?;
+                key(&args[1]).map_err(|msg| error(insts, pc, &msg))?;
+                push = Some(Ty::Any)
+            }
+            VMInst::SetIndex => {
+                expect(0, Ty::Map)?;
+                key(&args[1]).map_err(|msg| error(insts, pc, &msg))?;
+                push = Some(args[2])
+            }
+            VMInst::Iter => {
+                expect(0, Ty::Map)?;
+                push = Some(Ty::Iter)
+            }
+            VMInst::IterNext(dst) => {
+                expect(0, Ty::Iter)?;
+                let done = (target(dst)?, state.clone());
+                state.stack.push(Ty::Any);
+                state.stack.push(Ty::Any);
+                return Ok(vec![done, (pc + 1, state)]);
+            }
+            VMInst::Jmp(dst) => return Ok(vec![(target(dst)?, state)]),
+            VMInst::JmpIfFalse(dst) => {
+                expect(0, Ty::Bool)?;
+                return Ok(vec![(target(dst)?, state.clone()), (pc + 1, state)]);
+            }
+            VMInst::JmpTable(ref table) => {
+                match args[0] {
+                    Ty::Any => {}
+                    Ty::Variant(tag, _) if tag < table.len() => {}
+                    Ty::Variant(tag, _) => return err(&format!("has no target for tag {}", tag)),
+                    ty => return err(&format!("needs a variant but finds {:?}", ty)),
+                }
+                if table.is_empty() {
+                    return err("has no targets");
+                }
+                let mut next = vec![];
+                for &dst in table {
+                    next.push((target(dst)?, state.clone()));
+                }
+                return Ok(next);
+            }
+            VMInst::Ret if state.tries > 0 => return err("Ret before EndTry"),
+            VMInst::Ret if entry == 0 => {
+                if !state.frame {
+                    return err("Ret without Entry");
+                }
+                state.frame = false;
+                state.locals.clear();
+                state.stack.clear();
+                return Ok(vec![(pc + 1, state)]);
+            }
+            VMInst::Ret | VMInst::Throw => return Ok(vec![]),
+            // The handler runs with the stack as it is here and the value thrown, after
+            // any of the instructions up to EndTry, which may have set any local
+            VMInst::Try(dst) => {
+                let mut handler = State {
+                    locals: vec![Ty::Any; state.locals.len()],
+                    ..state.clone()
+                };
+                handler.stack.push(Ty::Any);
+                state.tries += 1;
+                return Ok(vec![(target(dst)?, handler), (pc + 1, state)]);
+            }
+            VMInst::EndTry => {
+                if state.tries == 0 {
+                    return err("EndTry without Try");
+                }
+                state.tries -= 1;
+            }
+            _ => push = Some(binop(inst, args[0], args[1]).map_err(|msg| error(insts, pc, &msg))?),
+        }
+        if let Some(ty) = push {
+            state.stack.push(ty);
+        }
+        Ok(vec![(pc + 1, state)])
+    }
+}
+
+// The types of the arguments and the result of a builtin function
+fn builtin(name: &str) -> Option<(Vec<Ty>, Ty)> {
+    Some(match name {
+        "print" => (vec![Ty::Any], Ty::Int),
+        "to_string" => (vec![Ty::Any], Ty::Str),
+        "insert" => (vec![Ty::Map, Ty::Any, Ty::Any], Ty::Int),
+        "remove" => (vec![Ty::Map, Ty::Any], Ty::Int),
+        "contains" => (vec![Ty::Map, Ty::Any], Ty::Bool),
+        "len" => (vec![Ty::Map], Ty::Int),
+        _ => return None,
+    })
+}
+
+fn key(ty: &Ty) -> Result<(), String> {
+    match *ty {
+        Ty::Any | Ty::Int | Ty::Str => Ok(()),
+        ty => Err(format!("uses {:?} as a map key", ty)),
+    }
+}
+
+// The type of the result of a binary operator (like vm::binop), if it applies to the types
+fn binop(inst: &VMInst, lhs: Ty, rhs: Ty) -> Result<Ty, String> {
+    let cmp = match *inst {
+        VMInst::Eq | VMInst::Ne => return Ok(Ty::Bool),
+        VMInst::Lt | VMInst::Gt | VMInst::Le | VMInst::Ge => true,
+        _ => false,
+    };
+    let ty = match (lhs, rhs) {
+        (Ty::Any, ty) | (ty, Ty::Any) => ty,
+        (lhs, rhs) if lhs == rhs => lhs,
+        (lhs, rhs) => return Err(format!("applies to {:?} and {:?}", lhs, rhs)),
+    };
+    let bits = matches!(*inst, VMInst::And | VMInst::Or | VMInst::Xor);
+    let shift = *inst == VMInst::Shl || *inst == VMInst::Shr;
+    let ok = match ty {
+        Ty::Any | Ty::Int | Ty::UInt => true,
+        Ty::Float => !bits && !shift,
+        Ty::Bool => bits,
+        Ty::Str => cmp || *inst == VMInst::Add,
+        _ => false,
+    };
+    if !ok {
+        return Err(format!("applies to {:?}", ty));
+    }
+    Ok(if cmp { Ty::Bool } else { ty })
+}
+
+#[test]
+fn test_verify() {
+    use vm::{compile_source, VM};
+    use typing::IntTy;
+    let src = "enum Shape { Circle(int), Rect(int, int) }
+               def area(s: Shape) -> int {
+                 match s { Shape::Circle(r) => r * r * 3, Shape::Rect(w, h) => w * h }
+               }
+               def count(n: int) -> int {
+                 def down(i: int) -> int { if i == 0 { 0 } else { down(i - 1) + 1 } }
+                 down(n)
+               }
+               names = {1: \"one\", 2: \"two\"}
+               for k, v in names { print(v) }
+               i = 0
+               while i < 3 { print(count(i)) \n i += 1 }
+               small:u8 = 250
+               print(try { 1 / (small - small) } catch e { print(e)\n 0 })
+               print(area(Shape::Rect(2, count(3))))";
+    let limit = VM::new().stack_limit;
+    for &opt_level in &[0, 1] {
+        assert_eq!(verify(&compile_source(src, opt_level).0, limit), Ok(()));
+    }
+
+    use vm_base::VMInst::*;
+    let bad = vec![
+        (vec![Entry(0), Ret, Ret], "at 2 (Ret): Ret without Entry"),
+        (vec![Entry(0), Add, Ret], "at 1 (Add): needs 2 values on the stack, but has 0"),
+        (vec![Entry(1), LoadV(1), Ret], "at 1 (LoadV(1)): uses local 1, but the function has 1"),
+        (vec![Entry(0), Jmp(5)], "at 1 (Jmp(5)): jumps out of the code"),
+        (
+            vec![Entry(0), MakeClosure(4, 0), Ret, Jmp(6), Entry(0), PushI(0)],
+            "at 5 (PushI(0)): runs off the end of the code",
+        ),
+        (
+            vec![Entry(0), PushB(true), JmpIfFalse(4), PushI(1), Ret],
+            "at 3 (PushI(1)): goes to 4 with 1 values on the stack, but others with 0",
+        ),
+        (
+            vec![Entry(0), PushI(1), PushS("a".to_string()), Add, Ret],
+            "at 3 (Add): applies to Int and Str",
+        ),
+        (vec![Entry(0), PushI(1), Not, Ret], "at 2 (Not): needs Bool but finds Int"),
+        (
+            vec![Entry(0), PushI(1), IntCast(IntTy::U8), PushI(2), Shl, Ret],
+            "at 4 (Shl): applies to UInt and Int",
+        ),
+        (
+            vec![Entry(0), LoadG(0), Ret],
+            "at 1 (LoadG(0)): loads a global not stored on every path here",
+        ),
+        (vec![Entry(0), LoadU(0), Ret], "at 1 (LoadU(0)): uses upvalue 0, but the function has 0"),
+        (
+            vec![
+                Entry(0),
+                Jmp(4),
+                Entry(1),
+                Ret,
+                MakeClosure(2, 0),
+                PushI(1),
+                PushI(2),
+                Apply(2),
+                Ret,
+            ],
+            "at 7 (Apply(2)): passes 2 arguments to the function at 2, which has 1 locals",
+        ),
+        (
+            vec![Entry(0), Jmp(4), Entry(0), Jmp(6), MakeClosure(2, 0), Pop, Ret],
+            "at 3 (Jmp(6)): goes to 6, in the function at 0",
+        ),
+        (
+            vec![Entry(0), MakeClosure(3, 0), Pop, Entry(0), Ret],
+            "at 2 (Pop): runs into the function at 3",
+        ),
+        (vec![Entry(0), Entry(0), Ret], "at 1 (Entry(0)): Entry in the middle of a function"),
+        (
+            vec![Entry(0), MakeClosure(1, 0), Ret],
+            "at 1 (MakeClosure(1, 0)): does not make a function",
+        ),
+        (
+            vec![Entry(0), Call("exit".to_string()), Ret],
+            "at 1 (Call(\"exit\")): calls an unknown builtin function",
+        ),
+        (
+            vec![Entry(1), LoadV(0), Apply(0), Ret],
+            "at 2 (Apply(0)): calls Int, not a function",
+        ),
+        (
+            vec![Entry(1), LoadV(0), PushS("a".to_string()), Add, Ret],
+            "at 3 (Add): applies to Int and Str",
+        ),
+        (
+            vec![Entry(0), NewVariant(0, 0), Field(3), Ret],
+            "at 2 (Field(3)): uses field 3 of a variant with 0",
+        ),
+        (
+            vec![Entry(0), NewVariant(5, 0), JmpTable(vec![3]), Ret],
+            "at 2 (JmpTable([3])): has no target for tag 5",
+        ),
+        (
+            vec![Entry(0), PushB(true), JmpIfFalse(6), PushI(1), StoreG(0), Pop, LoadG(0), Ret],
+            "at 6 (LoadG(0)): loads a global not stored on every path here",
+        ),
+        (
+            vec![Entry(1), LoadC(0), Ret],
+            "at 1 (LoadC(0)): needs local 0 to be a Cell, not Int",
+        ),
+        (
+            vec![
+                Entry(0),
+                NewMap(0),
+                PushF(1.0),
+                PushI(1),
+                Call("insert".to_string()),
+                Ret,
+            ],
+            "at 4 (Call(\"insert\")): uses Float as a map key",
+        ),
+        (
+            vec![
+                Entry(0),
+                Jmp(5),
+                Entry(0),
+                LoadG(0),
+                Ret,
+                MakeClosure(2, 0),
+                PushI(1),
+                StoreG(0),
+                Ret,
+            ],
+            "at 3 (LoadG(0)): loads a global not stored on every path here",
+        ),
+        (
+            vec![Entry(0), Try(3), Ret, PushI(0), Ret],
+            "at 2 (Ret): Ret before EndTry",
+        ),
+        (vec![Entry(0), EndTry, Ret], "at 1 (EndTry): EndTry without Try"),
+        (
+            vec![Entry(1 << 40), Ret],
+            "at 0 (Entry(1099511627776)): goes past the stack limit of 1048576",
+        ),
+        (
+            vec![Entry(0), PushI(1), StoreG(1 << 40), Pop, Ret],
+            "at 2 (StoreG(1099511627776)): uses more than 65536 globals",
+        ),
+        (
+            vec![Entry(usize::MAX), Ret],
+            "at 0 (Entry(18446744073709551615)): goes past the stack limit of 1048576",
+        ),
+    ];
+    for (insts, msg) in bad {
+        assert_eq!(verify(&insts, limit), Err(msg.to_string()), "{:?}", insts);
+    }
+}
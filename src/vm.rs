@@ -1,81 +1,958 @@
-use vm_base::VMInst;
+use vm_base::{DebugInfo, MapKey, Object, VMInst, Value};
+use gc::Heap;
+use trace::Tracer;
+use typing::{FloatTy, IntTy};
 
-use ansi_term::{Colour, Style};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
 
-#[derive(Clone)]
 pub struct VM {
-    pub stack: [i64; 1024],
+    pub stack: Vec<Value>, // Grows as needed, up to stack_limit values
+    pub stack_limit: usize,
     pub bp_stack: Vec<usize>,
+    pub frames: Vec<Frame>,
+    pub sp: usize,
+    pub bp: usize,
+    pub pc: usize,
+    pub handlers: Vec<Handler>,
+    pub globals: Vec<Value>,
+    pub heap: Heap,
+    pub output: Option<String>, // If set, `print` writes here instead of stdout
+    pub debug: DebugInfo,
+    pub tracer: Option<Tracer>, // If set, called after every instruction
+}
+
+// The stack during a call of `f(a, b)`, where `f` has the locals a, b and c:
+//
+//     ...                 <- frame.base (sp before the call)
+//     f                      the closure called
+//     a                   <- bp (local 0: the arguments are the first locals, in place)
+//     b                      local 1
+//     c                      local 2, set to 0 by Entry
+//     temporaries ...     <- sp (the last value pushed)
+//
+// Local n is at stack[bp + n]. The return address and the bp of the caller are kept in
+// `frames` and `bp_stack`, off the stack, so no store can clobber them. Ret discards
+// everything above frame.base and pushes the returned value. The code at the top level
+// has a frame of its own locals too, without a Frame, and leaves nothing on the stack:
+// each statement pushes one value, which is popped.
+
+// A call of a closure in progress
+#[derive(Clone)]
+pub struct Frame {
+    pub entry: usize,
+    pub ret_pc: usize,
+    pub base: usize,  // sp before the callee and its arguments were pushed
+    pub nargs: usize, // the first locals of the callee
+    pub cells: Vec<Value>,
+}
+
+// A `try` in progress, and the state to restore when an error is thrown in it
+#[derive(Clone)]
+pub struct Handler {
+    pub pc: usize,
     pub sp: usize,
     pub bp: usize,
+    pub frames: usize,
+    pub bp_stack: usize,
+}
+
+// The most globals a program may store, which bounds the memory StoreG may take
+pub const MAX_GLOBALS: usize = 1 << 16;
+
+// An error thrown and not caught, which ends the program
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub value: Value,      // What was thrown
+    pub backtrace: String, // The calls it was thrown in, as `VM::backtrace` shows them
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "uncaught error: {}\n{}", self.value, self.backtrace)
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
-            stack: [0; 1024],
+            stack: vec![Value::Int(0); 1024],
+            stack_limit: 1 << 20,
             bp_stack: Vec::new(),
+            frames: Vec::new(),
             sp: 0,
             bp: 0,
+            pc: 0,
+            handlers: Vec::new(),
+            globals: Vec::new(),
+            heap: Heap::new(),
+            output: None,
+            debug: DebugInfo::default(),
+            tracer: None,
         }
     }
 }
 
 impl VM {
-    pub fn run(&mut self, insts: Vec<VMInst>) {
-        for inst in insts {
-            self.run_inst(inst.clone());
-            for i in 0..8 {
-                print!(
-                    "{}{}{} ",
-                    if i == self.sp {
-                        Colour::Red.paint("[")
-                    } else if i == self.bp {
-                        Colour::Green.paint("[")
-                    } else {
-                        Style::new().bold().paint("[")
-                    },
-                    self.stack[i],
-                    if i == self.bp {
-                        Colour::Green.paint("]")
-                    } else if i == self.sp {
-                        Colour::Red.paint("]")
-                    } else {
-                        Style::new().bold().paint("]")
-                    },
-                );
-            }
-            println!("\t\t:{:?}", inst);
+    pub fn run(&mut self, insts: Vec<VMInst>) -> Result<(), RuntimeError> {
+        while self.pc < insts.len() {
+            self.step(&insts)?;
+        }
+        Ok(())
+    }
+
+    // Runs `insts`, which push a single value, and returns the value or the error thrown
+    pub fn eval(&mut self, insts: &[VMInst]) -> Result<Value, String> {
+        self.handlers.push(Handler {
+            pc: insts.len(),
+            sp: self.sp,
+            bp: self.bp,
+            frames: self.frames.len(),
+            bp_stack: self.bp_stack.len(),
+        });
+        self.pc = 0;
+        while self.pc < insts.len() {
+            self.step(insts).map_err(|e| e.value.to_string())?;
+        }
+        let val = self.pop();
+        match self.handlers.pop() {
+            Some(_) => Ok(val),
+            None => Err(val.to_string()),
         }
     }
 
-    pub fn run_inst(&mut self, inst: VMInst) {
-        match inst {
+    // Runs the instruction at pc
+    pub fn step(&mut self, insts: &[VMInst]) -> Result<(), RuntimeError> {
+        let pc = self.pc;
+        self.pc += 1;
+        self.run_inst(&insts[pc])?;
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.trace(self, pc, &insts[pc]);
+            self.tracer = Some(tracer);
+        }
+        Ok(())
+    }
+
+    pub fn run_inst(&mut self, inst: &VMInst) -> Result<(), RuntimeError> {
+        // No instruction pushes more than two values, besides the locals made by Entry
+        let needed = match *inst {
+            VMInst::Entry(n) => n.saturating_add(2),
+            _ => 2,
+        };
+        if !self.reserve(needed) {
+            return self.throw(Value::new_string("stack overflow".to_string()));
+        }
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        match self.exec(inst) {
+            Ok(()) => Ok(()),
+            Err(val) => self.throw(val),
+        }
+    }
+
+    // Runs `inst`, or returns the value it throws. Besides the errors of the program, such
+    // as Throw and division by zero, these are the values of the wrong type for `inst`,
+    // which the verifier allows where it does not know their types.
+    fn exec(&mut self, inst: &VMInst) -> Result<(), Value> {
+        match *inst {
             VMInst::Entry(n) => {
+                let nargs = self.frames.last().map_or(0, |frame| frame.nargs);
+                if nargs > n {
+                    return Err(error("Entry: more arguments than locals"));
+                }
                 self.bp_stack.push(self.bp);
-                self.sp += n;
-                self.bp = self.sp;
+                self.bp = self.sp + 1 - nargs;
+                for local in &mut self.stack[self.sp + 1..self.bp + n] {
+                    *local = Value::Int(0);
+                }
+                self.sp = self.bp + n - 1;
             }
-            VMInst::StoreV(n) => self.stack[self.bp - n] = self.stack[self.sp],
+            VMInst::StoreV(n) => self.stack[self.bp + n] = self.stack[self.sp].clone(),
             VMInst::LoadV(n) => {
-                self.sp += 1;
-                self.stack[self.sp] = self.stack[self.bp - n]
+                let val = self.stack[self.bp + n].clone();
+                self.push(val)
+            }
+            VMInst::StoreG(n) => {
+                if n >= MAX_GLOBALS {
+                    return Err(error("StoreG: too many globals"));
+                }
+                if n >= self.globals.len() {
+                    self.globals.resize(n + 1, Value::Int(0));
+                }
+                self.globals[n] = self.stack[self.sp].clone()
+            }
+            VMInst::LoadG(n) => match self.globals.get(n).cloned() {
+                Some(val) => self.push(val),
+                None => return Err(error("LoadG: not a global stored yet")),
+            },
+            VMInst::MakeCell(n) => {
+                let val = self.stack[self.sp].clone();
+                self.stack[self.bp + n] = self.heap.alloc(Object::Cell(val))
+            }
+            VMInst::StoreC(n) => {
+                let val = self.stack[self.sp].clone();
+                set_cell(&self.stack[self.bp + n], val)?
+            }
+            VMInst::LoadC(n) => {
+                let val = get_cell(&self.stack[self.bp + n])?;
+                self.push(val)
             }
-            VMInst::PushI(n) => {
-                self.sp += 1;
-                self.stack[self.sp] = n;
+            VMInst::StoreU(n) => {
+                let val = self.stack[self.sp].clone();
+                set_cell(&self.frames.last().unwrap().cells[n], val)?
             }
-            VMInst::Add => {
-                let a = self.stack[self.sp];
-                let b = self.stack[self.sp - 1];
-                self.sp -= 1;
-                self.stack[self.sp] = a + b;
+            VMInst::LoadU(n) => {
+                let val = get_cell(&self.frames.last().unwrap().cells[n])?;
+                self.push(val)
+            }
+            VMInst::CaptureU(n) => {
+                let cell = self.frames.last().unwrap().cells[n].clone();
+                self.push(cell)
+            }
+            VMInst::MakeClosure(entry, n) => {
+                let cells = self.stack[self.sp + 1 - n..self.sp + 1].to_vec();
+                self.sp -= n;
+                let closure = self.heap.alloc(Object::Closure(entry, cells));
+                self.push(closure)
+            }
+            VMInst::Apply(nargs) => {
+                let (entry, cells) = match self.stack[self.sp - nargs] {
+                    Value::Object(ref obj) => match *obj.borrow() {
+                        Object::Closure(entry, ref cells) => (entry, cells.clone()),
+                        _ => return Err(error("Apply: not a function")),
+                    },
+                    _ => return Err(error("Apply: not an object")),
+                };
+                self.frames.push(Frame {
+                    entry,
+                    ret_pc: self.pc,
+                    base: self.sp - nargs - 1,
+                    nargs,
+                    cells,
+                });
+                self.pc = entry
+            }
+            VMInst::PushB(b) => self.push(Value::Bool(b)),
+            VMInst::PushI(n) => self.push(Value::Int(n)),
+            VMInst::PushU(n) => self.push(Value::UInt(n)),
+            VMInst::PushF(f) => self.push(Value::Float(f)),
+            VMInst::PushS(ref s) => self.push(Value::new_string(s.clone())),
+            VMInst::Pop => self.sp -= 1,
+            VMInst::Call(ref name) => self.call_builtin(name)?,
+            VMInst::Not => match self.pop() {
+                Value::Bool(b) => self.push(Value::Bool(!b)),
+                _ => return Err(error("Not: not a bool")),
+            },
+            VMInst::Neg => {
+                let val = match self.pop() {
+                    Value::Int(n) => Value::Int(n.wrapping_neg()),
+                    Value::Float(f) => Value::Float(-f),
+                    _ => return Err(error("Neg: not a signed number")),
+                };
+                self.push(val)
+            }
+            VMInst::IntCast(ty) => {
+                let val = self.pop();
+                self.push(cast_int(val, ty).map_err(Value::new_string)?)
+            }
+            VMInst::FloatCast(ty) => {
+                let val = self.pop();
+                self.push(cast_float(val, ty).map_err(Value::new_string)?)
+            }
+            VMInst::NewVariant(tag, n) => {
+                let fields = self.stack[self.sp + 1 - n..self.sp + 1].to_vec();
+                self.sp -= n;
+                let variant = self.heap.alloc(Object::Variant(tag, fields));
+                self.push(variant)
+            }
+            VMInst::Field(i) => {
+                let field = match self.pop() {
+                    Value::Object(obj) => match *obj.borrow() {
+                        Object::Variant(_, ref fields) => match fields.get(i) {
+                            Some(field) => field.clone(),
+                            None => return Err(error("Field: no such field")),
+                        },
+                        _ => return Err(error("Field: not a variant")),
+                    },
+                    _ => return Err(error("Field: not an object")),
+                };
+                self.push(field)
+            }
+            VMInst::SetField(i) => {
+                let val = self.pop();
+                match self.pop() {
+                    Value::Object(obj) => match *obj.borrow_mut() {
+                        Object::Variant(_, ref mut fields) => match fields.get_mut(i) {
+                            Some(field) => *field = val.clone(),
+                            None => return Err(error("SetField: no such field")),
+                        },
+                        _ => return Err(error("SetField: not a variant")),
+                    },
+                    _ => return Err(error("SetField: not an object")),
+                }
+                self.push(val)
+            }
+            VMInst::NewMap(n) => {
+                let mut map = BTreeMap::new();
+                for pair in self.stack[self.sp + 1 - 2 * n..self.sp + 1].chunks(2) {
+                    map.insert(MapKey::from_value(&pair[0])?, pair[1].clone());
+                }
+                self.sp -= 2 * n;
+                let map = self.heap.alloc(Object::Map(map));
+                self.push(map)
+            }
+            VMInst::Index => {
+                let key = MapKey::from_value(&self.pop())?;
+                let val = match self.pop() {
+                    Value::Object(obj) => match *obj.borrow() {
+                        Object::Map(ref map) => match map.get(&key) {
+                            Some(val) => Ok(val.clone()),
+                            None => Err(format!("key {} not found", key.to_value())),
+                        },
+                        _ => return Err(error("Index: not a map")),
+                    },
+                    _ => return Err(error("Index: not an object")),
+                };
+                match val {
+                    Ok(val) => self.push(val),
+                    Err(msg) => return Err(Value::new_string(msg)),
+                }
+            }
+            VMInst::SetIndex => {
+                let val = self.pop();
+                let key = MapKey::from_value(&self.pop())?;
+                match self.pop() {
+                    Value::Object(obj) => match *obj.borrow_mut() {
+                        Object::Map(ref mut map) => map.insert(key, val.clone()),
+                        _ => return Err(error("SetIndex: not a map")),
+                    },
+                    _ => return Err(error("SetIndex: not an object")),
+                };
+                self.push(val)
+            }
+            VMInst::Iter => {
+                // Iterates over a snapshot, so the map may be modified in the loop
+                let entries = match self.pop() {
+                    Value::Object(obj) => match *obj.borrow() {
+                        Object::Map(ref map) => map.iter()
+                            .map(|(key, val)| (key.to_value(), val.clone()))
+                            .collect(),
+                        _ => return Err(error("Iter: not a map")),
+                    },
+                    _ => return Err(error("Iter: not an object")),
+                };
+                let iter = self.heap.alloc(Object::Iter(entries, 0));
+                self.push(iter)
+            }
+            VMInst::IterNext(dst) => {
+                let next = match self.pop() {
+                    Value::Object(obj) => match *obj.borrow_mut() {
+                        Object::Iter(ref entries, ref mut pos) => {
+                            *pos += 1;
+                            entries.get(*pos - 1).cloned()
+                        }
+                        _ => return Err(error("IterNext: not an iterator")),
+                    },
+                    _ => return Err(error("IterNext: not an object")),
+                };
+                match next {
+                    Some((key, val)) => {
+                        self.push(key);
+                        self.push(val)
+                    }
+                    None => self.pc = dst,
+                }
+            }
+            VMInst::Jmp(dst) => self.pc = dst,
+            VMInst::JmpIfFalse(dst) => if self.pop() == Value::Bool(false) {
+                self.pc = dst
+            },
+            VMInst::JmpTable(ref table) => {
+                self.pc = match self.pop() {
+                    Value::Object(obj) => match *obj.borrow() {
+                        Object::Variant(tag, _) => match table.get(tag) {
+                            Some(&dst) => dst,
+                            None => return Err(error("JmpTable: no target for the tag")),
+                        },
+                        _ => return Err(error("JmpTable: not a variant")),
+                    },
+                    _ => return Err(error("JmpTable: not an object")),
+                }
             }
             VMInst::Ret => {
-                self.bp = self.bp_stack.pop().unwrap();
-                self.sp = self.bp
+                let bp = mem::replace(&mut self.bp, self.bp_stack.pop().unwrap());
+                match self.frames.pop() {
+                    Some(frame) => {
+                        let val = self.stack[self.sp].clone();
+                        self.sp = frame.base;
+                        self.push(val);
+                        self.pc = frame.ret_pc
+                    }
+                    None => self.sp = bp - 1,
+                }
+            }
+            VMInst::Try(pc) => self.handlers.push(Handler {
+                pc,
+                sp: self.sp,
+                bp: self.bp,
+                frames: self.frames.len(),
+                bp_stack: self.bp_stack.len(),
+            }),
+            VMInst::EndTry => {
+                self.handlers.pop();
+            }
+            VMInst::Throw => return Err(self.pop()),
+            _ => {
+                let rhs = self.pop();
+                let lhs = self.pop();
+                let val = binop(inst, lhs, rhs).map_err(Value::new_string)?;
+                self.push(val)
+            }
+        }
+        Ok(())
+    }
+
+    // Unwinds to the innermost `try`, discarding the frames of the calls made in it. Without
+    // one, the error ends the program, with the VM left as it was when the error was thrown.
+    fn throw(&mut self, val: Value) -> Result<(), RuntimeError> {
+        let handler = match self.handlers.pop() {
+            Some(handler) => handler,
+            None => {
+                return Err(RuntimeError {
+                    value: val,
+                    backtrace: self.backtrace(),
+                })
+            }
+        };
+        self.frames.truncate(handler.frames);
+        self.bp_stack.truncate(handler.bp_stack);
+        self.sp = handler.sp;
+        self.bp = handler.bp;
+        self.push(val);
+        self.pc = handler.pc;
+        Ok(())
+    }
+
+    // The roots are the values on the stack, the globals and the upvalues of the calls
+    pub fn collect_garbage(&mut self) {
+        let stack = self.stack[..self.sp + 1].iter();
+        let cells = self.frames.iter().flat_map(|frame| frame.cells.iter());
+        self.heap.collect(stack.chain(&self.globals).chain(cells));
+    }
+
+    // Makes room for `n` more values on the stack, unless it would exceed the limit (which
+    // may be below the size the stack starts with)
+    fn reserve(&mut self, n: usize) -> bool {
+        let needed = self.sp.saturating_add(n).saturating_add(1);
+        if needed > self.stack_limit {
+            return false;
+        }
+        if needed <= self.stack.len() {
+            return true;
+        }
+        let len = (self.stack.len() * 2).max(needed).min(self.stack_limit);
+        self.stack.resize(len, Value::Int(0));
+        true
+    }
+
+    // The functions being called, innermost first, with recursive calls folded:
+    //
+    // backtrace:
+    //     f (3 times)
+    //     g
+    //     <top level>
+    pub fn backtrace(&self) -> String {
+        let mut names: Vec<String> = self.frames
+            .iter()
+            .rev()
+            .map(|frame| match self.debug.func_names.get(&frame.entry) {
+                Some(name) => name.clone(),
+                None => format!("<function at {}>", frame.entry),
+            })
+            .collect();
+        names.push("<top level>".to_string());
+        let mut trace = "backtrace:".to_string();
+        let mut i = 0;
+        while i < names.len() {
+            let count = names[i..].iter().take_while(|name| **name == names[i]).count();
+            trace.push_str(&format!("\n    {}", names[i]));
+            if count > 1 {
+                trace.push_str(&format!(" ({} times)", count));
+            }
+            i += count;
+        }
+        trace
+    }
+
+    fn call_builtin(&mut self, name: &str) -> Result<(), Value> {
+        match name {
+            "print" => {
+                let val = self.pop();
+                match self.output {
+                    Some(ref mut output) => output.push_str(format!("{}\n", val).as_str()),
+                    None => println!("{}", val),
+                }
+                self.push(Value::Int(0))
+            }
+            "to_string" => {
+                let val = self.pop();
+                self.push(Value::new_string(format!("{}", val)))
+            }
+            "insert" => {
+                let val = self.pop();
+                let key = MapKey::from_value(&self.pop())?;
+                self.with_map(|map| {
+                    map.insert(key, val);
+                    Value::Int(0)
+                })?
+            }
+            "remove" => {
+                let key = MapKey::from_value(&self.pop())?;
+                self.with_map(|map| {
+                    map.remove(&key);
+                    Value::Int(0)
+                })?
+            }
+            "contains" => {
+                let key = MapKey::from_value(&self.pop())?;
+                self.with_map(|map| Value::Bool(map.contains_key(&key)))?
+            }
+            "len" => self.with_map(|map| Value::Int(map.len() as i64))?,
+            _ => return Err(error("Call: not a builtin function")),
+        }
+        Ok(())
+    }
+
+    // Pops a map, applies `f` to it and pushes the result
+    fn with_map<F>(&mut self, f: F) -> Result<(), Value>
+    where
+        F: FnOnce(&mut BTreeMap<MapKey, Value>) -> Value,
+    {
+        let val = match self.pop() {
+            Value::Object(obj) => match *obj.borrow_mut() {
+                Object::Map(ref mut map) => f(map),
+                _ => return Err(error("Call: not a map")),
+            },
+            _ => return Err(error("Call: not an object")),
+        };
+        self.push(val);
+        Ok(())
+    }
+
+    fn push(&mut self, val: Value) {
+        self.sp += 1;
+        self.stack[self.sp] = val;
+    }
+
+    fn pop(&mut self) -> Value {
+        let val = self.stack[self.sp].clone();
+        self.sp -= 1;
+        val
+    }
+}
+
+fn error(msg: &str) -> Value {
+    Value::new_string(msg.to_string())
+}
+
+fn get_cell(cell: &Value) -> Result<Value, Value> {
+    match *cell {
+        Value::Object(ref obj) => match *obj.borrow() {
+            Object::Cell(ref val) => Ok(val.clone()),
+            _ => Err(error("not a cell")),
+        },
+        _ => Err(error("not an object")),
+    }
+}
+
+fn set_cell(cell: &Value, val: Value) -> Result<(), Value> {
+    match *cell {
+        Value::Object(ref obj) => match *obj.borrow_mut() {
+            Object::Cell(ref mut content) => {
+                *content = val;
+                Ok(())
+            }
+            _ => Err(error("not a cell")),
+        },
+        _ => Err(error("not an object")),
+    }
+}
+
+// Applies the binary operator `inst` (Add to Ge); errors are returned as the message to throw
+pub fn binop(inst: &VMInst, lhs: Value, rhs: Value) -> Result<Value, String> {
+    match *inst {
+        VMInst::Eq => return Ok(Value::Bool(lhs == rhs)),
+        VMInst::Ne => return Ok(Value::Bool(lhs != rhs)),
+        _ => {}
+    }
+    Ok(match (lhs, rhs) {
+        (Value::Int(_), Value::Int(0)) | (Value::UInt(_), Value::UInt(0))
+            if *inst == VMInst::Div || *inst == VMInst::Rem =>
+        {
+            return Err("attempt to divide by zero".to_string())
+        }
+        (Value::Int(a), Value::Int(b)) => match *inst {
+            VMInst::Add => Value::Int(a.wrapping_add(b)),
+            VMInst::Sub => Value::Int(a.wrapping_sub(b)),
+            VMInst::Mul => Value::Int(a.wrapping_mul(b)),
+            VMInst::Div => Value::Int(a.wrapping_div(b)),
+            VMInst::Rem => Value::Int(a.wrapping_rem(b)),
+            VMInst::And => Value::Int(a & b),
+            VMInst::Or => Value::Int(a | b),
+            VMInst::Xor => Value::Int(a ^ b),
+            VMInst::Shl => Value::Int(a << check_shift(b)?),
+            VMInst::Shr => Value::Int(a >> check_shift(b)?),
+            VMInst::Lt => Value::Bool(a < b),
+            VMInst::Gt => Value::Bool(a > b),
+            VMInst::Le => Value::Bool(a <= b),
+            VMInst::Ge => Value::Bool(a >= b),
+            _ => return Err(format!("{:?}: unsupported operands", inst)),
+        },
+        (Value::UInt(a), Value::UInt(b)) => match *inst {
+            VMInst::Add => Value::UInt(a.wrapping_add(b)),
+            VMInst::Sub => Value::UInt(a.wrapping_sub(b)),
+            VMInst::Mul => Value::UInt(a.wrapping_mul(b)),
+            VMInst::Div => Value::UInt(a / b),
+            VMInst::Rem => Value::UInt(a % b),
+            VMInst::And => Value::UInt(a & b),
+            VMInst::Or => Value::UInt(a | b),
+            VMInst::Xor => Value::UInt(a ^ b),
+            VMInst::Shl => Value::UInt(a << check_shift(b as i64)?),
+            VMInst::Shr => Value::UInt(a >> check_shift(b as i64)?),
+            VMInst::Lt => Value::Bool(a < b),
+            VMInst::Gt => Value::Bool(a > b),
+            VMInst::Le => Value::Bool(a <= b),
+            VMInst::Ge => Value::Bool(a >= b),
+            _ => return Err(format!("{:?}: unsupported operands", inst)),
+        },
+        (Value::Float(a), Value::Float(b)) => match *inst {
+            VMInst::Add => Value::Float(a + b),
+            VMInst::Sub => Value::Float(a - b),
+            VMInst::Mul => Value::Float(a * b),
+            VMInst::Div => Value::Float(a / b),
+            VMInst::Rem => Value::Float(a % b),
+            VMInst::Lt => Value::Bool(a < b),
+            VMInst::Gt => Value::Bool(a > b),
+            VMInst::Le => Value::Bool(a <= b),
+            VMInst::Ge => Value::Bool(a >= b),
+            _ => return Err(format!("{:?}: unsupported operands", inst)),
+        },
+        (Value::Bool(a), Value::Bool(b)) => Value::Bool(match *inst {
+            VMInst::And => a & b,
+            VMInst::Or => a | b,
+            VMInst::Xor => a ^ b,
+            _ => return Err(format!("{:?}: unsupported operands", inst)),
+        }),
+        (Value::Object(a), Value::Object(b)) => match (&*a.borrow(), &*b.borrow()) {
+            (Object::String(a), Object::String(b)) => match *inst {
+                VMInst::Add => Value::new_string(format!("{}{}", a, b)),
+                VMInst::Lt => Value::Bool(a < b),
+                VMInst::Gt => Value::Bool(a > b),
+                VMInst::Le => Value::Bool(a <= b),
+                VMInst::Ge => Value::Bool(a >= b),
+                _ => return Err(format!("{:?}: unsupported operands", inst)),
+            },
+            _ => return Err(format!("{:?}: unsupported operands", inst)),
+        },
+        _ => return Err(format!("{:?}: unsupported operands", inst)),
+    })
+}
+
+// Converts an integer, float or bool to `ty` the way Rust's `as` does: integers are
+// truncated or extended, floats are rounded toward zero and saturated.
+pub fn cast_int(val: Value, ty: IntTy) -> Result<Value, String> {
+    if let Value::Float(f) = val {
+        return Ok(match ty {
+            IntTy::I8 => Value::Int(f as i8 as i64),
+            IntTy::I16 => Value::Int(f as i16 as i64),
+            IntTy::I32 => Value::Int(f as i32 as i64),
+            IntTy::I64 => Value::Int(f as i64),
+            IntTy::U8 => Value::UInt(f as u8 as u64),
+            IntTy::U16 => Value::UInt(f as u16 as u64),
+            IntTy::U32 => Value::UInt(f as u32 as u64),
+            IntTy::U64 => Value::UInt(f as u64),
+        });
+    }
+    let n = match val {
+        Value::Bool(b) => b as i64,
+        Value::Int(n) => n,
+        Value::UInt(n) => n as i64,
+        _ => return Err("IntCast: not a number".to_string()),
+    };
+    Ok(match ty {
+        IntTy::I8 => Value::Int(n as i8 as i64),
+        IntTy::I16 => Value::Int(n as i16 as i64),
+        IntTy::I32 => Value::Int(n as i32 as i64),
+        IntTy::I64 => Value::Int(n),
+        IntTy::U8 => Value::UInt(n as u8 as u64),
+        IntTy::U16 => Value::UInt(n as u16 as u64),
+        IntTy::U32 => Value::UInt(n as u32 as u64),
+        IntTy::U64 => Value::UInt(n as u64),
+    })
+}
+
+pub fn cast_float(val: Value, ty: FloatTy) -> Result<Value, String> {
+    Ok(Value::Float(match val {
+        Value::Int(n) => match ty {
+            FloatTy::F32 => n as f32 as f64,
+            FloatTy::F64 => n as f64,
+        },
+        Value::UInt(n) => match ty {
+            FloatTy::F32 => n as f32 as f64,
+            FloatTy::F64 => n as f64,
+        },
+        Value::Float(f) => match ty {
+            FloatTy::F32 => f as f32 as f64,
+            FloatTy::F64 => f,
+        },
+        _ => return Err("FloatCast: not a number".to_string()),
+    }))
+}
+
+fn check_shift(amount: i64) -> Result<u32, String> {
+    if !(0..64).contains(&amount) {
+        return Err(format!("attempt to shift by {}", amount));
+    }
+    Ok(amount as u32)
+}
+
+// Compiles `src` at `opt_level`, for the tests of the language and the tools around the VM
+#[cfg(test)]
+pub fn compile_source(src: &str, opt_level: u32) -> (Vec<VMInst>, DebugInfo) {
+    use lexer::Lexer;
+    use parser::Parser;
+    use codegen::Codegen;
+    let mut lexer = Lexer::new_from_string(src.to_string());
+    let mut parser = Parser::new(&mut lexer);
+    let mut codegen = Codegen::new(&mut parser);
+    codegen.opt_level = opt_level;
+    codegen.gen().unwrap();
+    (codegen.vm_insts, codegen.debug)
+}
+
+// Compiles and runs `src`, returning what it prints
+#[cfg(test)]
+pub fn run_source(src: &str) -> String {
+    let (insts, _) = compile_source(src, 1);
+    let mut vm = VM::new();
+    vm.output = Some(String::new());
+    vm.run(insts).unwrap();
+    vm.output.unwrap()
+}
+
+#[test]
+fn test_try() {
+    // A caught error leaves the stack as it was before the try
+    let src = "def check(n: int) -> int { if n < 0 { throw \"negative\" } else { n } }
+               x = try { 1 + try { check(-5) } catch e { throw e } } catch e { print(e)\n 0 }
+               print(x)";
+    let mut vm = VM::new();
+    vm.output = Some(String::new());
+    vm.run(compile_source(src, 1).0).unwrap();
+    assert_eq!(vm.output.unwrap(), "negative\n0\n");
+    assert_eq!(vm.sp, 0);
+
+    // An uncaught error ends the program with the value thrown
+    let src = "def check(n: int) -> int { if n < 0 { throw \"negative\" } else { n } }
+               print(check(1))
+               check(-1)
+               print(2)";
+    let (insts, debug) = compile_source(src, 1);
+    let mut vm = VM::new();
+    vm.output = Some(String::new());
+    vm.debug = debug;
+    let err = vm.run(insts).unwrap_err();
+    assert_eq!(vm.output.unwrap(), "1\n");
+    assert_eq!(
+        err.to_string(),
+        "uncaught error: negative\nbacktrace:\n    check\n    <top level>"
+    );
+}
+
+#[test]
+fn test_stack_overflow() {
+    let src = "def down(n: int) -> int { if n == 0 { 0 } else { 1 + down(n - 1) } }
+               print(down(2000))
+               def forever(n: int) -> int { forever(n + 1) }
+               def start() -> int { forever(0) }
+               try { start() } catch e { print(e) }
+               print(down(10))
+               start()";
+    let (insts, debug) = compile_source(src, 1);
+    let mut vm = VM::new();
+    vm.output = Some(String::new());
+    vm.stack_limit = 10000;
+    vm.debug = debug;
+    let err = vm.run(insts).unwrap_err();
+    assert_eq!(vm.output.unwrap(), "2000\nstack overflow\n10\n");
+    assert!(vm.stack.len() <= 10000);
+    assert_eq!(err.value, Value::new_string("stack overflow".to_string()));
+    assert!(err.backtrace.starts_with("backtrace:\n    forever ("));
+    assert!(err.backtrace.ends_with(" times)\n    start\n    <top level>"));
+
+    // A limit below the size the stack starts with holds too
+    let src = "def down(n: int) -> int { if n == 0 { 0 } else { 1 + down(n - 1) } }
+               print(down(10))
+               print(down(100))";
+    let mut vm = VM::new();
+    vm.output = Some(String::new());
+    vm.stack_limit = 50;
+    let err = vm.run(compile_source(src, 1).0).unwrap_err();
+    assert_eq!(vm.output.unwrap(), "10\n");
+    assert_eq!(err.value, Value::new_string("stack overflow".to_string()));
+
+    // Huge operands in unverified code are errors, before anything is allocated for them
+    let err = VM::new().run(vec![VMInst::Entry(usize::MAX), VMInst::Ret]).unwrap_err();
+    assert_eq!(err.value, Value::new_string("stack overflow".to_string()));
+    let insts = vec![VMInst::Entry(0), VMInst::PushI(1), VMInst::StoreG(1 << 40)];
+    let err = VM::new().run(insts).unwrap_err();
+    assert_eq!(err.value, Value::new_string("StoreG: too many globals".to_string()));
+}
+
+#[test]
+fn test_frame_layout() {
+    let insts = vec![
+        VMInst::Entry(1), // The top level, with one local
+        VMInst::PushI(7),
+        VMInst::StoreV(0),
+        VMInst::Pop,
+        VMInst::MakeClosure(11, 0),
+        VMInst::PushI(1),
+        VMInst::PushI(2),
+        VMInst::Apply(2),
+        VMInst::Call("print".to_string()),
+        VMInst::Pop,
+        VMInst::Ret,
+        VMInst::Entry(3), // f(a, b) with the locals a, b and c
+        VMInst::LoadV(0),
+        VMInst::LoadV(1),
+        VMInst::Sub,
+        VMInst::StoreV(2),
+        VMInst::Ret,
+    ];
+    let mut vm = VM::new();
+    vm.output = Some(String::new());
+    let run_until = |vm: &mut VM, pc: usize| {
+        while vm.pc != pc {
+            let inst = &insts[vm.pc];
+            vm.pc += 1;
+            vm.run_inst(inst).unwrap();
+        }
+    };
+    run_until(&mut vm, 12);
+    assert_eq!(vm.bp_stack, vec![0, 1]);
+    assert_eq!(vm.stack[1], Value::Int(7));
+    assert_eq!(vm.frames[0].base, 1);
+    assert_eq!(vm.frames[0].ret_pc, 8);
+    assert_eq!(vm.stack[vm.bp - 1], Value::new_closure(11, vec![]));
+    assert_eq!(vm.bp, 3);
+    assert_eq!(vm.stack[3..6], [Value::Int(1), Value::Int(2), Value::Int(0)]);
+    assert_eq!(vm.sp, 5);
+    run_until(&mut vm, 10);
+    assert_eq!((vm.bp, vm.sp), (1, 1));
+    assert_eq!(vm.stack[1], Value::Int(7));
+    run_until(&mut vm, 11);
+    assert_eq!((vm.bp, vm.sp), (0, 0));
+    assert_eq!(vm.output.unwrap(), "-1\n");
+}
+
+// Random straight-line functions, with calls nested in expressions and expression
+// statements, compute the same values as evaluating them directly
+#[test]
+fn test_straight_line_programs() {
+    enum Expr {
+        Int(i64),
+        Var(usize),
+        Op(char, Box<Expr>, Box<Expr>),
+        Call(Box<Expr>, Box<Expr>), // g(a, b) = a * 3 - b
+    }
+    impl Expr {
+        fn to_src(&self) -> String {
+            match *self {
+                Expr::Int(n) => n.to_string(),
+                Expr::Var(v) => format!("v{}", v),
+                Expr::Op(op, ref a, ref b) => format!("({} {} {})", a.to_src(), op, b.to_src()),
+                Expr::Call(ref a, ref b) => format!("g({}, {})", a.to_src(), b.to_src()),
+            }
+        }
+        fn eval(&self, vars: &[i64]) -> i64 {
+            match *self {
+                Expr::Int(n) => n,
+                Expr::Var(v) => vars[v],
+                Expr::Op('+', ref a, ref b) => a.eval(vars).wrapping_add(b.eval(vars)),
+                Expr::Op('-', ref a, ref b) => a.eval(vars).wrapping_sub(b.eval(vars)),
+                Expr::Op(_, ref a, ref b) => a.eval(vars).wrapping_mul(b.eval(vars)),
+                Expr::Call(ref a, ref b) => a.eval(vars).wrapping_mul(3).wrapping_sub(b.eval(vars)),
+            }
+        }
+    }
+
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut rand = |n: u64| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed % n
+    };
+    fn gen<R: FnMut(u64) -> u64>(rand: &mut R, nvars: usize, depth: usize) -> Expr {
+        match if depth == 0 { rand(2) } else { rand(5) } {
+            0 => Expr::Int(rand(20) as i64),
+            1 => Expr::Var(rand(nvars as u64) as usize),
+            2 => Expr::Call(
+                Box::new(gen(rand, nvars, depth - 1)),
+                Box::new(gen(rand, nvars, depth - 1)),
+            ),
+            _ => Expr::Op(
+                ['+', '-', '*'][rand(3) as usize],
+                Box::new(gen(rand, nvars, depth - 1)),
+                Box::new(gen(rand, nvars, depth - 1)),
+            ),
+        }
+    }
+
+    for _ in 0..30 {
+        let args = (rand(100) as i64, rand(100) as i64);
+        let mut vars = vec![args.0, args.1]; // The parameters first
+        let mut body = vec![];
+        let mut expected = String::new();
+        for _ in 0..8 {
+            let expr = gen(&mut rand, vars.len(), 3);
+            let val = expr.eval(&vars);
+            match rand(3) {
+                0 if vars.len() < 6 => {
+                    body.push(format!("v{} = {}", vars.len(), expr.to_src()));
+                    vars.push(val);
+                }
+                0 => {
+                    let v = rand(vars.len() as u64) as usize;
+                    body.push(format!("v{} = {}", v, expr.to_src()));
+                    vars[v] = val;
+                }
+                1 => {
+                    body.push(format!("print({})", expr.to_src()));
+                    expected.push_str(&format!("{}\n", val));
+                }
+                // Not starting with `(`, which would call the value of the previous line
+                _ => body.push(format!("v0 * {}", expr.to_src())),
             }
-            _ => {}
         }
+        let ret = gen(&mut rand, vars.len(), 2);
+        expected.push_str(&format!("{}\n", ret.eval(&vars)));
+        let src = format!(
+            "def g(a: int, b: int) -> int {{ c = a * 3 \n c - b }}
+             def f(v0: int, v1: int) -> int {{ {} \n r = {} \n r }}
+             print(f({}, {}))",
+            body.join("\n"),
+            ret.to_src(),
+            args.0,
+            args.1
+        );
+        let mut vm = VM::new();
+        vm.output = Some(String::new());
+        vm.run(compile_source(&src, 0).0).unwrap();
+        assert_eq!(vm.output.unwrap(), expected, "{}", src);
+        assert_eq!(vm.sp, 0);
     }
 }
@@ -1,6 +1,16 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Range;
+use std::rc::Rc;
+
+use typing::{FloatTy, IntTy};
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum VMInst {
+    PushB(bool),
     PushI(i64),
+    PushU(u64),
     PushF(f64),
     PushS(String),
 
@@ -8,15 +18,184 @@ pub enum VMInst {
 
     Call(String),
 
+    Not,
+    Neg,
+
+    IntCast(IntTy), // also used to wrap the result of arithmetic on narrow types
+    FloatCast(FloatTy),
+
     Add,
     Sub,
     Mul,
     Div,
     Rem,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
 
     StoreV(usize),
     LoadV(usize),
 
+    // Globals are the variables declared at the top level, shared by every function
+    StoreG(usize),
+    LoadG(usize),
+
+    // Locals captured by closures live in cells, shared with the closures
+    MakeCell(usize), // stores the top of the stack into a new cell in the local
+    StoreC(usize),
+    LoadC(usize),
+    StoreU(usize), // upvalues are the cells captured by the running closure
+    LoadU(usize),
+    CaptureU(usize),            // pushes the cell of an upvalue itself
+    MakeClosure(usize, usize),  // entry, number of cells to capture
+    Apply(usize),               // number of arguments; the callee is below them
+
+    NewVariant(usize, usize), // tag, number of fields
+    Field(usize),
+    SetField(usize), // pops a value and an object, and pushes the value back
+
+    NewMap(usize), // number of key-value pairs
+    Index,
+    SetIndex, // pops a value, a key and a map, and pushes the value back
+
+    Iter,
+    IterNext(usize), // jumps to the operand when the iterator is exhausted
+
+    Jmp(usize),
+    JmpIfFalse(usize),
+    JmpTable(Vec<usize>), // indexed by the tag of the popped variant
+
     Entry(usize),
     Ret,
+
+    // Errors thrown in the instructions between Try and EndTry jump to the operand of
+    // Try, with the stack restored and the thrown value pushed
+    Try(usize),
+    EndTry,
+    Throw,
+}
+
+// What bytecode was compiled from, for backtraces and the debugger. The code at the top
+// level counts as the function with the entry 0.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DebugInfo {
+    pub func_names: HashMap<usize, String>, // entry -> name
+    pub lines: Vec<(Range<usize>, usize)>,  // The instructions of a statement, and its line
+    pub locals: HashMap<usize, Vec<(String, usize)>>, // entry -> names and ids of locals
+    pub globals: Vec<String>,               // The names of the globals, by index
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Int(i64),  // i8, i16, i32 and i64, always within the range of their type
+    UInt(u64), // u8, u16, u32 and u64, likewise
+    Float(f64), // f32 is stored rounded to single precision
+    Object(Rc<RefCell<Object>>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Object {
+    String(String),
+    Variant(usize, Vec<Value>),
+    Map(BTreeMap<MapKey, Value>), // Iterated in key order, so output is reproducible
+    Iter(Vec<(Value, Value)>, usize),
+    Cell(Value),
+    Closure(usize, Vec<Value>), // entry, captured cells
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MapKey {
+    Int(i64),
+    String(String),
+}
+
+impl MapKey {
+    // Only ints and strings are keys; any other value is the error to throw
+    pub fn from_value(val: &Value) -> Result<MapKey, Value> {
+        match *val {
+            Value::Int(n) => Ok(MapKey::Int(n)),
+            Value::Object(ref obj) => match *obj.borrow() {
+                Object::String(ref s) => Ok(MapKey::String(s.clone())),
+                _ => Err(Value::new_string("invalid map key".to_string())),
+            },
+            _ => Err(Value::new_string("invalid map key".to_string())),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match *self {
+            MapKey::Int(n) => Value::Int(n),
+            MapKey::String(ref s) => Value::new_string(s.clone()),
+        }
+    }
+}
+
+impl Value {
+    pub fn new_string(s: String) -> Value {
+        Value::Object(Rc::new(RefCell::new(Object::String(s))))
+    }
+
+    pub fn new_variant(tag: usize, fields: Vec<Value>) -> Value {
+        Value::Object(Rc::new(RefCell::new(Object::Variant(tag, fields))))
+    }
+
+    pub fn new_map(map: BTreeMap<MapKey, Value>) -> Value {
+        Value::Object(Rc::new(RefCell::new(Object::Map(map))))
+    }
+
+    pub fn new_cell(val: Value) -> Value {
+        Value::Object(Rc::new(RefCell::new(Object::Cell(val))))
+    }
+
+    pub fn new_closure(entry: usize, cells: Vec<Value>) -> Value {
+        Value::Object(Rc::new(RefCell::new(Object::Closure(entry, cells))))
+    }
+
+    pub fn new_iter(entries: Vec<(Value, Value)>) -> Value {
+        Value::Object(Rc::new(RefCell::new(Object::Iter(entries, 0))))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::UInt(n) => write!(f, "{}", n),
+            Value::Float(n) => write!(f, "{}", n),
+            Value::Object(ref obj) => match *obj.borrow() {
+                Object::String(ref s) => write!(f, "{}", s),
+                Object::Variant(tag, ref fields) => {
+                    write!(f, "<variant {}", tag)?;
+                    for field in fields {
+                        write!(f, " {}", field)?;
+                    }
+                    write!(f, ">")
+                }
+                Object::Map(ref map) => {
+                    write!(f, "{{")?;
+                    for (i, (key, val)) in map.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{}: {}", key.to_value(), val)?;
+                    }
+                    write!(f, "}}")
+                }
+                Object::Iter(_, _) => write!(f, "<iterator>"),
+                Object::Cell(_) => write!(f, "<cell>"),
+                Object::Closure(entry, _) => write!(f, "<function at {}>", entry),
+            },
+        }
+    }
 }
@@ -0,0 +1,485 @@
+use vm_base::{DebugInfo, VMInst};
+use typing::{FloatTy, IntTy};
+
+use std::collections::HashMap;
+
+// Compiled programs, saved so that they can be run without compiling them again. A .xsc
+// file is laid out as
+//
+//     magic     b"\0XSC"
+//     version   u32, little-endian; files of other versions are rejected
+//     checksum  u64, little-endian: FNV-1a of the body
+//     body      the strings (string constants and names), the instructions, then the
+//               function names, lines, locals and globals of the debug info
+//
+// In the body, unsigned numbers (counts, operands and indices into the strings) are
+// LEB128, and i64 and f64 operands 8 bytes, little-endian.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bytecode {
+    pub insts: Vec<VMInst>,
+    pub debug: DebugInfo,
+}
+
+pub const MAGIC: &[u8] = b"\0XSC";
+pub const VERSION: u32 = 1;
+
+const HEADER_LEN: usize = 16;
+
+impl Bytecode {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Writer::new();
+        for inst in &self.insts {
+            body.inst(inst);
+        }
+        body.uint(self.debug.func_names.len());
+        let mut func_names: Vec<_> = self.debug.func_names.iter().collect();
+        func_names.sort();
+        for (&entry, name) in func_names {
+            body.uint(entry);
+            body.string(name);
+        }
+        body.uint(self.debug.lines.len());
+        for &(ref pcs, line) in &self.debug.lines {
+            body.uint(pcs.start);
+            body.uint(pcs.end);
+            body.uint(line);
+        }
+        body.uint(self.debug.locals.len());
+        let mut locals: Vec<_> = self.debug.locals.iter().collect();
+        locals.sort_by_key(|&(&entry, _)| entry);
+        for (&entry, names) in locals {
+            body.uint(entry);
+            body.uint(names.len());
+            for &(ref name, id) in names {
+                body.string(name);
+                body.uint(id);
+            }
+        }
+        body.uint(self.debug.globals.len());
+        for name in &self.debug.globals {
+            body.string(name);
+        }
+
+        // The strings come first, so that reading the rest can look them up
+        let mut strings = Writer::new();
+        strings.uint(body.strings.len());
+        for s in &body.strings {
+            strings.uint(s.len());
+            strings.bytes.extend_from_slice(s.as_bytes());
+        }
+        strings.uint(self.insts.len());
+        strings.bytes.extend(body.bytes);
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&checksum(&strings.bytes).to_le_bytes());
+        bytes.extend(strings.bytes);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Bytecode, String> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+            return Err("not a compiled XScript program".to_string());
+        }
+        let version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        if version != VERSION {
+            return Err(format!(
+                "compiled for bytecode version {}, but this xscript runs version {}; \
+                 compile the program again",
+                version, VERSION
+            ));
+        }
+        let mut sum = [0; 8];
+        sum.copy_from_slice(&bytes[8..HEADER_LEN]);
+        if u64::from_le_bytes(sum) != checksum(&bytes[HEADER_LEN..]) {
+            return Err("the file is corrupted (its checksum does not match)".to_string());
+        }
+
+        let mut reader = Reader {
+            bytes: &bytes[HEADER_LEN..],
+            pos: 0,
+            strings: vec![],
+        };
+        for _ in 0..reader.uint()? {
+            let len = reader.uint()?;
+            let s = String::from_utf8(reader.take(len)?.to_vec())
+                .map_err(|_| "a string is not valid UTF-8".to_string())?;
+            reader.strings.push(s);
+        }
+        let mut insts = vec![];
+        for _ in 0..reader.uint()? {
+            insts.push(reader.inst()?);
+        }
+        let mut debug = DebugInfo::default();
+        for _ in 0..reader.uint()? {
+            let entry = reader.uint()?;
+            debug.func_names.insert(entry, reader.string()?);
+        }
+        for _ in 0..reader.uint()? {
+            let pcs = reader.uint()?..reader.uint()?;
+            debug.lines.push((pcs, reader.uint()?));
+        }
+        for _ in 0..reader.uint()? {
+            let entry = reader.uint()?;
+            let mut names = vec![];
+            for _ in 0..reader.uint()? {
+                let name = reader.string()?;
+                names.push((name, reader.uint()?));
+            }
+            debug.locals.insert(entry, names);
+        }
+        for _ in 0..reader.uint()? {
+            let name = reader.string()?;
+            debug.globals.push(name);
+        }
+        if reader.pos != reader.bytes.len() {
+            return Err("unexpected data after the program".to_string());
+        }
+        Ok(Bytecode {
+            insts,
+            debug,
+        })
+    }
+}
+
+// 64-bit FNV-1a
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &b in bytes {
+        hash = (hash ^ u64::from(b)).wrapping_mul(0x100_0000_01b3);
+    }
+    hash
+}
+
+const INT_TYS: [IntTy; 8] = [
+    IntTy::I8,
+    IntTy::I16,
+    IntTy::I32,
+    IntTy::I64,
+    IntTy::U8,
+    IntTy::U16,
+    IntTy::U32,
+    IntTy::U64,
+];
+
+const FLOAT_TYS: [FloatTy; 2] = [FloatTy::F32, FloatTy::F64];
+
+struct Writer {
+    bytes: Vec<u8>,
+    strings: Vec<String>,
+    string_ids: HashMap<String, usize>,
+}
+
+impl Writer {
+    fn new() -> Writer {
+        Writer {
+            bytes: vec![],
+            strings: vec![],
+            string_ids: HashMap::new(),
+        }
+    }
+
+    fn uint(&mut self, mut n: usize) {
+        while n >= 0x80 {
+            self.bytes.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        self.bytes.push(n as u8)
+    }
+
+    // Each string is stored once, and referred to by its index
+    fn string(&mut self, s: &str) {
+        let id = match self.string_ids.get(s) {
+            Some(&id) => id,
+            None => {
+                self.strings.push(s.to_string());
+                self.string_ids.insert(s.to_string(), self.strings.len() - 1);
+                self.strings.len() - 1
+            }
+        };
+        self.uint(id)
+    }
+
+    fn op(&mut self, op: u8, operands: &[usize]) {
+        self.bytes.push(op);
+        for &n in operands {
+            self.uint(n);
+        }
+    }
+
+    fn inst(&mut self, inst: &VMInst) {
+        match *inst {
+            VMInst::PushB(b) => self.op(0, &[b as usize]),
+            VMInst::PushI(n) => {
+                self.op(1, &[]);
+                self.bytes.extend_from_slice(&n.to_le_bytes())
+            }
+            VMInst::PushU(n) => {
+                self.op(2, &[]);
+                self.bytes.extend_from_slice(&n.to_le_bytes())
+            }
+            VMInst::PushF(f) => {
+                self.op(3, &[]);
+                self.bytes.extend_from_slice(&f.to_bits().to_le_bytes())
+            }
+            VMInst::PushS(ref s) => {
+                self.op(4, &[]);
+                self.string(s)
+            }
+            VMInst::Pop => self.op(5, &[]),
+            VMInst::Call(ref name) => {
+                self.op(6, &[]);
+                self.string(name)
+            }
+            VMInst::Not => self.op(7, &[]),
+            VMInst::Neg => self.op(8, &[]),
+            VMInst::IntCast(ty) => {
+                let ty = INT_TYS.iter().position(|&t| t == ty).unwrap();
+                self.op(9, &[ty])
+            }
+            VMInst::FloatCast(ty) => {
+                let ty = FLOAT_TYS.iter().position(|&t| t == ty).unwrap();
+                self.op(10, &[ty])
+            }
+            VMInst::Add => self.op(11, &[]),
+            VMInst::Sub => self.op(12, &[]),
+            VMInst::Mul => self.op(13, &[]),
+            VMInst::Div => self.op(14, &[]),
+            VMInst::Rem => self.op(15, &[]),
+            VMInst::And => self.op(16, &[]),
+            VMInst::Or => self.op(17, &[]),
+            VMInst::Xor => self.op(18, &[]),
+            VMInst::Shl => self.op(19, &[]),
+            VMInst::Shr => self.op(20, &[]),
+            VMInst::Eq => self.op(21, &[]),
+            VMInst::Ne => self.op(22, &[]),
+            VMInst::Lt => self.op(23, &[]),
+            VMInst::Gt => self.op(24, &[]),
+            VMInst::Le => self.op(25, &[]),
+            VMInst::Ge => self.op(26, &[]),
+            VMInst::StoreV(n) => self.op(27, &[n]),
+            VMInst::LoadV(n) => self.op(28, &[n]),
+            VMInst::StoreG(n) => self.op(29, &[n]),
+            VMInst::LoadG(n) => self.op(30, &[n]),
+            VMInst::MakeCell(n) => self.op(31, &[n]),
+            VMInst::StoreC(n) => self.op(32, &[n]),
+            VMInst::LoadC(n) => self.op(33, &[n]),
+            VMInst::StoreU(n) => self.op(34, &[n]),
+            VMInst::LoadU(n) => self.op(35, &[n]),
+            VMInst::CaptureU(n) => self.op(36, &[n]),
+            VMInst::MakeClosure(entry, n) => self.op(37, &[entry, n]),
+            VMInst::Apply(n) => self.op(38, &[n]),
+            VMInst::NewVariant(tag, n) => self.op(39, &[tag, n]),
+            VMInst::Field(n) => self.op(40, &[n]),
+            VMInst::SetField(n) => self.op(41, &[n]),
+            VMInst::NewMap(n) => self.op(42, &[n]),
+            VMInst::Index => self.op(43, &[]),
+            VMInst::SetIndex => self.op(44, &[]),
+            VMInst::Iter => self.op(45, &[]),
+            VMInst::IterNext(pc) => self.op(46, &[pc]),
+            VMInst::Jmp(pc) => self.op(47, &[pc]),
+            VMInst::JmpIfFalse(pc) => self.op(48, &[pc]),
+            VMInst::JmpTable(ref pcs) => {
+                self.op(49, &[pcs.len()]);
+                for &pc in pcs {
+                    self.uint(pc);
+                }
+            }
+            VMInst::Entry(n) => self.op(50, &[n]),
+            VMInst::Ret => self.op(51, &[]),
+            VMInst::Try(pc) => self.op(52, &[pc]),
+            VMInst::EndTry => self.op(53, &[]),
+            VMInst::Throw => self.op(54, &[]),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    strings: Vec<String>,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.pos < len {
+            return Err("the file is truncated".to_string());
+        }
+        self.pos += len;
+        Ok(&self.bytes[self.pos - len..self.pos])
+    }
+
+    fn uint(&mut self) -> Result<usize, String> {
+        let mut n = 0usize;
+        let mut shift = 0;
+        loop {
+            let b = self.take(1)?[0];
+            if shift >= 64 {
+                return Err("a number is too large".to_string());
+            }
+            n |= ((b & 0x7f) as usize) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+            shift += 7;
+        }
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let id = self.uint()?;
+        match self.strings.get(id) {
+            Some(s) => Ok(s.clone()),
+            None => Err(format!("no string #{}", id)),
+        }
+    }
+
+    fn inst(&mut self) -> Result<VMInst, String> {
+        let op = self.take(1)?[0];
+        Ok(match op {
+            0 => VMInst::PushB(self.uint()? != 0),
+            1 => VMInst::PushI(self.u64()? as i64),
+            2 => VMInst::PushU(self.u64()?),
+            3 => VMInst::PushF(f64::from_bits(self.u64()?)),
+            4 => VMInst::PushS(self.string()?),
+            5 => VMInst::Pop,
+            6 => VMInst::Call(self.string()?),
+            7 => VMInst::Not,
+            8 => VMInst::Neg,
+            9 => match INT_TYS.get(self.uint()?) {
+                Some(&ty) => VMInst::IntCast(ty),
+                None => return Err("unknown integer type".to_string()),
+            },
+            10 => match FLOAT_TYS.get(self.uint()?) {
+                Some(&ty) => VMInst::FloatCast(ty),
+                None => return Err("unknown float type".to_string()),
+            },
+            11 => VMInst::Add,
+            12 => VMInst::Sub,
+            13 => VMInst::Mul,
+            14 => VMInst::Div,
+            15 => VMInst::Rem,
+            16 => VMInst::And,
+            17 => VMInst::Or,
+            18 => VMInst::Xor,
+            19 => VMInst::Shl,
+            20 => VMInst::Shr,
+            21 => VMInst::Eq,
+            22 => VMInst::Ne,
+            23 => VMInst::Lt,
+            24 => VMInst::Gt,
+            25 => VMInst::Le,
+            26 => VMInst::Ge,
+            27 => VMInst::StoreV(self.uint()?),
+            28 => VMInst::LoadV(self.uint()?),
+            29 => VMInst::StoreG(self.uint()?),
+            30 => VMInst::LoadG(self.uint()?),
+            31 => VMInst::MakeCell(self.uint()?),
+            32 => VMInst::StoreC(self.uint()?),
+            33 => VMInst::LoadC(self.uint()?),
+            34 => VMInst::StoreU(self.uint()?),
+            35 => VMInst::LoadU(self.uint()?),
+            36 => VMInst::CaptureU(self.uint()?),
+            37 => VMInst::MakeClosure(self.uint()?, self.uint()?),
+            38 => VMInst::Apply(self.uint()?),
+            39 => VMInst::NewVariant(self.uint()?, self.uint()?),
+            40 => VMInst::Field(self.uint()?),
+            41 => VMInst::SetField(self.uint()?),
+            42 => VMInst::NewMap(self.uint()?),
+            43 => VMInst::Index,
+            44 => VMInst::SetIndex,
+            45 => VMInst::Iter,
+            46 => VMInst::IterNext(self.uint()?),
+            47 => VMInst::Jmp(self.uint()?),
+            48 => VMInst::JmpIfFalse(self.uint()?),
+            49 => {
+                let mut pcs = vec![];
+                for _ in 0..self.uint()? {
+                    pcs.push(self.uint()?);
+                }
+                VMInst::JmpTable(pcs)
+            }
+            50 => VMInst::Entry(self.uint()?),
+            51 => VMInst::Ret,
+            52 => VMInst::Try(self.uint()?),
+            53 => VMInst::EndTry,
+            54 => VMInst::Throw,
+            op => return Err(format!("unknown instruction {}", op)),
+        })
+    }
+}
+
+#[test]
+fn test_xsc() {
+    use vm::{compile_source, VM};
+    let src = "enum Shape { Circle(int), Rect(int, int) }
+               def area(s: Shape) -> int {
+                 match s { Shape::Circle(r) => r * r * 3, Shape::Rect(w, h) => w * h }
+               }
+               names = {1: \"one\", 2: \"two\"}
+               for k, v in names { print(v) }
+               print(area(Shape::Circle(2)) + area(Shape::Rect(2, -3)))
+               half = 1.5 / 2.0
+               print(half)
+               small:u8 = 250
+               print(small + 10)
+               print(try { 1 / 0 } catch e { print(e)\n 0 })";
+    let (insts, debug) = compile_source(src, 1);
+    let bytecode = Bytecode {
+        insts,
+        debug,
+    };
+    let bytes = bytecode.encode();
+    assert_eq!(bytes, bytecode.encode());
+    let loaded = Bytecode::decode(&bytes).unwrap();
+    assert_eq!(loaded, bytecode);
+    let mut vm = VM::new();
+    vm.output = Some(String::new());
+    vm.run(loaded.insts).unwrap();
+    assert_eq!(vm.output.unwrap(), "one\ntwo\n6\n0.75\n4\nattempt to divide by zero\n0\n");
+
+    // Every kind of instruction, with operands that need several bytes
+    let insts = vec![
+        VMInst::PushB(true),
+        VMInst::PushI(-1 << 40),
+        VMInst::PushU(u64::MAX),
+        VMInst::PushF(-0.1),
+        VMInst::Call("print".to_string()),
+        VMInst::IntCast(IntTy::U16),
+        VMInst::FloatCast(FloatTy::F32),
+        VMInst::MakeClosure(300, 2),
+        VMInst::NewVariant(1, 0),
+        VMInst::JmpTable(vec![128, 16384, 0]),
+        VMInst::Entry(1 << 30),
+        VMInst::Throw,
+    ];
+    let bytecode = Bytecode {
+        insts,
+        debug: DebugInfo::default(),
+    };
+    assert_eq!(Bytecode::decode(&bytecode.encode()).unwrap(), bytecode);
+
+    let mut corrupted = bytes.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    assert_eq!(
+        Bytecode::decode(&corrupted),
+        Err("the file is corrupted (its checksum does not match)".to_string())
+    );
+    let mut newer = bytes.clone();
+    newer[4] += 1;
+    assert_eq!(
+        Bytecode::decode(&newer),
+        Err("compiled for bytecode version 2, but this xscript runs version 1; \
+             compile the program again"
+            .to_string())
+    );
+    assert_eq!(
+        Bytecode::decode(src.as_bytes()),
+        Err("not a compiled XScript program".to_string())
+    );
+}